@@ -35,7 +35,7 @@ use tower_http::auth::{AsyncAuthorizeRequest, AsyncRequireAuthorizationLayer};
 use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
 
-use cozo::{DataValue, DbInstance, format_error_as_json, MultiTransaction, NamedRows, ScriptMutability, SimpleFixedRule};
+use cozo::{DataValue, DbInstance, format_error_as_json, MultiTransaction, NamedRows, QueryLimits, ScriptMutability, SimpleFixedRule};
 
 #[derive(Args, Debug)]
 pub(crate) struct ServerArgs {
@@ -63,9 +63,66 @@ pub(crate) struct ServerArgs {
     #[clap(short = 'P', long, default_value_t = 9070)]
     port: u16,
 
-    /// When set, the content of the named table will be used as a token table
+    /// When set, the content of the named table will be used as a token table.
+    /// Columns `max_rows`, `max_memory` and `max_epochs` of the table, if present when
+    /// the server starts, hold the resource limits of queries made with each token,
+    /// which apply on top of the limits of the server
     #[clap(long)]
     token_table: Option<String>,
+
+    /// Maximal number of rows derived by a query, intermediate results included
+    #[clap(long)]
+    max_rows: Option<usize>,
+
+    /// Maximal memory in bytes held by the intermediate results of a query
+    #[clap(long)]
+    max_memory: Option<usize>,
+
+    /// Maximal number of epochs of evaluation of each stratum of a query
+    #[clap(long)]
+    max_epochs: Option<usize>,
+}
+
+const LIMIT_COLUMNS: [&str; 3] = ["max_rows", "max_memory", "max_epochs"];
+
+struct TokenTable {
+    name: String,
+    db: DbInstance,
+    // the columns of the table in `LIMIT_COLUMNS`
+    limit_columns: Vec<&'static str>,
+}
+
+impl TokenTable {
+    fn new(name: String, db: DbInstance) -> Self {
+        let limit_columns = match db.run_script(
+            &format!("::columns {name}"),
+            Default::default(),
+            ScriptMutability::Immutable,
+        ) {
+            Ok(cols) => LIMIT_COLUMNS
+                .into_iter()
+                .filter(|c| cols.rows.iter().any(|row| row[0].get_str() == Some(*c)))
+                .collect_vec(),
+            Err(_) => vec![],
+        };
+        Self {
+            name,
+            db,
+            limit_columns,
+        }
+    }
+    fn limits_from_row(&self, row: &[DataValue]) -> QueryLimits {
+        let mut limits = QueryLimits::default();
+        for (col, val) in self.limit_columns.iter().zip(row) {
+            let val = val.get_int().and_then(|i| usize::try_from(i).ok());
+            match *col {
+                "max_rows" => limits.max_rows = val,
+                "max_memory" => limits.max_memory = val,
+                _ => limits.max_epochs = val,
+            }
+        }
+        limits
+    }
 }
 
 #[derive(Clone)]
@@ -81,7 +138,7 @@ struct DbState {
 struct MyAuth {
     skip_auth: bool,
     auth_guard: String,
-    token_table: Option<Arc<(String, DbInstance)>>,
+    token_table: Option<Arc<TokenTable>>,
 }
 
 impl AsyncAuthorizeRequest<Body> for MyAuth
@@ -97,9 +154,11 @@ impl AsyncAuthorizeRequest<Body> for MyAuth
         Box::pin(async move {
             if skip_auth {
                 request.extensions_mut().insert(ScriptMutability::Mutable);
+                request.extensions_mut().insert(QueryLimits::default());
                 return Ok(request);
             }
 
+            let mut limits = QueryLimits::default();
             let mutability = match request.headers().get("x-cozo-auth") {
                 None => match request.uri().query() {
                     Some(q_str) => {
@@ -123,18 +182,20 @@ impl AsyncAuthorizeRequest<Body> for MyAuth
                     None => match token_table {
                         None => None,
                         Some(tt) => {
-                            let (name, db) = tt.as_ref();
+                            let TokenTable { name, db, limit_columns } = tt.as_ref();
+                            let cols = ["mutable"].iter().chain(limit_columns).join(", ");
                             if let Some(auth_header) = request.headers().get("Authorization") {
                                 if let Ok(auth_str) = auth_header.to_str() {
                                     if let Some(token) = auth_str.strip_prefix("Bearer ") {
                                         match db.run_script(
-                                            &format!("?[mutable] := *{name} {{ token: $token, mutable }}"),
+                                            &format!("?[{cols}] := *{name} {{ token: $token, {cols} }}"),
                                             BTreeMap::from([(String::from("token"), DataValue::from(token))]),
                                             ScriptMutability::Immutable,
                                         ) {
                                             Ok(rows) => match rows.rows.first() {
                                                 None => None,
                                                 Some(val) => {
+                                                    limits = tt.limits_from_row(&val[1..]);
                                                     if val[0].get_bool() == Some(true) {
                                                         Some(ScriptMutability::Mutable)
                                                     } else {
@@ -172,6 +233,7 @@ impl AsyncAuthorizeRequest<Body> for MyAuth
             };
             if let Some(mutability) = mutability {
                 request.extensions_mut().insert(mutability);
+                request.extensions_mut().insert(limits);
                 Ok(request)
             } else {
                 let unauthorized_response = Response::builder()
@@ -190,6 +252,11 @@ fn x() {}
 
 pub(crate) async fn server_main(args: ServerArgs) {
     let db = DbInstance::new(&args.engine, &args.path, &args.config).unwrap();
+    db.set_default_limits(QueryLimits {
+        max_rows: args.max_rows,
+        max_memory: args.max_memory,
+        max_epochs: args.max_epochs,
+    });
     if let Some(p) = &args.restore {
         if let Err(err) = db.restore_backup(p) {
             error!("{}", err);
@@ -225,7 +292,9 @@ pub(crate) async fn server_main(args: ServerArgs) {
     let auth_obj = MyAuth {
         skip_auth,
         auth_guard,
-        token_table: args.token_table.map(|t| Arc::new((t, db.clone()))),
+        token_table: args
+            .token_table
+            .map(|t| Arc::new(TokenTable::new(t, db.clone()))),
     };
 
     let state = DbState {
@@ -242,6 +311,7 @@ pub(crate) async fn server_main(args: ServerArgs) {
 
     let app = Router::new()
         .route("/text-query", post(text_query))
+        .route("/cypher-query", post(cypher_query))
         .route("/export/:relations", get(export_relations))
         .route("/import", put(import_relations))
         .route("/backup", post(backup))
@@ -364,6 +434,7 @@ struct QueryPayload {
 
 async fn text_query(
     Extension(mutability): Extension<ScriptMutability>,
+    Extension(limits): Extension<QueryLimits>,
     State(st): State<DbState>,
     Json(payload): Json<QueryPayload>,
 ) -> (StatusCode, Json<serde_json::Value>) {
@@ -377,7 +448,7 @@ async fn text_query(
         ScriptMutability::Immutable => true,
     };
     let result = spawn_blocking(move || {
-        st.db.run_script_fold_err(
+        st.db.run_script_with_limits_fold_err(
             &payload.script,
             params,
             if immutable {
@@ -385,6 +456,7 @@ async fn text_query(
             } else {
                 ScriptMutability::Mutable
             },
+            limits,
         )
     })
         .await;
@@ -394,6 +466,27 @@ async fn text_query(
     }
 }
 
+async fn cypher_query(
+    Extension(limits): Extension<QueryLimits>,
+    State(st): State<DbState>,
+    Json(payload): Json<QueryPayload>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let params = payload
+        .params
+        .into_iter()
+        .map(|(k, v)| (k, DataValue::from(v)))
+        .collect();
+    let result = spawn_blocking(move || {
+        st.db
+            .run_cypher_with_limits_fold_err(&payload.script, params, limits)
+    })
+    .await;
+    match result {
+        Ok(res) => wrap_json(res),
+        Err(err) => internal_error(err),
+    }
+}
+
 async fn export_relations(
     State(st): State<DbState>,
     Path(relations): Path<String>,
@@ -491,14 +584,23 @@ async fn backup(
 struct BackupImportPayload {
     path: String,
     relations: Vec<String>,
+    /// skips checking the constraints of the target relations
+    #[serde(default)]
+    skip_checks: bool,
 }
 
 async fn import_from_backup(
     State(st): State<DbState>,
     Json(payload): Json<BackupImportPayload>,
 ) -> (StatusCode, Json<serde_json::Value>) {
-    let result =
-        spawn_blocking(move || st.db.import_from_backup(&payload.path, &payload.relations)).await;
+    let result = spawn_blocking(move || {
+        st.db.import_from_backup_with_checks(
+            &payload.path,
+            &payload.relations,
+            !payload.skip_checks,
+        )
+    })
+    .await;
 
     match result {
         Ok(Ok(())) => {
@@ -6,42 +6,129 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
 
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
 use miette::{bail, ensure, miette, Result};
 use rand::prelude::*;
 
-use crate::data::value::DataValue;
+use crate::data::value::{DataValue, DecimalWrapper, Num};
 
 pub(crate) struct Aggregation {
-    pub(crate) name: &'static str,
+    pub(crate) name: Cow<'static, str>,
     pub(crate) is_meet: bool,
     pub(crate) meet_op: Option<Box<dyn MeetAggrObj>>,
     pub(crate) normal_op: Option<Box<dyn NormalAggrObj>>,
+    pub(crate) custom: Option<Arc<dyn CustomAggregation>>,
 }
 
 impl Clone for Aggregation {
     fn clone(&self) -> Self {
         Self {
-            name: self.name,
+            name: self.name.clone(),
             is_meet: self.is_meet,
             meet_op: None,
             normal_op: None,
+            custom: self.custom.clone(),
         }
     }
 }
 
-pub(crate) trait NormalAggrObj: Send + Sync {
+/// The state of a normal aggregation for a single group.
+pub trait NormalAggrObj: Send + Sync {
+    /// Feed a value into the aggregation.
     fn set(&mut self, value: &DataValue) -> Result<()>;
+    /// Get the aggregated result for the values fed so far.
     fn get(&self) -> Result<DataValue>;
 }
 
-pub(crate) trait MeetAggrObj: Send + Sync {
+/// The operation of a meet aggregation. A meet aggregation must be idempotent, commutative
+/// and associative (i.e. it computes the meet of a semilattice),
+/// which is what allows it to be used in recursive rules.
+pub trait MeetAggrObj: Send + Sync {
+    /// The value of the aggregation when no value has been seen.
     fn init_val(&self) -> DataValue;
+    /// Merge `right` into `left` in place, returning whether `left` has changed.
     fn update(&self, left: &mut DataValue, right: &DataValue) -> Result<bool>;
 }
 
+/// A custom aggregation that can be registered with the database,
+/// to be used in rule heads in the same way as the built-in ones.
+///
+/// Implementations only need to provide one of `meet_init` and `normal_init`:
+/// a meet aggregation is also usable as a normal aggregation
+/// (for example, when it is mixed with normal aggregations in the same rule head).
+pub trait CustomAggregation: Send + Sync {
+    /// Whether this is a meet aggregation. Only meet aggregations are allowed in recursive rules,
+    /// and `meet_init` must be implemented for them.
+    fn is_meet(&self) -> bool;
+    /// Create the operation of the meet aggregation. `args` are the extra constant arguments
+    /// given in the rule head, e.g. `my_aggr(x, 1, 2)`.
+    fn meet_init(&self, _args: &[DataValue]) -> Result<Box<dyn MeetAggrObj>> {
+        bail!("not a meet aggregation")
+    }
+    /// Create the state of the normal aggregation for a new group.
+    fn normal_init(&self, args: &[DataValue]) -> Result<Box<dyn NormalAggrObj>> {
+        Ok(Box::new(MeetAsNormalAggr::new(self.meet_init(args)?)))
+    }
+}
+
+/// Runs a meet aggregation as a normal aggregation.
+struct MeetAsNormalAggr {
+    op: Box<dyn MeetAggrObj>,
+    accum: DataValue,
+}
+
+impl MeetAsNormalAggr {
+    fn new(op: Box<dyn MeetAggrObj>) -> Self {
+        let accum = op.init_val();
+        Self { op, accum }
+    }
+}
+
+impl NormalAggrObj for MeetAsNormalAggr {
+    fn set(&mut self, value: &DataValue) -> Result<()> {
+        self.op.update(&mut self.accum, value)?;
+        Ok(())
+    }
+
+    fn get(&self) -> Result<DataValue> {
+        Ok(self.accum.clone())
+    }
+}
+
+/// Reject names of custom aggregations that would be shadowed by built-in ones.
+pub(crate) fn check_custom_aggr_name(name: &str) -> Result<()> {
+    if parse_aggr(name).is_some() {
+        bail!(
+            "Cannot register aggregation {}: it conflicts with a built-in aggregation",
+            name
+        )
+    }
+    Ok(())
+}
+
+/// Look up an aggregation by name, built-in ones first, then the given custom ones.
+pub(crate) fn get_aggr(
+    name: &str,
+    custom_aggrs: &BTreeMap<String, Arc<dyn CustomAggregation>>,
+) -> Option<Aggregation> {
+    if let Some(aggr) = parse_aggr(name) {
+        return Some(aggr.clone());
+    }
+    let custom = custom_aggrs.get(name).cloned()?;
+    Some(Aggregation {
+        name: Cow::Owned(name.to_string()),
+        is_meet: custom.is_meet(),
+        meet_op: None,
+        normal_op: None,
+        custom: Some(custom),
+    })
+}
+
 impl PartialEq for Aggregation {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
@@ -57,10 +144,11 @@ impl Debug for Aggregation {
 macro_rules! define_aggr {
     ($name:ident, $is_meet:expr) => {
         const $name: Aggregation = Aggregation {
-            name: stringify!($name),
+            name: Cow::Borrowed(stringify!($name)),
             is_meet: $is_meet,
             meet_op: None,
             normal_op: None,
+            custom: None,
         };
     };
 }
@@ -501,23 +589,59 @@ define_aggr!(AGGR_MEAN, false);
 #[derive(Default)]
 pub(crate) struct AggrMean {
     count: i64,
-    sum: f64,
+    sum: NumSum,
 }
 
 impl NormalAggrObj for AggrMean {
     fn set(&mut self, value: &DataValue) -> Result<()> {
+        self.sum.add(value, "mean")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn get(&self) -> Result<DataValue> {
+        Ok(match self.sum.exact() {
+            Some(sum) => DataValue::Decimal(DecimalWrapper(sum / self.count)),
+            None => DataValue::from(self.sum.float_sum / (self.count as f64)),
+        })
+    }
+}
+
+/// Sum of numbers that is kept exact when decimals are present, unless floats are also present
+#[derive(Default)]
+struct NumSum {
+    float_sum: f64,
+    int_sum: i128,
+    decimal_sum: Option<BigDecimal>,
+    has_float: bool,
+}
+
+impl NumSum {
+    fn add(&mut self, value: &DataValue, aggr_name: &str) -> Result<()> {
         match value {
-            DataValue::Num(n) => {
-                self.sum += n.get_float();
-                self.count += 1;
+            DataValue::Num(Num::Int(i)) => {
+                self.float_sum += *i as f64;
+                self.int_sum += *i as i128;
+            }
+            DataValue::Num(Num::Float(f)) => {
+                self.float_sum += *f;
+                self.has_float = true;
+            }
+            DataValue::Decimal(d) => {
+                self.float_sum += d.0.to_f64().unwrap_or(f64::NAN);
+                *self.decimal_sum.get_or_insert_with(BigDecimal::zero) += &d.0;
             }
-            v => bail!("cannot compute 'mean': encountered value {:?}", v),
+            v => bail!("cannot compute '{}': encountered value {:?}", aggr_name, v),
         }
         Ok(())
     }
-
-    fn get(&self) -> Result<DataValue> {
-        Ok(DataValue::from(self.sum / (self.count as f64)))
+    fn exact(&self) -> Option<BigDecimal> {
+        if self.has_float {
+            return None;
+        }
+        self.decimal_sum
+            .as_ref()
+            .map(|sum| (sum + self.int_sum).normalized())
     }
 }
 
@@ -525,22 +649,19 @@ define_aggr!(AGGR_SUM, false);
 
 #[derive(Default)]
 pub(crate) struct AggrSum {
-    sum: f64,
+    sum: NumSum,
 }
 
 impl NormalAggrObj for AggrSum {
     fn set(&mut self, value: &DataValue) -> Result<()> {
-        match value {
-            DataValue::Num(n) => {
-                self.sum += n.get_float();
-            }
-            v => bail!("cannot compute 'sum': encountered value {:?}", v),
-        }
-        Ok(())
+        self.sum.add(value, "sum")
     }
 
     fn get(&self) -> Result<DataValue> {
-        Ok(DataValue::from(self.sum))
+        Ok(match self.sum.exact() {
+            Some(sum) => DataValue::Decimal(DecimalWrapper(sum)),
+            None => DataValue::from(self.sum.float_sum),
+        })
     }
 }
 
@@ -1155,6 +1276,69 @@ impl NormalAggrObj for AggrBitXor {
     }
 }
 
+// Window aggregations do not collapse groups: they compute a value for every row of a
+// partition, with the rows ordered by the sort keys given after the constant arguments,
+// e.g. `top_n(order, 3, -ts)`. They are evaluated by `initial_rule_window_eval`.
+
+define_aggr!(AGGR_TOP_N, false);
+define_aggr!(AGGR_ROW_NUMBER, false);
+define_aggr!(AGGR_RANK, false);
+define_aggr!(AGGR_LAG, false);
+define_aggr!(AGGR_LEAD, false);
+
+/// Computes a window aggregation over the rows of a partition, already ordered by the sort keys.
+/// `vals` holds the aggregated value of each row and `keys` its sort keys. Rows for which `None`
+/// is returned are dropped from the result.
+pub(crate) fn window_eval(
+    aggr: &Aggregation,
+    args: &[DataValue],
+    vals: &[DataValue],
+    keys: &[Vec<DataValue>],
+) -> Result<Vec<Option<DataValue>>> {
+    Ok(match aggr.name.as_ref() {
+        name if name == AGGR_TOP_N.name => {
+            let n = args[0].get_int().ok_or_else(|| {
+                miette!(
+                    "the argument to 'top_n' must be an integer, got {:?}",
+                    args[0]
+                )
+            })?;
+            ensure!(n > 0, "argument to 'top_n' must be positive, got {}", n);
+            vals.iter()
+                .enumerate()
+                .map(|(i, v)| (i < n as usize).then(|| v.clone()))
+                .collect()
+        }
+        name if name == AGGR_ROW_NUMBER.name => (0..vals.len())
+            .map(|i| Some(DataValue::from(i as i64 + 1)))
+            .collect(),
+        name if name == AGGR_RANK.name => {
+            let mut ret = Vec::with_capacity(vals.len());
+            let mut rank = 0;
+            for i in 0..vals.len() {
+                if i == 0 || keys[i] != keys[i - 1] {
+                    rank = i as i64 + 1;
+                }
+                ret.push(Some(DataValue::from(rank)));
+            }
+            ret
+        }
+        name if name == AGGR_LAG.name => (0..vals.len())
+            .map(|i| {
+                Some(if i == 0 {
+                    DataValue::Null
+                } else {
+                    vals[i - 1].clone()
+                })
+            })
+            .collect(),
+        name if name == AGGR_LEAD.name => (0..vals.len())
+            .map(|i| Some(vals.get(i + 1).cloned().unwrap_or(DataValue::Null)))
+            .collect(),
+        name => unreachable!("{}", name),
+    })
+}
+
 pub(crate) fn parse_aggr(name: &str) -> Option<&'static Aggregation> {
     Some(match name {
         "and" => &AGGR_AND,
@@ -1182,13 +1366,70 @@ pub(crate) fn parse_aggr(name: &str) -> Option<&'static Aggregation> {
         "latest_by" => &AGGR_LATEST_BY,
         "smallest_by" => &AGGR_SMALLEST_BY,
         "choice_rand" => &AGGR_CHOICE_RAND,
+        "top_n" => &AGGR_TOP_N,
+        "row_number" => &AGGR_ROW_NUMBER,
+        "rank" => &AGGR_RANK,
+        "lag" => &AGGR_LAG,
+        "lead" => &AGGR_LEAD,
         _ => return None,
     })
 }
 
 impl Aggregation {
-    pub(crate) fn meet_init(&mut self, _args: &[DataValue]) -> Result<()> {
-        self.meet_op.replace(match self.name {
+    /// Whether this is a window aggregation, computing a value for each row of its partition.
+    pub(crate) fn is_window(&self) -> bool {
+        self.custom.is_none()
+            && [
+                AGGR_TOP_N.name,
+                AGGR_ROW_NUMBER.name,
+                AGGR_RANK.name,
+                AGGR_LAG.name,
+                AGGR_LEAD.name,
+            ]
+            .contains(&self.name)
+    }
+    /// The number of constant arguments of a window aggregation, which come before its sort keys.
+    pub(crate) fn window_const_args(&self) -> usize {
+        if self.name == AGGR_TOP_N.name {
+            1
+        } else {
+            0
+        }
+    }
+    /// The sort keys of a window aggregation, given as the names of the variables and whether
+    /// the order is descending.
+    pub(crate) fn window_sort_keys<'a>(&self, args: &'a [DataValue]) -> Vec<(&'a str, bool)> {
+        match &args[self.window_const_args()] {
+            DataValue::List(keys) => keys
+                .iter()
+                .map(|key| match key {
+                    DataValue::List(key) => match &key[..] {
+                        [DataValue::Str(name), DataValue::Bool(desc)] => (name.as_str(), *desc),
+                        _ => unreachable!(),
+                    },
+                    _ => unreachable!(),
+                })
+                .collect(),
+            _ => unreachable!(),
+        }
+    }
+    /// The positions in the rule head of the variables partitioning the rows of a window
+    /// aggregation.
+    pub(crate) fn window_partition(&self, args: &[DataValue]) -> Vec<usize> {
+        match &args[self.window_const_args() + 1] {
+            DataValue::List(positions) => positions
+                .iter()
+                .map(|pos| pos.get_int().unwrap() as usize)
+                .collect(),
+            _ => unreachable!(),
+        }
+    }
+    pub(crate) fn meet_init(&mut self, args: &[DataValue]) -> Result<()> {
+        if let Some(custom) = &self.custom {
+            self.meet_op.replace(custom.meet_init(args)?);
+            return Ok(());
+        }
+        self.meet_op.replace(match self.name.as_ref() {
             name if name == AGGR_AND.name => Box::new(MeetAggrAnd),
             name if name == AGGR_OR.name => Box::new(MeetAggrOr),
             name if name == AGGR_MIN.name => Box::new(MeetAggrMin),
@@ -1205,8 +1446,12 @@ impl Aggregation {
         Ok(())
     }
     pub(crate) fn normal_init(&mut self, args: &[DataValue]) -> Result<()> {
+        if let Some(custom) = &self.custom {
+            self.normal_op.replace(custom.normal_init(args)?);
+            return Ok(());
+        }
         #[allow(clippy::box_default)]
-        self.normal_op.replace(match self.name {
+        self.normal_op.replace(match self.name.as_ref() {
             name if name == AGGR_AND.name => Box::new(AggrAnd::default()),
             name if name == AGGR_OR.name => Box::new(AggrOr::default()),
             name if name == AGGR_COUNT.name => Box::new(AggrCount::default()),
@@ -10,6 +10,7 @@ use std::cmp::{max, min};
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::mem;
+use std::sync::Arc;
 
 use itertools::Itertools;
 use miette::{bail, miette, Diagnostic, Result};
@@ -19,7 +20,6 @@ use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::functions::*;
-use crate::data::relation::NullableColType;
 use crate::data::symb::Symbol;
 use crate::data::value::{DataValue, LARGEST_UTF_CHAR};
 use crate::parse::expr::expr2bytecode;
@@ -45,6 +45,15 @@ pub enum Bytecode {
         #[serde(skip)]
         span: SourceSpan,
     },
+    /// pop n, push 1
+    ApplyCustom {
+        name: SmartString<LazyCompact>,
+        arity: usize,
+        #[serde(skip)]
+        func: Option<CustomFunction>,
+        #[serde(skip)]
+        span: SourceSpan,
+    },
     /// pop 1
     JumpIfFalse {
         jump_to: usize,
@@ -135,6 +144,19 @@ pub fn eval_bytecode(
                 stack.push(result);
                 pointer += 1;
             }
+            Bytecode::ApplyCustom {
+                name,
+                arity,
+                func,
+                span,
+            } => {
+                let frame_start = stack.len() - *arity;
+                let args_frame = &stack[frame_start..];
+                let result = apply_custom_op(name, func, args_frame, *span)?;
+                stack.truncate(frame_start);
+                stack.push(result);
+                pointer += 1;
+            }
             Bytecode::JumpIfFalse { jump_to, span } => {
                 let val = stack.pop().unwrap();
                 let cond = val
@@ -182,12 +204,17 @@ pub enum Expr {
         #[serde(skip)]
         span: SourceSpan,
     },
-    /// Unbound function application
+    /// Application of a function that is not built-in
     UnboundApply {
         /// Op representing the function to apply
         op: SmartString<LazyCompact>,
         /// Arguments to the application
         args: Box<[Expr]>,
+        /// The custom function registered with the database under the name, if any.
+        /// Not persisted: expressions loaded from storage are bound again by
+        /// [Expr::bind_custom_functions].
+        #[serde(skip)]
+        func: Option<CustomFunction>,
         /// Source span
         #[serde(skip)]
         span: SourceSpan,
@@ -354,12 +381,69 @@ impl Expr {
                     val.fill_binding_indices(binding_map)?;
                 }
             }
-            Expr::UnboundApply { op, span, .. } => {
-                bail!(NoImplementationError(*span, op.to_string()));
+            Expr::UnboundApply {
+                op,
+                args,
+                func,
+                span,
+            } => {
+                ensure_custom_op(op, func, *span)?;
+                for arg in args.iter_mut() {
+                    arg.fill_binding_indices(binding_map)?;
+                }
             }
         }
         Ok(())
     }
+    /// Resolve the applications of non-built-in functions against the custom functions
+    /// registered with the database. Names not registered are left unresolved.
+    /// Renames the bindings found in `renames`, as when the columns of a relation are renamed.
+    pub(crate) fn rename_bindings(
+        &mut self,
+        renames: &BTreeMap<SmartString<LazyCompact>, SmartString<LazyCompact>>,
+    ) {
+        match self {
+            Expr::Binding { var, .. } => {
+                if let Some(name) = renames.get(&var.name) {
+                    var.name = name.clone();
+                }
+            }
+            Expr::Const { .. } => {}
+            Expr::Apply { args, .. } | Expr::UnboundApply { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.rename_bindings(renames);
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.rename_bindings(renames);
+                    val.rename_bindings(renames);
+                }
+            }
+        }
+    }
+    pub(crate) fn bind_custom_functions(&mut self, custom_fns: &BTreeMap<String, CustomFunction>) {
+        match self {
+            Expr::Binding { .. } | Expr::Const { .. } => {}
+            Expr::Apply { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.bind_custom_functions(custom_fns);
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.bind_custom_functions(custom_fns);
+                    val.bind_custom_functions(custom_fns);
+                }
+            }
+            Expr::UnboundApply { op, args, func, .. } => {
+                *func = custom_fns.get(op.as_str()).cloned();
+                for arg in args.iter_mut() {
+                    arg.bind_custom_functions(custom_fns);
+                }
+            }
+        }
+    }
     #[allow(dead_code)]
     pub(crate) fn binding_indices(&self) -> Result<BTreeSet<usize>> {
         let mut ret = BTreeSet::default();
@@ -390,8 +474,16 @@ impl Expr {
             //         clause.do_binding_indices(coll)
             //     }
             // }
-            Expr::UnboundApply { op, span, .. } => {
-                bail!(NoImplementationError(*span, op.to_string()));
+            Expr::UnboundApply {
+                op,
+                args,
+                func,
+                span,
+            } => {
+                ensure_custom_op(op, func, *span)?;
+                for arg in args.iter() {
+                    arg.do_binding_indices(coll)?;
+                }
             }
         }
         Ok(())
@@ -406,10 +498,29 @@ impl Expr {
         self.partial_eval()?;
         match self {
             Expr::Const { val, .. } => Ok(val),
+            // calls to impure functions are not folded, but are still constant here
+            e if e.bindings()?.is_empty() => e.eval([]),
             _ => bail!(NotConstError),
         }
     }
     pub(crate) fn partial_eval(&mut self) -> Result<()> {
+        if let Expr::UnboundApply {
+            args, func, span, ..
+        } = self
+        {
+            let span = *span;
+            let mut all_evaluated = true;
+            for arg in args.iter_mut() {
+                arg.partial_eval()?;
+                all_evaluated = all_evaluated && matches!(arg, Expr::Const { .. });
+            }
+            // unregistered names are left alone, they may not be functions at all (e.g. tokenizers),
+            // and functions not declared pure may give a different result on each call
+            if all_evaluated && func.as_ref().is_some_and(|f| f.inner.is_pure()) {
+                let val = self.eval([])?;
+                *self = Expr::Const { val, span };
+            }
+        }
         if let Expr::Apply { args, span, .. } = self {
             let span = *span;
             let mut all_evaluated = true;
@@ -467,8 +578,16 @@ impl Expr {
                     val.collect_bindings(coll)?;
                 }
             }
-            Expr::UnboundApply { op, span, .. } => {
-                bail!(NoImplementationError(*span, op.to_string()));
+            Expr::UnboundApply {
+                op,
+                args,
+                func,
+                span,
+            } => {
+                ensure_custom_op(op, func, *span)?;
+                for arg in args.iter() {
+                    arg.collect_bindings(coll)?;
+                }
             }
         }
         Ok(())
@@ -514,8 +633,17 @@ impl Expr {
                 }
                 Ok(DataValue::Null)
             }
-            Expr::UnboundApply { op, span, .. } => {
-                bail!(NoImplementationError(*span, op.to_string()));
+            Expr::UnboundApply {
+                op,
+                args,
+                func,
+                span,
+            } => {
+                let args: Box<[DataValue]> = args
+                    .iter()
+                    .map(|v| v.eval(bindings.as_ref()))
+                    .try_collect()?;
+                apply_custom_op(op, func, &args, *span)
             }
         }
     }
@@ -527,9 +655,10 @@ impl Expr {
                     if let Some(symb) = args[0].get_binding() {
                         if let Some(val) = args[1].get_const() {
                             if target == symb {
-                                let tar_val = match val.get_int() {
-                                    Some(i) => DataValue::from(i),
-                                    None => val.clone(),
+                                // decimals are kept exact
+                                let tar_val = match (val, val.get_int()) {
+                                    (DataValue::Num(_), Some(i)) => DataValue::from(i),
+                                    _ => val.clone(),
                                 };
                                 return Ok(ValueRange::lower_bound(tar_val));
                             }
@@ -538,9 +667,9 @@ impl Expr {
                     if let Some(symb) = args[1].get_binding() {
                         if let Some(val) = args[0].get_const() {
                             if target == symb {
-                                let tar_val = match val.get_float() {
-                                    Some(i) => DataValue::from(i),
-                                    None => val.clone(),
+                                let tar_val = match (val, val.get_float()) {
+                                    (DataValue::Num(_), Some(i)) => DataValue::from(i),
+                                    _ => val.clone(),
                                 };
                                 return Ok(ValueRange::upper_bound(tar_val));
                             }
@@ -552,9 +681,9 @@ impl Expr {
                     if let Some(symb) = args[0].get_binding() {
                         if let Some(val) = args[1].get_const() {
                             if target == symb {
-                                let tar_val = match val.get_float() {
-                                    Some(i) => DataValue::from(i),
-                                    None => val.clone(),
+                                let tar_val = match (val, val.get_float()) {
+                                    (DataValue::Num(_), Some(i)) => DataValue::from(i),
+                                    _ => val.clone(),
                                 };
 
                                 return Ok(ValueRange::upper_bound(tar_val));
@@ -564,9 +693,9 @@ impl Expr {
                     if let Some(symb) = args[1].get_binding() {
                         if let Some(val) = args[0].get_const() {
                             if target == symb {
-                                let tar_val = match val.get_int() {
-                                    Some(i) => DataValue::from(i),
-                                    None => val.clone(),
+                                let tar_val = match (val, val.get_int()) {
+                                    (DataValue::Num(_), Some(i)) => DataValue::from(i),
+                                    _ => val.clone(),
                                 };
 
                                 return Ok(ValueRange::lower_bound(tar_val));
@@ -602,8 +731,9 @@ impl Expr {
                 }
                 _ => ValueRange::default(),
             },
-            Expr::UnboundApply { op, span, .. } => {
-                bail!(NoImplementationError(*span, op.to_string()));
+            Expr::UnboundApply { op, func, span, .. } => {
+                ensure_custom_op(op, func, *span)?;
+                ValueRange::default()
             }
         })
     }
@@ -629,8 +759,16 @@ impl Expr {
                     act.do_get_variables(coll)?;
                 }
             }
-            Expr::UnboundApply { op, span, .. } => {
-                bail!(NoImplementationError(*span, op.to_string()));
+            Expr::UnboundApply {
+                op,
+                args,
+                func,
+                span,
+            } => {
+                ensure_custom_op(op, func, *span)?;
+                for arg in args.iter() {
+                    arg.do_get_variables(coll)?;
+                }
             }
         }
         Ok(())
@@ -732,13 +870,92 @@ pub struct Op {
     pub(crate) inner: fn(&[DataValue]) -> Result<DataValue>,
 }
 
-/// Used as `Arc<dyn CustomOp>`
-pub trait CustomOp {
-    fn name(&self) -> &'static str;
-    fn min_arity(&self) -> usize;
-    fn vararg(&self) -> bool;
-    fn return_type(&self) -> NullableColType;
+/// A scalar function implemented natively, to be registered with [crate::Db::register_function].
+/// Once registered, the function can be used in expressions just like the built-in ones.
+/// Closures of the signature `Fn(&[DataValue]) -> Result<DataValue>` implement this trait.
+pub trait CustomOp: Send + Sync {
+    /// Apply the function. The number of arguments is checked against the registered arity
+    /// before this is called.
     fn call(&self, args: &[DataValue]) -> Result<DataValue>;
+    /// Whether the function always gives the same result for the same arguments
+    /// and has no side effects. Only calls to pure functions with constant arguments
+    /// are evaluated once when the query is compiled.
+    fn is_pure(&self) -> bool {
+        false
+    }
+}
+
+impl<F> CustomOp for F
+where
+    F: Fn(&[DataValue]) -> Result<DataValue> + Send + Sync,
+{
+    fn call(&self, args: &[DataValue]) -> Result<DataValue> {
+        self(args)
+    }
+}
+
+/// A registered custom function together with its arity
+#[derive(Clone)]
+pub struct CustomFunction {
+    pub(crate) arity: usize,
+    pub(crate) inner: Arc<Box<dyn CustomOp>>,
+}
+
+impl Debug for CustomFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CustomFunction<{}>", self.arity)
+    }
+}
+
+impl PartialEq for CustomFunction {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for CustomFunction {}
+
+pub(crate) fn check_custom_op_name(name: &str) -> Result<()> {
+    if get_op(name).is_some() || name == "cond" || name == "if" {
+        bail!(
+            "Cannot register function {}: it conflicts with a built-in function",
+            name
+        )
+    }
+    Ok(())
+}
+
+fn apply_custom_op(
+    name: &str,
+    func: &Option<CustomFunction>,
+    args: &[DataValue],
+    span: SourceSpan,
+) -> Result<DataValue> {
+    let func = func
+        .as_ref()
+        .ok_or_else(|| NoImplementationError(span, name.to_string()))?;
+    if func.arity != args.len() {
+        bail!(EvalRaisedError(
+            span,
+            format!(
+                "function '{}' requires exactly {} argument(s), got {}",
+                name,
+                func.arity,
+                args.len()
+            )
+        ))
+    }
+    Ok(func
+        .inner
+        .call(args)
+        .map_err(|err| EvalRaisedError(span, err.to_string()))?)
+}
+
+fn ensure_custom_op(name: &str, func: &Option<CustomFunction>, span: SourceSpan) -> Result<()> {
+    if func.is_none() {
+        bail!(NoImplementationError(span, name.to_string()));
+    }
+    Ok(())
 }
 
 impl serde::Serialize for &'_ Op {
@@ -904,6 +1121,7 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "windows" => &OP_WINDOWS,
         "to_int" => &OP_TO_INT,
         "to_float" => &OP_TO_FLOAT,
+        "to_decimal" => &OP_TO_DECIMAL,
         "to_string" => &OP_TO_STRING,
         "l2_dist" => &OP_L2_DIST,
         "l2_normalize" => &OP_L2_NORMALIZE,
@@ -928,8 +1146,21 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "now" => &OP_NOW,
         "format_timestamp" => &OP_FORMAT_TIMESTAMP,
         "parse_timestamp" => &OP_PARSE_TIMESTAMP,
+        "to_date" => &OP_TO_DATE,
+        "to_datetime" => &OP_TO_DATETIME,
+        "to_duration" => &OP_TO_DURATION,
+        "date_trunc" => &OP_DATE_TRUNC,
+        "add_months" => &OP_ADD_MONTHS,
+        "extract" => &OP_EXTRACT,
         "vec" => &OP_VEC,
         "rand_vec" => &OP_RAND_VEC,
+        "to_geometry" => &OP_TO_GEOMETRY,
+        "st_point" => &OP_ST_POINT,
+        "st_as_geojson" => &OP_ST_AS_GEOJSON,
+        "st_distance" => &OP_ST_DISTANCE,
+        "st_contains" => &OP_ST_CONTAINS,
+        "st_intersects" => &OP_ST_INTERSECTS,
+        "st_dwithin" => &OP_ST_DWITHIN,
         _ => return None,
     })
 }
@@ -15,7 +15,9 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use chrono::{DateTime, TimeZone, Utc};
+use bigdecimal::{BigDecimal, RoundingMode, ToPrimitive, Zero};
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use itertools::Itertools;
 #[cfg(target_arch = "wasm32")]
 use js_sys::Date;
@@ -28,10 +30,15 @@ use unicode_normalization::UnicodeNormalization;
 use uuid::v1::Timestamp;
 
 use crate::data::expr::Op;
-use crate::data::json::JsonValue;
+use crate::data::json::{duration_to_json, JsonValue};
 use crate::data::relation::VecElementType;
+use crate::data::temporal::{
+    date_to_days, datetime_to_date, days_to_date, format_date, local_to_datetime, parse_date,
+    parse_duration, parse_tz, round_micros, secs_to_micros, MICROS_PER_DAY, MICROS_PER_SEC,
+};
 use crate::data::value::{
-    DataValue, JsonData, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs, Vector,
+    Coord, DataValue, DateTimeTz, DecimalWrapper, Geometry, JsonData, Num, RegexWrapper,
+    UuidWrapper, Validity, ValidityTs, Vector,
 };
 
 macro_rules! define_op {
@@ -57,6 +64,12 @@ fn ensure_same_value_type(a: &DataValue, b: &DataValue) -> Result<()> {
             | (Regex(_), Regex(_))
             | (List(_), List(_))
             | (Set(_), Set(_))
+            | (Date(_), Date(_))
+            | (DateTime(_), DateTime(_))
+            | (Duration(_), Duration(_))
+            | (Decimal(_), Decimal(_))
+            | (Num(_), Decimal(_))
+            | (Decimal(_), Num(_))
             | (Bot, Bot)
     ) {
         bail!(
@@ -259,6 +272,22 @@ fn to_json(d: &DataValue) -> JsonValue {
         DataValue::Validity(vld) => {
             json!([vld.timestamp.0, vld.is_assert.0])
         }
+        DataValue::Date(d) => {
+            json!(format_date(*d))
+        }
+        DataValue::DateTime(dt) => {
+            json!(dt.to_string())
+        }
+        DataValue::Duration(d) => duration_to_json(*d),
+        DataValue::Decimal(d) => {
+            json!(d.to_string())
+        }
+        DataValue::Geometry(g) => g.to_geojson(),
+        DataValue::Record(r) => JsonValue::Object(
+            r.0.iter()
+                .map(|(k, v)| (k.to_string(), to_json(v)))
+                .collect(),
+        ),
         DataValue::Bot => {
             json!(null)
         }
@@ -299,6 +328,9 @@ pub(crate) fn op_eq(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(f)), DataValue::Num(Num::Int(i)))
         | (DataValue::Num(Num::Int(i)), DataValue::Num(Num::Float(f))) => *i as f64 == *f,
+        (DataValue::Decimal(d), DataValue::Num(n)) | (DataValue::Num(n), DataValue::Decimal(d)) => {
+            d.approx_float() == n.get_float()
+        }
         (a, b) => a == b,
     }))
 }
@@ -335,6 +367,9 @@ pub(crate) fn op_neq(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(f)), DataValue::Num(Num::Int(i)))
         | (DataValue::Num(Num::Int(i)), DataValue::Num(Num::Float(f))) => *i as f64 != *f,
+        (DataValue::Decimal(d), DataValue::Num(n)) | (DataValue::Num(n), DataValue::Decimal(d)) => {
+            d.approx_float() != n.get_float()
+        }
         (a, b) => a != b,
     }))
 }
@@ -345,6 +380,8 @@ pub(crate) fn op_gt(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l > *r as f64,
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => *l as f64 > *r,
+        (DataValue::Decimal(l), DataValue::Num(r)) => l.approx_float() > r.get_float(),
+        (DataValue::Num(l), DataValue::Decimal(r)) => l.get_float() > r.approx_float(),
         (a, b) => a > b,
     }))
 }
@@ -355,6 +392,8 @@ pub(crate) fn op_ge(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l >= *r as f64,
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => *l as f64 >= *r,
+        (DataValue::Decimal(l), DataValue::Num(r)) => l.approx_float() >= r.get_float(),
+        (DataValue::Num(l), DataValue::Decimal(r)) => l.get_float() >= r.approx_float(),
         (a, b) => a >= b,
     }))
 }
@@ -365,6 +404,8 @@ pub(crate) fn op_lt(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l < (*r as f64),
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => (*l as f64) < *r,
+        (DataValue::Decimal(l), DataValue::Num(r)) => l.approx_float() < r.get_float(),
+        (DataValue::Num(l), DataValue::Decimal(r)) => l.get_float() < r.approx_float(),
         (a, b) => a < b,
     }))
 }
@@ -375,6 +416,8 @@ pub(crate) fn op_le(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l <= (*r as f64),
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => (*l as f64) <= *r,
+        (DataValue::Decimal(l), DataValue::Num(r)) => l.approx_float() <= r.get_float(),
+        (DataValue::Num(l), DataValue::Decimal(r)) => l.get_float() <= r.approx_float(),
         (a, b) => a <= b,
     }))
 }
@@ -388,6 +431,16 @@ pub(crate) fn op_add(args: &[DataValue]) -> Result<DataValue> {
             DataValue::Num(Num::Int(i)) => i_accum += i,
             DataValue::Num(Num::Float(f)) => f_accum += f,
             DataValue::Vec(_) => return add_vecs(args),
+            DataValue::Decimal(_) => {
+                return args[1..].iter().try_fold(args[0].clone(), |accum, nxt| {
+                    decimal_arith(&accum, nxt, "addition", |a, b| Ok(a + b), |a, b| a + b)
+                });
+            }
+            DataValue::Date(_) | DataValue::DateTime(_) | DataValue::Duration(_) => {
+                return args[1..]
+                    .iter()
+                    .try_fold(args[0].clone(), |accum, nxt| add_temporal(&accum, nxt));
+            }
             _ => bail!("addition requires numbers"),
         }
     }
@@ -398,6 +451,59 @@ pub(crate) fn op_add(args: &[DataValue]) -> Result<DataValue> {
     }
 }
 
+fn decimal_operand(v: &DataValue) -> Option<BigDecimal> {
+    match v {
+        DataValue::Decimal(d) => Some(d.0.clone()),
+        DataValue::Num(Num::Int(i)) => Some(BigDecimal::from(*i)),
+        _ => None,
+    }
+}
+
+/// Arithmetic between a decimal and another number. The result is an exact decimal
+/// if the other number is an integer or a decimal, and a float if it is a float.
+fn decimal_arith(
+    a: &DataValue,
+    b: &DataValue,
+    op_name: &str,
+    exact: impl FnOnce(BigDecimal, BigDecimal) -> Result<BigDecimal>,
+    inexact: impl FnOnce(f64, f64) -> f64,
+) -> Result<DataValue> {
+    match (decimal_operand(a), decimal_operand(b)) {
+        (Some(a), Some(b)) => Ok(DataValue::Decimal(DecimalWrapper(exact(a, b)?))),
+        _ => match (a.get_float(), b.get_float()) {
+            (Some(a), Some(b)) => Ok(DataValue::from(inexact(a, b))),
+            _ => bail!("{} requires numbers", op_name),
+        },
+    }
+}
+
+fn add_temporal(a: &DataValue, b: &DataValue) -> Result<DataValue> {
+    Ok(match (a, b) {
+        (DataValue::Duration(a), DataValue::Duration(b)) => DataValue::Duration(
+            a.checked_add(*b)
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        (DataValue::DateTime(dt), DataValue::Duration(d))
+        | (DataValue::Duration(d), DataValue::DateTime(dt)) => DataValue::DateTime(dt.shifted(*d)?),
+        (DataValue::Date(days), DataValue::Duration(d))
+        | (DataValue::Duration(d), DataValue::Date(days)) => {
+            DataValue::Date(shift_date(*days, *d)?)
+        }
+        _ => bail!("only durations can be added to dates, datetimes and durations"),
+    })
+}
+
+fn shift_date(days: i32, micros: i64) -> Result<i32> {
+    ensure!(
+        micros % MICROS_PER_DAY == 0,
+        "only whole days can be added to or subtracted from dates"
+    );
+    let shifted = i32::try_from(days as i64 + micros / MICROS_PER_DAY)
+        .map_err(|_| miette!("date out of range"))?;
+    days_to_date(shifted)?;
+    Ok(shifted)
+}
+
 fn add_vecs(args: &[DataValue]) -> Result<DataValue> {
     if args.len() == 1 {
         return Ok(args[0].clone());
@@ -537,6 +643,35 @@ pub(crate) fn op_sub(args: &[DataValue]) -> Result<DataValue> {
                 }
             }
         }
+        (DataValue::Duration(a), DataValue::Duration(b)) => DataValue::Duration(
+            a.checked_sub(*b)
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        (DataValue::DateTime(a), DataValue::DateTime(b)) => DataValue::Duration(
+            a.micros
+                .checked_sub(b.micros)
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        (DataValue::Date(a), DataValue::Date(b)) => DataValue::Duration(
+            (*a as i64 - *b as i64)
+                .checked_mul(MICROS_PER_DAY)
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        (DataValue::DateTime(dt), DataValue::Duration(d)) => {
+            let d = d
+                .checked_neg()
+                .ok_or_else(|| miette!("duration out of range"))?;
+            DataValue::DateTime(dt.shifted(d)?)
+        }
+        (DataValue::Date(days), DataValue::Duration(d)) => {
+            let d = d
+                .checked_neg()
+                .ok_or_else(|| miette!("duration out of range"))?;
+            DataValue::Date(shift_date(*days, d)?)
+        }
+        (a @ DataValue::Decimal(_), b) | (a, b @ DataValue::Decimal(_)) => {
+            decimal_arith(a, b, "subtraction", |a, b| Ok(a - b), |a, b| a - b)?
+        }
         _ => bail!("subtraction requires numbers"),
     })
 }
@@ -550,6 +685,18 @@ pub(crate) fn op_mul(args: &[DataValue]) -> Result<DataValue> {
             DataValue::Num(Num::Int(i)) => i_accum *= i,
             DataValue::Num(Num::Float(f)) => f_accum *= f,
             DataValue::Vec(_) => return mul_vecs(args),
+            DataValue::Duration(_) => return mul_duration(args),
+            DataValue::Decimal(_) => {
+                return args[1..].iter().try_fold(args[0].clone(), |accum, nxt| {
+                    decimal_arith(
+                        &accum,
+                        nxt,
+                        "multiplication",
+                        |a, b| Ok(a * b),
+                        |a, b| a * b,
+                    )
+                });
+            }
             _ => bail!("multiplication requires numbers"),
         }
     }
@@ -560,6 +707,31 @@ pub(crate) fn op_mul(args: &[DataValue]) -> Result<DataValue> {
     }
 }
 
+fn mul_duration(args: &[DataValue]) -> Result<DataValue> {
+    let mut duration = None;
+    let mut i_accum = 1i64;
+    let mut f_accum = 1.0f64;
+    for arg in args {
+        match arg {
+            DataValue::Duration(d) if duration.is_none() => duration = Some(*d),
+            DataValue::Num(Num::Int(i)) => i_accum *= i,
+            DataValue::Num(Num::Float(f)) => f_accum *= f,
+            _ => bail!("durations can only be multiplied by numbers"),
+        }
+    }
+    let d = duration.unwrap();
+    if f_accum == 1.0f64 {
+        Ok(DataValue::Duration(
+            d.checked_mul(i_accum)
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ))
+    } else {
+        Ok(DataValue::Duration(round_micros(
+            d as f64 * i_accum as f64 * f_accum,
+        )?))
+    }
+}
+
 fn mul_vecs(args: &[DataValue]) -> Result<DataValue> {
     if args.len() == 1 {
         return Ok(args[0].clone());
@@ -663,6 +835,22 @@ pub(crate) fn op_div(args: &[DataValue]) -> Result<DataValue> {
                 Vector::F64(v) => DataValue::Vec(Vector::F64(a / v)),
             }
         }
+        (DataValue::Duration(a), DataValue::Duration(b)) => {
+            DataValue::Num(Num::Float(*a as f64 / *b as f64))
+        }
+        (DataValue::Duration(a), DataValue::Num(b)) => {
+            DataValue::Duration(round_micros(*a as f64 / b.get_float())?)
+        }
+        (a @ DataValue::Decimal(_), b) | (a, b @ DataValue::Decimal(_)) => decimal_arith(
+            a,
+            b,
+            "division",
+            |a, b| {
+                ensure!(!b.is_zero(), "division of decimals by zero");
+                Ok(a / b)
+            },
+            |a, b| a / b,
+        )?,
         _ => bail!("division requires numbers"),
     })
 }
@@ -674,6 +862,11 @@ pub(crate) fn op_minus(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(-(*f))),
         DataValue::Vec(Vector::F64(v)) => DataValue::Vec(Vector::F64(0. - v)),
         DataValue::Vec(Vector::F32(v)) => DataValue::Vec(Vector::F32(0. - v)),
+        DataValue::Duration(d) => DataValue::Duration(
+            d.checked_neg()
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        DataValue::Decimal(d) => DataValue::Decimal(DecimalWrapper(-&d.0)),
         _ => bail!("minus can only be applied to numbers"),
     })
 }
@@ -685,6 +878,11 @@ pub(crate) fn op_abs(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.abs())),
         DataValue::Vec(Vector::F64(v)) => DataValue::Vec(Vector::F64(v.mapv(|x| x.abs()))),
         DataValue::Vec(Vector::F32(v)) => DataValue::Vec(Vector::F32(v.mapv(|x| x.abs()))),
+        DataValue::Duration(d) => DataValue::Duration(
+            d.checked_abs()
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        DataValue::Decimal(d) => DataValue::Decimal(DecimalWrapper(d.0.abs())),
         _ => bail!("'abs' requires numbers"),
     })
 }
@@ -713,6 +911,7 @@ pub(crate) fn op_floor(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.floor())),
+        DataValue::Decimal(d) => round_decimal(d, RoundingMode::Floor),
         _ => bail!("'floor' requires numbers"),
     })
 }
@@ -722,6 +921,7 @@ pub(crate) fn op_ceil(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.ceil())),
+        DataValue::Decimal(d) => round_decimal(d, RoundingMode::Ceiling),
         _ => bail!("'ceil' requires numbers"),
     })
 }
@@ -731,10 +931,15 @@ pub(crate) fn op_round(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.round())),
+        DataValue::Decimal(d) => round_decimal(d, RoundingMode::HalfUp),
         _ => bail!("'round' requires numbers"),
     })
 }
 
+fn round_decimal(d: &DecimalWrapper, mode: RoundingMode) -> DataValue {
+    DataValue::Decimal(DecimalWrapper(d.0.with_scale_round(0, mode).normalized()))
+}
+
 define_op!(OP_EXP, 1, false);
 pub(crate) fn op_exp(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
@@ -1781,7 +1986,21 @@ fn get_impl(args: &[DataValue]) -> Result<DataValue> {
             let res = json2val(res);
             Ok(res)
         }
-        _ => bail!("first argument to 'get' mut be a list or json"),
+        DataValue::Record(r) => match &args[1] {
+            DataValue::Str(s) => Ok(r
+                .get(s)
+                .ok_or_else(|| miette!("field '{}' not found in record", s))?
+                .clone()),
+            DataValue::Num(i) => {
+                let n = i
+                    .get_int()
+                    .ok_or_else(|| miette!("index '{}' not found in record", i))?;
+                let idx = get_index(n, r.0.len(), false)?;
+                Ok(r.0[idx].1.clone())
+            }
+            _ => bail!("second argument to 'get' mut be a string or integer"),
+        },
+        _ => bail!("first argument to 'get' mut be a list, json or record"),
     }
 }
 
@@ -1930,6 +2149,11 @@ pub(crate) fn op_to_bool(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Set(s) => !s.is_empty(),
         DataValue::Vec(_) => true,
         DataValue::Validity(vld) => vld.is_assert.0,
+        DataValue::Date(_) | DataValue::DateTime(_) => true,
+        DataValue::Duration(d) => *d != 0,
+        DataValue::Decimal(d) => !d.0.is_zero(),
+        DataValue::Geometry(_) => true,
+        DataValue::Record(r) => !r.0.is_empty(),
         DataValue::Bot => false,
         DataValue::Json(json) => match &json.0 {
             Value::Null => false,
@@ -1956,6 +2180,11 @@ pub(crate) fn op_to_unity(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Set(s) => i64::from(!s.is_empty()),
         DataValue::Vec(_) => 1,
         DataValue::Validity(vld) => i64::from(vld.is_assert.0),
+        DataValue::Date(_) | DataValue::DateTime(_) => 1,
+        DataValue::Duration(d) => i64::from(*d != 0),
+        DataValue::Decimal(d) => i64::from(!d.0.is_zero()),
+        DataValue::Geometry(_) => 1,
+        DataValue::Record(r) => i64::from(!r.0.is_empty()),
         DataValue::Bot => 0,
         DataValue::Json(json) => match &json.0 {
             Value::Null => 0,
@@ -1987,6 +2216,12 @@ pub(crate) fn op_to_int(args: &[DataValue]) -> Result<DataValue> {
                 .into()
         }
         DataValue::Validity(vld) => DataValue::Num(Num::Int(vld.timestamp.0 .0)),
+        DataValue::Decimal(d) => {
+            d.0.with_scale_round(0, RoundingMode::Down)
+                .to_i64()
+                .ok_or_else(|| miette!("The decimal is too large to be converted to int"))?
+                .into()
+        }
         v => bail!("'to_int' does not recognize {:?}", v),
     })
 }
@@ -2007,10 +2242,25 @@ pub(crate) fn op_to_float(args: &[DataValue]) -> Result<DataValue> {
                 .map_err(|_| miette!("The string cannot be interpreted as float"))?
                 .into(),
         },
+        DataValue::Decimal(d) => d.0.to_f64().unwrap_or(f64::NAN).into(),
         v => bail!("'to_float' does not recognize {:?}", v),
     })
 }
 
+define_op!(OP_TO_DECIMAL, 1, false);
+pub(crate) fn op_to_decimal(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Decimal(match &args[0] {
+        DataValue::Decimal(d) => d.clone(),
+        DataValue::Num(n) => DecimalWrapper(
+            n.get_decimal()
+                .ok_or_else(|| miette!("'to_decimal' cannot convert {} to decimal", n))?,
+        ),
+        DataValue::Str(s) => DecimalWrapper::from_str(s)
+            .map_err(|_| miette!("The string cannot be interpreted as decimal"))?,
+        v => bail!("'to_decimal' does not recognize {:?}", v),
+    }))
+}
+
 define_op!(OP_TO_STRING, 1, false);
 pub(crate) fn op_to_string(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::Str(val2str(&args[0]).into()))
@@ -2020,6 +2270,8 @@ fn val2str(arg: &DataValue) -> String {
     match arg {
         DataValue::Str(s) => s.to_string(),
         DataValue::Json(JsonData(JsonValue::String(s))) => s.clone(),
+        DataValue::Decimal(d) => d.to_string(),
+        DataValue::Geometry(g) => g.to_string(),
         v => {
             let jv = to_json(v);
             jv.to_string()
@@ -2524,6 +2776,191 @@ pub(crate) fn str2vld(s: &str) -> Result<ValidityTs> {
     Ok(ValidityTs(Reverse(microseconds as i64)))
 }
 
+define_op!(OP_TO_DATE, 1, false);
+pub(crate) fn op_to_date(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Date(match &args[0] {
+        DataValue::Date(d) => *d,
+        DataValue::Str(s) => match parse_date(s) {
+            Ok(d) => d,
+            Err(_) => datetime_to_date(&DateTimeTz::parse(s, Tz::UTC)?)?,
+        },
+        DataValue::DateTime(dt) => datetime_to_date(dt)?,
+        v => {
+            let secs = v.get_float().ok_or_else(|| {
+                miette!("'to_date' requires a string, a datetime or seconds since epoch")
+            })?;
+            datetime_to_date(&DateTimeTz::from_micros(secs_to_micros(secs)?, Tz::UTC)?)?
+        }
+    }))
+}
+
+define_op!(OP_TO_DATETIME, 1, true);
+pub(crate) fn op_to_datetime(args: &[DataValue]) -> Result<DataValue> {
+    let tz = match args.get(1) {
+        Some(tz_v) => {
+            let tz_s = tz_v
+                .get_str()
+                .ok_or_else(|| miette!("'to_datetime' timezone specification requires a string"))?;
+            Some(parse_tz(tz_s)?)
+        }
+        None => None,
+    };
+    let dt = match &args[0] {
+        DataValue::DateTime(dt) => dt.clone(),
+        DataValue::Str(s) => DateTimeTz::parse(s, tz.unwrap_or(Tz::UTC))?,
+        DataValue::Date(d) => {
+            let midnight = days_to_date(*d)?.and_hms_opt(0, 0, 0).unwrap();
+            local_to_datetime(midnight, tz.unwrap_or(Tz::UTC))?
+        }
+        DataValue::Validity(vld) => DateTimeTz::from_micros(vld.timestamp.0 .0, Tz::UTC)?,
+        v => {
+            let secs = v.get_float().ok_or_else(|| {
+                miette!("'to_datetime' requires a string, a date or seconds since epoch")
+            })?;
+            DateTimeTz::from_micros(secs_to_micros(secs)?, Tz::UTC)?
+        }
+    };
+    Ok(DataValue::DateTime(match tz {
+        Some(tz) => DateTimeTz::from_micros(dt.micros, tz)?,
+        None => dt,
+    }))
+}
+
+define_op!(OP_TO_DURATION, 1, false);
+pub(crate) fn op_to_duration(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Duration(match &args[0] {
+        DataValue::Duration(d) => *d,
+        DataValue::Str(s) => parse_duration(s)?,
+        v => {
+            let secs = v
+                .get_float()
+                .ok_or_else(|| miette!("'to_duration' requires a string or seconds"))?;
+            secs_to_micros(secs)?
+        }
+    }))
+}
+
+define_op!(OP_DATE_TRUNC, 2, false);
+pub(crate) fn op_date_trunc(args: &[DataValue]) -> Result<DataValue> {
+    let unit = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'date_trunc' requires a string as the unit"))?;
+    match &args[1] {
+        DataValue::Date(d) => Ok(DataValue::Date(date_to_days(trunc_date(
+            unit,
+            days_to_date(*d)?,
+        )?))),
+        DataValue::DateTime(dt) => {
+            let local = dt.to_chrono()?.naive_local();
+            let date = local.date();
+            let truncated = match unit {
+                "hour" => date.and_hms_opt(local.hour(), 0, 0),
+                "minute" => date.and_hms_opt(local.hour(), local.minute(), 0),
+                "second" => date.and_hms_opt(local.hour(), local.minute(), local.second()),
+                unit => trunc_date(unit, date)?.and_hms_opt(0, 0, 0),
+            };
+            let truncated = truncated.ok_or_else(|| miette!("bad time: {}", local))?;
+            Ok(DataValue::DateTime(local_to_datetime(
+                truncated,
+                dt.timezone()?,
+            )?))
+        }
+        _ => bail!("'date_trunc' requires a date or a datetime"),
+    }
+}
+
+fn trunc_date(unit: &str, date: NaiveDate) -> Result<NaiveDate> {
+    let truncated = match unit {
+        "year" => NaiveDate::from_ymd_opt(date.year(), 1, 1),
+        "quarter" => NaiveDate::from_ymd_opt(date.year(), date.month0() / 3 * 3 + 1, 1),
+        "month" => date.with_day(1),
+        "week" => {
+            let since_monday = date.weekday().num_days_from_monday();
+            date.checked_sub_days(Days::new(since_monday as u64))
+        }
+        "day" => Some(date),
+        _ => bail!("bad unit for truncating dates: {}", unit),
+    };
+    truncated.ok_or_else(|| miette!("date out of range"))
+}
+
+define_op!(OP_ADD_MONTHS, 2, false);
+pub(crate) fn op_add_months(args: &[DataValue]) -> Result<DataValue> {
+    let n = args[1]
+        .get_int()
+        .ok_or_else(|| miette!("'add_months' requires an integer as the number of months"))?;
+    let months = Months::new(
+        u32::try_from(n.unsigned_abs()).map_err(|_| miette!("too many months: {}", n))?,
+    );
+    match &args[0] {
+        DataValue::Date(d) => {
+            let date = days_to_date(*d)?;
+            let shifted = if n < 0 {
+                date.checked_sub_months(months)
+            } else {
+                date.checked_add_months(months)
+            };
+            let shifted = shifted.ok_or_else(|| miette!("date out of range"))?;
+            Ok(DataValue::Date(date_to_days(shifted)))
+        }
+        DataValue::DateTime(dt) => {
+            let local = dt.to_chrono()?.naive_local();
+            let shifted = if n < 0 {
+                local.checked_sub_months(months)
+            } else {
+                local.checked_add_months(months)
+            };
+            let shifted = shifted.ok_or_else(|| miette!("datetime out of range"))?;
+            Ok(DataValue::DateTime(local_to_datetime(
+                shifted,
+                dt.timezone()?,
+            )?))
+        }
+        _ => bail!("'add_months' requires a date or a datetime"),
+    }
+}
+
+define_op!(OP_EXTRACT, 2, false);
+pub(crate) fn op_extract(args: &[DataValue]) -> Result<DataValue> {
+    let field = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'extract' requires a string as the field"))?;
+    let (date, time, epoch_micros) = match &args[1] {
+        DataValue::Date(d) => (days_to_date(*d)?, None, *d as i64 * MICROS_PER_DAY),
+        DataValue::DateTime(dt) => {
+            let local = dt.to_chrono()?.naive_local();
+            (local.date(), Some(local.time()), dt.micros)
+        }
+        DataValue::Duration(d) => {
+            return match field {
+                "epoch" => Ok(DataValue::from(*d as f64 / MICROS_PER_SEC as f64)),
+                _ => bail!("only 'epoch' can be extracted from durations"),
+            }
+        }
+        _ => bail!("'extract' requires a date, a datetime or a duration"),
+    };
+    Ok(match field {
+        "year" => DataValue::from(date.year() as i64),
+        "quarter" => DataValue::from((date.month0() / 3 + 1) as i64),
+        "month" => DataValue::from(date.month() as i64),
+        "day" => DataValue::from(date.day() as i64),
+        "dow" => DataValue::from(date.weekday().num_days_from_sunday() as i64),
+        "isodow" => DataValue::from(date.weekday().number_from_monday() as i64),
+        "doy" => DataValue::from(date.ordinal() as i64),
+        "week" => DataValue::from(date.iso_week().week() as i64),
+        "epoch" => DataValue::from(epoch_micros as f64 / MICROS_PER_SEC as f64),
+        "hour" | "minute" | "second" => {
+            let time = time.ok_or_else(|| miette!("cannot extract '{}' from a date", field))?;
+            match field {
+                "hour" => DataValue::from(time.hour() as i64),
+                "minute" => DataValue::from(time.minute() as i64),
+                _ => DataValue::from(time.second() as f64 + time.nanosecond() as f64 / 1e9),
+            }
+        }
+        _ => bail!("unknown field for 'extract': {}", field),
+    })
+}
+
 define_op!(OP_RAND_UUID_V1, 0, false);
 pub(crate) fn op_rand_uuid_v1(_args: &[DataValue]) -> Result<DataValue> {
     let mut rng = rand::thread_rng();
@@ -2585,3 +3022,72 @@ pub(crate) fn op_validity(args: &[DataValue]) -> Result<DataValue> {
         is_assert: Reverse(is_assert),
     }))
 }
+
+define_op!(OP_TO_GEOMETRY, 1, false);
+pub(crate) fn op_to_geometry(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Geometry(match &args[0] {
+        DataValue::Geometry(g) => g.clone(),
+        DataValue::Str(s) => Geometry::parse(s)?,
+        DataValue::Json(j) => Geometry::from_geojson(&j.0)?,
+        v => bail!("'to_geometry' does not recognize {:?}", v),
+    }))
+}
+
+define_op!(OP_ST_POINT, 2, false);
+pub(crate) fn op_st_point(args: &[DataValue]) -> Result<DataValue> {
+    let x = args[0]
+        .get_float()
+        .ok_or_else(|| miette!("'st_point' requires numbers"))?;
+    let y = args[1]
+        .get_float()
+        .ok_or_else(|| miette!("'st_point' requires numbers"))?;
+    ensure!(
+        x.is_finite() && y.is_finite(),
+        "'st_point' requires finite coordinates"
+    );
+    Ok(DataValue::Geometry(Geometry::Point(Coord { x, y })))
+}
+
+fn get_geometry<'a>(arg: &'a DataValue, op_name: &str) -> Result<&'a Geometry> {
+    match arg {
+        DataValue::Geometry(g) => Ok(g),
+        v => bail!("'{}' requires geometries, got {:?}", op_name, v),
+    }
+}
+
+define_op!(OP_ST_AS_GEOJSON, 1, false);
+pub(crate) fn op_st_as_geojson(args: &[DataValue]) -> Result<DataValue> {
+    let g = get_geometry(&args[0], "st_as_geojson")?;
+    Ok(DataValue::Json(JsonData(g.to_geojson())))
+}
+
+define_op!(OP_ST_DISTANCE, 2, false);
+pub(crate) fn op_st_distance(args: &[DataValue]) -> Result<DataValue> {
+    let a = get_geometry(&args[0], "st_distance")?;
+    let b = get_geometry(&args[1], "st_distance")?;
+    Ok(DataValue::from(a.distance(b)))
+}
+
+define_op!(OP_ST_CONTAINS, 2, false);
+pub(crate) fn op_st_contains(args: &[DataValue]) -> Result<DataValue> {
+    let a = get_geometry(&args[0], "st_contains")?;
+    let b = get_geometry(&args[1], "st_contains")?;
+    Ok(DataValue::from(a.contains(b)))
+}
+
+define_op!(OP_ST_INTERSECTS, 2, false);
+pub(crate) fn op_st_intersects(args: &[DataValue]) -> Result<DataValue> {
+    let a = get_geometry(&args[0], "st_intersects")?;
+    let b = get_geometry(&args[1], "st_intersects")?;
+    Ok(DataValue::from(a.intersects(b)))
+}
+
+define_op!(OP_ST_DWITHIN, 3, false);
+pub(crate) fn op_st_dwithin(args: &[DataValue]) -> Result<DataValue> {
+    let a = get_geometry(&args[0], "st_dwithin")?;
+    let b = get_geometry(&args[1], "st_dwithin")?;
+    let d = args[2]
+        .get_float()
+        .ok_or_else(|| miette!("'st_dwithin' requires a number as the distance"))?;
+    Ok(DataValue::from(a.distance(b) <= d))
+}
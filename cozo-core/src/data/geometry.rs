@@ -0,0 +1,531 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Planar geometry: parsing and formatting as WKT and GeoJSON, and the spatial predicates.
+//! All computations are done on the plane, coordinates are never interpreted as lon/lat.
+
+use std::fmt::{Display, Formatter};
+
+use itertools::Itertools;
+use miette::{bail, ensure, miette, Result};
+use serde_json::json;
+
+use crate::data::json::JsonValue;
+use crate::data::value::{Coord, Geometry};
+
+/// Bounding box as `[min_x, min_y, max_x, max_y]`
+pub(crate) type BBox = [f64; 4];
+
+pub(crate) fn bbox_intersects(a: &BBox, b: &BBox) -> bool {
+    a[0] <= b[2] && b[0] <= a[2] && a[1] <= b[3] && b[1] <= a[3]
+}
+
+pub(crate) fn bbox_union(a: &BBox, b: &BBox) -> BBox {
+    [
+        a[0].min(b[0]),
+        a[1].min(b[1]),
+        a[2].max(b[2]),
+        a[3].max(b[3]),
+    ]
+}
+
+pub(crate) fn bbox_area(a: &BBox) -> f64 {
+    (a[2] - a[0]) * (a[3] - a[1])
+}
+
+/// The smallest distance between any two points of the boxes
+pub(crate) fn bbox_distance(a: &BBox, b: &BBox) -> f64 {
+    let dx = (b[0] - a[2]).max(a[0] - b[2]).max(0.);
+    let dy = (b[1] - a[3]).max(a[1] - b[3]).max(0.);
+    dx.hypot(dy)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Location {
+    Inside,
+    Boundary,
+    Outside,
+}
+
+fn sub(a: Coord, b: Coord) -> (f64, f64) {
+    (a.x - b.x, a.y - b.y)
+}
+
+/// Twice the signed area of the triangle `abc`, positive if it turns counter-clockwise
+fn orient(a: Coord, b: Coord, c: Coord) -> f64 {
+    let (abx, aby) = sub(b, a);
+    let (acx, acy) = sub(c, a);
+    abx * acy - aby * acx
+}
+
+/// Whether `p`, known to be collinear with `a` and `b`, lies between them
+fn within_span(p: Coord, a: Coord, b: Coord) -> bool {
+    p.x >= a.x.min(b.x) && p.x <= a.x.max(b.x) && p.y >= a.y.min(b.y) && p.y <= a.y.max(b.y)
+}
+
+fn on_segment(p: Coord, a: Coord, b: Coord) -> bool {
+    orient(a, b, p) == 0. && within_span(p, a, b)
+}
+
+/// Whether the closed segments `ab` and `cd` share a point. Points are degenerate segments.
+fn segments_intersect(a: Coord, b: Coord, c: Coord, d: Coord) -> bool {
+    let d1 = orient(c, d, a);
+    let d2 = orient(c, d, b);
+    let d3 = orient(a, b, c);
+    let d4 = orient(a, b, d);
+    if d1 * d2 < 0. && d3 * d4 < 0. {
+        return true;
+    }
+    (d1 == 0. && within_span(a, c, d))
+        || (d2 == 0. && within_span(b, c, d))
+        || (d3 == 0. && within_span(c, a, b))
+        || (d4 == 0. && within_span(d, a, b))
+}
+
+fn point_segment_distance(p: Coord, a: Coord, b: Coord) -> f64 {
+    let (abx, aby) = sub(b, a);
+    let len2 = abx * abx + aby * aby;
+    let t = if len2 == 0. {
+        0.
+    } else {
+        let (apx, apy) = sub(p, a);
+        ((apx * abx + apy * aby) / len2).clamp(0., 1.)
+    };
+    (a.x + t * abx - p.x).hypot(a.y + t * aby - p.y)
+}
+
+fn segment_distance(a: Coord, b: Coord, c: Coord, d: Coord) -> f64 {
+    if segments_intersect(a, b, c, d) {
+        return 0.;
+    }
+    point_segment_distance(a, c, d)
+        .min(point_segment_distance(b, c, d))
+        .min(point_segment_distance(c, a, b))
+        .min(point_segment_distance(d, a, b))
+}
+
+/// The position of `p` along the segment `ab`, as a fraction of its length
+fn segment_param(p: Coord, a: Coord, b: Coord) -> f64 {
+    let (abx, aby) = sub(b, a);
+    let (apx, apy) = sub(p, a);
+    (apx * abx + apy * aby) / (abx * abx + aby * aby)
+}
+
+fn segment_point(a: Coord, b: Coord, t: f64) -> Coord {
+    Coord {
+        x: a.x + t * (b.x - a.x),
+        y: a.y + t * (b.y - a.y),
+    }
+}
+
+/// Parameters along `ab` at which it meets `cd`
+fn crossing_params(a: Coord, b: Coord, c: Coord, d: Coord, out: &mut Vec<f64>) {
+    if !segments_intersect(a, b, c, d) {
+        return;
+    }
+    let (abx, aby) = sub(b, a);
+    let (cdx, cdy) = sub(d, c);
+    let denom = abx * cdy - aby * cdx;
+    if denom != 0. {
+        let (acx, acy) = sub(c, a);
+        out.push((acx * cdy - acy * cdx) / denom);
+    } else {
+        for p in [c, d] {
+            if on_segment(p, a, b) {
+                out.push(segment_param(p, a, b));
+            }
+        }
+    }
+}
+
+fn ring_segments(ring: &[Coord]) -> impl Iterator<Item = (Coord, Coord)> + '_ {
+    ring.iter().copied().tuple_windows()
+}
+
+fn locate_in_ring(ring: &[Coord], p: Coord) -> Location {
+    let mut inside = false;
+    for (a, b) in ring_segments(ring) {
+        if on_segment(p, a, b) {
+            return Location::Boundary;
+        }
+        if (a.y > p.y) != (b.y > p.y) && p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+            inside = !inside;
+        }
+    }
+    if inside {
+        Location::Inside
+    } else {
+        Location::Outside
+    }
+}
+
+fn locate_in_polygon(rings: &[Vec<Coord>], p: Coord) -> Location {
+    let (exterior, holes) = rings.split_first().unwrap();
+    match locate_in_ring(exterior, p) {
+        Location::Inside => {}
+        loc => return loc,
+    }
+    for hole in holes {
+        match locate_in_ring(hole, p) {
+            Location::Outside => {}
+            Location::Inside => return Location::Outside,
+            Location::Boundary => return Location::Boundary,
+        }
+    }
+    Location::Inside
+}
+
+fn parse_number(s: &str, src: &str) -> Result<f64> {
+    let f: f64 = s
+        .parse()
+        .map_err(|_| miette!("bad coordinate '{}' in geometry: {}", s, src))?;
+    ensure!(f.is_finite(), "coordinates must be finite: {}", src);
+    Ok(f)
+}
+
+fn parse_wkt_coord(s: &str, src: &str) -> Result<Coord> {
+    let parts = s.split_whitespace().collect_vec();
+    ensure!(
+        parts.len() == 2,
+        "expect two coordinates in '{}': {}",
+        s.trim(),
+        src
+    );
+    Ok(Coord {
+        x: parse_number(parts[0], src)?,
+        y: parse_number(parts[1], src)?,
+    })
+}
+
+/// Strips one pair of enclosing parentheses
+fn strip_parens<'a>(s: &'a str, src: &str) -> Result<&'a str> {
+    s.trim()
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix(')'))
+        .ok_or_else(|| miette!("bad geometry: {}", src))
+}
+
+fn parse_wkt_path(s: &str, src: &str) -> Result<Vec<Coord>> {
+    strip_parens(s, src)?
+        .split(',')
+        .map(|c| parse_wkt_coord(c, src))
+        .try_collect()
+}
+
+fn check_path(path: Vec<Coord>) -> Result<Vec<Coord>> {
+    ensure!(path.len() >= 2, "a linestring needs at least two points");
+    Ok(path)
+}
+
+/// Closes the ring if the last point is not the first
+fn check_ring(mut ring: Vec<Coord>) -> Result<Vec<Coord>> {
+    if ring.first() != ring.last() {
+        ring.push(ring[0]);
+    }
+    ensure!(
+        ring.len() >= 4,
+        "a polygon ring needs at least three points"
+    );
+    Ok(ring)
+}
+
+fn check_rings(rings: Vec<Vec<Coord>>) -> Result<Vec<Vec<Coord>>> {
+    ensure!(!rings.is_empty(), "a polygon needs at least one ring");
+    rings.into_iter().map(check_ring).try_collect()
+}
+
+fn geojson_coord(v: &JsonValue) -> Result<Coord> {
+    let make_err = || miette!("bad GeoJSON position: {}", v);
+    let arr = v.as_array().ok_or_else(make_err)?;
+    if arr.len() < 2 {
+        return Err(make_err());
+    }
+    let x = arr[0].as_f64().ok_or_else(make_err)?;
+    let y = arr[1].as_f64().ok_or_else(make_err)?;
+    Ok(Coord { x, y })
+}
+
+fn geojson_path(v: &JsonValue) -> Result<Vec<Coord>> {
+    v.as_array()
+        .ok_or_else(|| miette!("bad GeoJSON coordinates: {}", v))?
+        .iter()
+        .map(geojson_coord)
+        .try_collect()
+}
+
+fn coord_to_json(c: &Coord) -> JsonValue {
+    json!([c.x, c.y])
+}
+
+fn path_to_json(path: &[Coord]) -> JsonValue {
+    JsonValue::Array(path.iter().map(coord_to_json).collect())
+}
+
+impl Geometry {
+    /// Parses WKT such as `POINT (1 2)`, or GeoJSON if the string starts with `{`
+    pub(crate) fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.starts_with('{') {
+            let v: JsonValue =
+                serde_json::from_str(s).map_err(|_| miette!("bad GeoJSON: {}", s))?;
+            Self::from_geojson(&v)
+        } else {
+            Self::parse_wkt(s)
+        }
+    }
+    pub(crate) fn parse_wkt(s: &str) -> Result<Self> {
+        let paren = s.find('(').ok_or_else(|| miette!("bad geometry: {}", s))?;
+        let (kind, body) = s.split_at(paren);
+        let body = strip_parens(body, s)?;
+        Ok(match kind.trim().to_ascii_uppercase().as_str() {
+            "POINT" => Geometry::Point(parse_wkt_coord(body, s)?),
+            "LINESTRING" => Geometry::LineString(check_path(
+                body.split(',')
+                    .map(|c| parse_wkt_coord(c, s))
+                    .try_collect()?,
+            )?),
+            "POLYGON" => {
+                let mut rings = vec![];
+                let mut rest = body.trim();
+                while !rest.is_empty() {
+                    let end = rest
+                        .find(')')
+                        .ok_or_else(|| miette!("bad geometry: {}", s))?;
+                    rings.push(parse_wkt_path(&rest[..=end], s)?);
+                    rest = rest[end + 1..].trim_start();
+                    if let Some(r) = rest.strip_prefix(',') {
+                        rest = r.trim_start();
+                    }
+                }
+                Geometry::Polygon(check_rings(rings)?)
+            }
+            _ => bail!("unsupported geometry type '{}': {}", kind.trim(), s),
+        })
+    }
+    pub(crate) fn from_geojson(v: &JsonValue) -> Result<Self> {
+        let kind = v
+            .get("type")
+            .and_then(|t| t.as_str())
+            .ok_or_else(|| miette!("bad GeoJSON geometry: {}", v))?;
+        let coords = v
+            .get("coordinates")
+            .ok_or_else(|| miette!("GeoJSON geometry without coordinates: {}", v))?;
+        Ok(match kind {
+            "Point" => Geometry::Point(geojson_coord(coords)?),
+            "LineString" => Geometry::LineString(check_path(geojson_path(coords)?)?),
+            "Polygon" => {
+                let rings: Vec<_> = coords
+                    .as_array()
+                    .ok_or_else(|| miette!("bad GeoJSON coordinates: {}", coords))?
+                    .iter()
+                    .map(geojson_path)
+                    .try_collect()?;
+                Geometry::Polygon(check_rings(rings)?)
+            }
+            _ => bail!("unsupported GeoJSON geometry type '{}'", kind),
+        })
+    }
+    pub(crate) fn to_geojson(&self) -> JsonValue {
+        match self {
+            Geometry::Point(c) => json!({"type": "Point", "coordinates": coord_to_json(c)}),
+            Geometry::LineString(path) => {
+                json!({"type": "LineString", "coordinates": path_to_json(path)})
+            }
+            Geometry::Polygon(rings) => json!({
+                "type": "Polygon",
+                "coordinates": rings.iter().map(|r| path_to_json(r)).collect_vec()
+            }),
+        }
+    }
+    pub(crate) fn coords(&self) -> Box<dyn Iterator<Item = Coord> + '_> {
+        match self {
+            Geometry::Point(c) => Box::new(std::iter::once(*c)),
+            Geometry::LineString(path) => Box::new(path.iter().copied()),
+            Geometry::Polygon(rings) => Box::new(rings.iter().flatten().copied()),
+        }
+    }
+    /// The line segments making up the geometry, a point being a single degenerate segment
+    fn segments(&self) -> Vec<(Coord, Coord)> {
+        match self {
+            Geometry::Point(c) => vec![(*c, *c)],
+            Geometry::LineString(path) => ring_segments(path).collect(),
+            Geometry::Polygon(rings) => rings.iter().flat_map(|r| ring_segments(r)).collect(),
+        }
+    }
+    fn first_coord(&self) -> Coord {
+        self.coords().next().unwrap()
+    }
+    pub(crate) fn bbox(&self) -> BBox {
+        self.coords().fold(
+            [
+                f64::INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::NEG_INFINITY,
+            ],
+            |b, c| [b[0].min(c.x), b[1].min(c.y), b[2].max(c.x), b[3].max(c.y)],
+        )
+    }
+    /// Whether the two geometries share at least one point
+    pub(crate) fn intersects(&self, other: &Geometry) -> bool {
+        if !bbox_intersects(&self.bbox(), &other.bbox()) {
+            return false;
+        }
+        let other_segments = other.segments();
+        for (a, b) in self.segments() {
+            for (c, d) in &other_segments {
+                if segments_intersect(a, b, *c, *d) {
+                    return true;
+                }
+            }
+        }
+        // without crossing boundaries, one can only intersect the other by being inside it
+        let covers_first = |outer: &Geometry, inner: &Geometry| match outer {
+            Geometry::Polygon(rings) => {
+                locate_in_polygon(rings, inner.first_coord()) != Location::Outside
+            }
+            _ => false,
+        };
+        covers_first(self, other) || covers_first(other, self)
+    }
+    /// Whether every point of `other` is a point of this geometry, boundaries included
+    pub(crate) fn contains(&self, other: &Geometry) -> bool {
+        let bbox = self.bbox();
+        let other_bbox = other.bbox();
+        if bbox_union(&bbox, &other_bbox) != bbox {
+            return false;
+        }
+        match self {
+            Geometry::Point(p) => other.coords().all(|c| c == *p),
+            Geometry::LineString(path) => {
+                if matches!(other, Geometry::Polygon(_)) {
+                    return false;
+                }
+                let segments = self.segments();
+                other
+                    .segments()
+                    .into_iter()
+                    .all(|(a, b)| path_covers_segment(path, &segments, a, b))
+            }
+            Geometry::Polygon(rings) => {
+                let segments = self.segments();
+                if !other
+                    .segments()
+                    .into_iter()
+                    .all(|(a, b)| polygon_covers_segment(rings, &segments, a, b))
+                {
+                    return false;
+                }
+                // a hole of ours inside the other polygon is not covered
+                if let Geometry::Polygon(other_rings) = other {
+                    for hole in &rings[1..] {
+                        if hole
+                            .iter()
+                            .any(|c| locate_in_ring(&other_rings[0], *c) == Location::Inside)
+                        {
+                            return false;
+                        }
+                    }
+                }
+                true
+            }
+        }
+    }
+    /// The smallest distance between any two points of the geometries
+    pub(crate) fn distance(&self, other: &Geometry) -> f64 {
+        if self.intersects(other) {
+            return 0.;
+        }
+        let other_segments = other.segments();
+        let mut ret = f64::INFINITY;
+        for (a, b) in self.segments() {
+            for (c, d) in &other_segments {
+                ret = ret.min(segment_distance(a, b, *c, *d));
+            }
+        }
+        ret
+    }
+}
+
+/// Splits `ab` at every vertex of the path and checks that each piece lies along the path
+fn path_covers_segment(path: &[Coord], segments: &[(Coord, Coord)], a: Coord, b: Coord) -> bool {
+    if a == b {
+        return segments.iter().any(|(c, d)| on_segment(a, *c, *d));
+    }
+    let mut params = vec![0., 1.];
+    for c in path {
+        if on_segment(*c, a, b) {
+            params.push(segment_param(*c, a, b));
+        }
+    }
+    params.sort_by(|x, y| x.total_cmp(y));
+    params.iter().tuple_windows().all(|(s, e)| {
+        let mid = segment_point(a, b, (s + e) / 2.);
+        segments.iter().any(|(c, d)| {
+            orient(a, b, *c) == 0. && orient(a, b, *d) == 0. && within_span(mid, *c, *d)
+        })
+    })
+}
+
+/// Splits `ab` wherever it meets the boundary and checks that no piece lies outside
+fn polygon_covers_segment(
+    rings: &[Vec<Coord>],
+    segments: &[(Coord, Coord)],
+    a: Coord,
+    b: Coord,
+) -> bool {
+    if locate_in_polygon(rings, a) == Location::Outside {
+        return false;
+    }
+    if a == b {
+        return true;
+    }
+    let mut params = vec![0., 1.];
+    for (c, d) in segments {
+        crossing_params(a, b, *c, *d, &mut params);
+    }
+    params.sort_by(|x, y| x.total_cmp(y));
+    params.iter().tuple_windows().all(|(s, e)| {
+        locate_in_polygon(rings, segment_point(a, b, (s + e) / 2.)) != Location::Outside
+            && locate_in_polygon(rings, segment_point(a, b, *e)) != Location::Outside
+    })
+}
+
+fn write_path(f: &mut Formatter<'_>, path: &[Coord]) -> std::fmt::Result {
+    write!(f, "(")?;
+    for (i, c) in path.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{} {}", c.x, c.y)?;
+    }
+    write!(f, ")")
+}
+
+/// Formats as WKT
+impl Display for Geometry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Geometry::Point(c) => write!(f, "POINT ({} {})", c.x, c.y),
+            Geometry::LineString(path) => {
+                write!(f, "LINESTRING ")?;
+                write_path(f, path)
+            }
+            Geometry::Polygon(rings) => {
+                write!(f, "POLYGON (")?;
+                for (i, ring) in rings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_path(f, ring)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
@@ -11,6 +11,7 @@ use base64::Engine;
 use serde_json::json;
 pub(crate) use serde_json::Value as JsonValue;
 
+use crate::data::temporal::{format_date, MICROS_PER_SEC};
 use crate::data::value::{DataValue, Num, Vector};
 use crate::JsonData;
 
@@ -96,6 +97,25 @@ impl From<DataValue> for JsonValue {
                 json!([v.timestamp.0, v.is_assert])
             }
             DataValue::Json(j) => j.0,
+            DataValue::Date(d) => JsonValue::String(format_date(d)),
+            DataValue::DateTime(dt) => JsonValue::String(dt.to_string()),
+            DataValue::Duration(d) => duration_to_json(d),
+            DataValue::Decimal(d) => JsonValue::String(d.to_string()),
+            DataValue::Geometry(g) => g.to_geojson(),
+            DataValue::Record(r) => JsonValue::Object(
+                r.0.into_iter()
+                    .map(|(k, v)| (k.to_string(), JsonValue::from(v)))
+                    .collect(),
+            ),
         }
     }
 }
+
+/// Durations are represented in JSON as seconds
+pub(crate) fn duration_to_json(micros: i64) -> JsonValue {
+    if micros % MICROS_PER_SEC == 0 {
+        json!(micros / MICROS_PER_SEC)
+    } else {
+        json!(micros as f64 / MICROS_PER_SEC as f64)
+    }
+}
@@ -11,11 +11,13 @@ use std::collections::BTreeSet;
 use std::io::Write;
 use std::str::FromStr;
 
+use bigdecimal::{BigDecimal, Signed, Zero};
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use regex::Regex;
 
 use crate::data::value::{
-    DataValue, JsonData, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs, Vector,
+    Coord, DataValue, DateTimeTz, DecimalWrapper, Geometry, JsonData, Num, Record, RegexWrapper,
+    UuidWrapper, Validity, ValidityTs, Vector,
 };
 
 const INIT_TAG: u8 = 0x00;
@@ -32,11 +34,28 @@ const LIST_TAG: u8 = 0x0A;
 const SET_TAG: u8 = 0x0B;
 const VLD_TAG: u8 = 0x0C;
 const JSON_TAG: u8 = 0x0D;
+const DATE_TAG: u8 = 0x0E;
+const DATETIME_TAG: u8 = 0x0F;
+const DURATION_TAG: u8 = 0x10;
+const GEOMETRY_TAG: u8 = 0x11;
+const RECORD_TAG: u8 = 0x12;
 const BOT_TAG: u8 = 0xFF;
 
 const VEC_F32: u8 = 0x01;
 const VEC_F64: u8 = 0x02;
 
+const DECIMAL_NEG: u8 = 0x00;
+const DECIMAL_ZERO: u8 = 0x01;
+const DECIMAL_POS: u8 = 0x02;
+
+const GEOM_POINT: u8 = 0x01;
+const GEOM_LINESTRING: u8 = 0x02;
+const GEOM_POLYGON: u8 = 0x03;
+/// Precedes every element of a sequence of coordinates, rings or record fields,
+/// which is terminated by `INIT_TAG`
+const SEQ_MORE: u8 = 0x01;
+
+const IS_DECIMAL: u8 = 0b01000000;
 const IS_FLOAT: u8 = 0b00010000;
 const IS_APPROX_INT: u8 = 0b00000100;
 const IS_EXACT_INT: u8 = 0b00000000;
@@ -121,9 +140,99 @@ pub(crate) trait MemCmpEncoder: Write {
                 self.write_u64::<BigEndian>(ts_flipped).unwrap();
                 self.write_u8(!vld.is_assert.0 as u8).unwrap();
             }
+            DataValue::Date(days) => {
+                self.write_u8(DATE_TAG).unwrap();
+                self.write_u32::<BigEndian>(order_encode_i32(*days))
+                    .unwrap();
+            }
+            DataValue::DateTime(dt) => {
+                self.write_u8(DATETIME_TAG).unwrap();
+                self.write_u64::<BigEndian>(order_encode_i64(dt.micros))
+                    .unwrap();
+                self.encode_bytes(dt.tz.as_bytes());
+            }
+            DataValue::Duration(d) => {
+                self.write_u8(DURATION_TAG).unwrap();
+                self.write_u64::<BigEndian>(order_encode_i64(*d)).unwrap();
+            }
+            DataValue::Decimal(d) => {
+                // among the numbers, after those of the same nearest float
+                self.write_u8(NUM_TAG).unwrap();
+                self.write_u64::<BigEndian>(order_encode_f64(d.approx_float()))
+                    .unwrap();
+                self.write_u8(IS_DECIMAL).unwrap();
+                self.encode_decimal(&d.0);
+            }
+            DataValue::Geometry(g) => {
+                self.write_u8(GEOMETRY_TAG).unwrap();
+                self.encode_geometry(g);
+            }
+            DataValue::Record(r) => {
+                self.write_u8(RECORD_TAG).unwrap();
+                for (name, val) in &r.0 {
+                    self.write_u8(SEQ_MORE).unwrap();
+                    self.encode_bytes(name.as_bytes());
+                    self.encode_datavalue(val);
+                }
+                self.write_u8(INIT_TAG).unwrap()
+            }
             DataValue::Bot => self.write_u8(BOT_TAG).unwrap(),
         }
     }
+    /// Decimals are encoded as a sign, followed by the exponent and the digits of
+    /// `0.d1d2d3... * 10^exponent` with trailing zeros removed. For negative numbers
+    /// the exponent and digits are inverted so that larger magnitudes come first.
+    fn encode_decimal(&mut self, d: &BigDecimal) {
+        if d.is_zero() {
+            self.write_u8(DECIMAL_ZERO).unwrap();
+            return;
+        }
+        let is_neg = d.is_negative();
+        let flip = |b: u8| if is_neg { !b } else { b };
+        let (int_val, scale) = d.normalized().into_bigint_and_exponent();
+        let digits = int_val.magnitude().to_string();
+        let exponent = digits.len() as i64 - scale;
+        self.write_u8(if is_neg { DECIMAL_NEG } else { DECIMAL_POS })
+            .unwrap();
+        let exponent = order_encode_i64(exponent);
+        self.write_u64::<BigEndian>(if is_neg { !exponent } else { exponent })
+            .unwrap();
+        for digit in digits.bytes() {
+            self.write_u8(flip(digit - b'0' + 1)).unwrap();
+        }
+        self.write_u8(flip(INIT_TAG)).unwrap();
+    }
+    fn encode_geometry(&mut self, g: &Geometry) {
+        match g {
+            Geometry::Point(c) => {
+                self.write_u8(GEOM_POINT).unwrap();
+                self.encode_coord(c);
+            }
+            Geometry::LineString(path) => {
+                self.write_u8(GEOM_LINESTRING).unwrap();
+                self.encode_coords(path);
+            }
+            Geometry::Polygon(rings) => {
+                self.write_u8(GEOM_POLYGON).unwrap();
+                for ring in rings {
+                    self.write_u8(SEQ_MORE).unwrap();
+                    self.encode_coords(ring);
+                }
+                self.write_u8(INIT_TAG).unwrap();
+            }
+        }
+    }
+    fn encode_coords(&mut self, coords: &[Coord]) {
+        for c in coords {
+            self.write_u8(SEQ_MORE).unwrap();
+            self.encode_coord(c);
+        }
+        self.write_u8(INIT_TAG).unwrap();
+    }
+    fn encode_coord(&mut self, c: &Coord) {
+        self.write_u64::<BigEndian>(order_encode_f64(c.x)).unwrap();
+        self.write_u64::<BigEndian>(order_encode_f64(c.y)).unwrap();
+    }
     fn encode_num(&mut self, v: Num) {
         let f = v.get_float();
         let u = order_encode_f64(f);
@@ -191,6 +300,79 @@ pub fn decode_bytes(data: &[u8]) -> (Vec<u8>, &[u8]) {
     }
 }
 
+fn decode_decimal(data: &[u8]) -> (BigDecimal, &[u8]) {
+    let (sign, rest) = data.split_first().unwrap();
+    if *sign == DECIMAL_ZERO {
+        return (BigDecimal::zero(), rest);
+    }
+    let is_neg = *sign == DECIMAL_NEG;
+    let flip = |b: u8| if is_neg { !b } else { b };
+    let (exponent_bytes, mut rest) = rest.split_at(8);
+    let exponent = BigEndian::read_u64(exponent_bytes);
+    let exponent = order_decode_i64(if is_neg { !exponent } else { exponent });
+    let mut repr = String::new();
+    if is_neg {
+        repr.push('-');
+    }
+    let mut n_digits = 0;
+    loop {
+        let (byte, next) = rest.split_first().unwrap();
+        rest = next;
+        let byte = flip(*byte);
+        if byte == INIT_TAG {
+            break;
+        }
+        repr.push((byte - 1 + b'0') as char);
+        n_digits += 1;
+    }
+    repr.push_str(&format!("E{}", exponent - n_digits));
+    (BigDecimal::from_str(&repr).unwrap(), rest)
+}
+
+fn decode_geometry(data: &[u8]) -> (Geometry, &[u8]) {
+    let (kind, rest) = data.split_first().unwrap();
+    match *kind {
+        GEOM_POINT => {
+            let (c, rest) = decode_coord(rest);
+            (Geometry::Point(c), rest)
+        }
+        GEOM_LINESTRING => {
+            let (path, rest) = decode_coords(rest);
+            (Geometry::LineString(path), rest)
+        }
+        GEOM_POLYGON => {
+            let mut rings = vec![];
+            let mut rest = rest;
+            while rest[0] != INIT_TAG {
+                let (ring, next) = decode_coords(&rest[1..]);
+                rings.push(ring);
+                rest = next;
+            }
+            (Geometry::Polygon(rings), &rest[1..])
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn decode_coords(data: &[u8]) -> (Vec<Coord>, &[u8]) {
+    let mut coords = vec![];
+    let mut rest = data;
+    while rest[0] != INIT_TAG {
+        let (c, next) = decode_coord(&rest[1..]);
+        coords.push(c);
+        rest = next;
+    }
+    (coords, &rest[1..])
+}
+
+fn decode_coord(data: &[u8]) -> (Coord, &[u8]) {
+    let (x_bytes, rest) = data.split_at(8);
+    let (y_bytes, rest) = rest.split_at(8);
+    let x = order_decode_f64(BigEndian::read_u64(x_bytes));
+    let y = order_decode_f64(BigEndian::read_u64(y_bytes));
+    (Coord { x, y }, rest)
+}
+
 const SIGN_MARK: u64 = 0x8000000000000000;
 
 fn order_encode_i64(v: i64) -> u64 {
@@ -201,6 +383,14 @@ fn order_decode_i64(u: u64) -> i64 {
     (u ^ SIGN_MARK) as i64
 }
 
+fn order_encode_i32(v: i32) -> u32 {
+    v as u32 ^ 0x80000000
+}
+
+fn order_decode_i32(u: u32) -> i32 {
+    (u ^ 0x80000000) as i32
+}
+
 fn order_encode_f64(v: f64) -> u64 {
     let u = v.to_bits();
     if v.is_sign_positive() {
@@ -261,6 +451,10 @@ impl DataValue {
             NULL_TAG => (DataValue::Null, remaining),
             FALSE_TAG => (DataValue::from(false), remaining),
             TRUE_TAG => (DataValue::from(true), remaining),
+            NUM_TAG if remaining[8] == IS_DECIMAL => {
+                let (d, rest) = decode_decimal(&remaining[9..]);
+                (DataValue::Decimal(DecimalWrapper(d)), rest)
+            }
             NUM_TAG => {
                 let (n, remaining) = Num::decode_from_key(remaining);
                 (DataValue::Num(n), remaining)
@@ -334,6 +528,45 @@ impl DataValue {
                     rest,
                 )
             }
+            DATE_TAG => {
+                let (days_bytes, rest) = remaining.split_at(4);
+                let days = order_decode_i32(BigEndian::read_u32(days_bytes));
+                (DataValue::Date(days), rest)
+            }
+            DATETIME_TAG => {
+                let (micros_bytes, rest) = remaining.split_at(8);
+                let micros = order_decode_i64(BigEndian::read_u64(micros_bytes));
+                let (tz_bytes, rest) = decode_bytes(rest);
+                let tz = unsafe { String::from_utf8_unchecked(tz_bytes) };
+                (
+                    DataValue::DateTime(DateTimeTz {
+                        micros,
+                        tz: tz.into(),
+                    }),
+                    rest,
+                )
+            }
+            DURATION_TAG => {
+                let (micros_bytes, rest) = remaining.split_at(8);
+                let micros = order_decode_i64(BigEndian::read_u64(micros_bytes));
+                (DataValue::Duration(micros), rest)
+            }
+            GEOMETRY_TAG => {
+                let (g, rest) = decode_geometry(remaining);
+                (DataValue::Geometry(g), rest)
+            }
+            RECORD_TAG => {
+                let mut fields = vec![];
+                let mut remaining = remaining;
+                while remaining[0] == SEQ_MORE {
+                    let (name_bytes, rest) = decode_bytes(&remaining[1..]);
+                    let name = unsafe { String::from_utf8_unchecked(name_bytes) };
+                    let (val, rest) = DataValue::decode_from_key(rest);
+                    fields.push((name.into(), val));
+                    remaining = rest;
+                }
+                (DataValue::Record(Record(fields)), &remaining[1..])
+            }
             BOT_TAG => (DataValue::Bot, remaining),
             VEC_TAG => {
                 let (t_tag, remaining) = remaining.split_first().unwrap();
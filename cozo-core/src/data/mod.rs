@@ -9,11 +9,13 @@
 pub(crate) mod aggr;
 pub(crate) mod expr;
 pub(crate) mod functions;
+pub(crate) mod geometry;
 pub(crate) mod json;
 pub(crate) mod memcmp;
 pub(crate) mod program;
 pub(crate) mod relation;
 pub(crate) mod symb;
+pub(crate) mod temporal;
 pub(crate) mod tuple;
 pub(crate) mod value;
 
@@ -28,9 +28,11 @@ use crate::query::compile::ContainedRuleMultiplicity;
 use crate::query::logical::{Disjunction, NamedFieldNotFound};
 use crate::runtime::hnsw::HnswIndexManifest;
 use crate::runtime::minhash_lsh::{LshSearch, MinHashLshIndexManifest};
+use crate::runtime::rtree::{SpatialIndexManifest, SpatialSearch};
 use crate::runtime::relation::{
     AccessLevel, InputRelationHandle, InsufficientAccessLevel, RelationHandle,
 };
+use crate::runtime::db::QueryLimits;
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 
@@ -51,10 +53,14 @@ pub(crate) struct QueryOutOptions {
     pub(crate) limit: Option<usize>,
     pub(crate) offset: Option<usize>,
     pub(crate) timeout: Option<f64>,
+    pub(crate) limits: QueryLimits,
     pub(crate) sleep: Option<f64>,
     pub(crate) sorters: Vec<(Symbol, SortDir)>,
     pub(crate) store_relation: Option<(InputRelationHandle, RelationOp, ReturnMutation)>,
+    /// mutations fed by the named rules instead of the entry, executed in order
+    pub(crate) rule_mutations: Vec<(InputRelationHandle, RelationOp, Symbol)>,
     pub(crate) assertion: Option<QueryAssertion>,
+    pub(crate) bag: bool,
 }
 
 impl Debug for QueryOutOptions {
@@ -74,6 +80,18 @@ impl Display for QueryOutOptions {
         if let Some(l) = self.timeout {
             writeln!(f, ":timeout {l};")?;
         }
+        if let Some(l) = self.limits.max_rows {
+            writeln!(f, ":max_rows {l};")?;
+        }
+        if let Some(l) = self.limits.max_memory {
+            writeln!(f, ":max_memory {l};")?;
+        }
+        if let Some(l) = self.limits.max_epochs {
+            writeln!(f, ":max_epochs {l};")?;
+        }
+        if self.bag {
+            writeln!(f, ":bag;")?;
+        }
         for (symb, dir) in &self.sorters {
             write!(f, ":order ")?;
             if *dir == SortDir::Dsc {
@@ -81,81 +99,16 @@ impl Display for QueryOutOptions {
             }
             writeln!(f, "{symb};")?;
         }
-        if let Some((
-                        InputRelationHandle {
-                            name,
-                            metadata: StoredRelationMetadata { keys, non_keys },
-                            key_bindings,
-                            dep_bindings,
-                            ..
-                        },
-                        op,
-                        return_mutation,
-                    )) = &self.store_relation
-        {
+        if let Some((handle, op, return_mutation)) = &self.store_relation {
             if *return_mutation == ReturnMutation::Returning {
                 writeln!(f, ":returning")?;
             }
-            match op {
-                RelationOp::Create => {
-                    write!(f, ":create ")?;
-                }
-                RelationOp::Replace => {
-                    write!(f, ":replace ")?;
-                }
-                RelationOp::Insert => {
-                    write!(f, ":insert ")?;
-                }
-                RelationOp::Put => {
-                    write!(f, ":put ")?;
-                }
-                RelationOp::Update => {
-                    write!(f, ":update ")?;
-                }
-                RelationOp::Rm => {
-                    write!(f, ":rm ")?;
-                }
-                RelationOp::Delete => {
-                    write!(f, ":delete ")?;
-                }
-                RelationOp::Ensure => {
-                    write!(f, ":ensure ")?;
-                }
-                RelationOp::EnsureNot => {
-                    write!(f, ":ensure_not ")?;
-                }
-            }
-            write!(f, "{name} {{")?;
-            let mut is_first = true;
-            for (col, bind) in keys.iter().zip(key_bindings) {
-                if is_first {
-                    is_first = false
-                } else {
-                    write!(f, ", ")?;
-                }
-                write!(f, "{}: {}", col.name, col.typing)?;
-                if let Some(gen) = &col.default_gen {
-                    write!(f, " default {gen}")?;
-                } else {
-                    write!(f, " = {bind}")?;
-                }
-            }
-            write!(f, " => ")?;
-            let mut is_first = true;
-            for (col, bind) in non_keys.iter().zip(dep_bindings) {
-                if is_first {
-                    is_first = false
-                } else {
-                    write!(f, ", ")?;
-                }
-                write!(f, "{}: {}", col.name, col.typing)?;
-                if let Some(gen) = &col.default_gen {
-                    write!(f, " default {gen}")?;
-                } else {
-                    write!(f, " = {bind}")?;
-                }
-            }
-            writeln!(f, "}};")?;
+            write_mutation(f, handle, *op)?;
+            writeln!(f, ";")?;
+        }
+        for (handle, op, rule) in &self.rule_mutations {
+            write_mutation(f, handle, *op)?;
+            writeln!(f, " from {rule};")?;
         }
 
         if let Some(a) = &self.assertion {
@@ -173,6 +126,106 @@ impl Display for QueryOutOptions {
     }
 }
 
+fn write_mutation(
+    f: &mut Formatter<'_>,
+    handle: &InputRelationHandle,
+    op: RelationOp,
+) -> std::fmt::Result {
+    let InputRelationHandle {
+        name,
+        metadata:
+            StoredRelationMetadata {
+                keys,
+                non_keys,
+                foreign_keys,
+                constraints,
+            },
+        key_bindings,
+        dep_bindings,
+        condition,
+        ..
+    } = handle;
+    match op {
+        RelationOp::Create => {
+            write!(f, ":create ")?;
+        }
+        RelationOp::Replace => {
+            write!(f, ":replace ")?;
+        }
+        RelationOp::Insert => {
+            write!(f, ":insert ")?;
+        }
+        RelationOp::Put => {
+            write!(f, ":put ")?;
+        }
+        RelationOp::PutIf => {
+            write!(f, ":put_if ")?;
+        }
+        RelationOp::Update => {
+            write!(f, ":update ")?;
+        }
+        RelationOp::Rm => {
+            write!(f, ":rm ")?;
+        }
+        RelationOp::Delete => {
+            write!(f, ":delete ")?;
+        }
+        RelationOp::Ensure => {
+            write!(f, ":ensure ")?;
+        }
+        RelationOp::EnsureNot => {
+            write!(f, ":ensure_not ")?;
+        }
+    }
+    write!(f, "{name} {{")?;
+    let mut is_first = true;
+    for (col, bind) in keys.iter().zip(key_bindings) {
+        if is_first {
+            is_first = false
+        } else {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}: {}", col.name, col.typing)?;
+        for fk in foreign_keys.iter().filter(|fk| fk.column == col.name) {
+            write!(f, " references {fk}")?;
+        }
+        for c in constraints.iter().filter(|c| c.column == col.name) {
+            write!(f, " {c}")?;
+        }
+        if let Some(gen) = &col.default_gen {
+            write!(f, " default {gen}")?;
+        } else {
+            write!(f, " = {bind}")?;
+        }
+    }
+    write!(f, " => ")?;
+    let mut is_first = true;
+    for (col, bind) in non_keys.iter().zip(dep_bindings) {
+        if is_first {
+            is_first = false
+        } else {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}: {}", col.name, col.typing)?;
+        for fk in foreign_keys.iter().filter(|fk| fk.column == col.name) {
+            write!(f, " references {fk}")?;
+        }
+        for c in constraints.iter().filter(|c| c.column == col.name) {
+            write!(f, " {c}")?;
+        }
+        if let Some(gen) = &col.default_gen {
+            write!(f, " default {gen}")?;
+        } else {
+            write!(f, " = {bind}")?;
+        }
+    }
+    write!(f, "}}")?;
+    if let Some(condition) = condition {
+        write!(f, " where {condition}")?;
+    }
+    Ok(())
+}
+
 impl QueryOutOptions {
     pub(crate) fn num_to_take(&self) -> Option<usize> {
         match (self.limit, self.offset) {
@@ -194,6 +247,7 @@ pub(crate) enum RelationOp {
     Create,
     Replace,
     Put,
+    PutIf,
     Insert,
     Update,
     Rm,
@@ -465,10 +519,15 @@ pub(crate) struct InputProgram {
     pub(crate) prog: BTreeMap<Symbol, InputInlineRulesOrFixed>,
     pub(crate) out_opts: QueryOutOptions,
     pub(crate) disable_magic_rewrite: bool,
+    /// Stored rule libraries pulled in with `use`, resolved before compilation
+    pub(crate) rule_libraries: Vec<Symbol>,
 }
 
 impl Display for InputProgram {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for lib in &self.rule_libraries {
+            writeln!(f, "use {lib};")?;
+        }
         for (name, rules) in &self.prog {
             match rules {
                 InputInlineRulesOrFixed::Rules { rules, .. } => {
@@ -554,16 +613,23 @@ struct EntryHeadNotExplicitlyDefinedError(#[label] SourceSpan);
 #[diagnostic(help("You need to have one rule named '?'"))]
 pub(crate) struct NoEntryError;
 
+#[derive(Debug, Diagnostic, Error)]
+#[error("Rule '{0}' not found")]
+#[diagnostic(code(parser::rule_not_found))]
+struct RuleNotFoundError(String, #[label] SourceSpan);
+
 impl InputProgram {
-    pub(crate) fn needs_write_lock(&self) -> Option<SmartString<LazyCompact>> {
-        if let Some((h, _, _)) = &self.out_opts.store_relation {
+    pub(crate) fn needs_write_locks(&self, collector: &mut BTreeSet<SmartString<LazyCompact>>) {
+        let handles = self
+            .out_opts
+            .store_relation
+            .iter()
+            .map(|(h, _, _)| h)
+            .chain(self.out_opts.rule_mutations.iter().map(|(h, _, _)| h));
+        for h in handles {
             if !h.name.name.starts_with('_') {
-                Some(h.name.name.clone())
-            } else {
-                None
+                collector.insert(h.name.name.clone());
             }
-        } else {
-            None
         }
     }
 
@@ -588,8 +654,33 @@ impl InputProgram {
             }
         }
     }
+    /// Like `get_entry_out_head_or_default`, for any rule of the program.
+    pub(crate) fn get_rule_out_head_or_default(&self, name: &Symbol) -> Result<Vec<Symbol>> {
+        match self.get_rule_out_head(name) {
+            Ok(r) => Ok(r),
+            Err(_) => {
+                let arity = match self.prog.get(name) {
+                    Some(InputInlineRulesOrFixed::Rules { rules }) => {
+                        rules.last().unwrap().head.len()
+                    }
+                    Some(InputInlineRulesOrFixed::Fixed { fixed }) => fixed.arity()?,
+                    None => bail!(RuleNotFoundError(name.to_string(), name.span)),
+                };
+                Ok((0..arity)
+                    .map(|i| Symbol::new(format!("_{i}"), SourceSpan(0, 0)))
+                    .collect())
+            }
+        }
+    }
     pub(crate) fn get_entry_out_head(&self) -> Result<Vec<Symbol>> {
-        if let Some(entry) = self.prog.get(&Symbol::new(PROG_ENTRY, SourceSpan(0, 0))) {
+        let entry = Symbol::new(PROG_ENTRY, SourceSpan(0, 0));
+        if !self.prog.contains_key(&entry) {
+            return Err(NoEntryError.into());
+        }
+        self.get_rule_out_head(&entry)
+    }
+    pub(crate) fn get_rule_out_head(&self, name: &Symbol) -> Result<Vec<Symbol>> {
+        if let Some(entry) = self.prog.get(name) {
             return match entry {
                 InputInlineRulesOrFixed::Rules { rules } => {
                     let head = &rules.last().unwrap().head;
@@ -600,10 +691,10 @@ impl InputProgram {
                             ret.push(Symbol::new(
                                 format!(
                                     "{}({})",
-                                    aggr.name
-                                        .strip_prefix("AGGR_")
-                                        .unwrap()
-                                        .to_ascii_lowercase(),
+                                    match aggr.name.strip_prefix("AGGR_") {
+                                        Some(name) => name.to_ascii_lowercase(),
+                                        None => aggr.name.to_string(),
+                                    },
                                     symb
                                 ),
                                 symb.span,
@@ -624,7 +715,7 @@ impl InputProgram {
             };
         }
 
-        Err(NoEntryError.into())
+        Err(RuleNotFoundError(name.to_string(), name.span).into())
     }
     pub(crate) fn into_normalized_program(
         self,
@@ -681,7 +772,7 @@ impl InputProgram {
                                 aggr: rule.aggr.clone(),
                                 body,
                             };
-                            collected_rules.push(normalized_rule.convert_to_well_ordered_rule()?);
+                            collected_rules.push(normalized_rule.convert_to_well_ordered_rule(tx)?);
                         }
                     }
                     prog.insert(
@@ -699,7 +790,15 @@ impl InputProgram {
         Ok((
             NormalFormProgram {
                 prog,
-                disable_magic_rewrite: self.disable_magic_rewrite,
+                // magic sets add rules that would collapse duplicate rows
+                disable_magic_rewrite: self.disable_magic_rewrite || self.out_opts.bag,
+                bag: self.out_opts.bag,
+                extra_outputs: self
+                    .out_opts
+                    .rule_mutations
+                    .iter()
+                    .map(|(_, _, rule)| rule.clone())
+                    .collect(),
             },
             self.out_opts,
         ))
@@ -728,6 +827,9 @@ impl NormalFormRulesOrFixed {
 pub(crate) struct NormalFormProgram {
     pub(crate) prog: BTreeMap<Symbol, NormalFormRulesOrFixed>,
     pub(crate) disable_magic_rewrite: bool,
+    pub(crate) bag: bool,
+    /// rules besides the entry whose results are read after evaluation
+    pub(crate) extra_outputs: BTreeSet<Symbol>,
 }
 
 #[derive(Debug)]
@@ -940,6 +1042,11 @@ pub(crate) enum InputAtom {
         inner: Box<InputAtom>,
         span: SourceSpan,
     },
+    /// A stored relation joined if present, with nulls bound otherwise
+    Optional {
+        inner: Box<InputAtom>,
+        span: SourceSpan,
+    },
     Conjunction {
         inner: Vec<InputAtom>,
         span: SourceSpan,
@@ -954,6 +1061,9 @@ pub(crate) enum InputAtom {
     Search {
         inner: SearchInput,
     },
+    Path {
+        inner: InputPathAtom,
+    },
 }
 
 #[derive(Clone)]
@@ -1168,6 +1278,185 @@ impl SearchInput {
 
         Ok(Disjunction::conj(conj))
     }
+    fn normalize_spatial(
+        mut self,
+        base_handle: RelationHandle,
+        idx_handle: RelationHandle,
+        manifest: SpatialIndexManifest,
+        gen: &mut TempSymbGen,
+    ) -> Result<Disjunction> {
+        let mut conj = Vec::with_capacity(self.bindings.len() + 8);
+        let mut bindings = Vec::with_capacity(self.bindings.len());
+        let mut seen_variables = BTreeSet::new();
+
+        for col in base_handle
+            .metadata
+            .keys
+            .iter()
+            .chain(base_handle.metadata.non_keys.iter())
+        {
+            if let Some(arg) = self.bindings.remove(&col.name) {
+                match arg {
+                    Expr::Binding { var, .. } => {
+                        if var.is_ignored_symbol() {
+                            bindings.push(gen.next_ignored(var.span));
+                        } else if seen_variables.insert(var.clone()) {
+                            bindings.push(var);
+                        } else {
+                            let span = var.span;
+                            let dup = gen.next(span);
+                            let unif = NormalFormAtom::Unification(Unification {
+                                binding: dup.clone(),
+                                expr: Expr::Binding {
+                                    var,
+                                    tuple_pos: None,
+                                },
+                                one_many_unif: false,
+                                span,
+                            });
+                            conj.push(unif);
+                            bindings.push(dup);
+                        }
+                    }
+                    expr => {
+                        let span = expr.span();
+                        let kw = gen.next(span);
+                        bindings.push(kw.clone());
+                        let unif = NormalFormAtom::Unification(Unification {
+                            binding: kw,
+                            expr,
+                            one_many_unif: false,
+                            span,
+                        });
+                        conj.push(unif)
+                    }
+                }
+            } else {
+                bindings.push(gen.next_ignored(self.span));
+            }
+        }
+
+        if let Some((name, _)) = self.bindings.pop_first() {
+            bail!(NamedFieldNotFound(
+                self.relation.name.to_string(),
+                name.to_string(),
+                self.span
+            ));
+        }
+
+        // expressions are bound to fresh variables by unification
+        let mut to_binding = |expr: Expr, conj: &mut Vec<NormalFormAtom>| match expr {
+            Expr::Binding { var, .. } => var,
+            expr => {
+                let span = expr.span();
+                let kw = gen.next(span);
+                let unif = NormalFormAtom::Unification(Unification {
+                    binding: kw.clone(),
+                    expr,
+                    one_many_unif: false,
+                    span,
+                });
+                conj.push(unif);
+                kw
+            }
+        };
+
+        let query = self
+            .parameters
+            .remove("query")
+            .map(|expr| to_binding(expr, &mut conj));
+        let bbox = self
+            .parameters
+            .remove("bbox")
+            .map(|expr| to_binding(expr, &mut conj));
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Field `query` or `bbox` is required for spatial search")]
+        #[diagnostic(code(parser::spatial_query_required))]
+        struct SpatialQueryRequired(#[label] SourceSpan);
+
+        ensure!(
+            query.is_some() || bbox.is_some(),
+            SpatialQueryRequired(self.span)
+        );
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Field `{0}` of spatial search requires `query` to be given")]
+        #[diagnostic(code(parser::spatial_param_requires_query))]
+        struct SpatialParamRequiresQuery(&'static str, #[label] SourceSpan);
+
+        let k = match self.parameters.remove("k") {
+            None => None,
+            Some(k_expr) => {
+                let k = k_expr.eval_to_const()?;
+                let k = k.get_int().ok_or(ExpectedPosIntForSpatialK(self.span))?;
+
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Expected positive integer for `k`")]
+                #[diagnostic(code(parser::expected_int_for_spatial_k))]
+                struct ExpectedPosIntForSpatialK(#[label] SourceSpan);
+
+                ensure!(k > 0, ExpectedPosIntForSpatialK(self.span));
+                Some(k as usize)
+            }
+        };
+
+        let radius = match self.parameters.remove("radius") {
+            None => None,
+            Some(expr) => {
+                let r = expr.eval_to_const()?;
+                let r = r
+                    .get_float()
+                    .ok_or(ExpectedFloatForSpatialRadius(self.span))?;
+
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Expected non-negative float for `radius`")]
+                #[diagnostic(code(parser::expected_float_for_spatial_radius))]
+                struct ExpectedFloatForSpatialRadius(#[label] SourceSpan);
+
+                ensure!(r >= 0.0, ExpectedFloatForSpatialRadius(self.span));
+                ensure!(
+                    query.is_some(),
+                    SpatialParamRequiresQuery("radius", self.span)
+                );
+                Some(r)
+            }
+        };
+
+        let bind_distance = self
+            .parameters
+            .remove("bind_distance")
+            .map(|expr| to_binding(expr, &mut conj));
+        ensure!(
+            bind_distance.is_none() || query.is_some(),
+            SpatialParamRequiresQuery("bind_distance", self.span)
+        );
+
+        let filter = self.parameters.remove("filter");
+
+        if !self.parameters.is_empty() {
+            bail!(
+                "Unexpected parameters for spatial search: {:?}",
+                self.parameters
+            );
+        }
+
+        conj.push(NormalFormAtom::SpatialSearch(SpatialSearch {
+            base_handle,
+            idx_handle,
+            manifest,
+            bindings,
+            k,
+            query,
+            bbox,
+            radius,
+            bind_distance,
+            filter,
+            span: self.span,
+        }));
+
+        Ok(Disjunction::conj(conj))
+    }
     fn normalize_fts(
         mut self,
         base_handle: RelationHandle,
@@ -1587,6 +1876,11 @@ impl SearchInput {
         {
             return self.normalize_lsh(base_handle, idx_handle, manifest, gen);
         }
+        if let Some((idx_handle, manifest)) =
+            base_handle.spatial_indices.get(&self.index.name).cloned()
+        {
+            return self.normalize_spatial(base_handle, idx_handle, manifest, gen);
+        }
         #[derive(Debug, Error, Diagnostic)]
         #[error("Index {name} not found on relation {relation}")]
         #[diagnostic(code(eval::hnsw_index_not_found))]
@@ -1652,6 +1946,14 @@ impl Display for InputAtom {
             InputAtom::Negation { inner, .. } => {
                 write!(f, "not {inner}")?;
             }
+            InputAtom::Optional { inner, .. } => {
+                write!(f, "?{inner}")?;
+            }
+            InputAtom::Path {
+                inner: InputPathAtom { from, to, path, .. },
+            } => {
+                write!(f, "{from} -[{path}]-> {to}")?;
+            }
             InputAtom::Conjunction { inner, .. } => {
                 for (i, a) in inner.iter().enumerate() {
                     if i > 0 {
@@ -1704,6 +2006,7 @@ impl InputAtom {
     pub(crate) fn span(&self) -> SourceSpan {
         match self {
             InputAtom::Negation { span, .. }
+            | InputAtom::Optional { span, .. }
             | InputAtom::Conjunction { span, .. }
             | InputAtom::Disjunction { span, .. } => *span,
             InputAtom::Rule { inner, .. } => inner.span,
@@ -1712,6 +2015,7 @@ impl InputAtom {
             InputAtom::Predicate { inner, .. } => inner.span(),
             InputAtom::Unification { inner, .. } => inner.span,
             InputAtom::Search { inner, .. } => inner.span,
+            InputAtom::Path { inner, .. } => inner.span,
         }
     }
 }
@@ -1722,11 +2026,13 @@ pub(crate) enum NormalFormAtom {
     Relation(NormalFormRelationApplyAtom),
     NegatedRule(NormalFormRuleApplyAtom),
     NegatedRelation(NormalFormRelationApplyAtom),
+    OptionalRelation(NormalFormRelationApplyAtom),
     Predicate(Expr),
     Unification(Unification),
     HnswSearch(HnswSearch),
     FtsSearch(FtsSearch),
     LshSearch(LshSearch),
+    SpatialSearch(SpatialSearch),
 }
 
 #[derive(Debug, Clone)]
@@ -1736,10 +2042,12 @@ pub(crate) enum MagicAtom {
     Predicate(Expr),
     NegatedRule(MagicRuleApplyAtom),
     NegatedRelation(MagicRelationApplyAtom),
+    OptionalRelation(MagicRelationApplyAtom),
     Unification(Unification),
     HnswSearch(HnswSearch),
     FtsSearch(FtsSearch),
     LshSearch(LshSearch),
+    SpatialSearch(SpatialSearch),
 }
 
 #[derive(Clone, Debug)]
@@ -1765,6 +2073,78 @@ pub(crate) struct InputRelationApplyAtom {
     pub(crate) span: SourceSpan,
 }
 
+/// `from -[path]-> to`, holding when `to` can be reached from `from` by a path matching
+/// the expression. Expanded into generated rules before normalization.
+#[derive(Clone, Debug)]
+pub(crate) struct InputPathAtom {
+    pub(crate) from: Expr,
+    pub(crate) to: Expr,
+    pub(crate) path: PathExpr,
+    pub(crate) span: SourceSpan,
+}
+
+/// A regular expression over the edges of stored relations. Each edge goes from the first
+/// column of the relation to its second column.
+#[derive(Clone, Debug)]
+pub(crate) enum PathExpr {
+    Step {
+        relation: Symbol,
+        inverse: bool,
+    },
+    Sequence(Vec<PathExpr>),
+    Alternation(Vec<PathExpr>),
+    Repetition {
+        inner: Box<PathExpr>,
+        min: usize,
+        max: Option<usize>,
+        span: SourceSpan,
+    },
+}
+
+impl Display for PathExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathExpr::Step { relation, inverse } => {
+                if *inverse {
+                    write!(f, "^")?;
+                }
+                write!(f, "*{relation}")
+            }
+            PathExpr::Sequence(steps) => {
+                for (i, step) in steps.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " / ")?;
+                    }
+                    write!(f, "{step}")?;
+                }
+                Ok(())
+            }
+            PathExpr::Alternation(choices) => {
+                write!(f, "(")?;
+                for (i, choice) in choices.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, "{choice}")?;
+                }
+                write!(f, ")")
+            }
+            PathExpr::Repetition {
+                inner, min, max, ..
+            } => {
+                write!(f, "({inner})")?;
+                match (min, max) {
+                    (0, None) => write!(f, "*"),
+                    (1, None) => write!(f, "+"),
+                    (0, Some(1)) => write!(f, "?"),
+                    (min, None) => write!(f, "[{min}..]"),
+                    (min, Some(max)) => write!(f, "[{min}..{max}]"),
+                }
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct NormalFormRuleApplyAtom {
     pub(crate) name: Symbol,
@@ -9,11 +9,13 @@
 use std::cmp::Reverse;
 use std::fmt::{Display, Formatter};
 use std::mem;
+use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 use chrono::DateTime;
+use chrono_tz::Tz;
 use itertools::Itertools;
 use miette::{bail, ensure, Diagnostic, Result};
 use serde_json::json;
@@ -21,7 +23,14 @@ use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::expr::Expr;
-use crate::data::value::{DataValue, JsonData, UuidWrapper, Validity, ValidityTs, Vector};
+use crate::data::json::{duration_to_json, JsonValue};
+use crate::data::temporal::{
+    datetime_to_date, format_date, parse_date, parse_duration, secs_to_micros, MICROS_PER_DAY,
+};
+use crate::data::value::{
+    DataValue, DateTimeTz, DecimalWrapper, Geometry, JsonData, Record, UuidWrapper, Validity,
+    ValidityTs, Vector,
+};
 use crate::Num;
 
 #[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
@@ -72,6 +81,21 @@ impl Display for NullableColType {
             ColType::Json => {
                 f.write_str("Json")?;
             }
+            ColType::Date => f.write_str("Date")?,
+            ColType::DateTime => f.write_str("DateTime")?,
+            ColType::Duration => f.write_str("Duration")?,
+            ColType::Decimal => f.write_str("Decimal")?,
+            ColType::Geometry => f.write_str("Geometry")?,
+            ColType::Record(fields) => {
+                f.write_str("{")?;
+                for (i, (name, typ)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(", ")?
+                    }
+                    write!(f, "{name}: {typ}")?;
+                }
+                f.write_str("}")?;
+            }
         }
         if self.nullable {
             f.write_str("?")?;
@@ -100,6 +124,12 @@ pub enum ColType {
     Tuple(Vec<NullableColType>),
     Validity,
     Json,
+    Date,
+    DateTime,
+    Duration,
+    Decimal,
+    Geometry,
+    Record(Vec<(SmartString<LazyCompact>, NullableColType)>),
 }
 
 #[derive(
@@ -121,6 +151,68 @@ pub(crate) struct ColumnDef {
 pub(crate) struct StoredRelationMetadata {
     pub(crate) keys: Vec<ColumnDef>,
     pub(crate) non_keys: Vec<ColumnDef>,
+    #[serde(default)]
+    pub(crate) foreign_keys: Vec<ForeignKey>,
+    #[serde(default)]
+    pub(crate) constraints: Vec<ColumnConstraint>,
+}
+
+/// A named constraint on the values of a column, declared by `check` or `in` in the schema.
+/// Null values are not subject to constraints.
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub(crate) struct ColumnConstraint {
+    pub(crate) name: SmartString<LazyCompact>,
+    pub(crate) column: SmartString<LazyCompact>,
+    pub(crate) rule: ConstraintRule,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub(crate) enum ConstraintRule {
+    /// a predicate over the columns of the row
+    Check(Expr),
+    /// the allowed values of the column
+    OneOf(Vec<DataValue>),
+}
+
+impl Display for ColumnConstraint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.rule {
+            ConstraintRule::Check(expr) => write!(f, "check {} ({})", self.name, expr),
+            ConstraintRule::OneOf(vals) => {
+                write!(f, "in {} [{}]", self.name, vals.iter().join(", "))
+            }
+        }
+    }
+}
+
+/// A column whose non-null values must be the key of a row in another relation,
+/// declared by `references` in the schema.
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub(crate) struct ForeignKey {
+    pub(crate) column: SmartString<LazyCompact>,
+    pub(crate) relation: SmartString<LazyCompact>,
+    /// the only key column of the referenced relation
+    pub(crate) target: SmartString<LazyCompact>,
+    pub(crate) on_delete: ForeignKeyAction,
+}
+
+/// What happens to the referencing rows when a referenced row is removed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub(crate) enum ForeignKeyAction {
+    /// the removal fails
+    Restrict,
+    /// the referencing rows are removed as well
+    Cascade,
+}
+
+impl Display for ForeignKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.relation, self.target)?;
+        if self.on_delete == ForeignKeyAction::Cascade {
+            f.write_str(" cascade")?;
+        }
+        Ok(())
+    }
 }
 
 impl StoredRelationMetadata {
@@ -171,6 +263,20 @@ impl StoredRelationMetadata {
 }
 
 impl NullableColType {
+    /// The type of a field of a record type given by a path such as `zip` or `addr.zip`.
+    /// The field is nullable if any record along the path is.
+    pub(crate) fn field_type(&self, path: &str) -> Option<NullableColType> {
+        let mut cur = self.clone();
+        for field in path.split('.') {
+            let nullable = cur.nullable;
+            cur = match cur.coltype {
+                ColType::Record(fields) => fields.into_iter().find(|(n, _)| n == field)?.1,
+                _ => return None,
+            };
+            cur.nullable |= nullable;
+        }
+        Some(cur)
+    }
     pub(crate) fn coerce(&self, data: DataValue, cur_vld: ValidityTs) -> Result<DataValue> {
         if matches!(data, DataValue::Null) {
             return if self.nullable {
@@ -448,10 +554,106 @@ impl NullableColType {
                 DataValue::Validity(vld) => {
                     json!([vld.timestamp.0, vld.is_assert.0])
                 }
+                DataValue::Date(d) => {
+                    json!(format_date(d))
+                }
+                DataValue::DateTime(dt) => {
+                    json!(dt.to_string())
+                }
+                DataValue::Duration(d) => duration_to_json(d),
+                DataValue::Decimal(d) => {
+                    json!(d.to_string())
+                }
+                DataValue::Geometry(g) => g.to_geojson(),
+                r @ DataValue::Record(_) => JsonValue::from(r),
                 DataValue::Bot => {
                     json!(null)
                 }
             })),
+            ColType::Date => match &data {
+                DataValue::Date(_) => data,
+                DataValue::Str(s) => DataValue::Date(parse_date(s).map_err(|_| make_err())?),
+                DataValue::DateTime(dt) => DataValue::Date(datetime_to_date(dt)?),
+                _ => bail!(make_err()),
+            },
+            ColType::DateTime => match &data {
+                DataValue::DateTime(_) => data,
+                DataValue::Str(s) => {
+                    DataValue::DateTime(DateTimeTz::parse(s, Tz::UTC).map_err(|_| make_err())?)
+                }
+                DataValue::Date(d) => {
+                    let micros = (*d as i64) * MICROS_PER_DAY;
+                    DataValue::DateTime(DateTimeTz::from_micros(micros, Tz::UTC)?)
+                }
+                DataValue::Num(n) => {
+                    let micros = secs_to_micros(n.get_float()).map_err(|_| make_err())?;
+                    DataValue::DateTime(
+                        DateTimeTz::from_micros(micros, Tz::UTC).map_err(|_| make_err())?,
+                    )
+                }
+                _ => bail!(make_err()),
+            },
+            ColType::Duration => match &data {
+                DataValue::Duration(_) => data,
+                DataValue::Str(s) => {
+                    DataValue::Duration(parse_duration(s).map_err(|_| make_err())?)
+                }
+                DataValue::Num(n) => {
+                    DataValue::Duration(secs_to_micros(n.get_float()).map_err(|_| make_err())?)
+                }
+                _ => bail!(make_err()),
+            },
+            ColType::Decimal => match &data {
+                DataValue::Decimal(_) => data,
+                DataValue::Num(n) => {
+                    DataValue::Decimal(DecimalWrapper(n.get_decimal().ok_or_else(make_err)?))
+                }
+                DataValue::Str(s) => {
+                    DataValue::Decimal(DecimalWrapper::from_str(s).map_err(|_| make_err())?)
+                }
+                _ => bail!(make_err()),
+            },
+            ColType::Geometry => match &data {
+                DataValue::Geometry(_) => data,
+                DataValue::Str(s) => DataValue::Geometry(Geometry::parse(s)?),
+                DataValue::Json(j) => DataValue::Geometry(Geometry::from_geojson(&j.0)?),
+                _ => bail!(make_err()),
+            },
+            ColType::Record(fields) => {
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("field '{1}' is not part of the record type {0}")]
+                #[diagnostic(code(eval::coercion_unknown_field))]
+                struct UnknownRecordField(NullableColType, String);
+
+                let mut given: Vec<(SmartString<LazyCompact>, DataValue)> = match data {
+                    DataValue::Record(r) => r.0,
+                    DataValue::Json(JsonData(JsonValue::Object(obj))) => obj
+                        .into_iter()
+                        .map(|(k, v)| (SmartString::from(k), DataValue::from(v)))
+                        .collect(),
+                    DataValue::List(l) => {
+                        ensure!(
+                            fields.len() == l.len(),
+                            BadListLength(self.clone(), l.len())
+                        );
+                        fields.iter().map(|(name, _)| name.clone()).zip(l).collect()
+                    }
+                    _ => bail!(make_err()),
+                };
+                let mut ret = Vec::with_capacity(fields.len());
+                for (name, typ) in fields {
+                    // missing fields are null, which is only allowed for nullable fields
+                    let val = match given.iter().position(|(k, _)| k == name) {
+                        Some(i) => given.swap_remove(i).1,
+                        None => DataValue::Null,
+                    };
+                    ret.push((name.clone(), typ.coerce(val, cur_vld)?));
+                }
+                if let Some((name, _)) = given.first() {
+                    bail!(UnknownRecordField(self.clone(), name.to_string()))
+                }
+                DataValue::Record(Record(ret))
+            }
         })
     }
 }
@@ -0,0 +1,235 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fmt::{Display, Formatter, Write};
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone};
+use chrono_tz::Tz;
+use miette::{ensure, miette, Result};
+use smartstring::SmartString;
+
+use crate::data::value::DateTimeTz;
+
+/// `num_days_from_ce` of 1970-01-01
+const EPOCH_DAYS_FROM_CE: i32 = 719_163;
+pub(crate) const MICROS_PER_SEC: i64 = 1_000_000;
+pub(crate) const MICROS_PER_MINUTE: i64 = 60 * MICROS_PER_SEC;
+pub(crate) const MICROS_PER_HOUR: i64 = 60 * MICROS_PER_MINUTE;
+pub(crate) const MICROS_PER_DAY: i64 = 24 * MICROS_PER_HOUR;
+
+pub(crate) fn parse_tz(s: &str) -> Result<Tz> {
+    Tz::from_str(s).map_err(|_| miette!("bad timezone specification: {}", s))
+}
+
+pub(crate) fn days_to_date(days: i32) -> Result<NaiveDate> {
+    days.checked_add(EPOCH_DAYS_FROM_CE)
+        .and_then(NaiveDate::from_num_days_from_ce_opt)
+        .ok_or_else(|| miette!("date out of range: {} days since epoch", days))
+}
+
+pub(crate) fn date_to_days(date: NaiveDate) -> i32 {
+    date.num_days_from_ce() - EPOCH_DAYS_FROM_CE
+}
+
+/// Parses a date of the form `2022-12-31`
+pub(crate) fn parse_date(s: &str) -> Result<i32> {
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| miette!("bad date: {}", s))?;
+    Ok(date_to_days(date))
+}
+
+/// The date of the datetime in its own time zone
+pub(crate) fn datetime_to_date(dt: &DateTimeTz) -> Result<i32> {
+    Ok(date_to_days(dt.to_chrono()?.date_naive()))
+}
+
+pub(crate) fn format_date(days: i32) -> String {
+    match days_to_date(days) {
+        Ok(date) => date.format("%Y-%m-%d").to_string(),
+        Err(_) => days.to_string(),
+    }
+}
+
+/// Resolves a wall clock time in the time zone, taking the earlier instant if it is ambiguous
+pub(crate) fn local_to_datetime(local: NaiveDateTime, tz: Tz) -> Result<DateTimeTz> {
+    let dt = tz
+        .from_local_datetime(&local)
+        .earliest()
+        .ok_or_else(|| miette!("{} does not exist in time zone {}", local, tz.name()))?;
+    Ok(DateTimeTz::from_chrono(&dt))
+}
+
+impl DateTimeTz {
+    pub(crate) fn from_chrono(dt: &DateTime<Tz>) -> Self {
+        Self {
+            micros: dt.timestamp_micros(),
+            tz: SmartString::from(dt.timezone().name()),
+        }
+    }
+    pub(crate) fn from_micros(micros: i64, tz: Tz) -> Result<Self> {
+        let dt = DateTime::from_timestamp_micros(micros)
+            .ok_or_else(|| miette!("datetime out of range: {} microseconds since epoch", micros))?;
+        Ok(Self::from_chrono(&dt.with_timezone(&tz)))
+    }
+    pub(crate) fn timezone(&self) -> Result<Tz> {
+        parse_tz(&self.tz)
+    }
+    pub(crate) fn to_chrono(&self) -> Result<DateTime<Tz>> {
+        let tz = self.timezone()?;
+        let dt = DateTime::from_timestamp_micros(self.micros).ok_or_else(|| {
+            miette!(
+                "datetime out of range: {} microseconds since epoch",
+                self.micros
+            )
+        })?;
+        Ok(dt.with_timezone(&tz))
+    }
+    /// The same datetime shifted by some microseconds, in the same time zone
+    pub(crate) fn shifted(&self, micros: i64) -> Result<Self> {
+        let micros = self
+            .micros
+            .checked_add(micros)
+            .ok_or_else(|| miette!("datetime out of range"))?;
+        Self::from_micros(micros, self.timezone()?)
+    }
+    /// Parses RFC 3339 strings, optionally followed by a time zone in brackets as in
+    /// `2022-12-31T23:00:00+08:00[Asia/Shanghai]`, as well as local date times and dates,
+    /// which are interpreted in the time zone in brackets, or in `tz` if there is none.
+    pub(crate) fn parse(s: &str, tz: Tz) -> Result<Self> {
+        let (body, tz) = match s.strip_suffix(']').and_then(|rest| rest.rsplit_once('[')) {
+            Some((body, zone)) => (body, parse_tz(zone)?),
+            None => (s, tz),
+        };
+        if let Ok(dt) = DateTime::parse_from_rfc3339(body) {
+            return Ok(Self::from_chrono(&dt.with_timezone(&tz)));
+        }
+        let local = NaiveDateTime::parse_from_str(body, "%Y-%m-%dT%H:%M:%S%.f")
+            .or_else(|_| NaiveDateTime::parse_from_str(body, "%Y-%m-%d %H:%M:%S%.f"))
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(body, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+            })
+            .ok_or_else(|| miette!("bad datetime: {}", s))?;
+        local_to_datetime(local, tz)
+    }
+}
+
+impl Display for DateTimeTz {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.to_chrono() {
+            Ok(dt) => {
+                write!(f, "{}", dt.to_rfc3339_opts(SecondsFormat::AutoSi, true))?;
+                if self.tz.as_str() != "UTC" {
+                    write!(f, "[{}]", self.tz)?;
+                }
+                Ok(())
+            }
+            Err(_) => write!(f, "{}[{}]", self.micros, self.tz),
+        }
+    }
+}
+
+const DURATION_UNITS: [(&str, i64); 7] = [
+    ("w", 7 * MICROS_PER_DAY),
+    ("d", MICROS_PER_DAY),
+    ("h", MICROS_PER_HOUR),
+    ("m", MICROS_PER_MINUTE),
+    ("s", MICROS_PER_SEC),
+    ("ms", 1000),
+    ("us", 1),
+];
+
+/// Formats a duration as days, hours, minutes and seconds, e.g. `1d2h3m4.5s`
+pub(crate) fn format_duration(micros: i64) -> String {
+    if micros == 0 {
+        return "0s".to_string();
+    }
+    let mut ret = String::new();
+    if micros < 0 {
+        ret.push('-');
+    }
+    let mut rest = micros.unsigned_abs();
+    for (unit, size) in [
+        ("d", MICROS_PER_DAY),
+        ("h", MICROS_PER_HOUR),
+        ("m", MICROS_PER_MINUTE),
+    ] {
+        let n = rest / size as u64;
+        if n > 0 {
+            write!(ret, "{n}{unit}").unwrap();
+            rest %= size as u64;
+        }
+    }
+    if rest > 0 {
+        let secs = rest / MICROS_PER_SEC as u64;
+        let frac = rest % MICROS_PER_SEC as u64;
+        if frac == 0 {
+            write!(ret, "{secs}s").unwrap();
+        } else {
+            let frac = format!("{frac:06}");
+            write!(ret, "{secs}.{}s", frac.trim_end_matches('0')).unwrap();
+        }
+    }
+    ret
+}
+
+/// Parses a duration written as numbers followed by units, e.g. `1d12h`, `-90s` or `1.5h`.
+/// The units are `w`, `d`, `h`, `m`, `s`, `ms` and `us`.
+pub(crate) fn parse_duration(s: &str) -> Result<i64> {
+    let make_err = || miette!("bad duration: {}", s);
+    let s = s.trim();
+    let (negative, mut rest) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    ensure!(!rest.is_empty(), "bad duration: {}", s);
+    let mut total = 0i64;
+    while !rest.is_empty() {
+        let num_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(make_err)?;
+        let (num, after) = rest.split_at(num_len);
+        let unit_len = after
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        let size = match DURATION_UNITS.iter().find(|(name, _)| *name == unit.trim()) {
+            Some((_, size)) => *size,
+            None => return Err(make_err()),
+        };
+        let micros = match num.parse::<i64>() {
+            Ok(n) => n.checked_mul(size).ok_or_else(make_err)?,
+            Err(_) => {
+                let n = num.parse::<f64>().map_err(|_| make_err())?;
+                let micros = (n * size as f64).round();
+                ensure!(micros.abs() < i64::MAX as f64, "bad duration: {}", s);
+                micros as i64
+            }
+        };
+        total = total.checked_add(micros).ok_or_else(make_err)?;
+        rest = after;
+    }
+    Ok(if negative { -total } else { total })
+}
+
+/// Converts seconds to microseconds, failing if the result is out of range
+pub(crate) fn secs_to_micros(secs: f64) -> Result<i64> {
+    round_micros(secs * MICROS_PER_SEC as f64)
+}
+
+pub(crate) fn round_micros(micros: f64) -> Result<i64> {
+    let rounded = micros.round();
+    ensure!(
+        rounded.is_finite() && rounded.abs() < i64::MAX as f64,
+        "microseconds out of range: {}",
+        micros
+    );
+    Ok(rounded as i64)
+}
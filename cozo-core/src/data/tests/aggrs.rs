@@ -6,11 +6,13 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::str::FromStr;
+
 use approx::AbsDiffEq;
 use itertools::Itertools;
 
 use crate::data::aggr::parse_aggr;
-use crate::data::value::DataValue;
+use crate::data::value::{DataValue, DecimalWrapper};
 
 #[test]
 fn test_and() {
@@ -299,6 +301,29 @@ fn test_sum() {
     assert_eq!(sum_aggr.get().unwrap(), DataValue::from(15.));
 }
 
+#[test]
+fn test_decimal_sum_mean() {
+    let dec = |s: &str| DataValue::Decimal(DecimalWrapper::from_str(s).unwrap());
+
+    let mut aggr = parse_aggr("sum").unwrap().clone();
+    aggr.normal_init(&[]).unwrap();
+    let mut sum_aggr = aggr.normal_op.unwrap();
+    sum_aggr.set(&dec("0.1")).unwrap();
+    sum_aggr.set(&dec("0.2")).unwrap();
+    sum_aggr.set(&DataValue::from(1)).unwrap();
+    assert_eq!(sum_aggr.get().unwrap(), dec("1.3"));
+    sum_aggr.set(&DataValue::from(0.5)).unwrap();
+    assert_eq!(sum_aggr.get().unwrap(), DataValue::from(1.8));
+
+    let mut aggr = parse_aggr("mean").unwrap().clone();
+    aggr.normal_init(&[]).unwrap();
+    let mut mean_aggr = aggr.normal_op.unwrap();
+    mean_aggr.set(&dec("10.10")).unwrap();
+    mean_aggr.set(&dec("0.20")).unwrap();
+    mean_aggr.set(&DataValue::from(2)).unwrap();
+    assert_eq!(mean_aggr.get().unwrap(), dec("4.1"));
+}
+
 #[test]
 fn test_product() {
     let mut aggr = parse_aggr("product").unwrap().clone();
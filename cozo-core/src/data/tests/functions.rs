@@ -12,7 +12,7 @@ use regex::Regex;
 use serde_json::json;
 
 use crate::data::functions::*;
-use crate::data::value::{DataValue, RegexWrapper};
+use crate::data::value::{DataValue, Record, RegexWrapper};
 use crate::DbInstance;
 
 #[test]
@@ -1168,6 +1168,23 @@ fn test_get() {
         .unwrap(),
         DataValue::from(2)
     );
+    let rec = DataValue::Record(Record(vec![
+        ("street".into(), DataValue::from("Main")),
+        ("zip".into(), DataValue::from(12345)),
+    ]));
+    assert_eq!(
+        op_get(&[rec.clone(), DataValue::from("zip")]).unwrap(),
+        DataValue::from(12345)
+    );
+    assert_eq!(
+        op_get(&[rec.clone(), DataValue::from(0)]).unwrap(),
+        DataValue::from("Main")
+    );
+    assert!(op_get(&[rec.clone(), DataValue::from("city")]).is_err());
+    assert_eq!(
+        op_maybe_get(&[rec, DataValue::from("city")]).unwrap(),
+        DataValue::Null
+    );
 }
 
 #[test]
@@ -1487,3 +1504,168 @@ fn test_range() {
         .into_json();
     assert_eq!(res["rows"][0][0], json!([15, 13, 11, 9, 7, 5]));
 }
+
+#[test]
+fn test_temporal() {
+    let db = DbInstance::default();
+    let eval = |expr: &str| {
+        db.run_default(&format!("?[a] := a = {expr}"))
+            .unwrap()
+            .into_json()["rows"][0][0]
+            .clone()
+    };
+    assert_eq!(
+        eval("add_months(to_date('2024-01-31'), 1)"),
+        json!("2024-02-29")
+    );
+    assert_eq!(
+        eval("add_months(to_date('2024-03-31'), -13)"),
+        json!("2023-02-28")
+    );
+    assert_eq!(
+        eval("add_months(to_datetime('2024-01-31T10:00:00', 'Europe/Paris'), 2)"),
+        json!("2024-03-31T10:00:00+02:00[Europe/Paris]")
+    );
+    assert_eq!(eval("extract('dow', to_date('2024-06-02'))"), json!(0));
+    assert_eq!(eval("extract('isodow', to_date('2024-06-02'))"), json!(7));
+    assert_eq!(eval("extract('quarter', to_date('2024-06-02'))"), json!(2));
+    assert_eq!(
+        eval("extract('hour', to_datetime('2024-03-15T10:20:30+08:00[Asia/Shanghai]'))"),
+        json!(10)
+    );
+    assert_eq!(
+        eval("extract('epoch', to_date('1970-01-02'))"),
+        json!(86400.0)
+    );
+    assert_eq!(
+        eval("date_trunc('week', to_date('2024-06-02'))"),
+        json!("2024-05-27")
+    );
+    assert_eq!(
+        eval("date_trunc('month', to_datetime('2024-03-15T10:20:30+08:00[Asia/Shanghai]'))"),
+        json!("2024-03-01T00:00:00+08:00[Asia/Shanghai]")
+    );
+    assert_eq!(
+        eval("to_datetime('2024-03-10T12:00:00Z') - to_datetime('2024-03-09T12:00:00Z')"),
+        json!(86400)
+    );
+    assert_eq!(
+        eval("to_datetime('2024-03-09T12:00:00', 'America/New_York') + to_duration('1d')"),
+        json!("2024-03-10T13:00:00-04:00[America/New_York]")
+    );
+    assert_eq!(
+        eval("to_date('2024-02-28') + to_duration('2d')"),
+        json!("2024-03-01")
+    );
+    assert_eq!(eval("to_duration('1h30m') * 2"), json!(10800));
+    assert_eq!(
+        eval("to_duration('90m') == to_duration('1.5h')"),
+        json!(true)
+    );
+    assert_eq!(
+        eval("to_date('2024-01-01') < to_date('2024-01-02')"),
+        json!(true)
+    );
+    assert!(db
+        .run_default("?[a] := a = to_date('2024-01-01') + to_duration('1h')")
+        .is_err());
+
+    assert_eq!(
+        op_to_duration(&[DataValue::from("-1d2h3m4.5s")]).unwrap(),
+        DataValue::Duration(-93_784_500_000)
+    );
+    assert_eq!(
+        DataValue::Duration(93_784_500_000).to_string(),
+        r#"to_duration("1d2h3m4.5s")"#
+    );
+}
+
+#[test]
+fn test_geometry() {
+    let db = DbInstance::default();
+    let eval = |expr: &str| {
+        db.run_default(&format!("?[a] := a = {expr}"))
+            .unwrap()
+            .into_json()["rows"][0][0]
+            .clone()
+    };
+    let square = "to_geometry('POLYGON ((0 0, 4 0, 4 4, 0 4, 0 0), (1 1, 2 1, 2 2, 1 2, 1 1))')";
+    assert_eq!(
+        eval("st_point(1, 2)"),
+        json!({"type": "Point", "coordinates": [1.0, 2.0]})
+    );
+    assert_eq!(
+        eval(r#"to_geometry('{"type": "LineString", "coordinates": [[0, 0], [3, 4]]}')"#),
+        json!({"type": "LineString", "coordinates": [[0.0, 0.0], [3.0, 4.0]]})
+    );
+    assert_eq!(
+        eval("to_geometry('POLYGON ((0 0, 1 0, 1 1))')"),
+        json!({"type": "Polygon", "coordinates": [[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 0.0]]]})
+    );
+    assert_eq!(
+        eval("st_distance(st_point(0, 0), st_point(3, 4))"),
+        json!(5.0)
+    );
+    assert_eq!(
+        eval("st_distance(st_point(0, 3), to_geometry('LINESTRING (-1 0, 1 0)'))"),
+        json!(3.0)
+    );
+    assert_eq!(
+        eval(&format!("st_distance({square}, st_point(7, 8))")),
+        json!(5.0)
+    );
+    assert_eq!(
+        eval(&format!("st_contains({square}, st_point(3, 3))")),
+        json!(true)
+    );
+    assert_eq!(
+        eval(&format!("st_contains({square}, st_point(4, 2))")),
+        json!(true)
+    );
+    // in the hole
+    assert_eq!(
+        eval(&format!("st_contains({square}, st_point(1.5, 1.5))")),
+        json!(false)
+    );
+    assert_eq!(
+        eval(&format!(
+            "st_contains({square}, to_geometry('LINESTRING (0.5 0.5, 3 3)'))"
+        )),
+        json!(false)
+    );
+    assert_eq!(
+        eval(&format!(
+            "st_contains({square}, to_geometry('LINESTRING (3 0.5, 3 3.5)'))"
+        )),
+        json!(true)
+    );
+    assert_eq!(
+        eval(&format!(
+            "st_intersects({square}, to_geometry('LINESTRING (-1 -1, 0.5 0.5)'))"
+        )),
+        json!(true)
+    );
+    assert_eq!(
+        eval(&format!("st_intersects({square}, st_point(1.5, 1.5))")),
+        json!(false)
+    );
+    assert_eq!(
+        eval("st_dwithin(st_point(0, 0), st_point(1, 1), 1.5)"),
+        json!(true)
+    );
+    assert_eq!(
+        eval("st_dwithin(st_point(0, 0), st_point(1, 1), 1.4)"),
+        json!(false)
+    );
+    assert!(db
+        .run_default("?[a] := a = to_geometry('LINESTRING (0 0)')")
+        .is_err());
+    assert!(db.run_default("?[a] := a = st_point(0, 'a')").is_err());
+
+    assert_eq!(
+        op_to_geometry(&[DataValue::from("POINT (1.5 -2)")])
+            .unwrap()
+            .to_string(),
+        r#"to_geometry("POINT (1.5 -2)")"#
+    );
+}
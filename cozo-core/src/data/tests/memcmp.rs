@@ -7,10 +7,18 @@
  *
  */
 
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::str::FromStr;
+
+use itertools::Itertools;
+use smartstring::SmartString;
 use uuid::Uuid;
 
 use crate::data::memcmp::{decode_bytes, MemCmpEncoder};
-use crate::data::value::{DataValue, Num, UuidWrapper};
+use crate::data::value::{
+    DataValue, DateTimeTz, DecimalWrapper, Geometry, Num, Record, UuidWrapper,
+};
 
 #[test]
 fn encode_decode_num() {
@@ -61,6 +69,146 @@ fn test_encode_decode_uuid() {
     assert!(remaining.is_empty());
 }
 
+#[test]
+fn encode_decode_temporal() {
+    let dt = |micros: i64, tz: &str| {
+        DataValue::DateTime(DateTimeTz {
+            micros,
+            tz: tz.into(),
+        })
+    };
+    let values = vec![
+        DataValue::Date(-719_162),
+        DataValue::Date(-1),
+        DataValue::Date(0),
+        DataValue::Date(19_782),
+        dt(-1_000_000, "UTC"),
+        dt(0, "Asia/Shanghai"),
+        dt(0, "UTC"),
+        dt(1_700_000_000_000_000, "America/New_York"),
+        DataValue::Duration(i64::MIN),
+        DataValue::Duration(-1),
+        DataValue::Duration(0),
+        DataValue::Duration(3_600_000_000),
+    ];
+    let mut sorted_values = values.clone();
+    sorted_values.sort();
+    assert_eq!(values, sorted_values);
+
+    let mut collected = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(&decoded, v);
+        assert!(remaining.is_empty());
+        collected.push(encoder);
+    }
+    let mut sorted_collected = collected.clone();
+    sorted_collected.sort();
+    assert_eq!(collected, sorted_collected);
+}
+
+#[test]
+fn encode_decode_decimal() {
+    let values = [
+        "-1e30",
+        "-123.45",
+        "-12",
+        "-11.9",
+        "-10",
+        "-1.5",
+        "-1",
+        "-0.05",
+        "0",
+        "0.000001",
+        "0.05",
+        "0.5",
+        "1",
+        "1.000000000000000000001",
+        "1.5",
+        "10",
+        "99.99",
+        "100",
+        "12345678901234567890",
+    ]
+    .iter()
+    .map(|s| DataValue::Decimal(DecimalWrapper::from_str(s).unwrap()))
+    .collect_vec();
+    let mut sorted_values = values.clone();
+    sorted_values.sort();
+    assert_eq!(values, sorted_values);
+
+    let mut collected = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(&decoded, v);
+        assert!(remaining.is_empty());
+        collected.push(encoder);
+    }
+    let mut sorted_collected = collected.clone();
+    sorted_collected.sort();
+    assert_eq!(collected, sorted_collected);
+
+    // trailing zeros do not matter
+    let mut a = vec![];
+    a.encode_datavalue(&DataValue::Decimal(
+        DecimalWrapper::from_str("1.50").unwrap(),
+    ));
+    let mut b = vec![];
+    b.encode_datavalue(&DataValue::Decimal(
+        DecimalWrapper::from_str("1.5").unwrap(),
+    ));
+    assert_eq!(a, b);
+}
+
+#[test]
+fn decimals_among_numbers() {
+    let dec = |s: &str| DataValue::Decimal(DecimalWrapper::from_str(s).unwrap());
+    let values = [
+        DataValue::from(f64::NEG_INFINITY),
+        dec("-1e400"),
+        DataValue::from(-2),
+        dec("-1.5"),
+        DataValue::from(1),
+        DataValue::from(1.0),
+        dec("0.99999999999999999999"),
+        dec("1"),
+        dec("1.000000000000000000001"),
+        DataValue::from(1.5),
+        dec("1.5"),
+        DataValue::from(9007199254740993i64),
+        dec("9007199254740992.5"),
+        dec("9007199254740993"),
+        DataValue::from(1e300),
+        DataValue::from(f64::INFINITY),
+        dec("1e400"),
+        DataValue::from(f64::NAN),
+        DataValue::from("a"),
+    ];
+    let mut sorted_values = values.to_vec();
+    sorted_values.sort();
+    assert_eq!(values.to_vec(), sorted_values);
+
+    let mut collected = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(decoded.cmp(v), std::cmp::Ordering::Equal);
+        assert!(remaining.is_empty());
+        collected.push(encoder);
+    }
+    let mut sorted_collected = collected.clone();
+    sorted_collected.sort();
+    assert_eq!(collected, sorted_collected);
+
+    let state = RandomState::new();
+    assert_eq!(state.hash_one(dec("1.50")), state.hash_one(dec("1.5")));
+}
+
 #[test]
 fn encode_decode_bytes() {
     let target = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit...";
@@ -108,6 +256,80 @@ fn specific_encode() {
     assert_eq!(b, DataValue::from("MSS"));
 }
 
+#[test]
+fn encode_decode_geometry() {
+    let values = [
+        "POINT (-1 2)",
+        "POINT (0 -3)",
+        "POINT (0 1.5)",
+        "LINESTRING (0 0, 1 1)",
+        "LINESTRING (0 0, 1 1, 2 0)",
+        "LINESTRING (0 1, 1 1)",
+        "POLYGON ((0 0, 1 0, 1 1, 0 0))",
+        "POLYGON ((0 0, 1 0, 1 1, 0 0), (0.5 0.2, 0.8 0.2, 0.8 0.5, 0.5 0.2))",
+        "POLYGON ((0 0, 2 0, 2 2, 0 0))",
+    ]
+    .iter()
+    .map(|s| DataValue::Geometry(Geometry::parse(s).unwrap()))
+    .collect_vec();
+    let mut sorted_values = values.clone();
+    sorted_values.sort();
+    assert_eq!(values, sorted_values);
+
+    let mut collected = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(&decoded, v);
+        assert!(remaining.is_empty());
+        collected.push(encoder);
+    }
+    let mut sorted_collected = collected.clone();
+    sorted_collected.sort();
+    assert_eq!(collected, sorted_collected);
+}
+
+#[test]
+fn encode_decode_record() {
+    let record = |fields: &[(&str, DataValue)]| {
+        DataValue::Record(Record(
+            fields
+                .iter()
+                .map(|(k, v)| (SmartString::from(*k), v.clone()))
+                .collect(),
+        ))
+    };
+    let values = [
+        record(&[]),
+        record(&[("a", DataValue::Null)]),
+        record(&[("a", DataValue::from(1))]),
+        record(&[("a", DataValue::from(1)), ("b", DataValue::from("x"))]),
+        record(&[("a", DataValue::from(2))]),
+        record(&[("ab", DataValue::from(0))]),
+        record(&[(
+            "b",
+            record(&[("c", DataValue::List(vec![DataValue::from(1)]))]),
+        )]),
+    ];
+    let mut sorted_values = values.to_vec();
+    sorted_values.sort();
+    assert_eq!(values.to_vec(), sorted_values);
+
+    let mut collected = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(&decoded, v);
+        assert!(remaining.is_empty());
+        collected.push(encoder);
+    }
+    let mut sorted_collected = collected.clone();
+    sorted_collected.sort();
+    assert_eq!(collected, sorted_collected);
+}
+
 #[test]
 fn encode_decode_datavalues() {
     let mut dv = vec![
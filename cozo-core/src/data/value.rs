@@ -8,15 +8,18 @@
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
+use bigdecimal::{BigDecimal, ParseBigDecimalError, ToPrimitive};
 use ndarray::Array1;
 use std::cmp::{Ordering, Reverse};
 use std::collections::BTreeSet;
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
+use std::str::FromStr;
 
 use crate::data::json::JsonValue;
 use crate::data::relation::VecElementType;
+use crate::data::temporal::{format_date, format_duration};
 use ordered_float::OrderedFloat;
 use regex::Regex;
 use serde::de::{SeqAccess, Visitor};
@@ -139,10 +142,161 @@ impl From<(i64, bool)> for Validity {
     }
 }
 
-/// A Value in the database
+/// Date and time in a time zone, ordered by the instant first and then by the zone name
 #[derive(
-Clone, PartialEq, Eq, PartialOrd, Ord, serde_derive::Deserialize, serde_derive::Serialize, Hash,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    serde_derive::Deserialize,
+    serde_derive::Serialize,
+    Hash,
+    Debug,
 )]
+pub struct DateTimeTz {
+    /// Microseconds since the UNIX epoch
+    pub micros: i64,
+    /// Name of the time zone in the IANA database
+    pub tz: SmartString<LazyCompact>,
+}
+
+/// Arbitrary-precision decimal number in the database
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub struct DecimalWrapper(pub BigDecimal);
+
+impl DecimalWrapper {
+    /// The nearest float, infinite beyond the range of floats. Rounding as in parsing keeps
+    /// larger decimals from getting smaller floats.
+    pub(crate) fn approx_float(&self) -> f64 {
+        self.0.to_string().parse().unwrap()
+    }
+}
+
+impl Hash for DecimalWrapper {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // equal decimals such as 1.5 and 1.50 differ only in trailing zeros
+        let (digits, exponent) = self.0.normalized().into_bigint_and_exponent();
+        digits.hash(state);
+        exponent.hash(state);
+    }
+}
+
+impl Serialize for DecimalWrapper {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+    {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for DecimalWrapper {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for DecimalWrapper {
+    type Err = ParseBigDecimalError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(DecimalWrapper(BigDecimal::from_str(s.trim())?))
+    }
+}
+
+impl Display for DecimalWrapper {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A coordinate of a planar geometry, compared and hashed bitwise so that it can be used in keys
+#[derive(Clone, Copy, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Coord {
+    /// x coordinate, or longitude
+    pub x: f64,
+    /// y coordinate, or latitude
+    pub y: f64,
+}
+
+impl PartialEq for Coord {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Coord {}
+
+impl PartialOrd for Coord {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Coord {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.x
+            .total_cmp(&other.x)
+            .then_with(|| self.y.total_cmp(&other.y))
+    }
+}
+
+impl Hash for Coord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.x.to_bits().hash(state);
+        self.y.to_bits().hash(state);
+    }
+}
+
+/// Planar geometry in the database
+#[derive(
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    serde_derive::Deserialize,
+    serde_derive::Serialize,
+    Hash,
+    Debug,
+)]
+pub enum Geometry {
+    /// a single point
+    Point(Coord),
+    /// a path of at least two points
+    LineString(Vec<Coord>),
+    /// closed rings, the exterior ring first followed by the holes
+    Polygon(Vec<Vec<Coord>>),
+}
+
+/// Record with named fields in the database, the fields in the order given by the record type
+#[derive(
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    serde_derive::Deserialize,
+    serde_derive::Serialize,
+    Hash,
+    Debug,
+)]
+pub struct Record(pub Vec<(SmartString<LazyCompact>, DataValue)>);
+
+impl Record {
+    /// Returns the value of the named field
+    pub fn get(&self, name: &str) -> Option<&DataValue> {
+        self.0.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+}
+
+/// A Value in the database
+#[derive(Clone, PartialEq, Eq, serde_derive::Deserialize, serde_derive::Serialize, Hash)]
 pub enum DataValue {
     /// null
     Null,
@@ -169,10 +323,92 @@ pub enum DataValue {
     Json(JsonData),
     /// validity,
     Validity(Validity),
+    /// calendar date, as days since 1970-01-01
+    Date(i32),
+    /// date and time in a time zone
+    DateTime(DateTimeTz),
+    /// duration, in microseconds
+    Duration(i64),
+    /// arbitrary-precision decimal number. Like integers and floats, it is compared with other
+    /// numbers by the nearest float, and sorted after the numbers of the same nearest float,
+    /// so numbers are only found among decimal keys when looked up in stored `Decimal` columns
+    Decimal(DecimalWrapper),
+    /// planar geometry
+    Geometry(Geometry),
+    /// record with named fields
+    Record(Record),
     /// bottom type, used internally only
     Bot,
 }
 
+impl DataValue {
+    /// The position of the kind of the value in the ordering of values, shared by decimals
+    /// and other numbers
+    fn kind_order(&self) -> u8 {
+        match self {
+            DataValue::Null => 0,
+            DataValue::Bool(_) => 1,
+            DataValue::Num(_) | DataValue::Decimal(_) => 2,
+            DataValue::Str(_) => 3,
+            DataValue::Bytes(_) => 4,
+            DataValue::Uuid(_) => 5,
+            DataValue::Regex(_) => 6,
+            DataValue::List(_) => 7,
+            DataValue::Set(_) => 8,
+            DataValue::Vec(_) => 9,
+            DataValue::Json(_) => 10,
+            DataValue::Validity(_) => 11,
+            DataValue::Date(_) => 12,
+            DataValue::DateTime(_) => 13,
+            DataValue::Duration(_) => 14,
+            DataValue::Geometry(_) => 15,
+            DataValue::Record(_) => 16,
+            DataValue::Bot => 17,
+        }
+    }
+}
+
+/// Decimals are ordered among the numbers as integers and floats are: by the nearest float
+/// first, and then integers and floats before decimals. This is also the order of the keys.
+fn cmp_num_decimal(n: &Num, d: &DecimalWrapper) -> Ordering {
+    n.get_float()
+        .total_cmp(&d.approx_float())
+        .then(Ordering::Less)
+}
+
+impl PartialOrd for DataValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DataValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (DataValue::Bool(l), DataValue::Bool(r)) => l.cmp(r),
+            (DataValue::Num(l), DataValue::Num(r)) => l.cmp(r),
+            (DataValue::Num(l), DataValue::Decimal(r)) => cmp_num_decimal(l, r),
+            (DataValue::Decimal(l), DataValue::Num(r)) => cmp_num_decimal(r, l).reverse(),
+            (DataValue::Decimal(l), DataValue::Decimal(r)) => l.cmp(r),
+            (DataValue::Str(l), DataValue::Str(r)) => l.cmp(r),
+            (DataValue::Bytes(l), DataValue::Bytes(r)) => l.cmp(r),
+            (DataValue::Uuid(l), DataValue::Uuid(r)) => l.cmp(r),
+            (DataValue::Regex(l), DataValue::Regex(r)) => l.cmp(r),
+            (DataValue::List(l), DataValue::List(r)) => l.cmp(r),
+            (DataValue::Set(l), DataValue::Set(r)) => l.cmp(r),
+            (DataValue::Vec(l), DataValue::Vec(r)) => l.cmp(r),
+            (DataValue::Json(l), DataValue::Json(r)) => l.cmp(r),
+            (DataValue::Validity(l), DataValue::Validity(r)) => l.cmp(r),
+            (DataValue::Date(l), DataValue::Date(r)) => l.cmp(r),
+            (DataValue::DateTime(l), DataValue::DateTime(r)) => l.cmp(r),
+            (DataValue::Duration(l), DataValue::Duration(r)) => l.cmp(r),
+            (DataValue::Geometry(l), DataValue::Geometry(r)) => l.cmp(r),
+            (DataValue::Record(l), DataValue::Record(r)) => l.cmp(r),
+            (l, r) => l.kind_order().cmp(&r.kind_order()),
+        }
+    }
+}
+
 /// Wrapper for JsonValue
 #[derive(Clone, PartialEq, Eq, serde_derive::Deserialize, serde_derive::Serialize)]
 pub struct JsonData(pub JsonValue);
@@ -526,6 +762,20 @@ impl Num {
             Num::Float(f) => *f,
         }
     }
+    /// The exact decimal value of integers, and the shortest decimal representation
+    /// of finite floats
+    pub(crate) fn get_decimal(&self) -> Option<BigDecimal> {
+        match self {
+            Num::Int(i) => Some(BigDecimal::from(*i)),
+            Num::Float(f) => {
+                if f.is_finite() {
+                    BigDecimal::from_str(&f.to_string()).ok()
+                } else {
+                    None
+                }
+            }
+        }
+    }
 }
 
 impl PartialEq for Num {
@@ -644,6 +894,15 @@ impl Display for DataValue {
                     write!(f, "json({})", j.0)
                 }
             }
+            DataValue::Date(d) => write!(f, "to_date({:?})", format_date(*d)),
+            DataValue::DateTime(dt) => write!(f, "to_datetime({:?})", dt.to_string()),
+            DataValue::Duration(d) => write!(f, "to_duration({:?})", format_duration(*d)),
+            DataValue::Decimal(d) => write!(f, "to_decimal({:?})", d.to_string()),
+            DataValue::Geometry(g) => write!(f, "to_geometry({:?})", g.to_string()),
+            DataValue::Record(r) => f
+                .debug_map()
+                .entries(r.0.iter().map(|(k, v)| (k, v)))
+                .finish(),
         }
     }
 }
@@ -674,6 +933,7 @@ impl DataValue {
     pub fn get_int(&self) -> Option<i64> {
         match self {
             DataValue::Num(n) => n.get_int(),
+            DataValue::Decimal(d) if d.0.is_integer() => d.0.to_i64(),
             _ => None,
         }
     }
@@ -689,6 +949,7 @@ impl DataValue {
     pub fn get_float(&self) -> Option<f64> {
         match self {
             DataValue::Num(n) => Some(n.get_float()),
+            DataValue::Decimal(d) => d.0.to_f64(),
             _ => None,
         }
     }
@@ -64,16 +64,18 @@ pub use storage::sqlite::{new_cozo_sqlite, SqliteStorage};
 pub use storage::tikv::{new_cozo_tikv, TiKvStorage};
 pub use storage::{Storage, StoreTx};
 
-pub use crate::data::expr::Expr;
+pub use crate::data::aggr::{CustomAggregation, MeetAggrObj, NormalAggrObj};
+pub use crate::data::expr::{CustomOp, Expr};
 use crate::data::json::JsonValue;
 pub use crate::data::symb::Symbol;
-pub use crate::data::value::{JsonData, Vector};
+pub use crate::data::value::{Coord, DateTimeTz, DecimalWrapper, Geometry, JsonData, Record, Vector};
 pub use crate::fixed_rule::SimpleFixedRule;
 pub use crate::parse::SourceSpan;
 pub use crate::runtime::callback::CallbackOp;
 pub use crate::runtime::db::evaluate_expressions;
 pub use crate::runtime::db::get_variables;
 pub use crate::runtime::db::Poison;
+pub use crate::runtime::db::QueryLimits;
 pub use crate::runtime::db::ScriptMutability;
 pub use crate::runtime::db::Payload;
 pub use crate::runtime::db::TransactionPayload;
@@ -189,6 +191,57 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.run_script(payload, params, mutability),
         }
     }
+    /// Dispatcher method. See [crate::Db::run_script_with_limits].
+    pub fn run_script_with_limits(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        mutability: ScriptMutability,
+        limits: QueryLimits,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_with_limits(payload, params, mutability, limits),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => {
+                db.run_script_with_limits(payload, params, mutability, limits)
+            }
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => {
+                db.run_script_with_limits(payload, params, mutability, limits)
+            }
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_with_limits(payload, params, mutability, limits),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_with_limits(payload, params, mutability, limits),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::run_cypher].
+    pub fn run_cypher(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        self.run_cypher_with_limits(payload, params, QueryLimits::default())
+    }
+    /// Dispatcher method. See [crate::Db::run_cypher_with_limits].
+    pub fn run_cypher_with_limits(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        limits: QueryLimits,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.run_cypher_with_limits(payload, params, limits),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_cypher_with_limits(payload, params, limits),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_cypher_with_limits(payload, params, limits),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_cypher_with_limits(payload, params, limits),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_cypher_with_limits(payload, params, limits),
+        }
+    }
     /// `run_script` with mutable script and no parameters
     pub fn run_default(&self, payload: &str) -> Result<NamedRows> {
         self.run_script(payload, BTreeMap::new(), ScriptMutability::Mutable)
@@ -201,11 +254,23 @@ impl DbInstance {
         payload: &str,
         params: BTreeMap<String, DataValue>,
         mutability: ScriptMutability,
+    ) -> JsonValue {
+        self.run_script_with_limits_fold_err(payload, params, mutability, QueryLimits::default())
+    }
+    /// Run the CozoScript passed in with the given resource limits.
+    /// Fold any error into the return JSON itself.
+    /// See [crate::Db::run_script_with_limits].
+    pub fn run_script_with_limits_fold_err(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        mutability: ScriptMutability,
+        limits: QueryLimits,
     ) -> JsonValue {
         #[cfg(not(target_arch = "wasm32"))]
             let start = Instant::now();
 
-        match self.run_script(payload, params, mutability) {
+        match self.run_script_with_limits(payload, params, mutability, limits) {
             Ok(named_rows) => {
                 let mut j_val = named_rows.into_json();
                 #[cfg(not(target_arch = "wasm32"))]
@@ -220,6 +285,33 @@ impl DbInstance {
             Err(err) => format_error_as_json(err, Some(payload)),
         }
     }
+    /// Run the Cypher query passed in with the given resource limits.
+    /// Fold any error into the return JSON itself.
+    /// See [crate::Db::run_cypher_with_limits].
+    pub fn run_cypher_with_limits_fold_err(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        limits: QueryLimits,
+    ) -> JsonValue {
+        #[cfg(not(target_arch = "wasm32"))]
+        let start = Instant::now();
+
+        match self.run_cypher_with_limits(payload, params, limits) {
+            Ok(named_rows) => {
+                let mut j_val = named_rows.into_json();
+                #[cfg(not(target_arch = "wasm32"))]
+                let took = start.elapsed().as_secs_f64();
+                let map = j_val.as_object_mut().unwrap();
+                map.insert("ok".to_string(), json!(true));
+                #[cfg(not(target_arch = "wasm32"))]
+                map.insert("took".to_string(), json!(took));
+
+                j_val
+            }
+            Err(err) => format_error_as_json(err, Some(payload)),
+        }
+    }
     /// Run the CozoScript passed in. The `params` argument is a map of parameters formatted as JSON.
     /// See [crate::Db::run_script].
     pub fn run_script_str(&self, payload: &str, params: &str, immutable: bool) -> String {
@@ -395,8 +487,38 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.import_from_backup(in_file, relations),
         }
     }
+    /// Dispatcher method. See [crate::Db::import_from_backup_with_checks].
+    pub fn import_from_backup_with_checks(
+        &self,
+        in_file: impl AsRef<Path>,
+        relations: &[String],
+        check_constraints: bool,
+    ) -> Result<()> {
+        match self {
+            DbInstance::Mem(db) => {
+                db.import_from_backup_with_checks(in_file, relations, check_constraints)
+            }
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => {
+                db.import_from_backup_with_checks(in_file, relations, check_constraints)
+            }
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => {
+                db.import_from_backup_with_checks(in_file, relations, check_constraints)
+            }
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => {
+                db.import_from_backup_with_checks(in_file, relations, check_constraints)
+            }
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => {
+                db.import_from_backup_with_checks(in_file, relations, check_constraints)
+            }
+        }
+    }
     /// Import relations from an Sqlite backup, with JSON string return value.
-    /// See [crate::Db::import_from_backup].
+    /// The checks of constraints are skipped if the field `skip_checks` of the payload is set.
+    /// See [crate::Db::import_from_backup_with_checks].
     pub fn import_from_backup_str(&self, payload: &str) -> String {
         match self.import_from_backup_str_inner(payload) {
             Ok(_) => json!({"ok": true}).to_string(),
@@ -408,10 +530,16 @@ impl DbInstance {
         struct Payload {
             path: String,
             relations: Vec<String>,
+            #[serde(default)]
+            skip_checks: bool,
         }
         let json_payload: Payload = serde_json::from_str(payload).into_diagnostic()?;
 
-        self.import_from_backup(&json_payload.path, &json_payload.relations)
+        self.import_from_backup_with_checks(
+            &json_payload.path,
+            &json_payload.relations,
+            !json_payload.skip_checks,
+        )
     }
 
     /// Dispatcher method. See [crate::Db::register_callback].
@@ -480,6 +608,98 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.unregister_fixed_rule(name),
         }
     }
+    /// Dispatcher method. See [crate::Db::register_function].
+    pub fn register_function<F>(&self, name: String, arity: usize, func: F) -> Result<()>
+        where
+            F: CustomOp + 'static,
+    {
+        match self {
+            DbInstance::Mem(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.register_function(name, arity, func),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::unregister_function]
+    pub fn unregister_function(&self, name: &str) -> Result<bool> {
+        match self {
+            DbInstance::Mem(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.unregister_function(name),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::register_aggregation].
+    pub fn register_aggregation<A>(&self, name: String, aggr: A) -> Result<()>
+        where
+            A: CustomAggregation + 'static,
+    {
+        match self {
+            DbInstance::Mem(db) => db.register_aggregation(name, aggr),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.register_aggregation(name, aggr),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.register_aggregation(name, aggr),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.register_aggregation(name, aggr),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.register_aggregation(name, aggr),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::unregister_aggregation]
+    pub fn unregister_aggregation(&self, name: &str) -> Result<bool> {
+        match self {
+            DbInstance::Mem(db) => db.unregister_aggregation(name),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.unregister_aggregation(name),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.unregister_aggregation(name),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.unregister_aggregation(name),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.unregister_aggregation(name),
+        }
+    }
+
+    /// Dispatcher method. See [crate::Db::set_memory_budget]
+    pub fn set_memory_budget(&self, bytes: Option<usize>) {
+        match self {
+            DbInstance::Mem(db) => db.set_memory_budget(bytes),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_memory_budget(bytes),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_memory_budget(bytes),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_memory_budget(bytes),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_memory_budget(bytes),
+        }
+    }
+
+    /// Dispatcher method. See [crate::Db::set_default_limits]
+    pub fn set_default_limits(&self, limits: QueryLimits) {
+        match self {
+            DbInstance::Mem(db) => db.set_default_limits(limits),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_default_limits(limits),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_default_limits(limits),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_default_limits(limits),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_default_limits(limits),
+        }
+    }
 
     /// Dispatcher method. See [crate::Db::run_multi_transaction]
     pub fn run_multi_transaction(
@@ -0,0 +1,1027 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! A front-end translating a subset of Cypher into CozoScript programs.
+//!
+//! Node labels and relationship types name stored relations through a mapping given by the
+//! caller. The node itself is the first column of its relation, and a relationship goes
+//! from the first column of its relation to the second, as for path expressions. The other
+//! columns are the properties. Results are sets, as if `RETURN DISTINCT` were always given.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use miette::{bail, ensure, Diagnostic, Result};
+use pest::pratt_parser::{Op, PrattParser};
+use pest::Parser;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::aggr::{parse_aggr, Aggregation};
+use crate::data::expr::{get_op, Expr};
+use crate::data::functions::{
+    OP_ADD, OP_AND, OP_DIV, OP_ENDS_WITH, OP_EQ, OP_GE, OP_GT, OP_IS_IN, OP_IS_NULL, OP_LE,
+    OP_LIST, OP_LT, OP_MINUS, OP_MOD, OP_MUL, OP_NEGATE, OP_NEQ, OP_OR, OP_POW, OP_STARTS_WITH,
+    OP_STR_INCLUDES, OP_SUB,
+};
+use crate::data::program::{
+    InputAtom, InputInlineRule, InputInlineRulesOrFixed, InputPathAtom, InputProgram,
+    InputRelationApplyAtom, InputRuleApplyAtom, PathExpr, QueryOutOptions, SortDir,
+};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::value::DataValue;
+use crate::parse::{ExtractSpan, ParseError, SourceSpan};
+
+#[derive(pest_derive::Parser)]
+#[grammar = "cypher.pest"]
+struct CypherParser;
+
+type Pair<'a> = pest::iterators::Pair<'a, Rule>;
+
+lazy_static! {
+    static ref PRATT_PARSER: PrattParser<Rule> = {
+        use pest::pratt_parser::Assoc::*;
+
+        PrattParser::new()
+            .op(Op::infix(Rule::op_or, Left))
+            .op(Op::infix(Rule::op_and, Left))
+            .op(Op::prefix(Rule::op_not))
+            .op(Op::infix(Rule::op_eq, Left)
+                | Op::infix(Rule::op_ne, Left)
+                | Op::infix(Rule::op_lt, Left)
+                | Op::infix(Rule::op_gt, Left)
+                | Op::infix(Rule::op_le, Left)
+                | Op::infix(Rule::op_ge, Left)
+                | Op::infix(Rule::op_starts_with, Left)
+                | Op::infix(Rule::op_ends_with, Left)
+                | Op::infix(Rule::op_contains, Left)
+                | Op::infix(Rule::op_in, Left))
+            .op(Op::infix(Rule::op_add, Left) | Op::infix(Rule::op_sub, Left))
+            .op(Op::infix(Rule::op_mul, Left)
+                | Op::infix(Rule::op_div, Left)
+                | Op::infix(Rule::op_mod, Left))
+            .op(Op::infix(Rule::op_pow, Right))
+            .op(Op::prefix(Rule::op_minus))
+            .op(Op::postfix(Rule::op_is_null) | Op::postfix(Rule::op_is_not_null))
+    };
+}
+
+/// The stored relation a label or relationship type is mapped to.
+#[derive(Debug, Clone)]
+pub(crate) struct CypherLabel {
+    pub(crate) relation: SmartString<LazyCompact>,
+    pub(crate) columns: Vec<SmartString<LazyCompact>>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Label {0} is not mapped to a stored relation")]
+#[diagnostic(code(cypher::unknown_label))]
+#[diagnostic(help(
+    "Labels are mapped by the rows of the stored relation declared with `::cypher_mapping`"
+))]
+struct UnknownLabel(String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Unknown variable {0}")]
+#[diagnostic(code(cypher::unknown_variable))]
+struct UnknownVariable(String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("No relation matched by {0} has the property {1}")]
+#[diagnostic(code(cypher::unknown_property))]
+struct UnknownProperty(String, String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Unsupported Cypher construct: {0}")]
+#[diagnostic(code(cypher::unsupported))]
+struct Unsupported(String, #[label] SourceSpan);
+
+/// Translates a Cypher query into a program. `labels` maps labels and relationship types
+/// to stored relations.
+pub(crate) fn parse_cypher(
+    src: &str,
+    param_pool: &BTreeMap<String, DataValue>,
+    labels: &BTreeMap<SmartString<LazyCompact>, CypherLabel>,
+) -> Result<InputProgram> {
+    let parsed = CypherParser::parse(Rule::cypher_query, src)
+        .map_err(|err| {
+            let span = match err.location {
+                pest::error::InputLocation::Pos(p) => SourceSpan(p, 0),
+                pest::error::InputLocation::Span((start, end)) => SourceSpan(start, end - start),
+            };
+            ParseError { span }
+        })?
+        .next()
+        .unwrap();
+    let span = parsed.extract_span();
+    let mut translator = Translator {
+        labels,
+        param_pool,
+        nodes: Default::default(),
+        edges: Default::default(),
+        properties: Default::default(),
+        body: vec![],
+        last_id: 0,
+    };
+
+    let mut clauses = parsed.into_inner().peekable();
+    let mut patterns = vec![];
+    while let Some(clause) = clauses.next_if(|p| p.as_rule() == Rule::match_clause) {
+        patterns.extend(clause.into_inner().skip(1));
+    }
+    for pattern in patterns {
+        translator.pattern(pattern)?;
+    }
+    if let Some(clause) = clauses.next_if(|p| p.as_rule() == Rule::where_clause) {
+        let condition = translator.expr(clause.into_inner().nth(1).unwrap())?;
+        translator
+            .body
+            .push(InputAtom::Predicate { inner: condition });
+    }
+
+    let return_clause = clauses.next().unwrap();
+    let mut columns: Vec<(Symbol, String)> = vec![];
+    let mut inner_head = vec![];
+    let mut inner_aggr = vec![];
+    for item in return_clause.into_inner().skip(1) {
+        if item.as_rule() == Rule::distinct {
+            continue;
+        }
+        let mut item = item.into_inner();
+        let expr = item.next().unwrap();
+        let text = expr.as_str().trim().to_string();
+        let column = match item.nth(1) {
+            Some(alias) => Symbol::new(alias.as_str(), alias.extract_span()),
+            None => Symbol::new(text.as_str(), expr.extract_span()),
+        };
+        if columns.iter().any(|(c, _)| c.name == column.name) {
+            bail!(Unsupported(
+                format!("duplicate column {column}"),
+                column.span
+            ))
+        }
+        let (mut var, aggr) = translator.return_item(expr)?;
+        if inner_head.contains(&var) {
+            let copy = Symbol::new(translator.fresh_var(), var.span);
+            translator.body.push(unify(
+                copy.clone(),
+                Expr::Binding {
+                    var,
+                    tuple_pos: None,
+                },
+            ));
+            var = copy;
+        }
+        inner_head.push(var);
+        inner_aggr.push(aggr);
+        columns.push((column, text));
+    }
+
+    let mut out_opts = QueryOutOptions::default();
+    for clause in clauses {
+        match clause.as_rule() {
+            Rule::order_clause => {
+                for item in clause.into_inner().skip(2) {
+                    let mut item = item.into_inner();
+                    let expr = item.next().unwrap();
+                    let text = expr.as_str().trim();
+                    let column = columns
+                        .iter()
+                        .find(|(c, t)| c.name.as_str() == text || t.as_str() == text)
+                        .map(|(c, _)| c.clone())
+                        .ok_or_else(|| {
+                            Unsupported(
+                                "ordering by a column that is not returned".to_string(),
+                                expr.extract_span(),
+                            )
+                        })?;
+                    let dir = match item.next() {
+                        Some(dir) if dir.as_rule() == Rule::sort_desc => SortDir::Dsc,
+                        _ => SortDir::Asc,
+                    };
+                    out_opts.sorters.push((column, dir));
+                }
+            }
+            Rule::skip_clause => {
+                out_opts.offset = Some(translator.count(clause.into_inner().nth(1).unwrap())?);
+            }
+            Rule::limit_clause => {
+                out_opts.limit = Some(translator.count(clause.into_inner().nth(1).unwrap())?);
+            }
+            Rule::EOI => {}
+            r => unreachable!("{:?}", r),
+        }
+    }
+
+    let inner_name = Symbol::new("match", span);
+    let mut prog = BTreeMap::new();
+    prog.insert(
+        inner_name.clone(),
+        InputInlineRulesOrFixed::Rules {
+            rules: vec![InputInlineRule {
+                head: inner_head,
+                aggr: inner_aggr,
+                body: translator.finish()?,
+                span,
+            }],
+        },
+    );
+    let head = columns.into_iter().map(|(c, _)| c).collect_vec();
+    prog.insert(
+        Symbol::new(PROG_ENTRY, span),
+        InputInlineRulesOrFixed::Rules {
+            rules: vec![InputInlineRule {
+                aggr: vec![None; head.len()],
+                body: vec![InputAtom::Rule {
+                    inner: InputRuleApplyAtom {
+                        name: inner_name,
+                        args: head
+                            .iter()
+                            .map(|h| Expr::Binding {
+                                var: h.clone(),
+                                tuple_pos: None,
+                            })
+                            .collect(),
+                        span,
+                    },
+                }],
+                head,
+                span,
+            }],
+        },
+    );
+    Ok(InputProgram {
+        prog,
+        out_opts,
+        disable_magic_rewrite: false,
+        rule_libraries: vec![],
+    })
+}
+
+/// A node variable, with the labels it is given in the patterns.
+struct NodeVar {
+    labels: BTreeSet<SmartString<LazyCompact>>,
+    span: SourceSpan,
+}
+
+/// A relationship variable: its single-hop occurrence binds its properties.
+struct EdgeVar {
+    types: Vec<SmartString<LazyCompact>>,
+    span: SourceSpan,
+}
+
+struct Translator<'a> {
+    labels: &'a BTreeMap<SmartString<LazyCompact>, CypherLabel>,
+    param_pool: &'a BTreeMap<String, DataValue>,
+    nodes: BTreeMap<SmartString<LazyCompact>, NodeVar>,
+    edges: BTreeMap<SmartString<LazyCompact>, EdgeVar>,
+    /// Properties read, as variable and property name
+    properties: BTreeSet<(SmartString<LazyCompact>, SmartString<LazyCompact>)>,
+    body: Vec<InputAtom>,
+    last_id: u32,
+}
+
+fn binding(name: &str, span: SourceSpan) -> Expr {
+    Expr::Binding {
+        var: Symbol::new(name, span),
+        tuple_pos: None,
+    }
+}
+
+fn property_var(var: &str, prop: &str, span: SourceSpan) -> Symbol {
+    Symbol::new(format!("{var}.{prop}"), span)
+}
+
+impl Translator<'_> {
+    fn label(&self, name: &Pair<'_>) -> Result<&CypherLabel> {
+        self.labels
+            .get(name.as_str())
+            .ok_or_else(|| UnknownLabel(name.as_str().to_string(), name.extract_span()).into())
+    }
+
+    fn fresh_var(&mut self) -> SmartString<LazyCompact> {
+        self.last_id += 1;
+        SmartString::from(format!("*anon{}", self.last_id))
+    }
+
+    fn pattern(&mut self, pattern: Pair<'_>) -> Result<()> {
+        let mut elements = pattern.into_inner();
+        let mut left = self.node(elements.next().unwrap())?;
+        while let Some(rel) = elements.next() {
+            let right = self.node(elements.next().unwrap())?;
+            self.relationship(rel, &left, &right)?;
+            left = right;
+        }
+        Ok(())
+    }
+
+    /// Registers the node of the pattern, returning its variable.
+    fn node(&mut self, node: Pair<'_>) -> Result<SmartString<LazyCompact>> {
+        let span = node.extract_span();
+        let mut var = None;
+        let mut label = None;
+        let mut properties = None;
+        for part in node.into_inner() {
+            match part.as_rule() {
+                Rule::variable => var = Some(SmartString::from(part.as_str())),
+                Rule::node_label => label = Some(part.into_inner().next().unwrap()),
+                Rule::properties => properties = Some(part),
+                r => unreachable!("{:?}", r),
+            }
+        }
+        let var = match var {
+            Some(var) => {
+                ensure!(
+                    !self.edges.contains_key(&var),
+                    Unsupported(format!("{var} is already a relationship"), span)
+                );
+                var
+            }
+            None => self.fresh_var(),
+        };
+        if let Some(label) = &label {
+            self.label(label)?;
+        }
+        let entry = self.nodes.entry(var.clone()).or_insert_with(|| NodeVar {
+            labels: Default::default(),
+            span,
+        });
+        if let Some(label) = label {
+            entry.labels.insert(SmartString::from(label.as_str()));
+        }
+        if let Some(properties) = properties {
+            self.property_constraints(&var, properties)?;
+        }
+        Ok(var)
+    }
+
+    fn relationship(
+        &mut self,
+        rel: Pair<'_>,
+        left: &SmartString<LazyCompact>,
+        right: &SmartString<LazyCompact>,
+    ) -> Result<()> {
+        let span = rel.extract_span();
+        let mut pointing_left = false;
+        let mut pointing_right = false;
+        let mut var = None;
+        let mut types = vec![];
+        let mut length = None;
+        let mut properties = None;
+        for part in rel.into_inner() {
+            match part.as_rule() {
+                Rule::rel_left => pointing_left = true,
+                Rule::rel_right => pointing_right = true,
+                Rule::rel_detail => {
+                    for detail in part.into_inner() {
+                        match detail.as_rule() {
+                            Rule::variable => var = Some(detail),
+                            Rule::rel_types => types.extend(detail.into_inner()),
+                            Rule::rel_length => length = Some(detail),
+                            Rule::properties => properties = Some(detail),
+                            r => unreachable!("{:?}", r),
+                        }
+                    }
+                }
+                r => unreachable!("{:?}", r),
+            }
+        }
+        ensure!(
+            !types.is_empty(),
+            Unsupported("relationship without a type".to_string(), span)
+        );
+        let mut directions = vec![];
+        if pointing_right || !pointing_left {
+            directions.push(false);
+        }
+        if pointing_left || !pointing_right {
+            directions.push(true);
+        }
+
+        if let Some(length) = length {
+            ensure!(
+                var.is_none() && properties.is_none(),
+                Unsupported(
+                    "variable or properties of a variable-length relationship".to_string(),
+                    span
+                )
+            );
+            let (min, max) = repetition_bounds(length)?;
+            let mut steps = vec![];
+            for ty in &types {
+                let relation = &self.label(ty)?.relation;
+                for inverse in &directions {
+                    steps.push(PathExpr::Step {
+                        relation: Symbol::new(relation.clone(), ty.extract_span()),
+                        inverse: *inverse,
+                    });
+                }
+            }
+            let step = if steps.len() == 1 {
+                steps.pop().unwrap()
+            } else {
+                PathExpr::Alternation(steps)
+            };
+            self.body.push(InputAtom::Path {
+                inner: InputPathAtom {
+                    from: binding(left, span),
+                    to: binding(right, span),
+                    path: PathExpr::Repetition {
+                        inner: step.into(),
+                        min,
+                        max,
+                        span,
+                    },
+                    span,
+                },
+            });
+            return Ok(());
+        }
+
+        let var = match var {
+            Some(var) => {
+                let name = SmartString::from(var.as_str());
+                ensure!(
+                    !self.nodes.contains_key(&name) && !self.edges.contains_key(&name),
+                    Unsupported(
+                        format!("reusing the variable {name} for a relationship"),
+                        var.extract_span()
+                    )
+                );
+                name
+            }
+            None => self.fresh_var(),
+        };
+        let mut type_names = vec![];
+        for ty in &types {
+            ensure!(
+                self.label(ty)?.columns.len() >= 2,
+                Unsupported(
+                    format!(
+                        "relationship type {} mapped to fewer than two columns",
+                        ty.as_str()
+                    ),
+                    ty.extract_span()
+                )
+            );
+            type_names.push(SmartString::from(ty.as_str()));
+        }
+        self.edges.insert(
+            var.clone(),
+            EdgeVar {
+                types: type_names,
+                span,
+            },
+        );
+        if let Some(properties) = properties {
+            self.property_constraints(&var, properties)?;
+        }
+        // the atoms are built at the end, when all the properties read are known
+        self.body.push(InputAtom::Disjunction {
+            inner: directions
+                .into_iter()
+                .map(|inverse| InputAtom::Rule {
+                    inner: InputRuleApplyAtom {
+                        name: Symbol::new(var.clone(), span),
+                        args: if inverse {
+                            vec![binding(right, span), binding(left, span)]
+                        } else {
+                            vec![binding(left, span), binding(right, span)]
+                        },
+                        span,
+                    },
+                })
+                .collect(),
+            span,
+        });
+        Ok(())
+    }
+
+    /// `{prop: expr, ...}` in a pattern, as equality predicates on the properties.
+    fn property_constraints(&mut self, var: &str, properties: Pair<'_>) -> Result<()> {
+        for property in properties.into_inner() {
+            let span = property.extract_span();
+            let mut property = property.into_inner();
+            let name = property.next().unwrap().as_str();
+            let value = self.expr(property.next().unwrap())?;
+            self.properties.insert((var.into(), name.into()));
+            self.body.push(InputAtom::Predicate {
+                inner: Expr::Apply {
+                    op: &OP_EQ,
+                    args: [
+                        Expr::Binding {
+                            var: property_var(var, name, span),
+                            tuple_pos: None,
+                        },
+                        value,
+                    ]
+                    .into(),
+                    span,
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// The variable holding the value of the item, and the aggregation applied to it.
+    fn return_item(
+        &mut self,
+        expr: Pair<'_>,
+    ) -> Result<(Symbol, Option<(Aggregation, Vec<DataValue>)>)> {
+        let span = expr.extract_span();
+        let mut terms = expr.clone().into_inner();
+        if let (Some(term), None) = (terms.next(), terms.next()) {
+            match term.as_rule() {
+                Rule::count_star => {
+                    let row = Symbol::new("*", span);
+                    let mut vars = self.nodes.keys().cloned().collect_vec();
+                    vars.extend(
+                        self.properties
+                            .iter()
+                            .map(|(v, p)| format!("{v}.{p}").into()),
+                    );
+                    self.body.push(unify(
+                        row.clone(),
+                        Expr::Apply {
+                            op: &OP_LIST,
+                            args: vars.iter().map(|v| binding(v, span)).collect(),
+                            span,
+                        },
+                    ));
+                    return Ok((row, Some((parse_aggr("count").unwrap().clone(), vec![]))));
+                }
+                Rule::function_call => {
+                    let mut inner = term.clone().into_inner();
+                    let name = inner.next().unwrap().as_str().to_ascii_lowercase();
+                    let distinct = inner.peek().map(|p| p.as_rule()) == Some(Rule::distinct);
+                    let aggr = match (name.as_str(), distinct) {
+                        ("count", false) => Some("count"),
+                        ("count", true) => Some("count_unique"),
+                        ("collect", false) => Some("collect"),
+                        ("collect", true) => Some("unique"),
+                        ("sum", false) => Some("sum"),
+                        ("avg", false) => Some("mean"),
+                        ("min", _) => Some("min"),
+                        ("max", _) => Some("max"),
+                        ("stdev", false) => Some("std_dev"),
+                        _ => None,
+                    };
+                    if let Some(aggr) = aggr {
+                        if distinct {
+                            inner.next();
+                        }
+                        let args = inner.collect_vec();
+                        ensure!(
+                            args.len() == 1,
+                            Unsupported(format!("{name} with {} arguments", args.len()), span)
+                        );
+                        let arg = self.expr(args.into_iter().next().unwrap())?;
+                        let var = self.bind(arg, span);
+                        return Ok((var, Some((parse_aggr(aggr).unwrap().clone(), vec![]))));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let value = self.expr(expr)?;
+        Ok((self.bind(value, span), None))
+    }
+
+    /// A variable holding the value of the expression.
+    fn bind(&mut self, expr: Expr, span: SourceSpan) -> Symbol {
+        match expr {
+            Expr::Binding { var, .. } => var,
+            expr => {
+                let var = Symbol::new(self.fresh_var(), span);
+                self.body.push(unify(var.clone(), expr));
+                var
+            }
+        }
+    }
+
+    fn count(&self, pair: Pair<'_>) -> Result<usize> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Expected a non-negative integer")]
+        #[diagnostic(code(cypher::bad_count))]
+        struct BadCount(#[label] SourceSpan);
+
+        let span = pair.extract_span();
+        let n = match pair.as_rule() {
+            Rule::param => self.param(&pair)?.get_int(),
+            _ => pair.as_str().parse::<i64>().ok(),
+        };
+        match n {
+            Some(n) if n >= 0 => Ok(n as usize),
+            _ => bail!(BadCount(span)),
+        }
+    }
+
+    fn param(&self, pair: &Pair<'_>) -> Result<DataValue> {
+        #[derive(Error, Diagnostic, Debug)]
+        #[error("Required parameter {0} not found")]
+        #[diagnostic(code(parser::param_not_found))]
+        struct ParamNotFoundError(String, #[label] SourceSpan);
+
+        let name = &pair.as_str()[1..];
+        match self.param_pool.get(name) {
+            Some(val) => Ok(val.clone()),
+            None => bail!(ParamNotFoundError(name.to_string(), pair.extract_span())),
+        }
+    }
+
+    fn expr(&mut self, pair: Pair<'_>) -> Result<Expr> {
+        PRATT_PARSER
+            .map_primary(|term| self.term(term))
+            .map_infix(|lhs, op, rhs| {
+                let (lhs, rhs) = (lhs?, rhs?);
+                let span = lhs.span().merge(rhs.span());
+                let op = match op.as_rule() {
+                    Rule::op_or => &OP_OR,
+                    Rule::op_and => &OP_AND,
+                    Rule::op_eq => &OP_EQ,
+                    Rule::op_ne => &OP_NEQ,
+                    Rule::op_lt => &OP_LT,
+                    Rule::op_gt => &OP_GT,
+                    Rule::op_le => &OP_LE,
+                    Rule::op_ge => &OP_GE,
+                    Rule::op_starts_with => &OP_STARTS_WITH,
+                    Rule::op_ends_with => &OP_ENDS_WITH,
+                    Rule::op_contains => &OP_STR_INCLUDES,
+                    Rule::op_in => &OP_IS_IN,
+                    Rule::op_add => &OP_ADD,
+                    Rule::op_sub => &OP_SUB,
+                    Rule::op_mul => &OP_MUL,
+                    Rule::op_div => &OP_DIV,
+                    Rule::op_mod => &OP_MOD,
+                    Rule::op_pow => &OP_POW,
+                    r => unreachable!("{:?}", r),
+                };
+                Ok(Expr::Apply {
+                    op,
+                    args: [lhs, rhs].into(),
+                    span,
+                })
+            })
+            .map_prefix(|op, rhs| {
+                let rhs = rhs?;
+                let span = op.extract_span().merge(rhs.span());
+                let op = match op.as_rule() {
+                    Rule::op_not => &OP_NEGATE,
+                    Rule::op_minus => &OP_MINUS,
+                    r => unreachable!("{:?}", r),
+                };
+                Ok(Expr::Apply {
+                    op,
+                    args: [rhs].into(),
+                    span,
+                })
+            })
+            .map_postfix(|lhs, op| {
+                let lhs = lhs?;
+                let span = lhs.span().merge(op.extract_span());
+                let is_null = Expr::Apply {
+                    op: &OP_IS_NULL,
+                    args: [lhs].into(),
+                    span,
+                };
+                Ok(match op.as_rule() {
+                    Rule::op_is_null => is_null,
+                    Rule::op_is_not_null => Expr::Apply {
+                        op: &OP_NEGATE,
+                        args: [is_null].into(),
+                        span,
+                    },
+                    r => unreachable!("{:?}", r),
+                })
+            })
+            .parse(pair.into_inner())
+    }
+
+    fn term(&mut self, pair: Pair<'_>) -> Result<Expr> {
+        let span = pair.extract_span();
+        Ok(match pair.as_rule() {
+            Rule::expr => self.expr(pair)?,
+            Rule::null => Expr::Const {
+                val: DataValue::Null,
+                span,
+            },
+            Rule::boolean => Expr::Const {
+                val: DataValue::from(pair.as_str().eq_ignore_ascii_case("true")),
+                span,
+            },
+            Rule::pos_int => Expr::Const {
+                val: DataValue::from(
+                    pair.as_str()
+                        .parse::<i64>()
+                        .map_err(|_| Unsupported("integer out of range".to_string(), span))?,
+                ),
+                span,
+            },
+            Rule::float => Expr::Const {
+                val: DataValue::from(pair.as_str().parse::<f64>().unwrap()),
+                span,
+            },
+            Rule::string => Expr::Const {
+                val: DataValue::Str(unescape(
+                    pair.into_inner()
+                        .next()
+                        .unwrap()
+                        .into_inner()
+                        .next()
+                        .unwrap(),
+                    span,
+                )?),
+                span,
+            },
+            Rule::param => Expr::Const {
+                val: self.param(&pair)?,
+                span,
+            },
+            Rule::list => Expr::Apply {
+                op: &OP_LIST,
+                args: pair
+                    .into_inner()
+                    .map(|p| self.expr(p))
+                    .collect::<Result<Vec<_>>>()?
+                    .into(),
+                span,
+            },
+            Rule::variable => {
+                let name = pair.as_str();
+                if self.nodes.contains_key(name) {
+                    binding(name, span)
+                } else if self.edges.contains_key(name) {
+                    bail!(Unsupported(
+                        "using a relationship other than for its properties".to_string(),
+                        span
+                    ))
+                } else {
+                    bail!(UnknownVariable(name.to_string(), span))
+                }
+            }
+            Rule::property_access => {
+                let mut inner = pair.into_inner();
+                let var = inner.next().unwrap().as_str();
+                let prop = inner.next().unwrap().as_str();
+                ensure!(
+                    self.nodes.contains_key(var) || self.edges.contains_key(var),
+                    UnknownVariable(var.to_string(), span)
+                );
+                self.properties.insert((var.into(), prop.into()));
+                Expr::Binding {
+                    var: property_var(var, prop, span),
+                    tuple_pos: None,
+                }
+            }
+            Rule::count_star => bail!(Unsupported(
+                "count(*) other than as a returned column".to_string(),
+                span
+            )),
+            Rule::function_call => {
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Function {0} is not supported")]
+                #[diagnostic(code(cypher::unknown_function))]
+                #[diagnostic(help("Aggregations can only be applied to returned columns"))]
+                struct UnknownFunction(String, #[label] SourceSpan);
+
+                let mut inner = pair.into_inner();
+                let name_p = inner.next().unwrap();
+                let name = name_p.as_str().to_ascii_lowercase();
+                let mapped = match name.as_str() {
+                    "tolower" => "lowercase",
+                    "toupper" => "uppercase",
+                    "size" => "length",
+                    "tostring" => "to_string",
+                    "tointeger" => "to_int",
+                    "tofloat" => "to_float",
+                    "abs" | "ceil" | "floor" | "round" | "sqrt" | "coalesce" | "trim" => {
+                        name.as_str()
+                    }
+                    _ => bail!(UnknownFunction(name_p.as_str().to_string(), span)),
+                };
+                let op = get_op(mapped).unwrap();
+                let mut args: Vec<_> = inner
+                    .filter(|p| p.as_rule() == Rule::expr)
+                    .map(|p| self.expr(p))
+                    .try_collect()?;
+                ensure!(
+                    args.len() == op.min_arity || (op.vararg && args.len() > op.min_arity),
+                    Unsupported(format!("{name} with {} arguments", args.len()), span)
+                );
+                op.post_process_args(&mut args);
+                Expr::Apply {
+                    op,
+                    args: args.into(),
+                    span,
+                }
+            }
+            r => unreachable!("{:?}", r),
+        })
+    }
+
+    /// The body of the rule: the atoms for the nodes and relationships, with the properties
+    /// read bound, and the predicates.
+    fn finish(mut self) -> Result<Vec<InputAtom>> {
+        let mut bound = BTreeSet::new();
+        let mut atoms = vec![];
+        for (var, node) in &self.nodes {
+            for label in &node.labels {
+                let label = &self.labels[label];
+                let mut args = vec![binding(var, node.span)];
+                for col in &label.columns[1..] {
+                    args.push(Self::property_arg(
+                        &self.properties,
+                        var,
+                        col,
+                        node.span,
+                        &mut bound,
+                    ));
+                }
+                atoms.push(relation_atom(label, args, node.span));
+            }
+        }
+        let mut connected = BTreeSet::new();
+        for atom in self.body.iter_mut() {
+            if let InputAtom::Path { inner } = atom {
+                for end in [&inner.from, &inner.to] {
+                    if let Expr::Binding { var, .. } = end {
+                        connected.insert(var.name.clone());
+                    }
+                }
+            }
+            if let InputAtom::Disjunction { inner, span } = atom {
+                // the placeholder for a single-hop relationship
+                let mut branches = vec![];
+                for placeholder in inner.iter() {
+                    let call = match placeholder {
+                        InputAtom::Rule { inner } => inner,
+                        _ => unreachable!(),
+                    };
+                    for end in &call.args {
+                        if let Expr::Binding { var, .. } = end {
+                            connected.insert(var.name.clone());
+                        }
+                    }
+                    let edge = &self.edges[&call.name.name];
+                    for ty in &edge.types {
+                        let label = &self.labels[ty];
+                        let mut args = call.args.clone();
+                        for col in &label.columns[2..] {
+                            args.push(Self::property_arg(
+                                &self.properties,
+                                &call.name.name,
+                                col,
+                                *span,
+                                &mut bound,
+                            ));
+                        }
+                        branches.push(relation_atom(label, args, *span));
+                    }
+                }
+                *inner = branches;
+            }
+        }
+        for (var, node) in &self.nodes {
+            ensure!(
+                !node.labels.is_empty() || connected.contains(var),
+                Unsupported(
+                    format!("node {var} without a label or a relationship"),
+                    node.span
+                )
+            );
+        }
+        for (var, prop) in &self.properties {
+            if !bound.contains(&(var.clone(), prop.clone())) {
+                let span = self
+                    .nodes
+                    .get(var)
+                    .map(|n| n.span)
+                    .or_else(|| self.edges.get(var).map(|e| e.span))
+                    .unwrap_or_default();
+                bail!(UnknownProperty(var.to_string(), prop.to_string(), span))
+            }
+        }
+        for (var, edge) in &self.edges {
+            for prop in self.properties.iter().filter(|(v, _)| v == var) {
+                for ty in &edge.types {
+                    ensure!(
+                        self.labels[ty].columns[2..].contains(&prop.1),
+                        UnknownProperty(var.to_string(), prop.1.to_string(), edge.span)
+                    );
+                }
+            }
+        }
+        atoms.append(&mut self.body);
+        Ok(atoms)
+    }
+
+    /// The variable of the property if it is read, `_` otherwise.
+    fn property_arg(
+        properties: &BTreeSet<(SmartString<LazyCompact>, SmartString<LazyCompact>)>,
+        var: &str,
+        col: &SmartString<LazyCompact>,
+        span: SourceSpan,
+        bound: &mut BTreeSet<(SmartString<LazyCompact>, SmartString<LazyCompact>)>,
+    ) -> Expr {
+        let key = (SmartString::from(var), col.clone());
+        if properties.contains(&key) {
+            bound.insert(key);
+            Expr::Binding {
+                var: property_var(var, col, span),
+                tuple_pos: None,
+            }
+        } else {
+            binding("_", span)
+        }
+    }
+}
+
+fn relation_atom(label: &CypherLabel, args: Vec<Expr>, span: SourceSpan) -> InputAtom {
+    InputAtom::Relation {
+        inner: InputRelationApplyAtom {
+            name: Symbol::new(label.relation.clone(), span),
+            args,
+            valid_at: None,
+            span,
+        },
+    }
+}
+
+fn unify(binding: Symbol, expr: Expr) -> InputAtom {
+    let span = expr.span();
+    InputAtom::Unification {
+        inner: crate::data::program::Unification {
+            binding,
+            expr,
+            one_many_unif: false,
+            span,
+        },
+    }
+}
+
+/// `*`, `*n`, `*m..n`, `*m..` or `*..n` of a variable-length relationship.
+fn repetition_bounds(length: Pair<'_>) -> Result<(usize, Option<usize>)> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Bad length for a variable-length relationship")]
+    #[diagnostic(code(cypher::bad_length))]
+    struct BadLength(#[label] SourceSpan);
+
+    let span = length.extract_span();
+    let parse = |p: Pair<'_>| -> Result<usize> {
+        let span = p.extract_span();
+        p.as_str()
+            .parse::<usize>()
+            .map_err(|_| BadLength(span).into())
+    };
+    let (min, max) = match length.into_inner().next() {
+        None => (1, None),
+        Some(bounds) => match bounds.as_rule() {
+            Rule::length_exact => {
+                let n = parse(bounds)?;
+                (n, Some(n))
+            }
+            Rule::length_range => {
+                let mut min = 1;
+                let mut max = None;
+                for bound in bounds.into_inner() {
+                    match bound.as_rule() {
+                        Rule::length_min => min = parse(bound)?,
+                        Rule::length_max => max = Some(parse(bound)?),
+                        r => unreachable!("{:?}", r),
+                    }
+                }
+                (min, max)
+            }
+            r => unreachable!("{:?}", r),
+        },
+    };
+    if let Some(max) = max {
+        ensure!(max > 0 && max >= min, BadLength(span));
+    }
+    Ok((min, max))
+}
+
+fn unescape(pair: Pair<'_>, span: SourceSpan) -> Result<SmartString<LazyCompact>> {
+    let mut ret = SmartString::new();
+    let mut chars = pair.as_str().chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some('t') => ret.push('\t'),
+            Some('r') => ret.push('\r'),
+            Some(c @ ('\\' | '\'' | '"')) => ret.push(c),
+            _ => bail!(Unsupported("escape sequence".to_string(), span)),
+        }
+    }
+    Ok(ret)
+}
@@ -23,7 +23,7 @@ use crate::data::functions::{
 };
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
-use crate::parse::{ExtractSpan, Pair, Rule, SourceSpan};
+use crate::parse::{CustomRegistry, ExtractSpan, Pair, Rule, SourceSpan};
 
 lazy_static! {
     static ref PRATT_PARSER: PrattParser<Rule> = {
@@ -107,21 +107,42 @@ pub(crate) fn expr2bytecode(expr: &Expr, collector: &mut Vec<Bytecode>) -> Resul
                 }
             }
         }
-        Expr::UnboundApply { op, span, .. } => {
-            bail!(NoImplementationError(*span, op.to_string()));
+        Expr::UnboundApply {
+            op,
+            args,
+            func,
+            span,
+        } => {
+            if func.is_none() {
+                bail!(NoImplementationError(*span, op.to_string()));
+            }
+            let arity = args.len();
+            for arg in args.iter() {
+                expr2bytecode(arg, collector)?;
+            }
+            collector.push(Bytecode::ApplyCustom {
+                name: op.clone(),
+                arity,
+                func: func.clone(),
+                span: *span,
+            })
         }
     }
     Ok(())
 }
 
-pub(crate) fn build_expr(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<Expr> {
+pub(crate) fn build_expr(
+    pair: Pair<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
+) -> Result<Expr> {
     ensure!(
         pair.as_rule() == Rule::expr,
         InvalidExpression(pair.extract_span())
     );
 
     PRATT_PARSER
-        .map_primary(|v| build_term(v, param_pool))
+        .map_primary(|v| build_term(v, param_pool, registry))
         .map_infix(build_expr_infix)
         .map_prefix(|op, rhs| {
             let rhs = rhs?;
@@ -175,7 +196,11 @@ fn build_expr_infix(lhs: Result<Expr>, op: Pair<'_>, rhs: Result<Expr>) -> Resul
     })
 }
 
-fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<Expr> {
+pub(crate) fn build_term(
+    pair: Pair<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
+) -> Result<Expr> {
     let span = pair.extract_span();
     let op = pair.as_rule();
     Ok(match op {
@@ -269,7 +294,7 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
         Rule::list => {
             let mut collected = vec![];
             for p in pair.into_inner() {
-                collected.push(build_expr(p, param_pool)?)
+                collected.push(build_expr(p, param_pool, registry)?)
             }
             Expr::Apply {
                 op: &OP_LIST,
@@ -283,8 +308,8 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 let mut p = p.into_inner();
                 let k = p.next().unwrap();
                 let v = p.next().unwrap();
-                let k = build_expr(k, param_pool)?;
-                let v = build_expr(v, param_pool)?;
+                let k = build_expr(k, param_pool, registry)?;
+                let v = build_expr(v, param_pool, registry)?;
                 args.push(k);
                 args.push(v);
             }
@@ -302,13 +327,18 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|v| build_expr(v, param_pool))
+                .map(|v| build_expr(v, param_pool, registry))
                 .try_collect()?;
             #[derive(Error, Diagnostic, Debug)]
             #[error("Named function '{0}' not found")]
             #[diagnostic(code(parser::func_not_function))]
             struct FuncNotFoundError(String, #[label] SourceSpan);
 
+            #[derive(Error, Diagnostic, Debug)]
+            #[error("Wrong number of arguments for function '{0}'")]
+            #[diagnostic(code(parser::func_wrong_num_args))]
+            struct WrongNumArgsError(String, #[label] SourceSpan, #[help] String);
+
             match ident {
                 "cond" => {
                     if args.is_empty() {
@@ -379,18 +409,27 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                     Expr::Cond { clauses, span }
                 }
                 _ => match get_op(ident) {
-                    None => Expr::UnboundApply {
-                        op: ident.into(),
-                        args: args.into(),
-                        span,
-                    },
+                    None => {
+                        let func = registry.functions.get(ident).cloned();
+                        if let Some(custom) = &func {
+                            ensure!(
+                                custom.arity == args.len(),
+                                WrongNumArgsError(
+                                    ident.to_string(),
+                                    span,
+                                    format!("Need exactly {} argument(s)", custom.arity)
+                                )
+                            );
+                        }
+                        Expr::UnboundApply {
+                            op: ident.into(),
+                            args: args.into(),
+                            func,
+                            span,
+                        }
+                    }
                     Some(op) => {
                         op.post_process_args(&mut args);
-                        #[derive(Error, Diagnostic, Debug)]
-                        #[error("Wrong number of arguments for function '{0}'")]
-                        #[diagnostic(code(parser::func_wrong_num_args))]
-                        struct WrongNumArgsError(String, #[label] SourceSpan, #[help] String);
-
                         if op.vararg {
                             ensure!(
                                 op.min_arity <= args.len(),
@@ -419,11 +458,214 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 },
             }
         }
-        Rule::grouping => build_expr(pair.into_inner().next().unwrap(), param_pool)?,
+        Rule::grouping => build_expr(pair.into_inner().next().unwrap(), param_pool, registry)?,
         r => unreachable!("Encountered unknown op {:?}", r),
     })
 }
 
+/// A function defined in CozoScript by `::fn create name(params) = body`.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredFunction {
+    pub(crate) name: Symbol,
+    pub(crate) params: Vec<Symbol>,
+    pub(crate) body: Expr,
+    /// The source of the definition, shown in errors about calls
+    pub(crate) definition: String,
+}
+
+pub(crate) fn build_stored_function(
+    pair: Pair<'_>,
+    registry: &CustomRegistry,
+) -> Result<StoredFunction> {
+    #[derive(Error, Diagnostic, Debug)]
+    #[error("Function '{0}' conflicts with a built-in or registered function")]
+    #[diagnostic(code(parser::stored_fn_conflict))]
+    struct StoredFnConflict(String, #[label] SourceSpan);
+
+    #[derive(Error, Diagnostic, Debug)]
+    #[error("Parameter '{0}' of the function is repeated")]
+    #[diagnostic(code(parser::stored_fn_dup_param))]
+    struct DuplicateParam(String, #[label] SourceSpan);
+
+    #[derive(Error, Diagnostic, Debug)]
+    #[error("Variable '{0}' in the body of the function is not a parameter")]
+    #[diagnostic(code(parser::stored_fn_unbound))]
+    struct UnboundInFunction(String, #[label] SourceSpan);
+
+    let definition = pair.as_str().to_string();
+    let mut src = pair.into_inner();
+    let name_p = src.next().unwrap();
+    let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+    ensure!(
+        !matches!(name.name.as_str(), "cond" | "if")
+            && get_op(&name.name).is_none()
+            && !registry.functions.contains_key(name.name.as_str()),
+        StoredFnConflict(name.name.to_string(), name.span)
+    );
+    let mut params: Vec<Symbol> = vec![];
+    let mut body = None;
+    for p in src {
+        match p.as_rule() {
+            Rule::var => {
+                let param = Symbol::new(p.as_str(), p.extract_span());
+                ensure!(
+                    !params.contains(&param),
+                    DuplicateParam(param.name.to_string(), param.span)
+                );
+                params.push(param);
+            }
+            _ => body = Some(build_expr(p, &Default::default(), registry)?),
+        }
+    }
+    // `Expr::bindings` would reject the calls to other stored functions
+    fn check_vars(expr: &Expr, params: &[Symbol]) -> Result<()> {
+        match expr {
+            Expr::Binding { var, .. } => ensure!(
+                params.contains(var),
+                UnboundInFunction(var.name.to_string(), var.span)
+            ),
+            Expr::Const { .. } => {}
+            Expr::Apply { args, .. } | Expr::UnboundApply { args, .. } => {
+                for arg in args.iter() {
+                    check_vars(arg, params)?;
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    check_vars(cond, params)?;
+                    check_vars(val, params)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    let body = body.unwrap();
+    check_vars(&body, &params)?;
+    Ok(StoredFunction {
+        name,
+        params,
+        body,
+        definition,
+    })
+}
+
+/// Replaces the calls to stored functions within the expression by their bodies, with the
+/// arguments substituted for the parameters. Calls to functions that `lookup` does not know
+/// are left for the registered custom functions.
+pub(crate) fn inline_stored_functions(
+    expr: &mut Expr,
+    lookup: &mut impl FnMut(&str) -> Result<Option<StoredFunction>>,
+    calling: &mut Vec<SmartString<LazyCompact>>,
+) -> Result<()> {
+    #[derive(Error, Diagnostic, Debug)]
+    #[error("Wrong number of arguments for function '{0}': {1} required")]
+    #[diagnostic(code(eval::stored_fn_wrong_num_args))]
+    struct StoredFnArity(String, usize, #[label] SourceSpan, #[help] String);
+
+    #[derive(Error, Diagnostic, Debug)]
+    #[error("Function '{0}' calls itself")]
+    #[diagnostic(code(eval::stored_fn_recursive))]
+    struct StoredFnRecursive(String, #[label] SourceSpan, #[help] String);
+
+    match expr {
+        Expr::Binding { .. } | Expr::Const { .. } => {}
+        Expr::Apply { args, .. } => {
+            for arg in args.iter_mut() {
+                inline_stored_functions(arg, lookup, calling)?;
+            }
+        }
+        Expr::Cond { clauses, .. } => {
+            for (cond, val) in clauses.iter_mut() {
+                inline_stored_functions(cond, lookup, calling)?;
+                inline_stored_functions(val, lookup, calling)?;
+            }
+        }
+        Expr::UnboundApply { op, args, span, .. } => {
+            for arg in args.iter_mut() {
+                inline_stored_functions(arg, lookup, calling)?;
+            }
+            let func = match lookup(op.as_str())? {
+                None => return Ok(()),
+                Some(func) => func,
+            };
+            ensure!(
+                func.params.len() == args.len(),
+                StoredFnArity(
+                    op.to_string(),
+                    func.params.len(),
+                    *span,
+                    format!("Defined as {}", func.definition)
+                )
+            );
+            ensure!(
+                !calling.contains(op),
+                StoredFnRecursive(
+                    op.to_string(),
+                    *span,
+                    format!("Called through {}", calling.iter().join(" -> "))
+                )
+            );
+            let substituted = instantiate(&func.body, &func.params, args, *span);
+            calling.push(op.clone());
+            *expr = substituted;
+            inline_stored_functions(expr, lookup, calling)?;
+            calling.pop();
+        }
+    }
+    Ok(())
+}
+
+/// The body of a function at a call site: the spans of the definition are meaningless
+/// there, so they are replaced by the span of the call.
+fn instantiate(body: &Expr, params: &[Symbol], args: &[Expr], span: SourceSpan) -> Expr {
+    match body {
+        Expr::Binding { var, .. } => {
+            let i = params.iter().position(|p| p == var).unwrap();
+            args[i].clone()
+        }
+        Expr::Const { val, .. } => Expr::Const {
+            val: val.clone(),
+            span,
+        },
+        Expr::Apply {
+            op, args: inner, ..
+        } => Expr::Apply {
+            op,
+            args: inner
+                .iter()
+                .map(|arg| instantiate(arg, params, args, span))
+                .collect(),
+            span,
+        },
+        Expr::UnboundApply {
+            op,
+            args: inner,
+            func,
+            ..
+        } => Expr::UnboundApply {
+            op: op.clone(),
+            args: inner
+                .iter()
+                .map(|arg| instantiate(arg, params, args, span))
+                .collect(),
+            func: func.clone(),
+            span,
+        },
+        Expr::Cond { clauses, .. } => Expr::Cond {
+            clauses: clauses
+                .iter()
+                .map(|(cond, val)| {
+                    (
+                        instantiate(cond, params, args, span),
+                        instantiate(val, params, args, span),
+                    )
+                })
+                .collect(),
+            span,
+        },
+    }
+}
+
 pub(crate) fn parse_int(s: &str, radix: u32) -> i64 {
     i64::from_str_radix(&s[2..].replace('_', ""), radix).unwrap()
 }
@@ -19,14 +19,15 @@ use thiserror::Error;
 use crate::parse::query::parse_query;
 use crate::parse::sys::parse_sys;
 use crate::parse::{
-    ExtractSpan, ImperativeProgram, ImperativeStmt, ImperativeStmtClause, ImperativeSysop, Pair,
-    Rule, SourceSpan,
+    CustomRegistry, ExtractSpan, ImperativeProgram, ImperativeStmt, ImperativeStmtClause,
+    ImperativeSysop, Pair, Rule, SourceSpan,
 };
 use crate::{DataValue, FixedRule, ValidityTs};
 
 pub(crate) fn parse_imperative_block(
     src: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<ImperativeProgram> {
@@ -39,6 +40,7 @@ pub(crate) fn parse_imperative_block(
         collected.push(parse_imperative_stmt(
             pair,
             param_pool,
+            registry,
             fixed_rules,
             cur_vld,
         )?);
@@ -60,6 +62,7 @@ struct DuplicateMarker(#[label] SourceSpan);
 fn parse_imperative_stmt(
     pair: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<ImperativeStmt> {
@@ -94,6 +97,7 @@ fn parse_imperative_stmt(
                         let prog = parse_query(
                             src.next().unwrap().into_inner(),
                             param_pool,
+                            registry,
                             fixed_rules,
                             cur_vld,
                         )?;
@@ -116,6 +120,7 @@ fn parse_imperative_stmt(
                     let prog = parse_query(
                         src.next().unwrap().into_inner(),
                         param_pool,
+                        registry,
                         fixed_rules,
                         cur_vld,
                     )?;
@@ -128,13 +133,13 @@ fn parse_imperative_stmt(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|p| parse_imperative_stmt(p, param_pool, fixed_rules, cur_vld))
+                .map(|p| parse_imperative_stmt(p, param_pool, registry, fixed_rules, cur_vld))
                 .try_collect()?;
             let else_body = match inner.next() {
                 None => vec![],
                 Some(rest) => rest
                     .into_inner()
-                    .map(|p| parse_imperative_stmt(p, param_pool, fixed_rules, cur_vld))
+                    .map(|p| parse_imperative_stmt(p, param_pool, registry, fixed_rules, cur_vld))
                     .try_collect()?,
             };
             ImperativeStmt::If {
@@ -152,7 +157,7 @@ fn parse_imperative_stmt(
                 mark = Some(SmartString::from(nxt.as_str()));
                 nxt = inner.next().unwrap();
             }
-            let body = parse_imperative_block(nxt, param_pool, fixed_rules, cur_vld)?;
+            let body = parse_imperative_block(nxt, param_pool, registry, fixed_rules, cur_vld)?;
             ImperativeStmt::Loop { label: mark, body }
         }
         Rule::temp_swap => {
@@ -182,6 +187,7 @@ fn parse_imperative_stmt(
             let sysop = parse_sys(
                 src.next().unwrap().into_inner(),
                 param_pool,
+                registry,
                 fixed_rules,
                 cur_vld,
             )?;
@@ -195,6 +201,7 @@ fn parse_imperative_stmt(
             let prog = parse_query(
                 src.next().unwrap().into_inner(),
                 param_pool,
+                registry,
                 fixed_rules,
                 cur_vld,
             )?;
@@ -209,6 +216,7 @@ fn parse_imperative_stmt(
             let prog = parse_query(
                 src.next().unwrap().into_inner(),
                 param_pool,
+                registry,
                 fixed_rules,
                 cur_vld,
             )?;
@@ -18,16 +18,19 @@ use pest::Parser;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::aggr::CustomAggregation;
+use crate::data::expr::CustomFunction;
 use crate::data::program::InputProgram;
 use crate::data::relation::NullableColType;
 use crate::data::value::{DataValue, ValidityTs};
-use crate::parse::expr::build_expr;
+use crate::parse::expr::{build_expr, build_stored_function, StoredFunction};
 use crate::parse::imperative::parse_imperative_block;
 use crate::parse::query::parse_query;
 use crate::parse::schema::parse_nullable_type;
 use crate::parse::sys::{parse_sys, SysOp};
 use crate::{Expr, FixedRule};
 
+pub(crate) mod cypher;
 pub(crate) mod expr;
 pub(crate) mod fts;
 pub(crate) mod imperative;
@@ -42,6 +45,14 @@ pub(crate) struct CozoScriptParser;
 pub(crate) type Pair<'a> = pest::iterators::Pair<'a, Rule>;
 pub(crate) type Pairs<'a> = pest::iterators::Pairs<'a, Rule>;
 
+/// The native functions and aggregations registered with a database, against which the parser
+/// resolves the names of functions and aggregations that are not built-in.
+#[derive(Default)]
+pub(crate) struct CustomRegistry {
+    pub(crate) functions: BTreeMap<String, CustomFunction>,
+    pub(crate) aggregations: BTreeMap<String, Arc<dyn CustomAggregation>>,
+}
+
 pub(crate) enum CozoScript {
     Single(InputProgram),
     Imperative(ImperativeProgram),
@@ -111,16 +122,12 @@ impl ImperativeStmt {
         match self {
             ImperativeStmt::Program { prog, .. }
             | ImperativeStmt::IgnoreErrorProgram { prog, .. } => {
-                if let Some(name) = prog.prog.needs_write_lock() {
-                    collector.insert(name);
-                }
+                prog.prog.needs_write_locks(collector);
             }
             ImperativeStmt::Return { returns, .. } => {
                 for ret in returns {
                     if let Left(prog) = ret {
-                        if let Some(name) = prog.prog.needs_write_lock() {
-                            collector.insert(name);
-                        }
+                        prog.prog.needs_write_locks(collector);
                     }
                 }
             }
@@ -131,9 +138,7 @@ impl ImperativeStmt {
                 ..
             } => {
                 if let ImperativeCondition::Right(prog) = condition {
-                    if let Some(name) = prog.prog.needs_write_lock() {
-                        collector.insert(name);
-                    }
+                    prog.prog.needs_write_locks(collector);
                 }
                 for prog in then_branch.iter().chain(else_branch.iter()) {
                     prog.needs_write_locks(collector);
@@ -155,6 +160,9 @@ impl ImperativeStmt {
                             collector.insert(rel.name.clone());
                         }
                     }
+                    SysOp::AlterRelation(rel, _) => {
+                        collector.insert(rel.name.clone());
+                    }
                     SysOp::RenameRelation(renames) => {
                         for (old, new) in renames {
                             collector.insert(old.name.clone());
@@ -177,9 +185,18 @@ impl ImperativeStmt {
                         collector.insert(m.base_relation.clone());
                         collector.insert(SmartString::from(format!("{}:{}", m.base_relation, m.index_name)));
                     }
+                    SysOp::CreateSpatialIndex(m) => {
+                        collector.insert(m.base_relation.clone());
+                        collector.insert(SmartString::from(format!("{}:{}", m.base_relation, m.index_name)));
+                    }
                     SysOp::RemoveIndex(rel, idx) => {
                         collector.insert(SmartString::from(format!("{}:{}", rel.name, idx.name)));
                     }
+                    SysOp::CreateView(name, _)
+                    | SysOp::RefreshView(name)
+                    | SysOp::RemoveView(name) => {
+                        collector.insert(name.name.clone());
+                    }
                     _ => {}
                 }
             }
@@ -257,6 +274,7 @@ pub(crate) fn parse_type(src: &str) -> Result<NullableColType> {
 pub(crate) fn parse_expressions(
     src: &str,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
 ) -> Result<Expr> {
     let parsed = CozoScriptParser::parse(Rule::expression_script, src)
         .map_err(|err| {
@@ -269,12 +287,31 @@ pub(crate) fn parse_expressions(
         .next()
         .unwrap();
 
-    build_expr(parsed.into_inner().next().unwrap(), param_pool)
+    build_expr(parsed.into_inner().next().unwrap(), param_pool, registry)
+}
+
+/// Parses the definition of a stored function, as kept in the system catalog.
+pub(crate) fn parse_stored_function(
+    src: &str,
+    registry: &CustomRegistry,
+) -> Result<StoredFunction> {
+    let parsed = CozoScriptParser::parse(Rule::stored_fn, src)
+        .map_err(|err| {
+            let span = match err.location {
+                InputLocation::Pos(p) => SourceSpan(p, 0),
+                InputLocation::Span((start, end)) => SourceSpan(start, end - start),
+            };
+            ParseError { span }
+        })?
+        .next()
+        .unwrap();
+    build_stored_function(parsed.into_inner().next().unwrap(), registry)
 }
 
 pub(crate) fn parse_script(
     src: &str,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<CozoScript> {
@@ -290,17 +327,24 @@ pub(crate) fn parse_script(
         .unwrap();
     Ok(match parsed.as_rule() {
         Rule::query_script => {
-            let q = parse_query(parsed.into_inner(), param_pool, fixed_rules, cur_vld)?;
+            let q = parse_query(
+                parsed.into_inner(),
+                param_pool,
+                registry,
+                fixed_rules,
+                cur_vld,
+            )?;
             CozoScript::Single(q)
         }
         Rule::imperative_script => {
-            let p = parse_imperative_block(parsed, param_pool, fixed_rules, cur_vld)?;
+            let p = parse_imperative_block(parsed, param_pool, registry, fixed_rules, cur_vld)?;
             CozoScript::Imperative(p)
         }
 
         Rule::sys_script => CozoScript::Sys(parse_sys(
             parsed.into_inner(),
             param_pool,
+            registry,
             fixed_rules,
             cur_vld,
         )?),
@@ -312,7 +356,7 @@ trait ExtractSpan {
     fn extract_span(&self) -> SourceSpan;
 }
 
-impl ExtractSpan for Pair<'_> {
+impl<R: pest::RuleType> ExtractSpan for pest::iterators::Pair<'_, R> {
     fn extract_span(&self) -> SourceSpan {
         let span = self.as_span();
         let start = span.start();
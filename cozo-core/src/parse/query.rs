@@ -13,29 +13,33 @@ use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 
-use either::{Left, Right};
+use either::{Either, Left, Right};
 use itertools::Itertools;
 use miette::{bail, ensure, Diagnostic, LabeledSpan, Report, Result};
+use pest::error::InputLocation;
 use pest::Parser;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::aggr::{parse_aggr, Aggregation};
+use crate::data::aggr::{get_aggr, Aggregation};
 use crate::data::expr::Expr;
-use crate::data::functions::{str2vld, MAX_VALIDITY_TS};
+use crate::data::functions::{str2vld, MAX_VALIDITY_TS, OP_MINUS};
 use crate::data::program::{
     FixedRuleApply, FixedRuleArg, InputAtom, InputInlineRule, InputInlineRulesOrFixed,
-    InputNamedFieldRelationApplyAtom, InputProgram, InputRelationApplyAtom, InputRuleApplyAtom,
-    QueryAssertion, QueryOutOptions, RelationOp, ReturnMutation, SearchInput, SortDir, Unification,
+    InputNamedFieldRelationApplyAtom, InputPathAtom, InputProgram, InputRelationApplyAtom,
+    InputRuleApplyAtom, PathExpr, QueryAssertion, QueryOutOptions, RelationOp, ReturnMutation,
+    SearchInput, SortDir, Unification,
 };
 use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
 use crate::data::symb::{Symbol, PROG_ENTRY};
 use crate::data::value::{DataValue, ValidityTs};
 use crate::fixed_rule::utilities::constant::Constant;
 use crate::fixed_rule::{FixedRuleHandle, FixedRuleNotFoundError};
-use crate::parse::expr::build_expr;
+use crate::parse::expr::{build_expr, build_term};
 use crate::parse::schema::parse_schema;
-use crate::parse::{CozoScriptParser, ExtractSpan, Pair, Pairs, Rule, SourceSpan};
+use crate::parse::{
+    CozoScriptParser, CustomRegistry, ExtractSpan, Pair, Pairs, ParseError, Rule, SourceSpan,
+};
 use crate::runtime::relation::InputRelationHandle;
 use crate::FixedRule;
 
@@ -106,20 +110,23 @@ fn merge_spans(symbs: &[Symbol]) -> SourceSpan {
 pub(crate) fn parse_query(
     src: Pairs<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<InputProgram> {
     let mut progs: BTreeMap<Symbol, InputInlineRulesOrFixed> = Default::default();
     let mut out_opts: QueryOutOptions = Default::default();
     let mut disable_magic_rewrite = false;
+    let mut rule_libraries = vec![];
 
     let mut stored_relation = None;
+    let mut rule_mutations = vec![];
     let mut returning_mutation = ReturnMutation::NotReturning;
 
     for pair in src {
         match pair.as_rule() {
             Rule::rule => {
-                let (name, rule) = parse_rule(pair, param_pool, cur_vld)?;
+                let (name, rule) = parse_rule(pair, param_pool, registry, cur_vld)?;
 
                 match progs.entry(name) {
                     Entry::Vacant(e) => {
@@ -163,7 +170,8 @@ pub(crate) fn parse_query(
             }
             Rule::fixed_rule => {
                 let rule_span = pair.extract_span();
-                let (name, apply) = parse_fixed_rule(pair, param_pool, fixed_rules, cur_vld)?;
+                let (name, apply) =
+                    parse_fixed_rule(pair, param_pool, registry, fixed_rules, cur_vld)?;
 
                 match progs.entry(name) {
                     Entry::Vacant(e) => {
@@ -185,7 +193,8 @@ pub(crate) fn parse_query(
             Rule::const_rule => {
                 let span = pair.extract_span();
                 let mut src = pair.into_inner();
-                let (name, mut head, aggr) = parse_rule_head(src.next().unwrap(), param_pool)?;
+                let (name, mut head, aggr) =
+                    parse_rule_head(src.next().unwrap(), param_pool, registry)?;
 
                 if let Some(found) = progs.get(&name) {
                     let mut found_span = match found {
@@ -213,7 +222,7 @@ pub(crate) fn parse_query(
                 }
                 let data_part = src.next().unwrap();
                 let data_part_str = data_part.as_str();
-                let data = build_expr(data_part.clone(), param_pool)?;
+                let data = build_expr(data_part.clone(), param_pool, registry)?;
                 let mut options = BTreeMap::new();
                 options.insert(SmartString::from("data"), data);
                 let handle = FixedRuleHandle {
@@ -260,7 +269,7 @@ pub(crate) fn parse_query(
             Rule::timeout_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
-                let timeout = build_expr(pair, param_pool)?
+                let timeout = build_expr(pair, param_pool, registry)?
                     .eval_to_const()
                     .map_err(|err| OptionNotConstantError("timeout", span, [err]))?
                     .get_float()
@@ -271,6 +280,21 @@ pub(crate) fn parse_query(
                     out_opts.timeout = None;
                 }
             }
+            Rule::max_rows_option | Rule::max_memory_option | Rule::max_epochs_option => {
+                let (name, target) = match pair.as_rule() {
+                    Rule::max_rows_option => ("max_rows", &mut out_opts.limits.max_rows),
+                    Rule::max_memory_option => ("max_memory", &mut out_opts.limits.max_memory),
+                    _ => ("max_epochs", &mut out_opts.limits.max_epochs),
+                };
+                let pair = pair.into_inner().next().unwrap();
+                let span = pair.extract_span();
+                let max = build_expr(pair, param_pool, registry)?
+                    .eval_to_const()
+                    .map_err(|err| OptionNotConstantError(name, span, [err]))?
+                    .get_non_neg_int()
+                    .ok_or(OptionNotNonNegIntError(name, span))?;
+                *target = Some(max as usize);
+            }
             Rule::sleep_option => {
                 #[cfg(target_arch = "wasm32")]
                 bail!(":sleep is not supported under WASM");
@@ -279,7 +303,7 @@ pub(crate) fn parse_query(
                 {
                     let pair = pair.into_inner().next().unwrap();
                     let span = pair.extract_span();
-                    let sleep = build_expr(pair, param_pool)?
+                    let sleep = build_expr(pair, param_pool, registry)?
                         .eval_to_const()
                         .map_err(|err| OptionNotConstantError("sleep", span, [err]))?
                         .get_float()
@@ -291,7 +315,7 @@ pub(crate) fn parse_query(
             Rule::limit_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
-                let limit = build_expr(pair, param_pool)?
+                let limit = build_expr(pair, param_pool, registry)?
                     .eval_to_const()
                     .map_err(|err| OptionNotConstantError("limit", span, [err]))?
                     .get_non_neg_int()
@@ -301,7 +325,7 @@ pub(crate) fn parse_query(
             Rule::offset_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
-                let offset = build_expr(pair, param_pool)?
+                let offset = build_expr(pair, param_pool, registry)?
                     .eval_to_const()
                     .map_err(|err| OptionNotConstantError("offset", span, [err]))?
                     .get_non_neg_int()
@@ -330,6 +354,9 @@ pub(crate) fn parse_query(
             Rule::returning_option => {
                 returning_mutation = ReturnMutation::Returning;
             }
+            Rule::bag_option => {
+                out_opts.bag = true;
+            }
             Rule::relation_option => {
                 let span = pair.extract_span();
                 let mut args = pair.into_inner();
@@ -337,6 +364,7 @@ pub(crate) fn parse_query(
                     Rule::relation_create => RelationOp::Create,
                     Rule::relation_replace => RelationOp::Replace,
                     Rule::relation_put => RelationOp::Put,
+                    Rule::relation_put_if => RelationOp::PutIf,
                     Rule::relation_insert => RelationOp::Insert,
                     Rule::relation_update => RelationOp::Update,
                     Rule::relation_rm => RelationOp::Rm,
@@ -348,28 +376,75 @@ pub(crate) fn parse_query(
 
                 let name_p = args.next().unwrap();
                 let name = Symbol::new(name_p.as_str(), name_p.extract_span());
-                match args.next() {
-                    None => stored_relation = Some(Left((name, span, op))),
+                let mut schema_p = None;
+                let mut condition = None;
+                let mut source = None;
+                for p in args {
+                    match p.as_rule() {
+                        Rule::table_schema => schema_p = Some(p),
+                        Rule::mutation_condition => {
+                            let cond_p = p.into_inner().last().unwrap();
+                            condition = Some(build_expr(cond_p, param_pool, registry)?);
+                        }
+                        Rule::mutation_source => {
+                            let source_p = p.into_inner().last().unwrap();
+                            source = Some(Symbol::new(source_p.as_str(), source_p.extract_span()));
+                        }
+                        r => unreachable!("{:?}", r),
+                    }
+                }
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Only the :put_if mutation takes a condition")]
+                #[diagnostic(code(parser::unexpected_mutation_condition))]
+                struct UnexpectedMutationCondition(#[label] SourceSpan);
+
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("The :put_if mutation requires a condition")]
+                #[diagnostic(code(parser::missing_mutation_condition))]
+                #[diagnostic(help("Follow the columns with 'where <condition>'"))]
+                struct MissingMutationCondition(#[label] SourceSpan);
+
+                if op == RelationOp::PutIf {
+                    ensure!(condition.is_some(), MissingMutationCondition(span));
+                } else {
+                    ensure!(condition.is_none(), UnexpectedMutationCondition(span));
+                }
+                let target = match schema_p {
+                    None => Left((name, span, op, condition)),
                     Some(schema_p) => {
                         let (mut metadata, mut key_bindings, mut dep_bindings) =
-                            parse_schema(schema_p)?;
+                            parse_schema(schema_p, registry)?;
                         if !matches!(op, RelationOp::Create | RelationOp::Replace) {
+                            #[derive(Debug, Error, Diagnostic)]
+                            #[error("Only :create and :replace may declare references or checks")]
+                            #[diagnostic(code(parser::reference_outside_create))]
+                            struct SchemaRuleOutsideCreate(#[label] SourceSpan);
+
+                            ensure!(
+                                metadata.foreign_keys.is_empty() && metadata.constraints.is_empty(),
+                                SchemaRuleOutsideCreate(span)
+                            );
                             key_bindings.extend(dep_bindings);
                             dep_bindings = vec![];
                             metadata.keys.extend(metadata.non_keys);
                             metadata.non_keys = vec![];
                         }
-                        stored_relation = Some(Right((
+                        Right((
                             InputRelationHandle {
                                 name,
                                 metadata,
                                 key_bindings,
                                 dep_bindings,
+                                condition,
                                 span,
                             },
                             op,
-                        )))
+                        ))
                     }
+                };
+                match source {
+                    None => stored_relation = Some(target),
+                    Some(source) => rule_mutations.push((target, source)),
                 }
             }
             Rule::assert_none_option => {
@@ -389,13 +464,20 @@ pub(crate) fn parse_query(
             Rule::disable_magic_rewrite_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
-                let val = build_expr(pair, param_pool)?
+                let val = build_expr(pair, param_pool, registry)?
                     .eval_to_const()
                     .map_err(|err| OptionNotConstantError("disable_magic_rewrite", span, [err]))?
                     .get_bool()
                     .ok_or(OptionNotBoolError("disable_magic_rewrite", span))?;
                 disable_magic_rewrite = val;
             }
+            Rule::use_rules => {
+                let name = pair.into_inner().nth(1).unwrap();
+                let lib = Symbol::new(name.as_str(), name.extract_span());
+                if !rule_libraries.contains(&lib) {
+                    rule_libraries.push(lib);
+                }
+            }
             Rule::EOI => break,
             r => unreachable!("{:?}", r),
         }
@@ -405,6 +487,7 @@ pub(crate) fn parse_query(
         prog: progs,
         out_opts,
         disable_magic_rewrite,
+        rule_libraries,
     };
 
     if prog.prog.is_empty() {
@@ -428,7 +511,7 @@ pub(crate) fn parse_query(
 
     match stored_relation {
         None => {}
-        Some(Left((name, span, op))) => {
+        Some(Left((name, span, op, condition))) => {
             let head = prog.get_entry_out_head()?;
             for symb in &head {
                 symb.ensure_valid_field()?;
@@ -447,6 +530,8 @@ pub(crate) fn parse_query(
                     })
                     .collect(),
                 non_keys: vec![],
+                foreign_keys: vec![],
+                constraints: vec![],
             };
 
             let handle = InputRelationHandle {
@@ -454,6 +539,7 @@ pub(crate) fn parse_query(
                 metadata,
                 key_bindings: head,
                 dep_bindings: vec![],
+                condition,
                 span,
             };
             prog.out_opts.store_relation = Some((handle, op, returning_mutation))
@@ -528,19 +614,145 @@ pub(crate) fn parse_query(
         }
     }
 
+    for (target, source) in rule_mutations {
+        let mutation = resolve_rule_mutation(&prog, target, source)?;
+        prog.out_opts.rule_mutations.push(mutation);
+    }
+
     Ok(prog)
 }
 
+/// Completes the target of a mutation fed by a rule other than the entry, using the output
+/// head of that rule where the mutation does not bind the columns explicitly.
+fn resolve_rule_mutation(
+    prog: &InputProgram,
+    target: Either<
+        (Symbol, SourceSpan, RelationOp, Option<Expr>),
+        (InputRelationHandle, RelationOp),
+    >,
+    source: Symbol,
+) -> Result<(InputRelationHandle, RelationOp, Symbol)> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Input relation '{0}' has no keys")]
+    #[diagnostic(code(parser::relation_has_no_keys))]
+    struct RelationHasNoKeys(String, #[label] SourceSpan);
+
+    let head = prog.get_rule_out_head(&source)?;
+    let (mut handle, op) = match target {
+        Left((name, span, op, condition)) => (
+            InputRelationHandle {
+                name,
+                metadata: StoredRelationMetadata {
+                    keys: vec![],
+                    non_keys: vec![],
+                    foreign_keys: vec![],
+                    constraints: vec![],
+                },
+                key_bindings: vec![],
+                dep_bindings: vec![],
+                condition,
+                span,
+            },
+            op,
+        ),
+        Right(target) => target,
+    };
+    if handle.key_bindings.is_empty() {
+        if !handle.dep_bindings.is_empty() || head.is_empty() {
+            bail!(RelationHasNoKeys(handle.name.to_string(), handle.span));
+        }
+        for symb in &head {
+            symb.ensure_valid_field()?;
+        }
+        handle.metadata = StoredRelationMetadata {
+            keys: head
+                .iter()
+                .map(|s| ColumnDef {
+                    name: s.name.clone(),
+                    typing: NullableColType {
+                        coltype: ColType::Any,
+                        nullable: true,
+                    },
+                    default_gen: None,
+                })
+                .collect(),
+            non_keys: vec![],
+            foreign_keys: vec![],
+            constraints: vec![],
+        };
+        handle.key_bindings = head;
+    }
+    Ok((handle, op, source))
+}
+
+/// Parses the body of a stored rule library, which may only contain inline rules.
+pub(crate) fn parse_rule_library(
+    src: &str,
+    registry: &CustomRegistry,
+    cur_vld: ValidityTs,
+) -> Result<BTreeMap<Symbol, Vec<InputInlineRule>>> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Rule library cannot define the entry rule")]
+    #[diagnostic(code(parser::entry_in_rule_library))]
+    #[diagnostic(help("Libraries hold reusable rules; the entry rule belongs to each query"))]
+    struct EntryInRuleLibrary(#[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Rule {0} has multiple definitions with conflicting heads")]
+    #[diagnostic(code(parser::head_aggr_mismatch))]
+    struct RuleHeadMismatch(String, #[label] SourceSpan, #[label] SourceSpan);
+
+    let body = CozoScriptParser::parse(Rule::rule_library, src)
+        .map_err(|err| {
+            let span = match err.location {
+                InputLocation::Pos(p) => SourceSpan(p, 0),
+                InputLocation::Span((start, end)) => SourceSpan(start, end - start),
+            };
+            ParseError { span }
+        })?
+        .next()
+        .unwrap()
+        .into_inner()
+        .next()
+        .unwrap();
+
+    let empty_params = BTreeMap::new();
+    let mut rules: BTreeMap<Symbol, Vec<InputInlineRule>> = BTreeMap::new();
+    for pair in body.into_inner() {
+        let (name, rule) = parse_rule(pair, &empty_params, registry, cur_vld)?;
+        ensure!(!name.is_prog_entry(), EntryInRuleLibrary(name.span));
+        match rules.entry(name) {
+            Entry::Vacant(e) => {
+                e.insert(vec![rule]);
+            }
+            Entry::Occupied(mut e) => {
+                let prev = e.get().first().unwrap();
+                ensure!(
+                    prev.aggr == rule.aggr,
+                    RuleHeadMismatch(
+                        e.key().to_string(),
+                        merge_spans(&prev.head),
+                        merge_spans(&rule.head),
+                    )
+                );
+                e.get_mut().push(rule);
+            }
+        }
+    }
+    Ok(rules)
+}
+
 fn parse_rule(
     src: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     cur_vld: ValidityTs,
 ) -> Result<(Symbol, InputInlineRule)> {
     let span = src.extract_span();
     let mut src = src.into_inner();
     let head = src.next().unwrap();
     let head_span = head.extract_span();
-    let (name, head, aggr) = parse_rule_head(head, param_pool)?;
+    let (name, head, aggr) = parse_rule_head(head, param_pool, registry)?;
 
     #[derive(Debug, Error, Diagnostic)]
     #[error("Horn-clause rule cannot have empty rule head")]
@@ -555,6 +767,7 @@ fn parse_rule(
         body_clauses.push(parse_disjunction(
             atom_src,
             param_pool,
+            registry,
             cur_vld,
             &mut ignored_counter,
         )?)
@@ -574,6 +787,7 @@ fn parse_rule(
 fn parse_disjunction(
     pair: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     cur_vld: ValidityTs,
     ignored_counter: &mut u32,
 ) -> Result<InputAtom> {
@@ -582,7 +796,13 @@ fn parse_disjunction(
         .into_inner()
         .filter_map(|v| match v.as_rule() {
             Rule::or_op => None,
-            _ => Some(parse_atom(v, param_pool, cur_vld, ignored_counter)),
+            _ => Some(parse_atom(
+                v,
+                param_pool,
+                registry,
+                cur_vld,
+                ignored_counter,
+            )),
         })
         .try_collect()?;
     Ok(if res.len() == 1 {
@@ -595,6 +815,7 @@ fn parse_disjunction(
 fn parse_atom(
     src: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     cur_vld: ValidityTs,
     ignored_counter: &mut u32,
 ) -> Result<InputAtom> {
@@ -603,26 +824,58 @@ fn parse_atom(
             let span = src.extract_span();
             let grouped: Vec<_> = src
                 .into_inner()
-                .map(|v| parse_disjunction(v, param_pool, cur_vld, ignored_counter))
+                .map(|v| parse_disjunction(v, param_pool, registry, cur_vld, ignored_counter))
                 .try_collect()?;
             InputAtom::Conjunction {
                 inner: grouped,
                 span,
             }
         }
-        Rule::disjunction => parse_disjunction(src, param_pool, cur_vld, ignored_counter)?,
+        Rule::disjunction => {
+            parse_disjunction(src, param_pool, registry, cur_vld, ignored_counter)?
+        }
         Rule::negation => {
             let span = src.extract_span();
             let mut src = src.into_inner();
             src.next().unwrap();
-            let inner = parse_atom(src.next().unwrap(), param_pool, cur_vld, ignored_counter)?;
+            let inner = parse_atom(
+                src.next().unwrap(),
+                param_pool,
+                registry,
+                cur_vld,
+                ignored_counter,
+            )?;
             InputAtom::Negation {
                 inner: inner.into(),
                 span,
             }
         }
+        Rule::optional_apply => {
+            let span = src.extract_span();
+            let inner = src.into_inner().next().unwrap();
+            let inner = parse_atom(inner, param_pool, registry, cur_vld, ignored_counter)?;
+            InputAtom::Optional {
+                inner: inner.into(),
+                span,
+            }
+        }
+        Rule::path_apply => {
+            let span = src.extract_span();
+            let mut src = src.into_inner();
+            let from = build_term(src.next().unwrap(), param_pool, registry)?;
+            let path = parse_path_expr(src.next().unwrap())?;
+            let to = build_term(src.next().unwrap(), param_pool, registry)?;
+            InputAtom::Path {
+                inner: InputPathAtom {
+                    from,
+                    to,
+                    path,
+                    span,
+                },
+            }
+        }
         Rule::expr => {
-            let expr = build_expr(src, param_pool)?;
+            let expr = build_expr(src, param_pool, registry)?;
             InputAtom::Predicate { inner: expr }
         }
         Rule::unify => {
@@ -634,7 +887,7 @@ fn parse_atom(
                 symb.name = format!("*^*{}", *ignored_counter).into();
                 *ignored_counter += 1;
             }
-            let expr = build_expr(src.next().unwrap(), param_pool)?;
+            let expr = build_expr(src.next().unwrap(), param_pool, registry)?;
             InputAtom::Unification {
                 inner: Unification {
                     binding: symb,
@@ -654,7 +907,7 @@ fn parse_atom(
                 *ignored_counter += 1;
             }
             src.next().unwrap();
-            let expr = build_expr(src.next().unwrap(), param_pool)?;
+            let expr = build_expr(src.next().unwrap(), param_pool, registry)?;
             InputAtom::Unification {
                 inner: Unification {
                     binding: symb,
@@ -672,7 +925,7 @@ fn parse_atom(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|v| build_expr(v, param_pool))
+                .map(|v| build_expr(v, param_pool, registry))
                 .try_collect()?;
             InputAtom::Rule {
                 inner: InputRuleApplyAtom {
@@ -690,12 +943,16 @@ fn parse_atom(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|v| build_expr(v, param_pool))
+                .map(|v| build_expr(v, param_pool, registry))
                 .try_collect()?;
             let valid_at = match src.next() {
                 None => None,
                 Some(vld_clause) => {
-                    let vld_expr = build_expr(vld_clause.into_inner().next().unwrap(), param_pool)?;
+                    let vld_expr = build_expr(
+                        vld_clause.into_inner().next().unwrap(),
+                        param_pool,
+                        registry,
+                    )?;
                     Some(expr2vld_spec(vld_expr, cur_vld)?)
                 }
             };
@@ -729,10 +986,10 @@ fn parse_atom(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|arg| extract_named_apply_arg(arg, param_pool))
+                .map(|arg| extract_named_apply_arg(arg, param_pool, registry))
                 .try_collect()?;
             let parameters: BTreeMap<SmartString<LazyCompact>, Expr> = src
-                .map(|arg| extract_named_apply_arg(arg, param_pool))
+                .map(|arg| extract_named_apply_arg(arg, param_pool, registry))
                 .try_collect()?;
 
             let opts = SearchInput {
@@ -754,12 +1011,16 @@ fn parse_atom(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|arg| extract_named_apply_arg(arg, param_pool))
+                .map(|arg| extract_named_apply_arg(arg, param_pool, registry))
                 .try_collect()?;
             let valid_at = match src.next() {
                 None => None,
                 Some(vld_clause) => {
-                    let vld_expr = build_expr(vld_clause.into_inner().next().unwrap(), param_pool)?;
+                    let vld_expr = build_expr(
+                        vld_clause.into_inner().next().unwrap(),
+                        param_pool,
+                        registry,
+                    )?;
                     Some(expr2vld_spec(vld_expr, cur_vld)?)
                 }
             };
@@ -776,15 +1037,93 @@ fn parse_atom(
     })
 }
 
+fn parse_path_expr(src: Pair<'_>) -> Result<PathExpr> {
+    Ok(match src.as_rule() {
+        Rule::path_alt => {
+            let mut choices: Vec<_> = src.into_inner().map(parse_path_expr).try_collect()?;
+            if choices.len() == 1 {
+                choices.pop().unwrap()
+            } else {
+                PathExpr::Alternation(choices)
+            }
+        }
+        Rule::path_seq => {
+            let mut steps: Vec<_> = src.into_inner().map(parse_path_expr).try_collect()?;
+            if steps.len() == 1 {
+                steps.pop().unwrap()
+            } else {
+                PathExpr::Sequence(steps)
+            }
+        }
+        Rule::path_repeat => {
+            let span = src.extract_span();
+            let mut src = src.into_inner();
+            let inner = parse_path_expr(src.next().unwrap())?;
+            let (min, max) = match src.next() {
+                None => return Ok(inner),
+                Some(quantifier) => match quantifier.as_rule() {
+                    Rule::path_star => (0, None),
+                    Rule::path_plus => (1, None),
+                    Rule::path_optional => (0, Some(1)),
+                    Rule::path_bounds => {
+                        #[derive(Debug, Error, Diagnostic)]
+                        #[error("Bad repetition bounds for path")]
+                        #[diagnostic(code(parser::bad_path_bounds))]
+                        #[diagnostic(help(
+                            "The upper bound must be positive and at least the lower bound"
+                        ))]
+                        struct BadPathBounds(#[label] SourceSpan);
+
+                        let bounds_span = quantifier.extract_span();
+                        let mut bounds = quantifier.into_inner().map(|p| {
+                            p.as_str()
+                                .replace('_', "")
+                                .parse::<usize>()
+                                .map_err(|_| BadPathBounds(p.extract_span()))
+                        });
+                        let min = bounds.next().unwrap()?;
+                        let max = bounds.next().transpose()?;
+                        if let Some(max) = max {
+                            ensure!(max > 0 && max >= min, BadPathBounds(bounds_span));
+                        }
+                        (min, max)
+                    }
+                    r => unreachable!("{:?}", r),
+                },
+            };
+            PathExpr::Repetition {
+                inner: inner.into(),
+                min,
+                max,
+                span,
+            }
+        }
+        Rule::path_step => {
+            let mut src = src.into_inner();
+            let mut name = src.next().unwrap();
+            let inverse = name.as_rule() == Rule::path_inverse;
+            if inverse {
+                name = src.next().unwrap();
+            }
+            PathExpr::Step {
+                relation: Symbol::new(&name.as_str()[1..], name.extract_span()),
+                inverse,
+            }
+        }
+        r => unreachable!("{:?}", r),
+    })
+}
+
 fn extract_named_apply_arg(
     pair: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
 ) -> Result<(SmartString<LazyCompact>, Expr)> {
     let mut inner = pair.into_inner();
     let name_p = inner.next().unwrap();
     let name = SmartString::from(name_p.as_str());
     let arg = match inner.next() {
-        Some(a) => build_expr(a, param_pool)?,
+        Some(a) => build_expr(a, param_pool, registry)?,
         None => Expr::Binding {
             var: Symbol::new(name.clone(), name_p.extract_span()),
             tuple_pos: None,
@@ -796,20 +1135,58 @@ fn extract_named_apply_arg(
 fn parse_rule_head(
     src: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
 ) -> Result<(
     Symbol,
     Vec<Symbol>,
     Vec<Option<(Aggregation, Vec<DataValue>)>>,
 )> {
+    let span = src.extract_span();
     let mut src = src.into_inner();
     let name = src.next().unwrap();
     let mut args = vec![];
     let mut aggrs = vec![];
     for p in src {
-        let (arg, aggr) = parse_rule_head_arg(p, param_pool)?;
+        let (arg, aggr) = parse_rule_head_arg(p, param_pool, registry)?;
         args.push(arg);
         aggrs.push(aggr);
     }
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Window aggregations cannot be mixed with other aggregations in the same rule head")]
+    #[diagnostic(code(parser::window_aggr_mixed))]
+    struct WindowAggrMixedError(#[label] SourceSpan);
+
+    let n_windows = aggrs
+        .iter()
+        .flatten()
+        .filter(|(aggr, _)| aggr.is_window())
+        .count();
+    ensure!(
+        n_windows == 0 || n_windows == aggrs.iter().flatten().count(),
+        WindowAggrMixedError(span)
+    );
+    if n_windows > 0 {
+        // rows are partitioned by the variables that are not used by any window aggregation
+        let mut used = BTreeSet::new();
+        for (symb, aggr) in args.iter().zip(aggrs.iter()) {
+            if let Some((aggr, params)) = aggr {
+                used.insert(symb.name.as_str());
+                used.extend(aggr.window_sort_keys(params).into_iter().map(|(k, _)| k));
+            }
+        }
+        let partition = DataValue::List(
+            args.iter()
+                .zip(aggrs.iter())
+                .enumerate()
+                .filter(|(_, (symb, aggr))| aggr.is_none() && !used.contains(symb.name.as_str()))
+                .map(|(i, _)| DataValue::from(i as i64))
+                .collect(),
+        );
+        for (_, params) in aggrs.iter_mut().flatten() {
+            params.push(partition.clone());
+        }
+    }
     Ok((Symbol::new(name.as_str(), name.extract_span()), args, aggrs))
 }
 
@@ -821,8 +1198,10 @@ struct AggrNotFound(String, #[label] SourceSpan);
 fn parse_rule_head_arg(
     src: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
 ) -> Result<(Symbol, Option<(Aggregation, Vec<DataValue>)>)> {
     let src = src.into_inner().next().unwrap();
+    let src_span = src.extract_span();
     Ok(match src.as_rule() {
         Rule::var => (Symbol::new(src.as_str(), src.extract_span()), None),
         Rule::aggr_arg => {
@@ -830,23 +1209,77 @@ fn parse_rule_head_arg(
             let aggr_p = inner.next().unwrap();
             let aggr_name = aggr_p.as_str();
             let var = inner.next().unwrap();
-            let args: Vec<_> = inner
-                .map(|v| -> Result<DataValue> { build_expr(v, param_pool)?.eval_to_const() })
-                .try_collect()?;
+            let aggr = get_aggr(aggr_name, &registry.aggregations)
+                .ok_or_else(|| AggrNotFound(aggr_name.to_string(), aggr_p.extract_span()))?;
+            let args: Vec<_> = if aggr.is_window() {
+                parse_window_args(&aggr, aggr_name, src_span, inner, param_pool, registry)?
+            } else {
+                inner
+                    .map(|v| -> Result<DataValue> {
+                        build_expr(v, param_pool, registry)?.eval_to_const()
+                    })
+                    .try_collect()?
+            };
             (
                 Symbol::new(var.as_str(), var.extract_span()),
-                Some((
-                    parse_aggr(aggr_name)
-                        .ok_or_else(|| AggrNotFound(aggr_name.to_string(), aggr_p.extract_span()))?
-                        .clone(),
-                    args,
-                )),
+                Some((aggr, args)),
             )
         }
         _ => unreachable!(),
     })
 }
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("Window aggregation '{0}' requires {1} constant argument(s) before its sort keys")]
+#[diagnostic(code(parser::window_args_missing))]
+struct WindowArgsMissing(String, usize, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad sort key for window aggregation")]
+#[diagnostic(code(parser::bad_window_sort_key))]
+#[diagnostic(help("Sort keys must be variables, prefixed with `-` for descending order"))]
+struct BadWindowSortKey(#[label] SourceSpan);
+
+/// The arguments of a window aggregation are its constant arguments, followed by the list of its
+/// sort keys, each encoded as the list of the name of the variable and whether it is descending.
+/// The positions of the partitioning variables are added by `parse_rule_head`.
+fn parse_window_args(
+    aggr: &Aggregation,
+    aggr_name: &str,
+    span: SourceSpan,
+    mut src: Pairs<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
+) -> Result<Vec<DataValue>> {
+    let n_consts = aggr.window_const_args();
+    let mut args = Vec::with_capacity(n_consts + 1);
+    for _ in 0..n_consts {
+        let arg = src
+            .next()
+            .ok_or_else(|| WindowArgsMissing(aggr_name.to_string(), n_consts, span))?;
+        args.push(build_expr(arg, param_pool, registry)?.eval_to_const()?);
+    }
+    let keys: Vec<_> = src
+        .map(|arg| -> Result<DataValue> {
+            let expr = build_expr(arg, param_pool, registry)?;
+            let (var, desc) = match &expr {
+                Expr::Binding { var, .. } => (var, false),
+                Expr::Apply { op, args, .. } if op.name == OP_MINUS.name => match args.first() {
+                    Some(Expr::Binding { var, .. }) => (var, true),
+                    _ => bail!(BadWindowSortKey(expr.span())),
+                },
+                _ => bail!(BadWindowSortKey(expr.span())),
+            };
+            Ok(DataValue::List(vec![
+                DataValue::from(var.name.as_str()),
+                DataValue::from(desc),
+            ]))
+        })
+        .try_collect()?;
+    args.push(DataValue::List(keys));
+    Ok(args)
+}
+
 #[derive(Debug, Error, Diagnostic)]
 #[error("bad specification of validity")]
 #[diagnostic(code(parser::bad_validity_spec))]
@@ -855,11 +1288,12 @@ struct BadValiditySpecification(#[label] SourceSpan);
 fn parse_fixed_rule(
     src: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<(Symbol, FixedRuleApply)> {
     let mut src = src.into_inner();
-    let (out_symbol, head, aggr) = parse_rule_head(src.next().unwrap(), param_pool)?;
+    let (out_symbol, head, aggr) = parse_rule_head(src.next().unwrap(), param_pool, registry)?;
 
     #[derive(Debug, Error, Diagnostic)]
     #[error("fixed rule cannot be combined with aggregation")]
@@ -941,7 +1375,7 @@ fn parse_fixed_rule(
                                 }
                                 Rule::validity_clause => {
                                     let vld_inner = v.into_inner().next().unwrap();
-                                    let vld_expr = build_expr(vld_inner, param_pool)?;
+                                    let vld_expr = build_expr(vld_inner, param_pool, registry)?;
                                     valid_at = Some(expr2vld_spec(vld_expr, cur_vld)?)
                                 }
                                 _ => unreachable!(),
@@ -986,7 +1420,7 @@ fn parse_fixed_rule(
                                 }
                                 Rule::validity_clause => {
                                     let vld_inner = p.into_inner().next().unwrap();
-                                    let vld_expr = build_expr(vld_inner, param_pool)?;
+                                    let vld_expr = build_expr(vld_inner, param_pool, registry)?;
                                     valid_at = Some(expr2vld_spec(vld_expr, cur_vld)?)
                                 }
                                 _ => unreachable!(),
@@ -1010,7 +1444,7 @@ fn parse_fixed_rule(
                 let mut inner = nxt.into_inner();
                 let name = inner.next().unwrap().as_str();
                 let val = inner.next().unwrap();
-                let val = build_expr(val, param_pool)?;
+                let val = build_expr(val, param_pool, registry)?;
                 options.insert(SmartString::from(name), val);
             }
             _ => unreachable!(),
@@ -10,17 +10,21 @@ use std::collections::BTreeSet;
 
 use itertools::Itertools;
 use miette::{bail, ensure, Diagnostic, Result, IntoDiagnostic};
-use smartstring::SmartString;
+use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::relation::{VecElementType, ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::relation::{
+    VecElementType, ColType, ColumnConstraint, ColumnDef, ConstraintRule, ForeignKey,
+    ForeignKeyAction, NullableColType, StoredRelationMetadata,
+};
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
 use crate::parse::expr::{build_expr};
-use crate::parse::{ExtractSpan, Pair, Rule, SourceSpan};
+use crate::parse::{CustomRegistry, ExtractSpan, Pair, Rule, SourceSpan};
 
 pub(crate) fn parse_schema(
     pair: Pair<'_>,
+    registry: &CustomRegistry,
 ) -> Result<(StoredRelationMetadata, Vec<Symbol>, Vec<Symbol>)> {
     let mut src = pair.into_inner();
     let mut keys = vec![];
@@ -33,24 +37,45 @@ pub(crate) fn parse_schema(
     #[error("Column {0} is defined multiple times")]
     #[diagnostic(code(parser::dup_name_in_cols))]
     struct DuplicateNameInCols(String, #[label] SourceSpan);
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Constraint {0} is defined multiple times")]
+    #[diagnostic(code(parser::dup_constraint_name))]
+    #[diagnostic(help("Constraints on the same column need explicit names"))]
+    struct DuplicateConstraintName(String, #[label] SourceSpan);
+    let mut foreign_keys = vec![];
+    let mut constraints: Vec<ColumnConstraint> = vec![];
     for p in src.next().unwrap().into_inner() {
         let span = p.extract_span();
-        let (col, ident) = parse_col(p)?;
+        let (col, ident, fk, cons) = parse_col(p, registry)?;
         if !seen_names.insert(col.name.clone()) {
             bail!(DuplicateNameInCols(col.name.to_string(), span));
         }
+        for c in cons {
+            if constraints.iter().any(|existing| existing.name == c.name) {
+                bail!(DuplicateConstraintName(c.name.to_string(), span));
+            }
+            constraints.push(c);
+        }
         keys.push(col);
-        key_bindings.push(ident)
+        key_bindings.push(ident);
+        foreign_keys.extend(fk);
     }
     if let Some(ps) = src.next() {
         for p in ps.into_inner() {
             let span = p.extract_span();
-            let (col, ident) = parse_col(p)?;
+            let (col, ident, fk, cons) = parse_col(p, registry)?;
             if !seen_names.insert(col.name.clone()) {
                 bail!(DuplicateNameInCols(col.name.to_string(), span));
             }
+            for c in cons {
+                if constraints.iter().any(|existing| existing.name == c.name) {
+                    bail!(DuplicateConstraintName(c.name.to_string(), span));
+                }
+                constraints.push(c);
+            }
             dependents.push(col);
-            dep_bindings.push(ident)
+            dep_bindings.push(ident);
+            foreign_keys.extend(fk);
         }
     }
 
@@ -58,13 +83,19 @@ pub(crate) fn parse_schema(
         StoredRelationMetadata {
             keys,
             non_keys: dependents,
+            foreign_keys,
+            constraints,
         },
         key_bindings,
         dep_bindings,
     ))
 }
 
-fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
+#[allow(clippy::type_complexity)]
+fn parse_col(
+    pair: Pair<'_>,
+    registry: &CustomRegistry,
+) -> Result<(ColumnDef, Symbol, Option<ForeignKey>, Vec<ColumnConstraint>)> {
     let mut src = pair.into_inner();
     let name_p = src.next().unwrap();
     let name = SmartString::from(name_p.as_str());
@@ -74,10 +105,16 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
     };
     let mut default_gen = None;
     let mut binding_candidate = None;
+    let mut foreign_key = None;
+    let mut constraints = vec![];
     for nxt in src {
         match nxt.as_rule() {
             Rule::col_type => typing = parse_nullable_type(nxt)?,
-            Rule::expr => default_gen = Some(build_expr(nxt, &Default::default())?),
+            Rule::col_reference => foreign_key = Some(parse_reference(&name, nxt)?),
+            Rule::col_check | Rule::col_one_of => {
+                constraints.push(parse_constraint(&name, nxt, registry)?)
+            }
+            Rule::expr => default_gen = Some(build_expr(nxt, &Default::default(), registry)?),
             Rule::out_arg => {
                 binding_candidate = Some(Symbol::new(nxt.as_str(), nxt.extract_span()))
             }
@@ -93,9 +130,68 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
             default_gen,
         },
         binding,
+        foreign_key,
+        constraints,
     ))
 }
 
+fn parse_constraint(
+    column: &SmartString<LazyCompact>,
+    pair: Pair<'_>,
+    registry: &CustomRegistry,
+) -> Result<ColumnConstraint> {
+    let is_check = pair.as_rule() == Rule::col_check;
+    let mut src = pair.into_inner().skip(1).peekable();
+    let name = match src.peek() {
+        Some(p) if p.as_rule() == Rule::ident => SmartString::from(src.next().unwrap().as_str()),
+        _ if is_check => SmartString::from(format!("{column}_check")),
+        _ => SmartString::from(format!("{column}_in")),
+    };
+    let rule = if is_check {
+        ConstraintRule::Check(build_expr(
+            src.next().unwrap(),
+            &Default::default(),
+            registry,
+        )?)
+    } else {
+        ConstraintRule::OneOf(
+            src.map(|p| build_expr(p, &Default::default(), registry)?.eval_to_const())
+                .try_collect()?,
+        )
+    };
+    Ok(ColumnConstraint {
+        name,
+        column: column.clone(),
+        rule,
+    })
+}
+
+fn parse_reference(column: &SmartString<LazyCompact>, pair: Pair<'_>) -> Result<ForeignKey> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("A reference must name a relation and its key column")]
+    #[diagnostic(code(parser::bad_reference))]
+    #[diagnostic(help("Write it as in 'references node.id'"))]
+    struct BadReference(#[label] SourceSpan);
+
+    let mut src = pair.into_inner();
+    src.next().unwrap();
+    let target_p = src.next().unwrap();
+    let (relation, target) = target_p
+        .as_str()
+        .rsplit_once('.')
+        .ok_or_else(|| BadReference(target_p.extract_span()))?;
+    let on_delete = match src.next().map(|p| p.as_rule()) {
+        Some(Rule::fk_cascade) => ForeignKeyAction::Cascade,
+        _ => ForeignKeyAction::Restrict,
+    };
+    Ok(ForeignKey {
+        column: column.clone(),
+        relation: SmartString::from(relation),
+        target: SmartString::from(target),
+        on_delete,
+    })
+}
+
 pub(crate) fn parse_nullable_type(pair: Pair<'_>) -> Result<NullableColType> {
     let nullable = pair.as_str().ends_with('?');
     let coltype = parse_type_inner(pair.into_inner().next().unwrap())?;
@@ -113,6 +209,11 @@ fn parse_type_inner(pair: Pair<'_>) -> Result<ColType> {
         Rule::uuid_type => ColType::Uuid,
         Rule::json_type => ColType::Json,
         Rule::validity_type => ColType::Validity,
+        Rule::date_type => ColType::Date,
+        Rule::datetime_type => ColType::DateTime,
+        Rule::duration_type => ColType::Duration,
+        Rule::decimal_type => ColType::Decimal,
+        Rule::geometry_type => ColType::Geometry,
         Rule::list_type => {
             let mut inner = pair.into_inner();
             let eltype = parse_nullable_type(inner.next().unwrap())?;
@@ -120,7 +221,7 @@ fn parse_type_inner(pair: Pair<'_>) -> Result<ColType> {
                 None => None,
                 Some(len_p) => {
                     let span = len_p.extract_span();
-                    let expr = build_expr(len_p, &Default::default())?;
+                    let expr = build_expr(len_p, &Default::default(), &Default::default())?;
                     let dv = expr.eval_to_const()?;
 
                     #[derive(Debug, Error, Diagnostic)]
@@ -155,6 +256,26 @@ fn parse_type_inner(pair: Pair<'_>) -> Result<ColType> {
         Rule::tuple_type => {
             ColType::Tuple(pair.into_inner().map(parse_nullable_type).try_collect()?)
         }
+        Rule::record_type => {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("Field '{0}' is defined more than once in record type")]
+            #[diagnostic(code(parser::dup_field_in_record_type))]
+            struct DuplicateRecordField(String, #[label] SourceSpan);
+
+            let mut fields: Vec<(SmartString<LazyCompact>, NullableColType)> = vec![];
+            for field_p in pair.into_inner() {
+                let span = field_p.extract_span();
+                let mut inner = field_p.into_inner();
+                let name = SmartString::from(inner.next().unwrap().as_str());
+                ensure!(
+                    fields.iter().all(|(n, _)| *n != name),
+                    DuplicateRecordField(name.to_string(), span)
+                );
+                let typ = parse_nullable_type(inner.next().unwrap())?;
+                fields.push((name, typ));
+            }
+            ColType::Record(fields)
+        }
         _ => unreachable!(),
     })
 }
@@ -16,28 +16,32 @@ use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::program::InputProgram;
-use crate::data::relation::VecElementType;
+use crate::data::relation::{ColumnDef, NullableColType, VecElementType};
 use crate::data::symb::Symbol;
 use crate::data::value::{DataValue, ValidityTs};
 use crate::fts::TokenizerConfig;
-use crate::parse::expr::{build_expr, parse_string};
-use crate::parse::query::parse_query;
-use crate::parse::{ExtractSpan, Pairs, Rule, SourceSpan};
+use crate::parse::expr::{build_expr, build_stored_function, parse_string};
+use crate::parse::query::{parse_query, parse_rule_library};
+use crate::parse::schema::parse_nullable_type;
+use crate::parse::{CustomRegistry, ExtractSpan, Pair, Pairs, Rule, SourceSpan};
 use crate::runtime::relation::AccessLevel;
 use crate::{Expr, FixedRule};
 
 #[derive(Debug)]
 pub(crate) enum SysOp {
     Compact,
+    Analyze(Vec<Symbol>),
     ListColumns(Symbol),
     ListIndices(Symbol),
     ListRelations,
     ListRunning,
     ListFixedRules,
+    ListFunctions,
     KillRunning(u64),
-    Explain(Box<InputProgram>),
+    Explain(Box<InputProgram>, bool),
     RemoveRelation(Vec<Symbol>),
     RenameRelation(Vec<(Symbol, Symbol)>),
+    AlterRelation(Symbol, Vec<AlterOp>),
     ShowTrigger(Symbol),
     SetTriggers(Symbol, Vec<String>, Vec<String>, Vec<String>),
     SetAccessLevel(Vec<Symbol>, AccessLevel),
@@ -45,10 +49,34 @@ pub(crate) enum SysOp {
     CreateVectorIndex(HnswIndexConfig),
     CreateFtsIndex(FtsIndexConfig),
     CreateMinHashLshIndex(MinHashLshConfig),
+    CreateSpatialIndex(SpatialIndexConfig),
     RemoveIndex(Symbol, Symbol),
+    CreateView(Symbol, String),
+    RefreshView(Symbol),
+    RemoveView(Symbol),
+    ListRuleLibraries,
+    DefineRules(Symbol, String),
+    RemoveRules(Symbol),
+    ListStoredFunctions,
+    DefineFunction(Symbol, String),
+    RemoveFunction(Symbol),
+    ShowCypherMapping,
+    SetCypherMapping(Symbol),
     DescribeRelation(Symbol, SmartString<LazyCompact>)
 }
 
+/// A change to the columns of a stored relation made by `::alter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AlterOp {
+    /// adds a non-key column, filled with its default
+    Add(ColumnDef),
+    Drop(Symbol),
+    Rename(Symbol, Symbol),
+    Change(Symbol, NullableColType),
+    /// moves a column to the keys if the flag is set, otherwise to the non-keys
+    Move(Symbol, bool),
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct FtsIndexConfig {
     pub(crate) base_relation: SmartString<LazyCompact>,
@@ -72,6 +100,14 @@ pub(crate) struct MinHashLshConfig {
     pub(crate) target_threshold: OrderedFloat<f64>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct SpatialIndexConfig {
+    pub(crate) base_relation: SmartString<LazyCompact>,
+    pub(crate) index_name: SmartString<LazyCompact>,
+    pub(crate) field: SmartString<LazyCompact>,
+    pub(crate) max_entries: usize,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct HnswIndexConfig {
     pub(crate) base_relation: SmartString<LazyCompact>,
@@ -101,19 +137,69 @@ pub(crate) enum HnswDistance {
 #[diagnostic(code(parser::not_proc_id))]
 struct ProcessIdError(String, #[label] SourceSpan);
 
+fn parse_alter_op(pair: Pair<'_>, registry: &CustomRegistry) -> Result<AlterOp> {
+    let rule_kind = pair.as_rule();
+    let span = pair.extract_span();
+    let mut src = pair.into_inner();
+    let col_p = src.next().unwrap();
+    let col = Symbol::new(col_p.as_str(), col_p.extract_span());
+    Ok(match rule_kind {
+        Rule::alter_add => {
+            let typing = parse_nullable_type(src.next().unwrap())?;
+            let default_gen = match src.next() {
+                None => None,
+                Some(p) => Some(build_expr(p, &Default::default(), registry)?),
+            };
+
+            #[derive(Debug, Diagnostic, Error)]
+            #[error("Column {0} is added without a default, so it must be nullable")]
+            #[diagnostic(code(parser::added_col_without_default))]
+            struct AddedColumnWithoutDefault(String, #[label] SourceSpan);
+
+            ensure!(
+                default_gen.is_some() || typing.nullable,
+                AddedColumnWithoutDefault(col.name.to_string(), span)
+            );
+            AlterOp::Add(ColumnDef {
+                name: col.name,
+                typing,
+                default_gen,
+            })
+        }
+        Rule::alter_drop => AlterOp::Drop(col),
+        Rule::alter_rename => {
+            let new_p = src.next().unwrap();
+            AlterOp::Rename(col, Symbol::new(new_p.as_str(), new_p.extract_span()))
+        }
+        Rule::alter_change => AlterOp::Change(col, parse_nullable_type(src.next().unwrap())?),
+        Rule::alter_move => {
+            AlterOp::Move(col, src.next().unwrap().as_rule() == Rule::alter_to_key)
+        }
+        _ => unreachable!(),
+    })
+}
+
 pub(crate) fn parse_sys(
     mut src: Pairs<'_>,
     param_pool: &BTreeMap<String, DataValue>,
+    registry: &CustomRegistry,
     algorithms: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<SysOp> {
     let inner = src.next().unwrap();
     Ok(match inner.as_rule() {
         Rule::compact_op => SysOp::Compact,
+        Rule::analyze_op => {
+            let rels = inner
+                .into_inner()
+                .map(|rels_p| Symbol::new(rels_p.as_str(), rels_p.extract_span()))
+                .collect_vec();
+            SysOp::Analyze(rels)
+        }
         Rule::running_op => SysOp::ListRunning,
         Rule::kill_op => {
             let i_expr = inner.into_inner().next().unwrap();
-            let i_val = build_expr(i_expr, param_pool)?;
+            let i_val = build_expr(i_expr, param_pool, registry)?;
             let i_val = i_val.eval_to_const()?;
             let i_val = i_val
                 .get_int()
@@ -121,13 +207,20 @@ pub(crate) fn parse_sys(
             SysOp::KillRunning(i_val as u64)
         }
         Rule::explain_op => {
+            let mut inner = inner.into_inner();
+            let mut prog_p = inner.next().unwrap();
+            let analyze = prog_p.as_rule() == Rule::explain_analyze;
+            if analyze {
+                prog_p = inner.next().unwrap();
+            }
             let prog = parse_query(
-                inner.into_inner().next().unwrap().into_inner(),
+                prog_p.into_inner(),
                 param_pool,
+                registry,
                 algorithms,
                 cur_vld,
             )?;
-            SysOp::Explain(Box::new(prog))
+            SysOp::Explain(Box::new(prog), analyze)
         }
         Rule::describe_relation_op => {
             let mut inner = inner.into_inner();
@@ -172,6 +265,13 @@ pub(crate) fn parse_sys(
                 .collect_vec();
             SysOp::RenameRelation(rename_pairs)
         }
+        Rule::alter_op => {
+            let mut src = inner.into_inner();
+            let rel_p = src.next().unwrap();
+            let rel = Symbol::new(rel_p.as_str(), rel_p.extract_span());
+            let ops = src.map(|p| parse_alter_op(p, registry)).try_collect()?;
+            SysOp::AlterRelation(rel, ops)
+        }
         Rule::access_level_op => {
             let mut ps = inner.into_inner();
             let access_level = match ps.next().unwrap().as_str() {
@@ -208,6 +308,7 @@ pub(crate) fn parse_sys(
                 parse_query(
                     script.into_inner(),
                     &Default::default(),
+                    registry,
                     algorithms,
                     cur_vld,
                 )?;
@@ -215,7 +316,7 @@ pub(crate) fn parse_sys(
                     Rule::trigger_put => puts.push(script_str.to_string()),
                     Rule::trigger_rm => rms.push(script_str.to_string()),
                     Rule::trigger_replace => replaces.push(script_str.to_string()),
-                    r => unreachable!("{:?}", r),
+                    _ => unreachable!(),
                 }
             }
             SysOp::SetTriggers(rel, puts, rms, replaces)
@@ -245,7 +346,7 @@ pub(crate) fn parse_sys(
                         let opt_val = opt_inner.next().unwrap();
                         match opt_name.as_str() {
                             "false_positive_weight" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 false_positive_weight = v.get_float().ok_or_else(|| {
@@ -253,7 +354,7 @@ pub(crate) fn parse_sys(
                                 })?;
                             }
                             "false_negative_weight" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 false_negative_weight = v.get_float().ok_or_else(|| {
@@ -261,7 +362,7 @@ pub(crate) fn parse_sys(
                                 })?;
                             }
                             "n_gram" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 n_gram = v
@@ -270,7 +371,7 @@ pub(crate) fn parse_sys(
                                     as usize;
                             }
                             "n_perm" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 n_perm = v
@@ -279,7 +380,7 @@ pub(crate) fn parse_sys(
                                     as usize;
                             }
                             "target_threshold" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 target_threshold = v
@@ -287,17 +388,17 @@ pub(crate) fn parse_sys(
                                     .ok_or_else(|| miette!("target_threshold must be a float"))?;
                             }
                             "extractor" => {
-                                let mut ex = build_expr(opt_val, param_pool)?;
+                                let mut ex = build_expr(opt_val, param_pool, registry)?;
                                 ex.partial_eval()?;
                                 extractor = ex.to_string();
                             }
                             "extract_filter" => {
-                                let mut ex = build_expr(opt_val, param_pool)?;
+                                let mut ex = build_expr(opt_val, param_pool, registry)?;
                                 ex.partial_eval()?;
                                 extract_filter = ex.to_string();
                             }
                             "tokenizer" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 match expr {
                                     Expr::UnboundApply { op, args, .. } => {
@@ -317,7 +418,7 @@ pub(crate) fn parse_sys(
                                 }
                             }
                             "filters" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 match expr {
                                     Expr::Apply { op, args, .. } => {
@@ -398,7 +499,7 @@ pub(crate) fn parse_sys(
                         Symbol::new(name.as_str(), name.extract_span()),
                     )
                 }
-                r => unreachable!("{:?}", r),
+                _ => unreachable!(),
             }
         }
         Rule::fts_idx_op => {
@@ -421,17 +522,17 @@ pub(crate) fn parse_sys(
                         let opt_val = opt_inner.next().unwrap();
                         match opt_name.as_str() {
                             "extractor" => {
-                                let mut ex = build_expr(opt_val, param_pool)?;
+                                let mut ex = build_expr(opt_val, param_pool, registry)?;
                                 ex.partial_eval()?;
                                 extractor = ex.to_string();
                             }
                             "extract_filter" => {
-                                let mut ex = build_expr(opt_val, param_pool)?;
+                                let mut ex = build_expr(opt_val, param_pool, registry)?;
                                 ex.partial_eval()?;
                                 extract_filter = ex.to_string();
                             }
                             "tokenizer" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 match expr {
                                     Expr::UnboundApply { op, args, .. } => {
@@ -451,7 +552,7 @@ pub(crate) fn parse_sys(
                                 }
                             }
                             "filters" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, param_pool, registry)?;
                                 expr.partial_eval()?;
                                 match expr {
                                     Expr::Apply { op, args, .. } => {
@@ -508,7 +609,62 @@ pub(crate) fn parse_sys(
                         Symbol::new(name.as_str(), name.extract_span()),
                     )
                 }
-                r => unreachable!("{:?}", r),
+                _ => unreachable!(),
+            }
+        }
+        Rule::spatial_idx_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::index_create_adv => {
+                    let mut inner = inner.into_inner();
+                    let rel = inner.next().unwrap();
+                    let name = inner.next().unwrap();
+                    let mut field = None;
+                    let mut max_entries = 16;
+                    for opt_pair in inner {
+                        let mut opt_inner = opt_pair.into_inner();
+                        let opt_name = opt_inner.next().unwrap();
+                        let opt_val = opt_inner.next().unwrap();
+                        let opt_val_str = opt_val.as_str();
+                        match opt_name.as_str() {
+                            "field" => {
+                                match build_expr(opt_val, &Default::default(), &Default::default())?
+                                {
+                                    Expr::Binding { var, .. } => field = Some(var.name),
+                                    _ => bail!("Invalid field: {}", opt_val_str),
+                                }
+                            }
+                            "max_entries" => {
+                                let v = build_expr(opt_val, param_pool, registry)?
+                                    .eval_to_const()?
+                                    .get_int()
+                                    .ok_or_else(|| {
+                                        miette!("Invalid max_entries: {}", opt_val_str)
+                                    })?;
+                                ensure!(v >= 4, "max_entries must be at least 4, got {}", v);
+                                max_entries = v as usize;
+                            }
+                            _ => return Err(miette!("Invalid option: {}", opt_name.as_str())),
+                        }
+                    }
+                    let field = field.ok_or_else(|| miette!("field must be set"))?;
+                    SysOp::CreateSpatialIndex(SpatialIndexConfig {
+                        base_relation: SmartString::from(rel.as_str()),
+                        index_name: SmartString::from(name.as_str()),
+                        field,
+                        max_entries,
+                    })
+                }
+                Rule::index_drop => {
+                    let mut inner = inner.into_inner();
+                    let rel = inner.next().unwrap();
+                    let name = inner.next().unwrap();
+                    SysOp::RemoveIndex(
+                        Symbol::new(rel.as_str(), rel.extract_span()),
+                        Symbol::new(name.as_str(), name.extract_span()),
+                    )
+                }
+                _ => unreachable!(),
             }
         }
         Rule::vec_idx_op => {
@@ -536,7 +692,7 @@ pub(crate) fn parse_sys(
                         let opt_val_str = opt_val.as_str();
                         match opt_name.as_str() {
                             "dim" => {
-                                let v = build_expr(opt_val, param_pool)?
+                                let v = build_expr(opt_val, param_pool, registry)?
                                     .eval_to_const()?
                                     .get_int()
                                     .ok_or_else(|| miette!("Invalid vec_dim: {}", opt_val_str))?;
@@ -544,7 +700,7 @@ pub(crate) fn parse_sys(
                                 vec_dim = v as usize;
                             }
                             "ef_construction" | "ef" => {
-                                let v = build_expr(opt_val, param_pool)?
+                                let v = build_expr(opt_val, param_pool, registry)?
                                     .eval_to_const()?
                                     .get_int()
                                     .ok_or_else(|| {
@@ -554,7 +710,7 @@ pub(crate) fn parse_sys(
                                 ef_construction = v as usize;
                             }
                             "m_neighbours" | "m" => {
-                                let v = build_expr(opt_val, param_pool)?
+                                let v = build_expr(opt_val, param_pool, registry)?
                                     .eval_to_const()?
                                     .get_int()
                                     .ok_or_else(|| {
@@ -573,7 +729,8 @@ pub(crate) fn parse_sys(
                                 }
                             }
                             "fields" => {
-                                let fields = build_expr(opt_val, &Default::default())?;
+                                let fields =
+                                    build_expr(opt_val, &Default::default(), &Default::default())?;
                                 vec_fields = fields.to_var_list()?;
                             }
                             "distance" | "dist" => {
@@ -630,7 +787,7 @@ pub(crate) fn parse_sys(
                         Symbol::new(name.as_str(), name.extract_span()),
                     )
                 }
-                r => unreachable!("{:?}", r),
+                _ => unreachable!(),
             }
         }
         Rule::index_op => {
@@ -669,7 +826,72 @@ pub(crate) fn parse_sys(
                 _ => unreachable!(),
             }
         }
+        Rule::view_op => {
+            let inner = inner.into_inner().next().unwrap();
+            let rule_kind = inner.as_rule();
+            let mut inner = inner.into_inner();
+            let name_p = inner.next().unwrap();
+            let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+            match rule_kind {
+                Rule::view_create => {
+                    let script = inner.next().unwrap();
+                    let script_str = script.as_str();
+                    parse_query(
+                        script.into_inner(),
+                        &Default::default(),
+                        registry,
+                        algorithms,
+                        cur_vld,
+                    )?;
+                    SysOp::CreateView(name, script_str.to_string())
+                }
+                Rule::view_refresh => SysOp::RefreshView(name),
+                Rule::view_drop => SysOp::RemoveView(name),
+                _ => unreachable!(),
+            }
+        }
+        Rule::rules_op => match inner.into_inner().next() {
+            None => SysOp::ListRuleLibraries,
+            Some(inner) => {
+                let rule_kind = inner.as_rule();
+                let mut inner = inner.into_inner();
+                let name_p = inner.next().unwrap();
+                let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+                match rule_kind {
+                    Rule::rules_define => {
+                        let body = inner.next().unwrap().as_str();
+                        parse_rule_library(body, registry, cur_vld)?;
+                        SysOp::DefineRules(name, body.to_string())
+                    }
+                    Rule::rules_drop => SysOp::RemoveRules(name),
+                    _ => unreachable!(),
+                }
+            }
+        },
+        Rule::fn_op => match inner.into_inner().next() {
+            None => SysOp::ListStoredFunctions,
+            Some(inner) => match inner.as_rule() {
+                Rule::fn_create => {
+                    let definition = inner.into_inner().next().unwrap();
+                    let src = definition.as_str().to_string();
+                    let func = build_stored_function(definition, registry)?;
+                    SysOp::DefineFunction(func.name, src)
+                }
+                Rule::fn_drop => {
+                    let name_p = inner.into_inner().next().unwrap();
+                    SysOp::RemoveFunction(Symbol::new(name_p.as_str(), name_p.extract_span()))
+                }
+                _ => unreachable!(),
+            },
+        },
+        Rule::cypher_mapping_op => match inner.into_inner().next() {
+            None => SysOp::ShowCypherMapping,
+            Some(name_p) => {
+                SysOp::SetCypherMapping(Symbol::new(name_p.as_str(), name_p.extract_span()))
+            }
+        },
         Rule::list_fixed_rules => SysOp::ListFixedRules,
-        r => unreachable!("{:?}", r),
+        Rule::list_functions => SysOp::ListFunctions,
+        _ => unreachable!(),
     })
 }
@@ -14,15 +14,17 @@ use thiserror::Error;
 
 use crate::data::aggr::Aggregation;
 use crate::data::expr::Expr;
+use crate::data::functions::{OP_EQ, OP_GET, OP_MAYBE_GET};
 use crate::data::program::{
     MagicAtom, MagicFixedRuleApply, MagicInlineRule, MagicRulesOrFixed, MagicSymbol,
     StratifiedMagicProgram,
 };
+use crate::data::relation::ColType;
 use crate::data::symb::Symbol;
-use crate::data::value::DataValue;
+use crate::data::value::{DataValue, Num};
 use crate::parse::SourceSpan;
 use crate::query::ra::RelAlgebra;
-use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel};
+use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel, RelationHandle};
 use crate::runtime::transact::SessionTx;
 
 pub(crate) type CompiledProgram = BTreeMap<MagicSymbol, CompiledRuleSet>;
@@ -38,6 +40,7 @@ pub(crate) enum AggrKind {
     None,
     Normal,
     Meet,
+    Window,
 }
 
 impl CompiledRuleSet {
@@ -50,6 +53,14 @@ impl CompiledRuleSet {
     pub(crate) fn aggr_kind(&self) -> AggrKind {
         match self {
             CompiledRuleSet::Rules(rules) => {
+                if rules[0]
+                    .aggr
+                    .iter()
+                    .flatten()
+                    .any(|(aggr, _)| aggr.is_window())
+                {
+                    return AggrKind::Window;
+                }
                 let mut has_non_meet = false;
                 let mut has_aggr = false;
                 for maybe_aggr in rules[0].aggr.iter() {
@@ -77,6 +88,24 @@ impl CompiledRuleSet {
     }
 }
 
+/// The bindings produced by the body of a rule: those in the head, followed by the sort keys of
+/// window aggregations that are not in the head. The latter are dropped by the evaluation.
+fn window_header(rule: &MagicInlineRule) -> Vec<Symbol> {
+    let mut header = rule.head.clone();
+    for (symb, aggr) in rule.head.iter().zip(rule.aggr.iter()) {
+        let (aggr, args) = match aggr {
+            Some((aggr, args)) if aggr.is_window() => (aggr, args),
+            _ => continue,
+        };
+        for (name, _) in aggr.window_sort_keys(args) {
+            if !header.iter().any(|h| h.name.as_str() == name) {
+                header.push(Symbol::new(name, symb.span));
+            }
+        }
+    }
+    header
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub(crate) enum ContainedRuleMultiplicity {
     One,
@@ -108,6 +137,122 @@ pub(crate) enum IndexPositionUse {
     Ignored,
 }
 
+/// An index on a field of a record column, usable for a filter such as `addr->'zip' == 12345`
+struct FieldLookup {
+    index: RelationHandle,
+    /// the positions of the columns of the index in the relation
+    mapper: Vec<usize>,
+    /// the value of the field to look up
+    value: Expr,
+}
+
+/// Whether the rows equal to `value` by `==` are exactly those with the value as the key.
+/// Numbers of different kinds are equal by value, so they are only looked up as constants
+/// of the kind stored in the column.
+fn is_exact_lookup(coltype: &ColType, value: &Expr) -> bool {
+    match coltype {
+        ColType::Int => matches!(
+            value.get_const(),
+            Some(DataValue::Null | DataValue::Num(Num::Int(_)))
+        ),
+        // zero is equal to negative zero
+        ColType::Float => match value.get_const() {
+            Some(DataValue::Null) => true,
+            Some(DataValue::Num(Num::Float(f))) => *f != 0.,
+            _ => false,
+        },
+        ColType::Decimal => matches!(
+            value.get_const(),
+            Some(DataValue::Null | DataValue::Decimal(_))
+        ),
+        ColType::Any => false,
+        _ => true,
+    }
+}
+
+/// Finds among the filters of `rest` an equality between a field of a record column newly
+/// bound by `args` and a value computed from `bound` variables, for which `store` has an
+/// index leading with the field.
+fn find_field_lookup(
+    store: &RelationHandle,
+    args: &[Symbol],
+    rest: &[MagicAtom],
+    bound: &BTreeSet<Symbol>,
+) -> Option<FieldLookup> {
+    // the column and the path of fields of `addr->'zip'`, or `addr->'a'->'b'`
+    fn field_path(expr: &Expr) -> Option<(&Symbol, Vec<&str>)> {
+        match expr {
+            Expr::Apply { op, args, .. } if **op == OP_MAYBE_GET || **op == OP_GET => {
+                let field = match args[1].get_const()? {
+                    DataValue::Str(s) => s.as_str(),
+                    _ => return None,
+                };
+                let (var, mut path) = match &args[0] {
+                    Expr::Binding { var, .. } => (var, vec![]),
+                    inner => field_path(inner)?,
+                };
+                path.push(field);
+                Some((var, path))
+            }
+            _ => None,
+        }
+    }
+
+    if store
+        .indices
+        .values()
+        .all(|(idx, _)| !idx.metadata.keys[0].name.contains('.'))
+    {
+        return None;
+    }
+    let cols = store
+        .metadata
+        .keys
+        .iter()
+        .chain(store.metadata.non_keys.iter())
+        .collect_vec();
+    for atom in rest {
+        let filter = match atom {
+            MagicAtom::Predicate(p) => p,
+            _ => continue,
+        };
+        for conj in filter.to_conjunction() {
+            let sides = match &conj {
+                Expr::Apply { op, args, .. } if **op == OP_EQ => {
+                    [(&args[0], &args[1]), (&args[1], &args[0])]
+                }
+                _ => continue,
+            };
+            for (field, value) in sides {
+                let (var, path) = match field_path(field) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                let pos = match args.iter().position(|arg| arg == var) {
+                    Some(pos) if !bound.contains(var) => pos,
+                    _ => continue,
+                };
+                match value.bindings() {
+                    Ok(vars) if vars.is_subset(bound) => {}
+                    _ => continue,
+                }
+                let name = format!("{}.{}", cols[pos].name, path.join("."));
+                for (index, mapper) in store.indices.values() {
+                    let col = &index.metadata.keys[0];
+                    if col.name == name && is_exact_lookup(&col.typing.coltype, value) {
+                        return Some(FieldLookup {
+                            index: index.clone(),
+                            mapper: mapper.clone(),
+                            value: value.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
 impl<'a> SessionTx<'a> {
     pub(crate) fn stratified_magic_compile(
         &mut self,
@@ -134,9 +279,9 @@ impl<'a> SessionTx<'a> {
                             MagicRulesOrFixed::Rules { rules: body } => {
                                 let mut collected = Vec::with_capacity(body.len());
                                 for rule in body.iter() {
-                                    let header = &rule.head;
+                                    let header = window_header(rule);
                                     let mut relation =
-                                        self.compile_magic_rule_body(rule, &k, &store_arities, header)?;
+                                        self.compile_magic_rule_body(rule, &k, &store_arities, &header)?;
                                     relation.fill_binding_indices_and_compile().with_context(|| {
                                         format!(
                                             "error encountered when filling binding indices for {relation:#?}"
@@ -170,13 +315,16 @@ impl<'a> SessionTx<'a> {
     ) -> Result<RelAlgebra> {
         let mut ret = RelAlgebra::unit(rule_name.symbol().span);
         let mut seen_variables = BTreeSet::new();
+        // estimated number of rows produced by `ret`, known only while
+        // every application so far is of an analyzed relation
+        let mut est_rows = Some(1.0f64);
         let mut serial_id = 0;
         let mut gen_symb = |span| {
             let ret = Symbol::new(&format!("**{serial_id}") as &str, span);
             serial_id += 1;
             ret
         };
-        for atom in &rule.body {
+        for (atom_idx, atom) in rule.body.iter().enumerate() {
             match atom {
                 MagicAtom::Rule(rule_app) => {
                     let store_arity = store_arities.get(&rule_app.name).ok_or_else(|| {
@@ -215,6 +363,7 @@ impl<'a> SessionTx<'a> {
                         RelAlgebra::derived(right_vars, rule_app.name.clone(), rule_app.span);
                     debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                     ret = ret.join(right, prev_joiner_vars, right_joiner_vars, rule_app.span);
+                    est_rows = None;
                 }
                 MagicAtom::Relation(rel_app) => {
                     let store = self.get_relation(&rel_app.name, false)?;
@@ -246,6 +395,7 @@ impl<'a> SessionTx<'a> {
                     let mut right_vars = vec![];
                     // used for choosing indices
                     let mut join_indices = vec![];
+                    let bound_before = seen_variables.clone();
 
                     for (i, var) in rel_app.args.iter().enumerate() {
                         if seen_variables.contains(var) {
@@ -269,9 +419,93 @@ impl<'a> SessionTx<'a> {
 
                     let chosen_index =
                         store.choose_index(&join_indices, rel_app.valid_at.is_some());
+                    // without bound keys, a filter on a field of a record column may still
+                    // narrow the rows down through an index on the field
+                    let field_lookup = match (&chosen_index, join_indices.first()) {
+                        (
+                            None,
+                            Some(IndexPositionUse::BindForLater | IndexPositionUse::Ignored),
+                        ) if rel_app.valid_at.is_none() => find_field_lookup(
+                            &store,
+                            &rel_app.args,
+                            &rule.body[atom_idx + 1..],
+                            &bound_before,
+                        ),
+                        _ => None,
+                    };
+
+                    let left_est_rows = est_rows;
+                    est_rows = match (est_rows, &store.stats) {
+                        (Some(left), Some(stats)) => {
+                            let bound = join_indices
+                                .iter()
+                                .map(|u| *u == IndexPositionUse::Join)
+                                .collect_vec();
+                            Some(left * stats.estimate_rows(&bound))
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(lookup) = field_lookup {
+                        // the value of the field is looked up in the index, and the rows of the
+                        // relation by the keys found there. The filter itself is kept.
+                        let value_var = gen_symb(rel_app.span);
+                        ret = ret.unify(value_var.clone(), lookup.value, false, rel_app.span);
+                        let index_vars = (0..lookup.index.arity())
+                            .map(|_| gen_symb(rel_app.span))
+                            .collect_vec();
+                        let mut left_keys = vec![];
+                        let mut right_keys = vec![];
+                        for ((index_var, &orig_idx), col) in index_vars
+                            .iter()
+                            .zip(lookup.mapper.iter())
+                            .zip(lookup.index.metadata.keys.iter())
+                        {
+                            if orig_idx < store.metadata.keys.len() && !col.name.contains('.') {
+                                left_keys.push(index_var.clone());
+                                right_keys.push(right_vars[orig_idx].clone());
+                            }
+                        }
+                        let index = RelAlgebra::relation(
+                            index_vars.clone(),
+                            lookup.index,
+                            rel_app.span,
+                            None,
+                        )?;
+                        ret = ret.join(
+                            index,
+                            vec![value_var],
+                            vec![index_vars[0].clone()],
+                            rel_app.span,
+                        );
+                        let relation = RelAlgebra::relation(right_vars, store, rel_app.span, None)?;
+                        ret = ret.join(relation, left_keys, right_keys, rel_app.span);
+                        for (left, right) in prev_joiner_vars.into_iter().zip(right_joiner_vars) {
+                            ret = ret.filter(Expr::build_equate(
+                                vec![
+                                    Expr::Binding {
+                                        var: left,
+                                        tuple_pos: None,
+                                    },
+                                    Expr::Binding {
+                                        var: right,
+                                        tuple_pos: None,
+                                    },
+                                ],
+                                rel_app.span,
+                            ))?;
+                        }
+                        continue;
+                    }
 
                     match chosen_index {
                         None => {
+                            // a single scan beats one lookup per left row
+                            // if there are more left rows than right rows
+                            let materialize = match (left_est_rows, &store.stats) {
+                                (Some(left), Some(stats)) => left > stats.rows as f64,
+                                _ => false,
+                            };
                             // scan original relation
                             let right = RelAlgebra::relation(
                                 right_vars,
@@ -282,9 +516,45 @@ impl<'a> SessionTx<'a> {
                             debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                             ret =
                                 ret.join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
+                            if materialize {
+                                ret = ret.prefer_materialized();
+                            }
                         }
                         Some((chosen_index, mapper, false)) => {
                             // index-only
+                            // only the bound prefix of the index is joined on, so that the index
+                            // is scanned by prefix, the other bound columns are filtered afterwards
+                            let prefix_len = mapper
+                                .iter()
+                                .take_while(|i| right_joiner_vars_pos_rev[**i].is_some())
+                                .count();
+                            let mut left_keys = vec![];
+                            let mut right_keys = vec![];
+                            let mut post_filters = vec![];
+                            for (pos, &orig_idx) in mapper.iter().enumerate() {
+                                if let Some(join_idx) = right_joiner_vars_pos_rev[orig_idx] {
+                                    let left = prev_joiner_vars[join_idx].clone();
+                                    let right = right_joiner_vars[join_idx].clone();
+                                    if pos < prefix_len {
+                                        left_keys.push(left);
+                                        right_keys.push(right);
+                                    } else {
+                                        post_filters.push(Expr::build_equate(
+                                            vec![
+                                                Expr::Binding {
+                                                    var: left,
+                                                    tuple_pos: None,
+                                                },
+                                                Expr::Binding {
+                                                    var: right,
+                                                    tuple_pos: None,
+                                                },
+                                            ],
+                                            rel_app.span,
+                                        ));
+                                    }
+                                }
+                            }
                             let new_right_vars = mapper
                                 .into_iter()
                                 .map(|i| right_vars[i].clone())
@@ -295,9 +565,10 @@ impl<'a> SessionTx<'a> {
                                 rel_app.span,
                                 rel_app.valid_at,
                             )?;
-                            debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
-                            ret =
-                                ret.join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
+                            ret = ret.join(right, left_keys, right_keys, rel_app.span);
+                            for filter in post_filters {
+                                ret = ret.filter(filter)?;
+                            }
                         }
                         Some((chosen_index, mapper, true)) => {
                             // index-with-join
@@ -307,11 +578,18 @@ impl<'a> SessionTx<'a> {
                             {
                                 let mut left_keys = vec![];
                                 let mut right_keys = vec![];
+                                // Only the bound prefix of the index is joined on, the other
+                                // bound columns are checked after the lookup into the relation
+                                let mut in_prefix = true;
                                 for &orig_idx in mapper.iter() {
                                     // Create a new symbol for the column in the index relation
                                     let tv = gen_symb(right_vars[orig_idx].span);
                                     // Check for the existance of this column among the joiner columns
-                                    if let Some(join_idx) = right_joiner_vars_pos_rev[orig_idx] {
+                                    in_prefix =
+                                        in_prefix && right_joiner_vars_pos_rev[orig_idx].is_some();
+                                    if let (true, Some(join_idx)) =
+                                        (in_prefix, right_joiner_vars_pos_rev[orig_idx])
+                                    {
                                         // Mark the field as bound, since it is used in the join
                                         not_bound[join_idx] = false;
                                         // Push the joiner symbol to the left side
@@ -374,6 +652,49 @@ impl<'a> SessionTx<'a> {
                         }
                     }
                 }
+                MagicAtom::OptionalRelation(rel_app) => {
+                    let store = self.get_relation(&rel_app.name, false)?;
+                    if store.access_level < AccessLevel::ReadOnly {
+                        bail!(InsufficientAccessLevel(
+                            store.name.to_string(),
+                            "reading rows".to_string(),
+                            store.access_level
+                        ));
+                    }
+                    ensure!(
+                        store.arity() == rel_app.args.len(),
+                        ArityMismatch(
+                            rel_app.name.to_string(),
+                            store.arity(),
+                            rel_app.args.len(),
+                            rel_app.span
+                        )
+                    );
+
+                    // the variables bound before are the keys of the outer join,
+                    // the others are bound to null for left rows without a match
+                    let mut prev_joiner_vars = vec![];
+                    let mut right_joiner_vars = vec![];
+                    let mut right_vars = vec![];
+
+                    for var in &rel_app.args {
+                        if seen_variables.contains(var) {
+                            prev_joiner_vars.push(var.clone());
+                            let rk = gen_symb(var.span);
+                            right_vars.push(rk.clone());
+                            right_joiner_vars.push(rk);
+                        } else {
+                            seen_variables.insert(var.clone());
+                            right_vars.push(var.clone());
+                        }
+                    }
+
+                    let right =
+                        RelAlgebra::relation(right_vars, store, rel_app.span, rel_app.valid_at)?;
+                    debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
+                    ret = ret.left_join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
+                    est_rows = None;
+                }
                 MagicAtom::NegatedRule(rule_app) => {
                     let store_arity = store_arities.get(&rule_app.name).ok_or_else(|| {
                         RuleNotFound(
@@ -498,6 +819,7 @@ impl<'a> SessionTx<'a> {
                     ret = ret.filter(p.clone())?;
                 }
                 MagicAtom::HnswSearch(s) => {
+                    est_rows = None;
                     debug_assert!(
                         seen_variables.contains(&s.query),
                         "HNSW search query must be bound"
@@ -532,6 +854,7 @@ impl<'a> SessionTx<'a> {
                     }
                 }
                 MagicAtom::FtsSearch(s) => {
+                    est_rows = None;
                     debug_assert!(
                         seen_variables.contains(&s.query),
                         "FTS search query must be bound"
@@ -566,6 +889,7 @@ impl<'a> SessionTx<'a> {
                     }
                 }
                 MagicAtom::LshSearch(s) => {
+                    est_rows = None;
                     debug_assert!(
                         seen_variables.contains(&s.query),
                         "FTS search query must be bound"
@@ -599,6 +923,41 @@ impl<'a> SessionTx<'a> {
                         ret = ret.filter(Expr::build_and(post_filters, s.span))?;
                     }
                 }
+                MagicAtom::SpatialSearch(s) => {
+                    est_rows = None;
+                    debug_assert!(
+                        s.input_bindings().all(|b| seen_variables.contains(b)),
+                        "spatial search query must be bound"
+                    );
+                    let mut own_bindings = vec![];
+                    let mut post_filters = vec![];
+                    for var in s.all_bindings() {
+                        if seen_variables.contains(var) {
+                            let rk = gen_symb(var.span);
+                            post_filters.push(Expr::build_equate(
+                                vec![
+                                    Expr::Binding {
+                                        var: var.clone(),
+                                        tuple_pos: None,
+                                    },
+                                    Expr::Binding {
+                                        var: rk.clone(),
+                                        tuple_pos: None,
+                                    },
+                                ],
+                                var.span,
+                            ));
+                            own_bindings.push(rk);
+                        } else {
+                            seen_variables.insert(var.clone());
+                            own_bindings.push(var.clone());
+                        }
+                    }
+                    ret = ret.spatial_search(s.clone(), own_bindings)?;
+                    if !post_filters.is_empty() {
+                        ret = ret.filter(Expr::build_and(post_filters, s.span))?;
+                    }
+                }
                 MagicAtom::Unification(u) => {
                     if seen_variables.contains(&u.binding) {
                         let expr = if u.one_many_unif {
@@ -6,17 +6,21 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::cmp::Ordering as CmpOrdering;
 use std::collections::btree_map::Entry;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+use std::mem;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use itertools::Itertools;
 use log::{debug, trace};
-use miette::Result;
+use miette::{bail, Diagnostic, Result};
 #[cfg(not(target_arch = "wasm32"))]
 use rayon::prelude::*;
+use thiserror::Error;
 
-use crate::data::aggr::Aggregation;
+use crate::data::aggr::{window_eval, Aggregation};
 use crate::data::program::{MagicSymbol, NoEntryError};
 use crate::data::symb::{Symbol, PROG_ENTRY};
 use crate::data::tuple::Tuple;
@@ -26,14 +30,34 @@ use crate::parse::SourceSpan;
 use crate::query::compile::{
     AggrKind, CompiledProgram, CompiledRule, CompiledRuleSet, ContainedRuleMultiplicity,
 };
-use crate::runtime::db::Poison;
+use crate::runtime::db::{Poison, QueryLimits};
+use crate::runtime::spill::{tuple_size, ExternalSorter, MemoryReservation};
 use crate::runtime::temp_store::{EpochStore, MeetAggrStore, RegularTempStore};
 use crate::runtime::transact::SessionTx;
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("The query derived more than {0} rows")]
+#[diagnostic(code(eval::max_rows_exceeded))]
+#[diagnostic(help(
+    "Rows of intermediate results count towards the limit, which is set with `:max_rows`"
+))]
+struct MaxRowsExceeded(usize);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Evaluation of the query did not converge within {0} epochs")]
+#[diagnostic(code(eval::max_epochs_exceeded))]
+#[diagnostic(help(
+    "A recursive rule may derive new rows forever. The limit is set with `:max_epochs`"
+))]
+struct MaxEpochsExceeded(usize);
+
 pub(crate) struct QueryLimiter {
     total: Option<usize>,
     skip: Option<usize>,
     counter: AtomicUsize,
+    max_rows: Option<usize>,
+    max_epochs: Option<usize>,
+    rows_derived: AtomicUsize,
 }
 
 impl QueryLimiter {
@@ -59,6 +83,33 @@ impl QueryLimiter {
             Some(i) => i > self.counter.load(Ordering::Relaxed),
         }
     }
+    /// Called before a row is put into the store of a rule, with a test of whether
+    /// the store holds the row already.
+    fn count_row(&self, is_new: impl FnOnce() -> bool) -> Result<()> {
+        if self.max_rows.is_some() && is_new() {
+            self.count_rows(1)
+        } else {
+            Ok(())
+        }
+    }
+    /// Fails as soon as more rows than allowed are derived.
+    fn count_rows(&self, n: usize) -> Result<()> {
+        if let Some(max) = self.max_rows {
+            if self.rows_derived.fetch_add(n, Ordering::Relaxed) + n > max {
+                bail!(MaxRowsExceeded(max))
+            }
+        }
+        Ok(())
+    }
+    /// Called after an epoch that derived new rows.
+    fn check_epoch(&self, epoch: u32) -> Result<()> {
+        if let Some(max) = self.max_epochs {
+            if epoch as usize >= max {
+                bail!(MaxEpochsExceeded(max))
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a> SessionTx<'a> {
@@ -68,10 +119,14 @@ impl<'a> SessionTx<'a> {
         store_lifetimes: BTreeMap<MagicSymbol, usize>,
         total_num_to_take: Option<usize>,
         num_to_skip: Option<usize>,
+        limits: &QueryLimits,
+        bag: bool,
+        extra_outputs: &BTreeSet<Symbol>,
         poison: Poison,
-    ) -> Result<(EpochStore, bool)> {
+    ) -> Result<(EpochStore, BTreeMap<Symbol, EpochStore>, bool)> {
         let mut stores: BTreeMap<MagicSymbol, EpochStore> = BTreeMap::new();
         let mut early_return = false;
+        let mut rows_derived = 0;
         for (stratum, cur_prog) in strata.iter().enumerate() {
             if stratum > 0 {
                 // remove stores that have outlived their usefulness!
@@ -83,7 +138,9 @@ impl<'a> SessionTx<'a> {
             }
             for (rule_name, rule_set) in cur_prog {
                 let store = match rule_set.aggr_kind() {
-                    AggrKind::None | AggrKind::Normal => EpochStore::new_normal(rule_set.arity()),
+                    AggrKind::None | AggrKind::Normal | AggrKind::Window => {
+                        EpochStore::new_normal(rule_set.arity(), &self.memory)
+                    }
                     AggrKind::Meet => {
                         let rs = match rule_set {
                             CompiledRuleSet::Rules(rs) => rs,
@@ -100,6 +157,9 @@ impl<'a> SessionTx<'a> {
                 &mut stores,
                 total_num_to_take,
                 num_to_skip,
+                limits,
+                bag,
+                &mut rows_derived,
                 poison.clone(),
             )?;
         }
@@ -107,7 +167,16 @@ impl<'a> SessionTx<'a> {
             inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
         };
         let ret_area = stores.remove(&entry_symbol).ok_or(NoEntryError)?;
-        Ok((ret_area, early_return))
+        let mut extra_areas = BTreeMap::new();
+        for output in extra_outputs {
+            let symbol = MagicSymbol::Muggle {
+                inner: output.clone(),
+            };
+            if let Some(store) = stores.remove(&symbol) {
+                extra_areas.insert(output.clone(), store);
+            }
+        }
+        Ok((ret_area, extra_areas, early_return))
     }
     /// returns true if early return is activated
     fn semi_naive_magic_evaluate(
@@ -116,18 +185,27 @@ impl<'a> SessionTx<'a> {
         stores: &mut BTreeMap<MagicSymbol, EpochStore>,
         total_num_to_take: Option<usize>,
         num_to_skip: Option<usize>,
+        limits: &QueryLimits,
+        bag: bool,
+        rows_derived: &mut usize,
         poison: Poison,
     ) -> Result<bool> {
         let limiter = QueryLimiter {
             total: total_num_to_take,
             skip: num_to_skip,
             counter: 0.into(),
+            max_rows: limits.max_rows,
+            max_epochs: limits.max_epochs,
+            rows_derived: (*rows_derived).into(),
         };
 
         let used_limiter: AtomicBool = false.into();
 
         for epoch in 0u32.. {
             debug!("epoch {}", epoch);
+            if let Some(profiler) = &self.profiler {
+                profiler.set_epoch(epoch);
+            }
             let mut to_merge = BTreeMap::new();
             let borrowed_stores = stores as &BTreeMap<_, _>;
             if epoch == 0 {
@@ -135,6 +213,15 @@ impl<'a> SessionTx<'a> {
                 let execution = |(k, compiled_ruleset): (_, &CompiledRuleSet)| -> Result<_> {
                     let new_store = match compiled_ruleset {
                         CompiledRuleSet::Rules(ruleset) => match compiled_ruleset.aggr_kind() {
+                            AggrKind::None if bag => self
+                                .initial_rule_bag_eval(
+                                    k,
+                                    &ruleset,
+                                    borrowed_stores,
+                                    &limiter,
+                                    poison.clone(),
+                                )?
+                                .wrap(),
                             AggrKind::None => {
                                 let res = self.initial_rule_non_aggr_eval(
                                     k,
@@ -157,11 +244,23 @@ impl<'a> SessionTx<'a> {
                                 used_limiter.fetch_or(res.0, Ordering::Relaxed);
                                 res.1.wrap()
                             }
+                            AggrKind::Window => {
+                                let res = self.initial_rule_window_eval(
+                                    k,
+                                    &ruleset,
+                                    borrowed_stores,
+                                    &limiter,
+                                    poison.clone(),
+                                )?;
+                                used_limiter.fetch_or(res.0, Ordering::Relaxed);
+                                res.1.wrap()
+                            }
                             AggrKind::Meet => {
                                 let new = self.initial_rule_meet_eval(
                                     k,
                                     &ruleset,
                                     borrowed_stores,
+                                    &limiter,
                                     poison.clone(),
                                 )?;
                                 new.wrap()
@@ -169,13 +268,14 @@ impl<'a> SessionTx<'a> {
                         },
                         CompiledRuleSet::Fixed(fixed) => {
                             let fixed_impl = fixed.fixed_impl.as_ref();
-                            let mut out = RegularTempStore::default();
+                            let mut out = RegularTempStore::with_budget(&self.memory);
                             let payload = FixedRulePayload {
                                 manifest: &fixed,
                                 stores: borrowed_stores,
                                 tx: self,
                             };
                             fixed_impl.run(payload, &mut out, poison.clone())?;
+                            limiter.count_rows(out.size_hint())?;
                             out.wrap()
                         }
                     };
@@ -220,6 +320,10 @@ impl<'a> SessionTx<'a> {
                     let new_store = match compiled_ruleset {
                         CompiledRuleSet::Rules(ruleset) => {
                             match compiled_ruleset.aggr_kind() {
+                                AggrKind::None if bag => {
+                                    // rules with bag semantics are complete after the first epoch
+                                    RegularTempStore::default().wrap()
+                                }
                                 AggrKind::None => {
                                     let res = self.incremental_rule_non_aggr_eval(
                                         k,
@@ -237,11 +341,12 @@ impl<'a> SessionTx<'a> {
                                         k,
                                         &ruleset,
                                         borrowed_stores,
+                                        &limiter,
                                         poison.clone(),
                                     )?;
                                     new.wrap()
                                 }
-                                AggrKind::Normal => {
+                                AggrKind::Normal | AggrKind::Window => {
                                     // not doing anything
                                     RegularTempStore::default().wrap()
                                 }
@@ -293,12 +398,17 @@ impl<'a> SessionTx<'a> {
                 let old_store = stores.get_mut(k).unwrap();
                 old_store.merge_in(new_store)?;
                 trace!("delta for {}: {}", k, old_store.has_delta());
-                changed |= old_store.has_delta();
+                if old_store.has_delta() {
+                    changed = true;
+                }
             }
+            self.memory.check()?;
             if !changed {
                 break;
             }
+            limiter.check_epoch(epoch)?;
         }
+        *rows_derived = limiter.rows_derived.load(Ordering::Relaxed);
         Ok(used_limiter.load(Ordering::Acquire))
     }
     /// returns true is early return is activated
@@ -310,7 +420,7 @@ impl<'a> SessionTx<'a> {
         limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_budget(&self.memory);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
 
         for (rule_n, rule) in ruleset.iter().enumerate() {
@@ -320,6 +430,7 @@ impl<'a> SessionTx<'a> {
                 trace!("item for {:?}.{}: {:?} at {}", rule_symb, rule_n, item, 0);
                 if should_check_limit {
                     if !out_store.exists(&item) {
+                        limiter.count_row(|| true)?;
                         if limiter.should_skip_next() {
                             out_store.put_with_skip(item);
                         } else {
@@ -331,19 +442,45 @@ impl<'a> SessionTx<'a> {
                         }
                     }
                 } else {
+                    limiter.count_row(|| !out_store.exists(&item))?;
                     out_store.put(item);
                 }
             }
             poison.check()?;
+            self.memory.check()?;
         }
 
         Ok((should_check_limit, out_store))
     }
+    /// Every derivation of a tuple is counted, so that the store holds a multiset.
+    fn initial_rule_bag_eval(
+        &self,
+        rule_symb: &MagicSymbol,
+        ruleset: &[CompiledRule],
+        stores: &BTreeMap<MagicSymbol, EpochStore>,
+        limiter: &QueryLimiter,
+        poison: Poison,
+    ) -> Result<RegularTempStore> {
+        let mut out_store = RegularTempStore::bag_with_budget(&self.memory);
+        for (rule_n, rule) in ruleset.iter().enumerate() {
+            debug!("calculation for bag rule {:?}.{}", rule_symb, rule_n);
+            for item_res in rule.relation.iter(self, None, stores)? {
+                let item = item_res?;
+                trace!("item for {:?}.{}: {:?}", rule_symb, rule_n, item);
+                limiter.count_row(|| !out_store.exists(&item))?;
+                out_store.put_counted(item);
+            }
+            poison.check()?;
+            self.memory.check()?;
+        }
+        Ok(out_store)
+    }
     fn initial_rule_meet_eval(
         &self,
         rule_symb: &MagicSymbol,
         ruleset: &[CompiledRule],
         stores: &BTreeMap<MagicSymbol, EpochStore>,
+        limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<MeetAggrStore> {
         let mut out_store = MeetAggrStore::new(ruleset[0].aggr.clone())?;
@@ -357,9 +494,11 @@ impl<'a> SessionTx<'a> {
             for item_res in rule.relation.iter(self, None, stores)? {
                 let item = item_res?;
                 trace!("item for {:?}.{}: {:?} at {}", rule_symb, rule_n, item, 0);
+                limiter.count_row(|| !out_store.exists(&item))?;
                 out_store.meet_put(item)?;
             }
             poison.check()?;
+            self.memory.check()?;
         }
         if out_store.is_empty() && ruleset[0].aggr.iter().all(|a| a.is_some()) {
             let mut aggr = ruleset[0].aggr.clone();
@@ -386,9 +525,19 @@ impl<'a> SessionTx<'a> {
         limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_budget(&self.memory);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
         let mut aggr_work: BTreeMap<Vec<DataValue>, Vec<Aggregation>> = BTreeMap::new();
+        // Once the memory budget is exceeded, no more groups are started in memory.
+        // Tuples of other groups are then sorted by group, together with a sequence number
+        // keeping their order within the group, and aggregated at the end.
+        let mut aggr_reservation = MemoryReservation::new(&self.memory);
+        let aggrs_size = ruleset[0].aggr.len() * mem::size_of::<Aggregation>();
+        let n_keys = ruleset[0].aggr.iter().filter(|a| a.is_none()).count();
+        let by_group = move |a: &Tuple, b: &Tuple| a[..=n_keys].cmp(&b[..=n_keys]);
+        let mut spilled = ExternalSorter::new(&self.memory, by_group);
+        let mut spilling = false;
+        let mut n_spilled = 0i64;
 
         for (rule_n, rule) in ruleset.iter().enumerate() {
             debug!(
@@ -432,6 +581,16 @@ impl<'a> SessionTx<'a> {
                         }
                     }
                     Entry::Vacant(ent) => {
+                        if spilling {
+                            let mut to_spill = ent.into_key();
+                            to_spill.push(DataValue::from(n_spilled));
+                            n_spilled += 1;
+                            to_spill.extend(
+                                val_indices_and_aggrs.iter().map(|(i, _)| item[*i].clone()),
+                            );
+                            spilled.push(to_spill)?;
+                            continue;
+                        }
                         let mut aggr_ops = Vec::with_capacity(val_indices_and_aggrs.len());
                         for (i, (aggr, params)) in &val_indices_and_aggrs {
                             let mut cur_aggr = aggr.clone();
@@ -439,11 +598,17 @@ impl<'a> SessionTx<'a> {
                             cur_aggr.normal_op.as_mut().unwrap().set(&item[*i])?;
                             aggr_ops.push(cur_aggr)
                         }
+                        aggr_reservation.grow(tuple_size(ent.key()) + aggrs_size);
                         ent.insert(aggr_ops);
+                        if aggr_reservation.should_spill() {
+                            debug!("spilling groups of aggregation {:?}", rule_symb);
+                            spilling = true;
+                        }
                     }
                 }
             }
             poison.check()?;
+            self.memory.check()?;
         }
 
         let mut inv_indices = Vec::with_capacity(ruleset[0].aggr.len());
@@ -474,7 +639,45 @@ impl<'a> SessionTx<'a> {
             out_store.put(empty_result);
         }
 
-        for (keys, aggrs) in aggr_work {
+        let mut spilled = spilled.finish()?.peekable();
+        let spilled_groups = iter::from_fn(|| {
+            let first = match spilled.next()? {
+                Ok(first) => first,
+                Err(err) => return Some(Err(err)),
+            };
+            let keys = first[..n_keys].to_vec();
+            let mut aggr_ops = vec![];
+            for (aggr, params) in ruleset[0].aggr.iter().flatten() {
+                let mut cur_aggr = aggr.clone();
+                if let Err(err) = cur_aggr.normal_init(params) {
+                    return Some(Err(err));
+                }
+                aggr_ops.push(cur_aggr);
+            }
+            let same_group = iter::from_fn(|| {
+                spilled
+                    .next_if(|t| matches!(t, Ok(t) if t[..n_keys] == keys[..]))
+                    .map(|t| t.unwrap())
+            });
+            for tuple in iter::once(first).chain(same_group) {
+                for (aggr, val) in aggr_ops.iter_mut().zip(&tuple[n_keys + 1..]) {
+                    if let Err(err) = aggr.normal_op.as_mut().unwrap().set(val) {
+                        return Some(Err(err));
+                    }
+                }
+            }
+            Some(Ok((keys, aggr_ops)))
+        });
+        let groups = aggr_work
+            .into_iter()
+            .map(Ok)
+            .merge_by(spilled_groups, |a, b| match (a, b) {
+                (Ok(a), Ok(b)) => a.0 <= b.0,
+                _ => true,
+            });
+
+        for group in groups {
+            let (keys, aggrs) = group?;
             let tuple_data: Vec<_> = inv_indices
                 .iter()
                 .map(|(is_aggr, idx)| {
@@ -488,6 +691,7 @@ impl<'a> SessionTx<'a> {
             let tuple = tuple_data;
             if should_check_limit {
                 if !out_store.exists(&tuple) {
+                    limiter.count_row(|| true)?;
                     if limiter.should_skip_next() {
                         out_store.put_with_skip(tuple);
                     } else {
@@ -499,11 +703,144 @@ impl<'a> SessionTx<'a> {
                 }
                 // else, do nothing
             } else {
+                limiter.count_row(|| !out_store.exists(&tuple))?;
                 out_store.put(tuple);
             }
         }
         Ok((should_check_limit, out_store))
     }
+    /// Rows derived by all the bodies are partitioned by the variables not used by the window
+    /// aggregations, and each partition is ordered by the sort keys, ties being broken by the
+    /// rows themselves.
+    fn initial_rule_window_eval(
+        &self,
+        rule_symb: &MagicSymbol,
+        ruleset: &[CompiledRule],
+        stores: &BTreeMap<MagicSymbol, EpochStore>,
+        limiter: &QueryLimiter,
+        poison: Poison,
+    ) -> Result<(bool, RegularTempStore)> {
+        let mut out_store = RegularTempStore::with_budget(&self.memory);
+        let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
+        let arity = ruleset[0].aggr.len();
+        let windows = ruleset[0]
+            .aggr
+            .iter()
+            .enumerate()
+            .filter_map(|(i, a)| a.as_ref().map(|(aggr, args)| (i, aggr, args)))
+            .collect_vec();
+        let partition = windows[0].1.window_partition(windows[0].2);
+
+        // each row is stored as the values of the head, followed by the values of the sort keys
+        let mut rows: BTreeSet<Tuple> = BTreeSet::new();
+        let mut reservation = MemoryReservation::new(&self.memory);
+        for (rule_n, rule) in ruleset.iter().enumerate() {
+            debug!(
+                "Calculation for window aggr rule {:?}.{}",
+                rule_symb, rule_n
+            );
+            trace!("{:?}", rule);
+
+            let bindings = rule.relation.bindings_after_eliminate();
+            let key_indices = windows
+                .iter()
+                .flat_map(|(_, aggr, args)| aggr.window_sort_keys(args))
+                .map(|(name, _)| {
+                    bindings
+                        .iter()
+                        .position(|b| b.name.as_str() == name)
+                        .unwrap()
+                })
+                .collect_vec();
+            for item_res in rule.relation.iter(self, None, stores)? {
+                let item = item_res?;
+                let mut row = item[..arity].to_vec();
+                row.extend(key_indices.iter().map(|i| item[*i].clone()));
+                let size = tuple_size(&row);
+                if rows.insert(row) {
+                    reservation.grow(size);
+                }
+            }
+            poison.check()?;
+            self.memory.check()?;
+        }
+
+        // the rows are moved into the groups, so only the keys of the groups are new
+        let mut groups: BTreeMap<Vec<DataValue>, Vec<Tuple>> = BTreeMap::new();
+        for row in rows {
+            let group = partition.iter().map(|i| row[*i].clone()).collect_vec();
+            match groups.entry(group) {
+                Entry::Occupied(mut e) => e.get_mut().push(row),
+                Entry::Vacant(e) => {
+                    reservation.grow(tuple_size(e.key()));
+                    e.insert(vec![row]);
+                }
+            }
+        }
+        self.memory.check()?;
+
+        for group in groups.into_values() {
+            // the values computed by each aggregation, for each row of the group
+            let mut results = Vec::with_capacity(windows.len());
+            let mut key_start = arity;
+            for (i, aggr, args) in windows.iter() {
+                let keys = aggr.window_sort_keys(args);
+                let key_range = key_start..key_start + keys.len();
+                key_start += keys.len();
+                // rows of the group are already in order, and the sort is stable
+                let mut order = (0..group.len()).collect_vec();
+                order.sort_by(|a, b| {
+                    let l_keys = &group[*a][key_range.clone()];
+                    let r_keys = &group[*b][key_range.clone()];
+                    for ((l, r), (_, desc)) in l_keys.iter().zip(r_keys).zip(keys.iter()) {
+                        let ord = if *desc { r.cmp(l) } else { l.cmp(r) };
+                        if ord != CmpOrdering::Equal {
+                            return ord;
+                        }
+                    }
+                    CmpOrdering::Equal
+                });
+                let vals = order.iter().map(|j| group[*j][*i].clone()).collect_vec();
+                let key_vals = order
+                    .iter()
+                    .map(|j| group[*j][key_range.clone()].to_vec())
+                    .collect_vec();
+                let computed =
+                    window_eval(aggr, &args[..aggr.window_const_args()], &vals, &key_vals)?;
+                let mut by_row = vec![None; group.len()];
+                for (j, val) in order.into_iter().zip(computed) {
+                    by_row[j] = val;
+                }
+                results.push((*i, by_row));
+            }
+            'rows: for (j, row) in group.iter().enumerate() {
+                let mut tuple = row[..arity].to_vec();
+                for (i, by_row) in results.iter() {
+                    match &by_row[j] {
+                        Some(val) => tuple[*i] = val.clone(),
+                        None => continue 'rows,
+                    }
+                }
+                if should_check_limit {
+                    if !out_store.exists(&tuple) {
+                        limiter.count_row(|| true)?;
+                        if limiter.should_skip_next() {
+                            out_store.put_with_skip(tuple);
+                        } else {
+                            out_store.put(tuple);
+                        }
+                        if limiter.incr_and_should_stop() {
+                            return Ok((true, out_store));
+                        }
+                    }
+                } else {
+                    limiter.count_row(|| !out_store.exists(&tuple))?;
+                    out_store.put(tuple);
+                }
+            }
+        }
+        Ok((should_check_limit, out_store))
+    }
     fn incremental_rule_non_aggr_eval(
         &self,
         rule_symb: &MagicSymbol,
@@ -514,7 +851,7 @@ impl<'a> SessionTx<'a> {
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
         let prev_store = stores.get(rule_symb).unwrap();
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_budget(&self.memory);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
         for (rule_n, rule) in ruleset.iter().enumerate() {
             let mut need_complete_run = false;
@@ -555,6 +892,7 @@ impl<'a> SessionTx<'a> {
                             item,
                             epoch
                         );
+                        limiter.count_row(|| !out_store.exists(&item))?;
                         if limiter.should_skip_next() {
                             out_store.put_with_skip(item);
                         } else {
@@ -567,6 +905,7 @@ impl<'a> SessionTx<'a> {
                     }
                 }
                 poison.check()?;
+                self.memory.check()?;
             } else {
                 for (delta_key, _) in stores.iter() {
                     if !rule.contained_rules.contains_key(delta_key) {
@@ -595,6 +934,7 @@ impl<'a> SessionTx<'a> {
                                 item,
                                 epoch
                             );
+                            limiter.count_row(|| !out_store.exists(&item))?;
                             if limiter.should_skip_next() {
                                 out_store.put_with_skip(item);
                             } else {
@@ -607,6 +947,7 @@ impl<'a> SessionTx<'a> {
                         }
                     }
                     poison.check()?;
+                    self.memory.check()?;
                 }
             }
         }
@@ -617,6 +958,7 @@ impl<'a> SessionTx<'a> {
         rule_symb: &MagicSymbol,
         ruleset: &[CompiledRule],
         stores: &BTreeMap<MagicSymbol, EpochStore>,
+        limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<MeetAggrStore> {
         let mut out_store = MeetAggrStore::new(ruleset[0].aggr.clone())?;
@@ -646,9 +988,12 @@ impl<'a> SessionTx<'a> {
             if need_complete_run {
                 debug!("complete run for rule {:?}.{}", rule_symb, rule_n);
                 for item_res in rule.relation.iter(self, None, stores)? {
-                    out_store.meet_put(item_res?)?;
+                    let item = item_res?;
+                    limiter.count_row(|| !out_store.exists(&item))?;
+                    out_store.meet_put(item)?;
                 }
                 poison.check()?;
+                self.memory.check()?;
             } else {
                 for (delta_key, _) in stores.iter() {
                     if !rule.contained_rules.contains_key(delta_key) {
@@ -659,9 +1004,12 @@ impl<'a> SessionTx<'a> {
                         delta_key, rule_symb, rule_n
                     );
                     for item_res in rule.relation.iter(self, Some(delta_key), stores)? {
-                        out_store.meet_put(item_res?)?;
+                        let item = item_res?;
+                        limiter.count_row(|| !out_store.exists(&item))?;
+                        out_store.meet_put(item)?;
                     }
                     poison.check()?;
+                    self.memory.check()?;
                 }
             }
         }
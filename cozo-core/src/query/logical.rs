@@ -63,7 +63,9 @@ impl InputAtom {
             a @ (InputAtom::Rule { .. }
             | InputAtom::NamedFieldRelation { .. }
             | InputAtom::Predicate { .. }
-            | InputAtom::Relation { .. }) => a,
+            | InputAtom::Relation { .. }
+            | InputAtom::Optional { .. }
+            | InputAtom::Path { .. }) => a,
             InputAtom::Conjunction { inner: args, span } => InputAtom::Conjunction {
                 inner: args
                     .into_iter()
@@ -82,7 +84,8 @@ impl InputAtom {
             InputAtom::Negation { inner: arg, span } => match *arg {
                 a @ (InputAtom::Rule { .. }
                 | InputAtom::NamedFieldRelation { .. }
-                | InputAtom::Relation { .. }) => InputAtom::Negation {
+                | InputAtom::Relation { .. }
+                | InputAtom::Path { .. }) => InputAtom::Negation {
                     inner: Box::new(a),
                     span,
                 },
@@ -124,6 +127,9 @@ impl InputAtom {
                 InputAtom::Search { inner } => {
                     bail!(UnsafeNegation(inner.span))
                 }
+                InputAtom::Optional { span, .. } => {
+                    bail!(UnsafeNegation(span))
+                }
             },
             InputAtom::Search { inner } => InputAtom::Search { inner },
         })
@@ -212,19 +218,31 @@ impl InputAtom {
             InputAtom::Rule { inner: r } => r.normalize(false, gen),
             InputAtom::NamedFieldRelation { inner } => {
                 let r = Self::convert_named_field_relation(inner, gen, tx)?;
-                r.normalize(false, gen)
+                r.normalize(NormalFormAtom::Relation, gen)
             }
-            InputAtom::Relation { inner: v } => v.normalize(false, gen),
+            InputAtom::Relation { inner: v } => v.normalize(NormalFormAtom::Relation, gen),
             InputAtom::Predicate { inner: mut p } => {
                 p.partial_eval()?;
                 Disjunction::singlet(NormalFormAtom::Predicate(p))
             }
             InputAtom::Negation { inner: n, .. } => match *n {
                 InputAtom::Rule { inner: r } => r.normalize(true, gen),
-                InputAtom::Relation { inner: v } => v.normalize(true, gen),
+                InputAtom::Relation { inner: v } => {
+                    v.normalize(NormalFormAtom::NegatedRelation, gen)
+                }
                 InputAtom::NamedFieldRelation { inner } => {
                     let r = Self::convert_named_field_relation(inner, gen, tx)?;
-                    r.normalize(true, gen)
+                    r.normalize(NormalFormAtom::NegatedRelation, gen)
+                }
+                _ => unreachable!(),
+            },
+            InputAtom::Optional { inner: o, .. } => match *o {
+                InputAtom::Relation { inner: v } => {
+                    v.normalize(NormalFormAtom::OptionalRelation, gen)
+                }
+                InputAtom::NamedFieldRelation { inner } => {
+                    let r = Self::convert_named_field_relation(inner, gen, tx)?;
+                    r.normalize(NormalFormAtom::OptionalRelation, gen)
                 }
                 _ => unreachable!(),
             },
@@ -232,6 +250,8 @@ impl InputAtom {
                 Disjunction::singlet(NormalFormAtom::Unification(u))
             }
             InputAtom::Search { inner } => inner.normalize(gen, tx)?,
+            // expanded into rules before the program is normalized
+            InputAtom::Path { .. } => unreachable!(),
         })
     }
 }
@@ -297,7 +317,11 @@ impl InputRuleApplyAtom {
 }
 
 impl InputRelationApplyAtom {
-    fn normalize(self, is_negated: bool, gen: &mut TempSymbGen) -> Disjunction {
+    fn normalize(
+        self,
+        kind: fn(NormalFormRelationApplyAtom) -> NormalFormAtom,
+        gen: &mut TempSymbGen,
+    ) -> Disjunction {
         let mut ret = Vec::with_capacity(self.args.len() + 1);
         let mut args = Vec::with_capacity(self.args.len());
         let mut seen_variables = BTreeSet::new();
@@ -339,21 +363,12 @@ impl InputRelationApplyAtom {
             }
         }
 
-        ret.push(if is_negated {
-            NormalFormAtom::NegatedRelation(NormalFormRelationApplyAtom {
-                name: self.name,
-                args,
-                valid_at: self.valid_at,
-                span: self.span,
-            })
-        } else {
-            NormalFormAtom::Relation(NormalFormRelationApplyAtom {
-                name: self.name,
-                args,
-                valid_at: self.valid_at,
-                span: self.span,
-            })
-        });
+        ret.push(kind(NormalFormRelationApplyAtom {
+            name: self.name,
+            args,
+            valid_at: self.valid_at,
+            span: self.span,
+        }));
         Disjunction::conj(ret)
     }
 }
@@ -30,7 +30,7 @@ use crate::runtime::transact::SessionTx;
 impl NormalFormProgram {
     pub(crate) fn exempt_aggr_rules_for_magic_sets(&self, exempt_rules: &mut BTreeSet<Symbol>) {
         for (name, rule_set) in self.prog.iter() {
-            if self.disable_magic_rewrite {
+            if self.disable_magic_rewrite || self.extra_outputs.contains(name) {
                 exempt_rules.insert(name.clone());
                 continue;
             }
@@ -172,6 +172,10 @@ fn magic_rewrite_ruleset(
                     seen_bindings.extend(v.args.iter().cloned());
                     collected_atoms.push(MagicAtom::Relation(v));
                 }
+                MagicAtom::OptionalRelation(v) => {
+                    seen_bindings.extend(v.args.iter().cloned());
+                    collected_atoms.push(MagicAtom::OptionalRelation(v));
+                }
                 MagicAtom::Unification(u) => {
                     seen_bindings.insert(u.binding.clone());
                     collected_atoms.push(MagicAtom::Unification(u));
@@ -188,6 +192,10 @@ fn magic_rewrite_ruleset(
                     seen_bindings.extend(s.all_bindings().cloned());
                     collected_atoms.push(MagicAtom::LshSearch(s));
                 }
+                MagicAtom::SpatialSearch(s) => {
+                    seen_bindings.extend(s.all_bindings().cloned());
+                    collected_atoms.push(MagicAtom::SpatialSearch(s));
+                }
                 MagicAtom::Rule(r_app) => {
                     if r_app.name.has_bound_adornment() {
                         // we are guaranteed to have a magic rule application
@@ -555,6 +563,14 @@ impl NormalFormAtom {
                 }
                 MagicAtom::LshSearch(s.clone())
             }
+            NormalFormAtom::SpatialSearch(s) => {
+                for arg in s.all_bindings() {
+                    if !seen_bindings.contains(arg) {
+                        seen_bindings.insert(arg.clone());
+                    }
+                }
+                MagicAtom::SpatialSearch(s.clone())
+            }
 
             NormalFormAtom::Predicate(p) => {
                 // predicate cannot introduce new bindings
@@ -605,6 +621,15 @@ impl NormalFormAtom {
                     span: nv.span,
                 })
             }
+            NormalFormAtom::OptionalRelation(v) => {
+                seen_bindings.extend(v.args.iter().cloned());
+                MagicAtom::OptionalRelation(MagicRelationApplyAtom {
+                    name: v.name.clone(),
+                    args: v.args.clone(),
+                    valid_at: v.valid_at,
+                    span: v.span,
+                })
+            }
             NormalFormAtom::Unification(u) => {
                 seen_bindings.insert(u.binding.clone());
                 MagicAtom::Unification(u.clone())
@@ -0,0 +1,340 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Regular path queries: `from -[path]-> to` atoms are expanded into generated inline rules,
+//! recursive for the repetitions. Since the rules have set semantics, cycles in the graph
+//! cannot make the evaluation diverge, and since they are ordinary rules, magic sets
+//! rewriting specializes them for bound endpoints.
+
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use miette::{ensure, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::data::expr::{Expr, Op};
+use crate::data::functions::{OP_ADD, OP_GE, OP_LT};
+use crate::data::program::{
+    InputAtom, InputInlineRule, InputInlineRulesOrFixed, InputPathAtom, InputProgram,
+    InputRelationApplyAtom, InputRuleApplyAtom, PathExpr, Unification,
+};
+use crate::data::symb::Symbol;
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+use crate::runtime::transact::SessionTx;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Stored relation {0} has fewer than two columns and cannot be used as edges of a path")]
+#[diagnostic(code(eval::bad_path_relation))]
+struct BadPathRelation(String, #[label] SourceSpan);
+
+impl<'a> SessionTx<'a> {
+    /// Replaces the path atoms of the program by calls to generated rules.
+    pub(crate) fn expand_path_atoms(&self, program: &mut InputProgram) -> Result<()> {
+        let mut expander = PathExpander {
+            tx: self,
+            generated: Default::default(),
+            last_id: 0,
+        };
+        for rules in program.prog.values_mut() {
+            if let InputInlineRulesOrFixed::Rules { rules } = rules {
+                for rule in rules.iter_mut() {
+                    for atom in rule.body.iter_mut() {
+                        expander.expand_atom(atom)?;
+                    }
+                }
+            }
+        }
+        for (name, rules) in expander.generated {
+            program
+                .prog
+                .insert(name, InputInlineRulesOrFixed::Rules { rules });
+        }
+        Ok(())
+    }
+}
+
+/// One step of a path: an edge of a stored relation, or a path matched by a generated rule.
+enum Edge {
+    Relation {
+        name: Symbol,
+        arity: usize,
+        inverse: bool,
+    },
+    Rule(Symbol),
+}
+
+impl Edge {
+    fn atom(&self, from: Expr, to: Expr, span: SourceSpan) -> InputAtom {
+        match self {
+            Edge::Relation {
+                name,
+                arity,
+                inverse,
+            } => {
+                let mut args = if *inverse {
+                    vec![to, from]
+                } else {
+                    vec![from, to]
+                };
+                args.resize_with(*arity, || var("_", span));
+                InputAtom::Relation {
+                    inner: InputRelationApplyAtom {
+                        name: name.clone(),
+                        args,
+                        valid_at: None,
+                        span,
+                    },
+                }
+            }
+            Edge::Rule(name) => InputAtom::Rule {
+                inner: InputRuleApplyAtom {
+                    name: name.clone(),
+                    args: vec![from, to],
+                    span,
+                },
+            },
+        }
+    }
+}
+
+/// Atoms connecting `from` to `to` through the edges in turn.
+fn chain(edges: &[&Edge], span: SourceSpan) -> Vec<InputAtom> {
+    let node = |i: usize| {
+        if i == 0 {
+            var("from", span)
+        } else if i == edges.len() {
+            var("to", span)
+        } else {
+            var(&format!("via{i}"), span)
+        }
+    };
+    edges
+        .iter()
+        .enumerate()
+        .map(|(i, edge)| edge.atom(node(i), node(i + 1), span))
+        .collect()
+}
+
+struct PathExpander<'a, 'b> {
+    tx: &'a SessionTx<'b>,
+    generated: BTreeMap<Symbol, Vec<InputInlineRule>>,
+    last_id: u32,
+}
+
+impl PathExpander<'_, '_> {
+    fn expand_atom(&mut self, atom: &mut InputAtom) -> Result<()> {
+        match atom {
+            InputAtom::Path {
+                inner:
+                    InputPathAtom {
+                        from,
+                        to,
+                        path,
+                        span,
+                    },
+            } => {
+                let edge = self.edge(path, *span)?;
+                *atom = edge.atom(from.clone(), to.clone(), *span);
+            }
+            InputAtom::Negation { inner, .. } => self.expand_atom(inner)?,
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                for atom in inner {
+                    self.expand_atom(atom)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn edge(&mut self, path: &PathExpr, span: SourceSpan) -> Result<Edge> {
+        Ok(match path {
+            PathExpr::Step { relation, inverse } => {
+                let arity = self.tx.get_relation(relation, false)?.arity();
+                ensure!(
+                    arity >= 2,
+                    BadPathRelation(relation.to_string(), relation.span)
+                );
+                Edge::Relation {
+                    name: relation.clone(),
+                    arity,
+                    inverse: *inverse,
+                }
+            }
+            PathExpr::Sequence(steps) => {
+                let edges: Vec<_> = steps
+                    .iter()
+                    .map(|step| self.edge(step, span))
+                    .try_collect()?;
+                let body = chain(&edges.iter().collect_vec(), span);
+                self.generate(&["from", "to"], vec![body], span)
+            }
+            PathExpr::Alternation(choices) => {
+                let mut bodies = vec![];
+                for choice in choices {
+                    let edge = self.edge(choice, span)?;
+                    bodies.push(vec![edge.atom(var("from", span), var("to", span), span)]);
+                }
+                self.generate(&["from", "to"], bodies, span)
+            }
+            PathExpr::Repetition {
+                inner,
+                min,
+                max,
+                span,
+            } => self.repetition(inner, *min, *max, *span)?,
+        })
+    }
+
+    fn repetition(
+        &mut self,
+        inner: &PathExpr,
+        min: usize,
+        max: Option<usize>,
+        span: SourceSpan,
+    ) -> Result<Edge> {
+        let edge = self.edge(inner, span)?;
+        let repeated = match max {
+            None => {
+                // longer walks extend shorter ones by one step: revisiting a node derives
+                // nothing new, so the recursion ends even if the graph has cycles
+                let name = self.next_name(span);
+                let shortest = chain(&vec![&edge; min.max(1)], span);
+                let extended = vec![
+                    Edge::Rule(name.clone()).atom(var("from", span), var("via", span), span),
+                    edge.atom(var("via", span), var("to", span), span),
+                ];
+                self.add_rules(&name, &["from", "to"], vec![shortest, extended], span);
+                Edge::Rule(name)
+            }
+            Some(max) => {
+                // walks carry their length, so that the recursion stops at the maximum
+                let counted = self.next_name(span);
+                let first = vec![
+                    edge.atom(var("from", span), var("to", span), span),
+                    unify("len", int(1, span), span),
+                ];
+                let extended = vec![
+                    InputAtom::Rule {
+                        inner: InputRuleApplyAtom {
+                            name: counted.clone(),
+                            args: vec![var("from", span), var("via", span), var("prev", span)],
+                            span,
+                        },
+                    },
+                    predicate(&OP_LT, var("prev", span), int(max, span), span),
+                    edge.atom(var("via", span), var("to", span), span),
+                    unify(
+                        "len",
+                        apply(&OP_ADD, var("prev", span), int(1, span), span),
+                        span,
+                    ),
+                ];
+                self.add_rules(
+                    &counted,
+                    &["from", "to", "len"],
+                    vec![first, extended],
+                    span,
+                );
+                let mut body = vec![InputAtom::Rule {
+                    inner: InputRuleApplyAtom {
+                        name: counted,
+                        args: vec![var("from", span), var("to", span), var("len", span)],
+                        span,
+                    },
+                }];
+                if min > 1 {
+                    body.push(predicate(&OP_GE, var("len", span), int(min, span), span));
+                }
+                self.generate(&["from", "to"], vec![body], span)
+            }
+        };
+        if min > 0 {
+            return Ok(repeated);
+        }
+        // the empty path connects every node the repeated path touches to itself
+        let name = self.next_name(span);
+        let body = vec![repeated.atom(var("from", span), var("to", span), span)];
+        self.add_rules(&name, &["from", "to"], vec![body], span);
+        let touched = vec![
+            vec![edge.atom(var("from", span), var("_", span), span)],
+            vec![edge.atom(var("_", span), var("from", span), span)],
+        ];
+        self.add_rules(&name, &["from", "from"], touched, span);
+        Ok(Edge::Rule(name))
+    }
+
+    fn next_name(&mut self, span: SourceSpan) -> Symbol {
+        self.last_id += 1;
+        Symbol::new(format!("*path*{}", self.last_id), span)
+    }
+
+    fn generate(&mut self, head: &[&str], bodies: Vec<Vec<InputAtom>>, span: SourceSpan) -> Edge {
+        let name = self.next_name(span);
+        self.add_rules(&name, head, bodies, span);
+        Edge::Rule(name)
+    }
+
+    fn add_rules(
+        &mut self,
+        name: &Symbol,
+        head: &[&str],
+        bodies: Vec<Vec<InputAtom>>,
+        span: SourceSpan,
+    ) {
+        let rules = self.generated.entry(name.clone()).or_default();
+        for body in bodies {
+            rules.push(InputInlineRule {
+                head: head.iter().map(|h| Symbol::new(*h, span)).collect(),
+                aggr: vec![None; head.len()],
+                body,
+                span,
+            })
+        }
+    }
+}
+
+fn var(name: &str, span: SourceSpan) -> Expr {
+    Expr::Binding {
+        var: Symbol::new(name, span),
+        tuple_pos: None,
+    }
+}
+
+fn int(i: usize, span: SourceSpan) -> Expr {
+    Expr::Const {
+        val: DataValue::from(i as i64),
+        span,
+    }
+}
+
+fn apply(op: &'static Op, lhs: Expr, rhs: Expr, span: SourceSpan) -> Expr {
+    Expr::Apply {
+        op,
+        args: [lhs, rhs].into(),
+        span,
+    }
+}
+
+fn predicate(op: &'static Op, lhs: Expr, rhs: Expr, span: SourceSpan) -> InputAtom {
+    InputAtom::Predicate {
+        inner: apply(op, lhs, rhs, span),
+    }
+}
+
+fn unify(binding: &str, expr: Expr, span: SourceSpan) -> InputAtom {
+    InputAtom::Unification {
+        inner: Unification {
+            binding: Symbol::new(binding, span),
+            expr,
+            one_many_unif: false,
+            span,
+        },
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use miette::Result;
+
+use crate::data::tuple::{Tuple, TupleIter};
+use crate::query::ra::RelAlgebra;
+
+/// Runtime statistics of a single relational algebra node within a single epoch.
+#[derive(Default)]
+pub(crate) struct OpStats {
+    /// Tuples produced by the node
+    pub(crate) produced: AtomicU64,
+    /// Tuples discarded by the filters evaluated by the node itself
+    pub(crate) filtered: AtomicU64,
+    /// Range scans (including point lookups) issued against stored relations or temp stores
+    pub(crate) scans: AtomicU64,
+    /// Wall time spent in the node, including the time spent in its children
+    pub(crate) nanos: AtomicU64,
+}
+
+impl OpStats {
+    pub(crate) fn incr_filtered(&self) {
+        self.filtered.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn incr_scans(&self) {
+        self.scans.fetch_add(1, Ordering::Relaxed);
+    }
+    fn add_time(&self, watch: &Stopwatch) {
+        self.nanos
+            .fetch_add(watch.elapsed_nanos(), Ordering::Relaxed);
+    }
+}
+
+/// Collects per-node, per-epoch statistics when a query is run by `::explain analyze`.
+/// Nodes are identified by their addresses, which are stable during the evaluation
+/// since the compiled program is not touched.
+#[derive(Default)]
+pub(crate) struct QueryProfiler {
+    epoch: AtomicU32,
+    stats: Mutex<BTreeMap<(usize, u32), Arc<OpStats>>>,
+}
+
+impl QueryProfiler {
+    pub(crate) fn set_epoch(&self, epoch: u32) {
+        self.epoch.store(epoch, Ordering::Release);
+    }
+    pub(crate) fn stats_for(&self, node: &RelAlgebra) -> Arc<OpStats> {
+        let key = (node_id(node), self.epoch.load(Ordering::Acquire));
+        self.stats.lock().unwrap().entry(key).or_default().clone()
+    }
+    /// All statistics collected for the node, ordered by epoch.
+    pub(crate) fn collected(&self, node: &RelAlgebra) -> Vec<(u32, Arc<OpStats>)> {
+        let id = node_id(node);
+        self.stats
+            .lock()
+            .unwrap()
+            .range((id, 0)..=(id, u32::MAX))
+            .map(|((_, epoch), stats)| (*epoch, stats.clone()))
+            .collect()
+    }
+}
+
+fn node_id(node: &RelAlgebra) -> usize {
+    node as *const RelAlgebra as usize
+}
+
+/// Times the construction of the iterator of a node, and then wraps it
+/// so that the produced tuples and the time spent in them are counted.
+pub(crate) fn profile_iter<'a>(
+    stats: Arc<OpStats>,
+    make_iter: impl FnOnce(&Arc<OpStats>) -> Result<TupleIter<'a>>,
+) -> Result<TupleIter<'a>> {
+    let watch = Stopwatch::start();
+    let inner = make_iter(&stats);
+    stats.add_time(&watch);
+    Ok(Box::new(ProfiledIter {
+        inner: inner?,
+        stats,
+    }))
+}
+
+struct ProfiledIter<'a> {
+    inner: TupleIter<'a>,
+    stats: Arc<OpStats>,
+}
+
+impl<'a> Iterator for ProfiledIter<'a> {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let watch = Stopwatch::start();
+        let ret = self.inner.next();
+        self.stats.add_time(&watch);
+        if let Some(Ok(_)) = ret {
+            self.stats.produced.fetch_add(1, Ordering::Relaxed);
+        }
+        ret
+    }
+}
+
+/// Timing is not available on WASM, where all times are reported as zero.
+struct Stopwatch {
+    #[cfg(not(target_arch = "wasm32"))]
+    started: Instant,
+}
+
+impl Stopwatch {
+    fn start() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            started: Instant::now(),
+        }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    fn elapsed_nanos(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+    #[cfg(target_arch = "wasm32")]
+    fn elapsed_nanos(&self) -> u64 {
+        0
+    }
+}
@@ -6,9 +6,12 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{Debug, Formatter, Write};
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::iter;
+use std::sync::Arc;
 
 use either::{Left, Right};
 use itertools::Itertools;
@@ -24,11 +27,14 @@ use crate::data::symb::Symbol;
 use crate::data::tuple::{Tuple, TupleIter};
 use crate::data::value::{DataValue, ValidityTs};
 use crate::parse::SourceSpan;
+use crate::query::profile::{profile_iter, OpStats};
 use crate::runtime::minhash_lsh::LshSearch;
 use crate::runtime::relation::RelationHandle;
+use crate::runtime::rtree::SpatialSearch;
+use crate::runtime::spill::{tuple_size, MemoryBudget, MemoryReservation};
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
-use crate::utils::swap_option_result;
+use crate::utils::{swap_option_result, TempCollector};
 
 pub(crate) enum RelAlgebra {
     Fixed(InlineFixedRA),
@@ -37,12 +43,14 @@ pub(crate) enum RelAlgebra {
     StoredWithValidity(StoredWithValidityRA),
     Join(Box<InnerJoin>),
     NegJoin(Box<NegJoin>),
+    LeftJoin(Box<LeftJoin>),
     Reorder(ReorderRA),
     Filter(FilteredRA),
     Unification(UnificationRA),
     HnswSearch(HnswSearchRA),
     FtsSearch(FtsSearchRA),
     LshSearch(LshSearchRA),
+    SpatialSearch(SpatialSearchRA),
 }
 
 impl RelAlgebra {
@@ -53,6 +61,7 @@ impl RelAlgebra {
             RelAlgebra::Stored(i) => i.span,
             RelAlgebra::Join(i) => i.span,
             RelAlgebra::NegJoin(i) => i.span,
+            RelAlgebra::LeftJoin(i) => i.span,
             RelAlgebra::Reorder(i) => i.relation.span(),
             RelAlgebra::Filter(i) => i.span,
             RelAlgebra::Unification(i) => i.span,
@@ -60,6 +69,7 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(i) => i.hnsw_search.span,
             RelAlgebra::FtsSearch(i) => i.fts_search.span,
             RelAlgebra::LshSearch(i) => i.lsh_search.span,
+            RelAlgebra::SpatialSearch(i) => i.spatial_search.span,
         }
     }
 }
@@ -219,6 +229,7 @@ impl FilteredRA {
         tx: &'a SessionTx<'_>,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
         let bindings = self.parent.bindings_after_eliminate();
         let eliminate_indices = get_eliminate_indices(&bindings, &self.to_eliminate);
@@ -230,7 +241,12 @@ impl FilteredRA {
                     Ok(t) => {
                         for (p, span) in self.filters_bytecodes.iter() {
                             match eval_bytecode_pred(p, &t, &mut stack, *span) {
-                                Ok(false) => return None,
+                                Ok(false) => {
+                                    if let Some(stats) = &stats {
+                                        stats.incr_filtered();
+                                    }
+                                    return None;
+                                }
                                 Err(e) => return Some(Err(e)),
                                 Ok(true) => {}
                             }
@@ -299,6 +315,11 @@ impl Debug for RelAlgebra {
                 .field(&bindings)
                 .field(&s.lsh_search.idx_handle.name)
                 .finish(),
+            RelAlgebra::SpatialSearch(s) => f
+                .debug_tuple("SpatialSearch")
+                .field(&bindings)
+                .field(&s.spatial_search.idx_handle.name)
+                .finish(),
             RelAlgebra::StoredWithValidity(r) => f
                 .debug_tuple("StoredWithValidity")
                 .field(&bindings)
@@ -325,6 +346,13 @@ impl Debug for RelAlgebra {
                 .field(&r.left)
                 .field(&r.right)
                 .finish(),
+            RelAlgebra::LeftJoin(r) => f
+                .debug_tuple("LeftJoin")
+                .field(&bindings)
+                .field(&r.joiner)
+                .field(&r.left)
+                .field(&r.right)
+                .finish(),
             RelAlgebra::Reorder(r) => f
                 .debug_tuple("Reorder")
                 .field(&r.new_order)
@@ -374,6 +402,9 @@ impl RelAlgebra {
             RelAlgebra::LshSearch(s) => {
                 s.fill_binding_indices_and_compile()?;
             }
+            RelAlgebra::SpatialSearch(s) => {
+                s.fill_binding_indices_and_compile()?;
+            }
             RelAlgebra::StoredWithValidity(v) => {
                 v.fill_binding_indices_and_compile()?;
             }
@@ -387,6 +418,10 @@ impl RelAlgebra {
             RelAlgebra::NegJoin(r) => {
                 r.left.fill_binding_indices_and_compile()?;
             }
+            RelAlgebra::LeftJoin(r) => {
+                r.left.fill_binding_indices_and_compile()?;
+                r.right.fill_binding_indices_and_compile()?;
+            }
             RelAlgebra::Unification(u) => {
                 u.parent.fill_binding_indices_and_compile()?;
                 u.fill_binding_indices_and_compile()?
@@ -469,10 +504,12 @@ impl RelAlgebra {
             s @ (RelAlgebra::Fixed(_)
             | RelAlgebra::Reorder(_)
             | RelAlgebra::NegJoin(_)
+            | RelAlgebra::LeftJoin(_)
             | RelAlgebra::Unification(_)
             | RelAlgebra::HnswSearch(_)
             | RelAlgebra::FtsSearch(_)
-            | RelAlgebra::LshSearch(_)) => {
+            | RelAlgebra::LshSearch(_)
+            | RelAlgebra::SpatialSearch(_)) => {
                 let span = filter.span();
                 RelAlgebra::Filter(FilteredRA {
                     parent: Box::new(s),
@@ -563,8 +600,8 @@ impl RelAlgebra {
                     mut right,
                     joiner,
                     to_eliminate,
+                    prefer_materialized,
                     span,
-                    ..
                 } = *inner;
                 for filter in filters {
                     let f_bindings = filter.bindings()?;
@@ -581,6 +618,7 @@ impl RelAlgebra {
                     right,
                     joiner,
                     to_eliminate,
+                    prefer_materialized,
                     span,
                 }));
                 if !remaining.is_empty() {
@@ -649,6 +687,18 @@ impl RelAlgebra {
             own_bindings,
         }))
     }
+    pub(crate) fn spatial_search(
+        self,
+        spatial_search: SpatialSearch,
+        own_bindings: Vec<Symbol>,
+    ) -> Result<Self> {
+        Ok(Self::SpatialSearch(SpatialSearchRA {
+            parent: Box::new(self),
+            spatial_search,
+            filter_bytecode: None,
+            own_bindings,
+        }))
+    }
     pub(crate) fn join(
         self,
         right: RelAlgebra,
@@ -664,9 +714,18 @@ impl RelAlgebra {
                 right_keys,
             },
             to_eliminate: Default::default(),
+            prefer_materialized: false,
             span,
         }))
     }
+    /// Makes the join, if this is one, scan and materialize its right side
+    /// even when the right side could be joined by prefix lookups.
+    pub(crate) fn prefer_materialized(mut self) -> Self {
+        if let RelAlgebra::Join(inner) = &mut self {
+            inner.prefer_materialized = true;
+        }
+        self
+    }
     pub(crate) fn neg_join(
         self,
         right: RelAlgebra,
@@ -685,6 +744,24 @@ impl RelAlgebra {
             span,
         }))
     }
+    pub(crate) fn left_join(
+        self,
+        right: RelAlgebra,
+        left_keys: Vec<Symbol>,
+        right_keys: Vec<Symbol>,
+        span: SourceSpan,
+    ) -> Self {
+        RelAlgebra::LeftJoin(Box::new(LeftJoin {
+            left: self,
+            right,
+            joiner: Joiner {
+                left_keys,
+                right_keys,
+            },
+            to_eliminate: Default::default(),
+            span,
+        }))
+    }
 }
 
 #[derive(Debug)]
@@ -851,12 +928,18 @@ fn invert_option_err<T>(v: Result<Option<T>>) -> Option<Result<T>> {
 fn filter_iter(
     filters_bytecodes: Vec<(Vec<Bytecode>, SourceSpan)>,
     it: impl Iterator<Item = Result<Tuple>>,
+    stats: Option<Arc<OpStats>>,
 ) -> impl Iterator<Item = Result<Tuple>> {
     let mut stack = vec![];
     it.filter_map_ok(move |t| -> Option<Result<Tuple>> {
         for (p, span) in filters_bytecodes.iter() {
             match eval_bytecode_pred(p, &t, &mut stack, *span) {
-                Ok(false) => return None,
+                Ok(false) => {
+                    if let Some(stats) = &stats {
+                        stats.incr_filtered();
+                    }
+                    return None;
+                }
                 Err(e) => {
                     debug!("{:?}", t);
                     return Some(Err(e));
@@ -973,6 +1056,71 @@ impl LshSearchRA {
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct SpatialSearchRA {
+    pub(crate) parent: Box<RelAlgebra>,
+    pub(crate) spatial_search: SpatialSearch,
+    pub(crate) filter_bytecode: Option<(Vec<Bytecode>, SourceSpan)>,
+    pub(crate) own_bindings: Vec<Symbol>,
+}
+
+impl SpatialSearchRA {
+    fn fill_binding_indices_and_compile(&mut self) -> Result<()> {
+        self.parent.fill_binding_indices_and_compile()?;
+        if self.spatial_search.filter.is_some() {
+            let bindings: BTreeMap<_, _> = self
+                .own_bindings
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(|(a, b)| (b, a))
+                .collect();
+            let filter = self.spatial_search.filter.as_mut().unwrap();
+            filter.fill_binding_indices(&bindings)?;
+            self.filter_bytecode = Some((filter.compile()?, filter.span()));
+        }
+        Ok(())
+    }
+    fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        let bindings = self.parent.bindings_after_eliminate();
+        let position_of = |symb: &Option<Symbol>| {
+            symb.as_ref()
+                .and_then(|s| bindings.iter().position(|b| b == s))
+        };
+        let query_idx = position_of(&self.spatial_search.query);
+        let bbox_idx = position_of(&self.spatial_search.bbox);
+        let config = self.spatial_search.clone();
+        let filter_code = self.filter_bytecode.clone();
+        let mut stack = vec![];
+
+        let it = self
+            .parent
+            .iter(tx, delta_rule, stores)?
+            .map_ok(move |tuple| -> Result<_> {
+                let res = tx.spatial_search(
+                    query_idx.map(|i| &tuple[i]),
+                    bbox_idx.map(|i| &tuple[i]),
+                    &config,
+                    &filter_code,
+                    &mut stack,
+                )?;
+                Ok(res.into_iter().map(move |t| {
+                    let mut r = tuple.clone();
+                    r.extend(t);
+                    r
+                }))
+            })
+            .map(flatten_err)
+            .flatten_ok();
+        Ok(Box::new(it))
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct FtsSearchRA {
     pub(crate) parent: Box<RelAlgebra>,
@@ -1146,12 +1294,19 @@ impl StoredWithValidityRA {
         }
         Ok(())
     }
-    fn iter<'a>(&'a self, tx: &'a SessionTx<'_>) -> Result<TupleIter<'a>> {
+    fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        stats: Option<Arc<OpStats>>,
+    ) -> Result<TupleIter<'a>> {
+        if let Some(stats) = &stats {
+            stats.incr_scans();
+        }
         let it = self.storage.skip_scan_all(tx, self.valid_at);
         Ok(if self.filters.is_empty() {
             Box::new(it)
         } else {
-            Box::new(filter_iter(self.filters_bytecodes.clone(), it))
+            Box::new(filter_iter(self.filters_bytecodes.clone(), it, stats))
         })
     }
     fn prefix_join<'a>(
@@ -1160,6 +1315,7 @@ impl StoredWithValidityRA {
         left_iter: TupleIter<'a>,
         (left_join_indices, right_join_indices): (Vec<usize>, Vec<usize>),
         eliminate_indices: BTreeSet<usize>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
         let mut right_invert_indices = right_join_indices.iter().enumerate().collect_vec();
         right_invert_indices.sort_by_key(|(_, b)| **b);
@@ -1172,9 +1328,14 @@ impl StoredWithValidityRA {
 
         let it = left_iter
             .map_ok(move |tuple| {
+                if let Some(stats) = &stats {
+                    stats.incr_scans();
+                }
+                let stats = stats.clone();
                 let prefix = left_to_prefix_indices
                     .iter()
-                    .map(|i| tuple[*i].clone())
+                    .enumerate()
+                    .map(|(j, i)| self.storage.coerce_lookup(j, tuple[*i].clone()))
                     .collect_vec();
 
                 if !skip_range_check && !self.filters.is_empty() {
@@ -1183,6 +1344,11 @@ impl StoredWithValidityRA {
                         Ok(b) => b,
                         _ => (vec![], vec![]),
                     };
+                    let offset = right_join_indices.len();
+                    let coerce =
+                        |(j, v): (usize, DataValue)| self.storage.coerce_lookup(offset + j, v);
+                    let l_bound = l_bound.into_iter().enumerate().map(coerce).collect_vec();
+                    let u_bound = u_bound.into_iter().enumerate().map(coerce).collect_vec();
                     if !l_bound.iter().all(|v| *v == DataValue::Null)
                         || !u_bound.iter().all(|v| *v == DataValue::Bot)
                     {
@@ -1198,10 +1364,13 @@ impl StoredWithValidityRA {
                                 )
                                 .map(move |res_found| -> Result<Option<Tuple>> {
                                     let found = res_found?;
-                                    for (p, span) in self.filters_bytecodes.iter() {
-                                        if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
-                                            return Ok(None);
-                                        }
+                                    if !passes_filters(
+                                        &self.filters_bytecodes,
+                                        &found,
+                                        &mut stack,
+                                        &stats,
+                                    )? {
+                                        return Ok(None);
                                     }
                                     let mut ret = tuple.clone();
                                     ret.extend(found);
@@ -1218,10 +1387,9 @@ impl StoredWithValidityRA {
                         .skip_scan_prefix(tx, &prefix, self.valid_at)
                         .map(move |res_found| -> Result<Option<Tuple>> {
                             let found = res_found?;
-                            for (p, span) in self.filters_bytecodes.iter() {
-                                if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
-                                    return Ok(None);
-                                }
+                            if !passes_filters(&self.filters_bytecodes, &found, &mut stack, &stats)?
+                            {
+                                return Ok(None);
                             }
                             let mut ret = tuple.clone();
                             ret.extend(found);
@@ -1265,28 +1433,31 @@ impl StoredRA {
         eliminate_indices: BTreeSet<usize>,
         left_join_indices: Vec<usize>,
         right_join_indices: Vec<usize>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
         let mut stack = vec![];
 
         let it = left_iter
             .map_ok(move |tuple| -> Result<Option<Tuple>> {
+                if let Some(stats) = &stats {
+                    stats.incr_scans();
+                }
                 let prefix = left_to_prefix_indices
                     .iter()
-                    .map(|i| tuple[*i].clone())
+                    .enumerate()
+                    .map(|(j, i)| self.storage.coerce_lookup(j, tuple[*i].clone()))
                     .collect_vec();
                 let key = &prefix[0..key_len];
                 match self.storage.get(tx, key)? {
                     None => Ok(None),
                     Some(found) => {
                         for (lk, rk) in left_join_indices.iter().zip(right_join_indices.iter()) {
-                            if tuple[*lk] != found[*rk] {
+                            if self.storage.coerce_lookup(*rk, tuple[*lk].clone()) != found[*rk] {
                                 return Ok(None);
                             }
                         }
-                        for (p, span) in self.filters_bytecodes.iter() {
-                            if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
-                                return Ok(None);
-                            }
+                        if !passes_filters(&self.filters_bytecodes, &found, &mut stack, &stats)? {
+                            return Ok(None);
                         }
                         let mut ret = tuple;
                         ret.extend(found);
@@ -1309,6 +1480,7 @@ impl StoredRA {
         left_iter: TupleIter<'a>,
         (left_join_indices, right_join_indices): (Vec<usize>, Vec<usize>),
         eliminate_indices: BTreeSet<usize>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
         let mut right_invert_indices = right_join_indices.iter().enumerate().collect_vec();
         right_invert_indices.sort_by_key(|(_, b)| **b);
@@ -1327,6 +1499,7 @@ impl StoredRA {
                 eliminate_indices,
                 left_join_indices,
                 right_join_indices,
+                stats,
             );
         }
 
@@ -1334,9 +1507,14 @@ impl StoredRA {
         // In some cases, maybe we can stop as soon as we get one result?
         let it = left_iter
             .map_ok(move |tuple| {
+                if let Some(stats) = &stats {
+                    stats.incr_scans();
+                }
+                let stats = stats.clone();
                 let prefix = left_to_prefix_indices
                     .iter()
-                    .map(|i| tuple[*i].clone())
+                    .enumerate()
+                    .map(|(j, i)| self.storage.coerce_lookup(j, tuple[*i].clone()))
                     .collect_vec();
                 let mut stack = vec![];
 
@@ -1346,6 +1524,11 @@ impl StoredRA {
                         Ok(b) => b,
                         _ => (vec![], vec![]),
                     };
+                    let offset = right_join_indices.len();
+                    let coerce =
+                        |(j, v): (usize, DataValue)| self.storage.coerce_lookup(offset + j, v);
+                    let l_bound = l_bound.into_iter().enumerate().map(coerce).collect_vec();
+                    let u_bound = u_bound.into_iter().enumerate().map(coerce).collect_vec();
                     if !l_bound.iter().all(|v| *v == DataValue::Null)
                         || !u_bound.iter().all(|v| *v == DataValue::Bot)
                     {
@@ -1354,10 +1537,13 @@ impl StoredRA {
                                 .scan_bounded_prefix(tx, &prefix, &l_bound, &u_bound)
                                 .map(move |res_found| -> Result<Option<Tuple>> {
                                     let found = res_found?;
-                                    for (p, span) in self.filters_bytecodes.iter() {
-                                        if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
-                                            return Ok(None);
-                                        }
+                                    if !passes_filters(
+                                        &self.filters_bytecodes,
+                                        &found,
+                                        &mut stack,
+                                        &stats,
+                                    )? {
+                                        return Ok(None);
                                     }
                                     let mut ret = tuple.clone();
                                     ret.extend(found);
@@ -1373,10 +1559,9 @@ impl StoredRA {
                         .scan_prefix(tx, &prefix)
                         .map(move |res_found| -> Result<Option<Tuple>> {
                             let found = res_found?;
-                            for (p, span) in self.filters_bytecodes.iter() {
-                                if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
-                                    return Ok(None);
-                                }
+                            if !passes_filters(&self.filters_bytecodes, &found, &mut stack, &stats)?
+                            {
+                                return Ok(None);
                             }
                             let mut ret = tuple.clone();
                             ret.extend(found);
@@ -1418,7 +1603,8 @@ impl StoredRA {
                     .map_ok(move |tuple| -> Result<Option<Tuple>> {
                         let prefix = left_to_prefix_indices
                             .iter()
-                            .map(|i| tuple[*i].clone())
+                            .enumerate()
+                            .map(|(j, i)| self.storage.coerce_lookup(j, tuple[*i].clone()))
                             .collect_vec();
 
                         'outer: for found in self.storage.scan_prefix(tx, &prefix) {
@@ -1426,7 +1612,10 @@ impl StoredRA {
                             for (left_idx, right_idx) in
                                 left_join_indices.iter().zip(right_join_indices.iter())
                             {
-                                if tuple[*left_idx] != found[*right_idx] {
+                                let left_val = self
+                                    .storage
+                                    .coerce_lookup(*right_idx, tuple[*left_idx].clone());
+                                if left_val != found[*right_idx] {
                                     continue 'outer;
                                 }
                             }
@@ -1468,7 +1657,8 @@ impl StoredRA {
                     .map_ok(move |tuple| -> Result<Option<Tuple>> {
                         let left_join_vals: Box<[DataValue]> = left_join_indices
                             .iter()
-                            .map(|i| tuple[*i].clone())
+                            .zip(right_join_indices.iter())
+                            .map(|(l, r)| self.storage.coerce_lookup(*r, tuple[*l].clone()))
                             .collect();
                         if right_join_vals.contains(&left_join_vals) {
                             return Ok(None);
@@ -1496,16 +1686,60 @@ impl StoredRA {
         }
     }
 
-    fn iter<'a>(&'a self, tx: &'a SessionTx<'_>) -> Result<TupleIter<'a>> {
+    fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        stats: Option<Arc<OpStats>>,
+    ) -> Result<TupleIter<'a>> {
+        if let Some(stats) = &stats {
+            stats.incr_scans();
+        }
         let it = self.storage.scan_all(tx);
         Ok(if self.filters.is_empty() {
             Box::new(it)
         } else {
-            Box::new(filter_iter(self.filters_bytecodes.clone(), it))
+            Box::new(filter_iter(self.filters_bytecodes.clone(), it, stats))
         })
     }
 }
 
+fn passes_filters(
+    filters_bytecodes: &[(Vec<Bytecode>, SourceSpan)],
+    tuple: &Tuple,
+    stack: &mut Vec<DataValue>,
+    stats: &Option<Arc<OpStats>>,
+) -> Result<bool> {
+    for (p, span) in filters_bytecodes.iter() {
+        if !eval_bytecode_pred(p, tuple, stack, *span)? {
+            if let Some(stats) = stats {
+                stats.incr_filtered();
+            }
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Negative prefix joins scan the right relation once for every tuple from the left,
+/// whereas the other negative joins scan it once in total.
+fn count_join_scans(
+    left_iter: TupleIter<'_>,
+    is_prefix: bool,
+    stats: Option<Arc<OpStats>>,
+) -> TupleIter<'_> {
+    match stats {
+        None => left_iter,
+        Some(stats) => {
+            if is_prefix {
+                Box::new(left_iter.inspect(move |_| stats.incr_scans()))
+            } else {
+                stats.incr_scans();
+                left_iter
+            }
+        }
+    }
+}
+
 fn join_is_prefix(right_join_indices: &[usize]) -> bool {
     // We do not consider partial index match to be "prefix", e.g. [a, u => c]
     // with a, c bound and u unbound is not "prefix", as it is not clear that
@@ -1545,7 +1779,11 @@ impl TempStoreRA {
         &'a self,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
+        if let Some(stats) = &stats {
+            stats.incr_scans();
+        }
         let storage = stores.get(&self.storage_key).unwrap();
 
         let scan_epoch = match delta_rule {
@@ -1560,7 +1798,7 @@ impl TempStoreRA {
         Ok(if self.filters.is_empty() {
             Box::new(it)
         } else {
-            Box::new(filter_iter(self.filters_bytecodes.clone(), it))
+            Box::new(filter_iter(self.filters_bytecodes.clone(), it, stats))
         })
     }
     fn neg_join<'a>(
@@ -1668,6 +1906,7 @@ impl TempStoreRA {
         eliminate_indices: BTreeSet<usize>,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
         let storage = stores.get(&self.storage_key).unwrap();
 
@@ -1684,6 +1923,10 @@ impl TempStoreRA {
         let mut skip_range_check = false;
         let it = left_iter
             .map_ok(move |tuple| {
+                if let Some(stats) = &stats {
+                    stats.incr_scans();
+                }
+                let stats = stats.clone();
                 let prefix = left_to_prefix_indices
                     .iter()
                     .map(|i| tuple[*i].clone())
@@ -1712,14 +1955,17 @@ impl TempStoreRA {
                             it.map(move |res_found| -> Result<Option<Tuple>> {
                                 if self.filters.is_empty() {
                                     let mut ret = tuple.clone();
-                                    ret.extend(res_found.into_iter().cloned());
+                                    ret.extend(res_found.iter().cloned());
                                     Ok(Some(ret))
                                 } else {
                                     let found = res_found.into_tuple();
-                                    for (p, span) in self.filters_bytecodes.iter() {
-                                        if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
-                                            return Ok(None);
-                                        }
+                                    if !passes_filters(
+                                        &self.filters_bytecodes,
+                                        &found,
+                                        &mut stack,
+                                        &stats,
+                                    )? {
+                                        return Ok(None);
                                     }
                                     let mut ret = tuple.clone();
                                     ret.extend(found);
@@ -1742,14 +1988,13 @@ impl TempStoreRA {
                     it.map(move |res_found| -> Result<Option<Tuple>> {
                         if self.filters.is_empty() {
                             let mut ret = tuple.clone();
-                            ret.extend(res_found.into_iter().cloned());
+                            ret.extend(res_found.iter().cloned());
                             Ok(Some(ret))
                         } else {
                             let found = res_found.into_tuple();
-                            for (p, span) in self.filters_bytecodes.iter() {
-                                if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
-                                    return Ok(None);
-                                }
+                            if !passes_filters(&self.filters_bytecodes, &found, &mut stack, &stats)?
+                            {
+                                return Ok(None);
                             }
                             let mut ret = tuple.clone();
                             ret.extend(found);
@@ -1829,10 +2074,12 @@ impl RelAlgebra {
             RelAlgebra::Reorder(r) => r.relation.eliminate_temp_vars(used),
             RelAlgebra::Filter(r) => r.do_eliminate_temp_vars(used),
             RelAlgebra::NegJoin(r) => r.do_eliminate_temp_vars(used),
+            RelAlgebra::LeftJoin(r) => r.do_eliminate_temp_vars(used),
             RelAlgebra::Unification(r) => r.do_eliminate_temp_vars(used),
             RelAlgebra::HnswSearch(_) => Ok(()),
             RelAlgebra::FtsSearch(_) => Ok(()),
             RelAlgebra::LshSearch(_) => Ok(()),
+            RelAlgebra::SpatialSearch(_) => Ok(()),
         }
     }
 
@@ -1846,10 +2093,12 @@ impl RelAlgebra {
             RelAlgebra::Reorder(_) => None,
             RelAlgebra::Filter(r) => Some(&r.to_eliminate),
             RelAlgebra::NegJoin(r) => Some(&r.to_eliminate),
+            RelAlgebra::LeftJoin(r) => Some(&r.to_eliminate),
             RelAlgebra::Unification(u) => Some(&u.to_eliminate),
             RelAlgebra::HnswSearch(_) => None,
             RelAlgebra::FtsSearch(_) => None,
             RelAlgebra::LshSearch(_) => None,
+            RelAlgebra::SpatialSearch(_) => None,
         }
     }
 
@@ -1874,6 +2123,7 @@ impl RelAlgebra {
             RelAlgebra::Reorder(r) => r.bindings(),
             RelAlgebra::Filter(r) => r.parent.bindings_after_eliminate(),
             RelAlgebra::NegJoin(j) => j.left.bindings_after_eliminate(),
+            RelAlgebra::LeftJoin(j) => j.bindings(),
             RelAlgebra::Unification(u) => {
                 let mut bindings = u.parent.bindings_after_eliminate();
                 bindings.push(u.binding.clone());
@@ -1894,6 +2144,11 @@ impl RelAlgebra {
                 bindings.extend_from_slice(&s.own_bindings);
                 bindings
             }
+            RelAlgebra::SpatialSearch(s) => {
+                let mut bindings = s.parent.bindings_after_eliminate();
+                bindings.extend_from_slice(&s.own_bindings);
+                bindings
+            }
         }
     }
     pub(crate) fn iter<'a>(
@@ -1901,20 +2156,36 @@ impl RelAlgebra {
         tx: &'a SessionTx<'_>,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        match &tx.profiler {
+            None => self.iter_with_stats(tx, delta_rule, stores, None),
+            Some(profiler) => profile_iter(profiler.stats_for(self), |stats| {
+                self.iter_with_stats(tx, delta_rule, stores, Some(stats.clone()))
+            }),
+        }
+    }
+    fn iter_with_stats<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
         match self {
             RelAlgebra::Fixed(f) => Ok(Box::new(f.data.iter().map(|t| Ok(t.clone())))),
-            RelAlgebra::TempStore(r) => r.iter(delta_rule, stores),
-            RelAlgebra::Stored(v) => v.iter(tx),
-            RelAlgebra::StoredWithValidity(v) => v.iter(tx),
-            RelAlgebra::Join(j) => j.iter(tx, delta_rule, stores),
+            RelAlgebra::TempStore(r) => r.iter(delta_rule, stores, stats),
+            RelAlgebra::Stored(v) => v.iter(tx, stats),
+            RelAlgebra::StoredWithValidity(v) => v.iter(tx, stats),
+            RelAlgebra::Join(j) => j.iter(tx, delta_rule, stores, stats),
             RelAlgebra::Reorder(r) => r.iter(tx, delta_rule, stores),
-            RelAlgebra::Filter(r) => r.iter(tx, delta_rule, stores),
-            RelAlgebra::NegJoin(r) => r.iter(tx, delta_rule, stores),
+            RelAlgebra::Filter(r) => r.iter(tx, delta_rule, stores, stats),
+            RelAlgebra::NegJoin(r) => r.iter(tx, delta_rule, stores, stats),
+            RelAlgebra::LeftJoin(r) => r.iter(tx, delta_rule, stores, stats),
             RelAlgebra::Unification(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::HnswSearch(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::FtsSearch(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::LshSearch(r) => r.iter(tx, delta_rule, stores),
+            RelAlgebra::SpatialSearch(r) => r.iter(tx, delta_rule, stores),
         }
     }
 }
@@ -1983,6 +2254,7 @@ impl NegJoin {
         tx: &'a SessionTx<'_>,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
         let bindings = self.left.bindings_after_eliminate();
         let eliminate_indices = get_eliminate_indices(&bindings, &self.to_eliminate);
@@ -1995,8 +2267,9 @@ impl NegJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
+                let is_prefix = join_is_prefix(&join_indices.1);
                 r.neg_join(
-                    self.left.iter(tx, delta_rule, stores)?,
+                    count_join_scans(self.left.iter(tx, delta_rule, stores)?, is_prefix, stats),
                     join_indices,
                     eliminate_indices,
                     stores,
@@ -2010,9 +2283,10 @@ impl NegJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
+                let is_prefix = join_is_prefix(&join_indices.1);
                 v.neg_join(
                     tx,
-                    self.left.iter(tx, delta_rule, stores)?,
+                    count_join_scans(self.left.iter(tx, delta_rule, stores)?, is_prefix, stats),
                     join_indices,
                     eliminate_indices,
                 )
@@ -2024,12 +2298,138 @@ impl NegJoin {
     }
 }
 
+/// Joins each tuple from the left with the matching tuples of the stored relation on the right,
+/// or with nulls in place of the right tuple if there are none.
+#[derive(Debug)]
+pub(crate) struct LeftJoin {
+    pub(crate) left: RelAlgebra,
+    pub(crate) right: RelAlgebra,
+    pub(crate) joiner: Joiner,
+    pub(crate) to_eliminate: BTreeSet<Symbol>,
+    pub(crate) span: SourceSpan,
+}
+
+impl LeftJoin {
+    pub(crate) fn do_eliminate_temp_vars(&mut self, used: &BTreeSet<Symbol>) -> Result<()> {
+        for binding in self.bindings() {
+            if !used.contains(&binding) {
+                self.to_eliminate.insert(binding.clone());
+            }
+        }
+        let mut left = used.clone();
+        left.extend(self.joiner.left_keys.clone());
+        self.left.eliminate_temp_vars(&left)?;
+        // right is a stored relation, nothing to eliminate
+        Ok(())
+    }
+
+    pub(crate) fn bindings(&self) -> Vec<Symbol> {
+        let mut ret = self.left.bindings_after_eliminate();
+        ret.extend(self.right.bindings_after_eliminate());
+        debug_assert_eq!(ret.len(), ret.iter().collect::<BTreeSet<_>>().len());
+        ret
+    }
+
+    fn join_indices(&self) -> (Vec<usize>, Vec<usize>) {
+        self.joiner
+            .join_indices(
+                &self.left.bindings_after_eliminate(),
+                &self.right.bindings_after_eliminate(),
+            )
+            .unwrap()
+    }
+
+    /// Whether the matches for each tuple from the left are found by a prefix scan of the right,
+    /// instead of looking them up in a hash table of the whole right side.
+    fn is_prefix(&self) -> bool {
+        let (_, right_join_indices) = self.join_indices();
+        !right_join_indices.is_empty() && join_is_prefix(&right_join_indices)
+    }
+
+    pub(crate) fn join_type(&self) -> &str {
+        if self.is_prefix() {
+            "stored_left_prefix_join"
+        } else {
+            "left_hash_join"
+        }
+    }
+
+    pub(crate) fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+        stats: Option<Arc<OpStats>>,
+    ) -> Result<TupleIter<'a>> {
+        let eliminate_indices = get_eliminate_indices(&self.bindings(), &self.to_eliminate);
+        let right_arity = self.right.bindings_after_eliminate().len();
+        let pad = move |mut tuple: Tuple| {
+            tuple.extend(iter::repeat_n(DataValue::Null, right_arity));
+            tuple
+        };
+        let join_indices = self.join_indices();
+        let left_iter = self.left.iter(tx, delta_rule, stores)?;
+        let joined: TupleIter<'a> = if self.is_prefix() {
+            Box::new(
+                left_iter
+                    .map_ok(move |tuple| -> Result<Vec<Tuple>> {
+                        let single: TupleIter<'a> = Box::new(iter::once(Ok(tuple.clone())));
+                        let found: Vec<Tuple> = match &self.right {
+                            RelAlgebra::Stored(r) => r.prefix_join(
+                                tx,
+                                single,
+                                join_indices.clone(),
+                                BTreeSet::new(),
+                                stats.clone(),
+                            )?,
+                            RelAlgebra::StoredWithValidity(r) => r.prefix_join(
+                                tx,
+                                single,
+                                join_indices.clone(),
+                                BTreeSet::new(),
+                                stats.clone(),
+                            )?,
+                            _ => unreachable!(),
+                        }
+                        .try_collect()?;
+                        Ok(if found.is_empty() {
+                            vec![pad(tuple)]
+                        } else {
+                            found
+                        })
+                    })
+                    .map(flatten_err)
+                    .flatten_ok(),
+            )
+        } else {
+            let (left_join_indices, right_join_indices) = join_indices;
+            left_hash_join_tuples(
+                self.right.iter(tx, delta_rule, stores)?,
+                right_join_indices,
+                left_iter,
+                left_join_indices,
+                right_arity,
+                HASH_JOIN_SPILL_THRESHOLD,
+                &tx.memory,
+            )?
+        };
+        Ok(if eliminate_indices.is_empty() {
+            joined
+        } else {
+            Box::new(joined.map_ok(move |t| eliminate_from_tuple(t, &eliminate_indices)))
+        })
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct InnerJoin {
     pub(crate) left: RelAlgebra,
     pub(crate) right: RelAlgebra,
     pub(crate) joiner: Joiner,
     pub(crate) to_eliminate: BTreeSet<Symbol>,
+    /// Set by the planner when prefix lookups into a stored relation
+    /// are expected to cost more than a single scan of it
+    pub(crate) prefer_materialized: bool,
     pub(crate) span: SourceSpan,
 }
 
@@ -2077,7 +2477,7 @@ impl InnerJoin {
                 if join_is_prefix(&join_indices.1) {
                     "mem_prefix_join"
                 } else {
-                    "mem_mat_join"
+                    "hash_join"
                 }
             }
             RelAlgebra::Stored(_) => {
@@ -2088,15 +2488,16 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if join_is_prefix(&join_indices.1) && !self.prefer_materialized {
                     "stored_prefix_join"
                 } else {
-                    "stored_mat_join"
+                    "hash_join"
                 }
             }
             RelAlgebra::HnswSearch(_) => "hnsw_search_join",
             RelAlgebra::FtsSearch(_) => "fts_search_join",
             RelAlgebra::LshSearch(_) => "lsh_search_join",
+            RelAlgebra::SpatialSearch(_) => "spatial_search_join",
             RelAlgebra::StoredWithValidity(_) => {
                 let join_indices = self
                     .joiner
@@ -2105,15 +2506,16 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if join_is_prefix(&join_indices.1) && !self.prefer_materialized {
                     "stored_prefix_join"
                 } else {
-                    "stored_mat_join"
+                    "hash_join"
                 }
             }
-            RelAlgebra::Join(_) | RelAlgebra::Filter(_) | RelAlgebra::Unification(_) => {
-                "generic_mat_join"
-            }
+            RelAlgebra::Join(_)
+            | RelAlgebra::LeftJoin(_)
+            | RelAlgebra::Filter(_)
+            | RelAlgebra::Unification(_) => "hash_join",
             RelAlgebra::Reorder(_) => {
                 panic!("joining on reordered")
             }
@@ -2127,6 +2529,7 @@ impl InnerJoin {
         tx: &'a SessionTx<'_>,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+        stats: Option<Arc<OpStats>>,
     ) -> Result<TupleIter<'a>> {
         let bindings = self.bindings();
         let eliminate_indices = get_eliminate_indices(&bindings, &self.to_eliminate);
@@ -2160,9 +2563,10 @@ impl InnerJoin {
                         eliminate_indices,
                         delta_rule,
                         stores,
+                        stats,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, eliminate_indices, delta_rule, stores)
                 }
             }
             RelAlgebra::Stored(r) => {
@@ -2173,15 +2577,16 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if join_is_prefix(&join_indices.1) && !self.prefer_materialized {
                     r.prefix_join(
                         tx,
                         self.left.iter(tx, delta_rule, stores)?,
                         join_indices,
                         eliminate_indices,
+                        stats,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, eliminate_indices, delta_rule, stores)
                 }
             }
             RelAlgebra::StoredWithValidity(r) => {
@@ -2192,24 +2597,27 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if join_is_prefix(&join_indices.1) && !self.prefer_materialized {
                     r.prefix_join(
                         tx,
                         self.left.iter(tx, delta_rule, stores)?,
                         join_indices,
                         eliminate_indices,
+                        stats,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, eliminate_indices, delta_rule, stores)
                 }
             }
             RelAlgebra::Join(_)
+            | RelAlgebra::LeftJoin(_)
             | RelAlgebra::Filter(_)
             | RelAlgebra::Unification(_)
             | RelAlgebra::HnswSearch(_)
             | RelAlgebra::FtsSearch(_)
-            | RelAlgebra::LshSearch(_) => {
-                self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+            | RelAlgebra::LshSearch(_)
+            | RelAlgebra::SpatialSearch(_) => {
+                self.hash_join(tx, eliminate_indices, delta_rule, stores)
             }
             RelAlgebra::Reorder(_) => {
                 panic!("joining on reordered")
@@ -2219,163 +2627,389 @@ impl InnerJoin {
             }
         }
     }
-    fn materialized_join<'a>(
+    /// Number of tuples the right side is expected to produce, if it is cheaply known.
+    fn right_size_hint(
+        &self,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Option<usize> {
+        match &self.right {
+            RelAlgebra::TempStore(r) => stores
+                .get(&r.storage_key)
+                .map(|store| store.size_hint(delta_rule == Some(&r.storage_key))),
+            RelAlgebra::Stored(StoredRA { storage, .. })
+            | RelAlgebra::StoredWithValidity(StoredWithValidityRA { storage, .. }) => {
+                storage.stats.as_ref().map(|stats| stats.rows as usize)
+            }
+            _ => None,
+        }
+    }
+    fn hash_join<'a>(
         &'a self,
         tx: &'a SessionTx<'_>,
         eliminate_indices: BTreeSet<usize>,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
     ) -> Result<TupleIter<'a>> {
-        debug!("using materialized join");
-        let right_bindings = self.right.bindings_after_eliminate();
+        debug!("using hash join");
         let (left_join_indices, right_join_indices) = self
             .joiner
-            .join_indices(&self.left.bindings_after_eliminate(), &right_bindings)
+            .join_indices(
+                &self.left.bindings_after_eliminate(),
+                &self.right.bindings_after_eliminate(),
+            )
             .unwrap();
 
+        // The left side is buffered until it is known to be larger than the right side,
+        // in which case the right side is hashed. Otherwise the buffered left side is hashed.
+        // Without a size hint for the right side, the left side is hashed only if it
+        // has at most one tuple.
+        let right_size = self.right_size_hint(delta_rule, stores).unwrap_or(0);
         let mut left_iter = self.left.iter(tx, delta_rule, stores)?;
-        let left_cache = match left_iter.next() {
-            None => return Ok(Box::new(iter::empty())),
-            Some(Err(err)) => return Err(err),
-            Some(Ok(data)) => data,
-        };
-
-        let right_join_indices_set = BTreeSet::from_iter(right_join_indices.iter().cloned());
-        let mut right_store_indices = right_join_indices;
-        for i in 0..right_bindings.len() {
-            if !right_join_indices_set.contains(&i) {
-                right_store_indices.push(i)
-            }
-        }
-
-        let right_invert_indices = right_store_indices
-            .iter()
-            .enumerate()
-            .sorted_by_key(|(_, b)| **b)
-            .map(|(a, _)| a)
-            .collect_vec();
-        let cached_data = {
-            let mut cache = BTreeSet::new();
-            for item in self.right.iter(tx, delta_rule, stores)? {
-                match item {
-                    Ok(tuple) => {
-                        let stored_tuple = right_store_indices
-                            .iter()
-                            .map(|i| tuple[*i].clone())
-                            .collect_vec();
-                        cache.insert(stored_tuple);
-                    }
-                    Err(e) => return Err(e),
+        let mut left_buffer = vec![];
+        let mut left_exhausted = false;
+        while left_buffer.len() <= right_size {
+            match left_iter.next() {
+                None => {
+                    left_exhausted = true;
+                    break;
                 }
+                Some(tuple) => left_buffer.push(tuple?),
             }
-            cache.into_iter().collect_vec()
-        };
+        }
+        if left_buffer.is_empty() {
+            return Ok(Box::new(iter::empty()));
+        }
 
-        let (prefix, right_idx) =
-            build_mat_range_iter(&cached_data, &left_join_indices, &left_cache);
-
-        let it = CachedMaterializedIterator {
-            eliminate_indices,
-            left: left_iter,
-            left_cache,
-            left_join_indices,
-            materialized: cached_data,
-            right_invert_indices,
-            right_idx,
-            prefix,
+        let right_iter = self.right.iter(tx, delta_rule, stores)?;
+        let joined = if left_exhausted {
+            hash_join_tuples(
+                left_buffer.into_iter().map(Ok),
+                left_join_indices,
+                right_iter,
+                right_join_indices,
+                true,
+                HASH_JOIN_SPILL_THRESHOLD,
+                &tx.memory,
+            )?
+        } else {
+            hash_join_tuples(
+                right_iter,
+                right_join_indices,
+                left_buffer.into_iter().map(Ok).chain(left_iter),
+                left_join_indices,
+                false,
+                HASH_JOIN_SPILL_THRESHOLD,
+                &tx.memory,
+            )?
         };
-        Ok(Box::new(it))
+        Ok(Box::new(joined.map_ok(move |tuple| {
+            eliminate_from_tuple(tuple, &eliminate_indices)
+        })))
     }
 }
 
-struct CachedMaterializedIterator<'a> {
-    materialized: Vec<Tuple>,
-    eliminate_indices: BTreeSet<usize>,
-    left_join_indices: Vec<usize>,
-    right_invert_indices: Vec<usize>,
-    right_idx: usize,
-    prefix: Tuple,
-    left: TupleIter<'a>,
-    left_cache: Tuple,
+/// Number of tuples on the build side of a hash join above which
+/// both sides are partitioned into collections that may be swapped to disk.
+const HASH_JOIN_SPILL_THRESHOLD: usize = 1 << 20;
+
+/// Joins `build` and `probe` on the given columns by hashing `build` and looking up each
+/// tuple of `probe`, producing for each match the left tuple followed by the right tuple.
+/// As in the other joins, the right side is treated as a set, so that repeated right tuples
+/// only match once, whereas repeated left tuples each produce their matches.
+/// If `build` has more than `spill_threshold` tuples, or uses up the memory budget,
+/// both sides are partitioned by the hash of their join columns first,
+/// so that only one partition of `build` is hashed at a time.
+/// The same happens if the right tuples remembered to skip their repetitions grow too many.
+pub(crate) fn hash_join_tuples<'a>(
+    build: impl Iterator<Item = Result<Tuple>> + 'a,
+    build_keys: Vec<usize>,
+    probe: impl Iterator<Item = Result<Tuple>> + 'a,
+    probe_keys: Vec<usize>,
+    build_is_left: bool,
+    spill_threshold: usize,
+    budget: &Arc<MemoryBudget>,
+) -> Result<TupleIter<'a>> {
+    let table = JoinHashTable::new(build_keys, probe_keys, build_is_left);
+    hash_join_with_table(table, build, probe, spill_threshold, budget)
 }
 
-impl<'a> CachedMaterializedIterator<'a> {
-    fn advance_right(&mut self) -> Option<&Tuple> {
-        if self.right_idx == self.materialized.len() {
-            None
-        } else {
-            let ret = &self.materialized[self.right_idx];
-            if ret.starts_with(&self.prefix) {
-                self.right_idx += 1;
-                Some(ret)
-            } else {
-                None
-            }
+/// Left outer join of `left` with `right` by hashing `right`, spilling as [hash_join_tuples] does.
+/// Left tuples without a match are padded with `right_arity` nulls.
+pub(crate) fn left_hash_join_tuples<'a>(
+    right: impl Iterator<Item = Result<Tuple>> + 'a,
+    right_keys: Vec<usize>,
+    left: impl Iterator<Item = Result<Tuple>> + 'a,
+    left_keys: Vec<usize>,
+    right_arity: usize,
+    spill_threshold: usize,
+    budget: &Arc<MemoryBudget>,
+) -> Result<TupleIter<'a>> {
+    let mut table = JoinHashTable::new(right_keys, left_keys, false);
+    table.pad = Some(right_arity);
+    hash_join_with_table(table, right, left, spill_threshold, budget)
+}
+
+fn hash_join_with_table<'a>(
+    mut table: JoinHashTable,
+    mut build: impl Iterator<Item = Result<Tuple>> + 'a,
+    mut probe: impl Iterator<Item = Result<Tuple>> + 'a,
+    spill_threshold: usize,
+    budget: &Arc<MemoryBudget>,
+) -> Result<TupleIter<'a>> {
+    let mut reservation = MemoryReservation::new(budget);
+    let mut n_built = 0;
+    let mut should_spill = false;
+    for tuple in build.by_ref() {
+        let tuple = tuple?;
+        let size = tuple_size(&tuple);
+        if table.insert(tuple) {
+            reservation.grow(size);
+        }
+        n_built += 1;
+        if n_built > spill_threshold || reservation.should_spill() {
+            should_spill = true;
+            break;
         }
     }
-    fn next_inner(&mut self) -> Result<Option<Tuple>> {
-        loop {
-            let right_nxt = self.advance_right();
-            match right_nxt {
-                Some(data) => {
-                    let data = data.clone();
-                    let mut ret = self.left_cache.clone();
-                    for i in &self.right_invert_indices {
-                        ret.push(data[*i].clone());
-                    }
-                    let tuple = eliminate_from_tuple(ret, &self.eliminate_indices);
-                    return Ok(Some(tuple));
-                }
-                None => {
-                    let next_left = self.left.next();
-                    match next_left {
-                        None => return Ok(None),
-                        Some(l) => {
-                            let left_tuple = l?;
-                            let (prefix, idx) = build_mat_range_iter(
-                                &self.materialized,
-                                &self.left_join_indices,
-                                &left_tuple,
-                            );
-                            self.left_cache = left_tuple;
-
-                            self.right_idx = idx;
-                            self.prefix = prefix;
-                        }
-                    }
-                }
+    let probe_is_right = table.build_is_left;
+    if should_spill {
+        debug!("hash join spilling after {} tuples", n_built);
+        let partitioner = JoinPartitioner::default();
+        let build_keys = table.build_keys.clone();
+        let build_parts = partitioner.split(table.drain().map(Ok).chain(build), &build_keys)?;
+        drop(reservation);
+        let probe_parts = partitioner.split(probe, &table.probe_keys)?;
+        return Ok(join_partitions(
+            table.empty_like(),
+            build_parts,
+            probe_parts,
+            None,
+        ));
+    }
+    if !probe_is_right {
+        // the table is accounted for until the probe side is exhausted
+        return Ok(Box::new(probe.flat_map(move |tuple| match tuple {
+            Ok(tuple) => Left(table.probe(&tuple).into_iter().map(Ok)),
+            Err(err) => Right(iter::once(Err(err))),
+        })));
+    }
+    // Right tuples that found a match are remembered so that their repetitions are skipped.
+    // If they grow too many, the rest of the join is partitioned as above, together with them.
+    let mut seen_right = HashSet::new();
+    let mut found = vec![].into_iter();
+    let mut spilled: Option<TupleIter<'a>> = None;
+    Ok(Box::new(iter::from_fn(move || loop {
+        if let Some(tuple) = found.next() {
+            return Some(Ok(tuple));
+        }
+        if let Some(rest) = &mut spilled {
+            return rest.next();
+        }
+        let tuple = match probe.next()? {
+            Ok(tuple) => tuple,
+            Err(err) => return Some(Err(err)),
+        };
+        if seen_right.contains(&tuple) {
+            continue;
+        }
+        let matches = table.probe(&tuple);
+        if matches.is_empty() {
+            continue;
+        }
+        found = matches.into_iter();
+        reservation.grow(tuple_size(&tuple));
+        seen_right.insert(tuple);
+        if seen_right.len() > spill_threshold || reservation.should_spill() {
+            debug!(
+                "hash join spilling after {} distinct right tuples",
+                seen_right.len()
+            );
+            let partitioner = JoinPartitioner::default();
+            let build_keys = table.build_keys.clone();
+            let parts = partitioner
+                .split(table.drain().map(Ok), &build_keys)
+                .and_then(|build_parts| {
+                    let seen_parts =
+                        partitioner.split(seen_right.drain().map(Ok), &table.probe_keys)?;
+                    let probe_parts = partitioner.split(probe.by_ref(), &table.probe_keys)?;
+                    Ok((build_parts, probe_parts, seen_parts))
+                });
+            reservation.release();
+            spilled = Some(match parts {
+                Ok((build_parts, probe_parts, seen_parts)) => join_partitions(
+                    table.empty_like(),
+                    build_parts,
+                    probe_parts,
+                    Some(seen_parts),
+                ),
+                Err(err) => Box::new(iter::once(Err(err))),
+            });
+        }
+    })))
+}
+
+const HASH_JOIN_PARTITIONS: usize = 64;
+
+/// Splits the tuples of a hash join into collections that may be swapped to disk,
+/// by the hash of their join columns.
+#[derive(Default)]
+struct JoinPartitioner {
+    hasher: RandomState,
+}
+
+impl JoinPartitioner {
+    fn split(
+        &self,
+        tuples: impl Iterator<Item = Result<Tuple>>,
+        keys: &[usize],
+    ) -> Result<Vec<TempCollector<Tuple>>> {
+        let mut parts = (0..HASH_JOIN_PARTITIONS)
+            .map(|_| TempCollector::default())
+            .collect_vec();
+        for tuple in tuples {
+            let tuple = tuple?;
+            let mut state = self.hasher.build_hasher();
+            for i in keys {
+                tuple[*i].hash(&mut state);
             }
+            parts[state.finish() as usize % HASH_JOIN_PARTITIONS].push(tuple);
         }
+        Ok(parts)
     }
 }
 
-fn build_mat_range_iter(
-    mat: &[Tuple],
-    left_join_indices: &[usize],
-    left_tuple: &Tuple,
-) -> (Tuple, usize) {
-    let prefix = left_join_indices
-        .iter()
-        .map(|i| left_tuple[*i].clone())
-        .collect_vec();
-    let idx = match mat.binary_search(&prefix) {
-        Ok(i) => i,
-        Err(i) => i,
+/// Joins the partitions of both sides of a hash join pairwise, hashing one partition
+/// of the build side at a time. `seen_parts` are the partitioned right tuples
+/// whose matches have already been produced, if the right side is the probe side.
+fn join_partitions<'a>(
+    template: JoinHashTable,
+    build_parts: Vec<TempCollector<Tuple>>,
+    probe_parts: Vec<TempCollector<Tuple>>,
+    seen_parts: Option<Vec<TempCollector<Tuple>>>,
+) -> TupleIter<'a> {
+    let probe_is_right = template.build_is_left;
+    let seen_parts = match seen_parts {
+        Some(parts) => Left(parts.into_iter().map(Some)),
+        None => Right(iter::repeat_with(|| None)),
     };
-    (prefix, idx)
+    Box::new(
+        build_parts
+            .into_iter()
+            .zip(probe_parts)
+            .zip(seen_parts)
+            .flat_map(move |((build_part, probe_part), seen_part)| {
+                let mut table = template.empty_like();
+                for tuple in build_part.into_iter() {
+                    table.insert(tuple);
+                }
+                // repeated right tuples end up in the same partition
+                let mut seen_right: HashSet<Tuple> = seen_part
+                    .map(|part| part.into_iter().collect())
+                    .unwrap_or_default();
+                probe_part
+                    .into_iter()
+                    .filter(move |tuple| !probe_is_right || seen_right.insert(tuple.clone()))
+                    .flat_map(move |tuple| table.probe(&tuple).into_iter().map(Ok))
+            }),
+    )
 }
 
-impl<'a> Iterator for CachedMaterializedIterator<'a> {
-    type Item = Result<Tuple>;
+/// Build side of a hash join, with the tuples grouped by their join columns.
+/// Each tuple is stored with the number of times it was inserted, which is at most one
+/// for right tuples, as the right side of a join is a set.
+struct JoinHashTable {
+    map: HashMap<Tuple, BTreeMap<Tuple, usize>>,
+    build_keys: Vec<usize>,
+    probe_keys: Vec<usize>,
+    build_is_left: bool,
+    // for left outer joins, the number of nulls padding the left tuples without a match
+    pad: Option<usize>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        swap_option_result(self.next_inner())
+impl JoinHashTable {
+    fn new(build_keys: Vec<usize>, probe_keys: Vec<usize>, build_is_left: bool) -> Self {
+        Self {
+            map: Default::default(),
+            build_keys,
+            probe_keys,
+            build_is_left,
+            pad: None,
+        }
+    }
+    fn empty_like(&self) -> Self {
+        let mut ret = Self::new(
+            self.build_keys.clone(),
+            self.probe_keys.clone(),
+            self.build_is_left,
+        );
+        ret.pad = self.pad;
+        ret
+    }
+    /// Returns whether the tuple was not in the table before.
+    fn insert(&mut self, tuple: Tuple) -> bool {
+        let key = self
+            .build_keys
+            .iter()
+            .map(|i| tuple[*i].clone())
+            .collect_vec();
+        let count = self.map.entry(key).or_default().entry(tuple).or_insert(0);
+        let is_new = *count == 0;
+        if is_new || self.build_is_left {
+            *count += 1;
+        }
+        is_new
+    }
+    fn drain(&mut self) -> impl Iterator<Item = Tuple> + '_ {
+        self.map.drain().flat_map(|(_, tuples)| {
+            tuples
+                .into_iter()
+                .flat_map(|(tuple, count)| iter::repeat_n(tuple, count))
+        })
+    }
+    fn probe(&self, tuple: &Tuple) -> Vec<Tuple> {
+        let key = self
+            .probe_keys
+            .iter()
+            .map(|i| tuple[*i].clone())
+            .collect_vec();
+        match self.map.get(&key) {
+            None => match self.pad {
+                None => vec![],
+                Some(n) => {
+                    let mut ret = tuple.clone();
+                    ret.extend(iter::repeat_n(DataValue::Null, n));
+                    vec![ret]
+                }
+            },
+            Some(found) => found
+                .iter()
+                .flat_map(|(other, count)| iter::repeat_n(other, *count))
+                .map(|other| {
+                    let (left, right) = if self.build_is_left {
+                        (other, tuple)
+                    } else {
+                        (tuple, other)
+                    };
+                    let mut ret = Vec::with_capacity(left.len() + right.len());
+                    ret.extend_from_slice(left);
+                    ret.extend_from_slice(right);
+                    ret
+                })
+                .collect_vec(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use itertools::Itertools;
+
     use crate::data::value::DataValue;
+    use std::sync::Arc;
+
+    use crate::query::ra::{hash_join_tuples, left_hash_join_tuples};
+    use crate::runtime::spill::MemoryBudget;
     use crate::DbInstance;
 
     #[test]
@@ -2395,4 +3029,168 @@ mod tests {
             vec![vec![DataValue::from(1)], vec![DataValue::from(2)]]
         )
     }
+
+    #[test]
+    fn test_hash_join() {
+        let db = DbInstance::default();
+        let script = r#"
+        l[a, b] := a in int_range(100), b = a % 7
+        r[d, c] := d in int_range(10), c = d * 3
+        ?[a, d] := l[a, b], r[d, b]
+        "#;
+        let res = db.run_default(script).unwrap().rows;
+        let expected = (0..100)
+            .filter(|a| (a % 7) % 3 == 0 && (a % 7) / 3 < 10)
+            .map(|a| vec![DataValue::from(a), DataValue::from((a % 7) / 3)])
+            .collect_vec();
+        assert_eq!(res, expected);
+        let ops = db
+            .run_default(&format!("::explain {{ {script} }}"))
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| row[4].get_str().unwrap().to_string())
+            .collect_vec();
+        assert!(ops.contains(&"hash_join".to_string()));
+    }
+
+    #[test]
+    fn test_hash_join_spill() {
+        let left = (0..50)
+            .map(|i| vec![DataValue::from(i), DataValue::from(i % 5)])
+            .collect_vec();
+        let right = (0..10)
+            .map(|i| vec![DataValue::from(i % 5), DataValue::from(i)])
+            .collect_vec();
+        let join = |build_is_left: bool, spill_threshold: usize| {
+            let (build, build_keys, probe, probe_keys) = if build_is_left {
+                (left.clone(), vec![1], right.clone(), vec![0])
+            } else {
+                (right.clone(), vec![0], left.clone(), vec![1])
+            };
+            hash_join_tuples(
+                build.into_iter().map(Ok),
+                build_keys,
+                probe.into_iter().map(Ok),
+                probe_keys,
+                build_is_left,
+                spill_threshold,
+                &Arc::new(MemoryBudget::unlimited()),
+            )
+            .unwrap()
+            .map(|t| t.unwrap())
+            .sorted()
+            .collect_vec()
+        };
+        let expected = join(true, usize::MAX);
+        assert_eq!(expected.len(), 100);
+        assert!(expected.iter().all(|t| t[1] == t[2] && t.len() == 4));
+        assert_eq!(join(true, 3), expected);
+        assert_eq!(join(false, usize::MAX), expected);
+        assert_eq!(join(false, 3), expected);
+    }
+
+    #[test]
+    fn test_hash_join_duplicates() {
+        // the join columns of `right` are what is left after projection, hence the duplicates
+        let left = (0..20)
+            .map(|i| vec![DataValue::from(i % 10), DataValue::from(i % 2)])
+            .collect_vec();
+        let right = (0..10).map(|i| vec![DataValue::from(i % 2)]).collect_vec();
+        let join = |build_is_left: bool, spill_threshold: usize| {
+            let (build, build_keys, probe, probe_keys) = if build_is_left {
+                (left.clone(), vec![1], right.clone(), vec![0])
+            } else {
+                (right.clone(), vec![0], left.clone(), vec![1])
+            };
+            hash_join_tuples(
+                build.into_iter().map(Ok),
+                build_keys,
+                probe.into_iter().map(Ok),
+                probe_keys,
+                build_is_left,
+                spill_threshold,
+                &Arc::new(MemoryBudget::unlimited()),
+            )
+            .unwrap()
+            .map(|t| t.unwrap())
+            .sorted()
+            .collect_vec()
+        };
+        let expected = left
+            .iter()
+            .map(|t| vec![t[0].clone(), t[1].clone(), t[1].clone()])
+            .sorted()
+            .collect_vec();
+        assert_eq!(join(true, usize::MAX), expected);
+        assert_eq!(join(true, 3), expected);
+        assert_eq!(join(false, usize::MAX), expected);
+        assert_eq!(join(false, 3), expected);
+    }
+
+    #[test]
+    fn test_left_hash_join_spill() {
+        let left = (0..20)
+            .map(|i| vec![DataValue::from(i), DataValue::from(i % 7)])
+            .collect_vec();
+        let right = (0..10)
+            .map(|i| vec![DataValue::from(i % 5), DataValue::from(i)])
+            .collect_vec();
+        let join = |spill_threshold: usize| {
+            left_hash_join_tuples(
+                right.clone().into_iter().map(Ok),
+                vec![0],
+                left.clone().into_iter().map(Ok),
+                vec![1],
+                2,
+                spill_threshold,
+                &Arc::new(MemoryBudget::unlimited()),
+            )
+            .unwrap()
+            .map(|t| t.unwrap())
+            .sorted()
+            .collect_vec()
+        };
+        let expected = join(usize::MAX);
+        // left tuples with 5 or 6 in the join column have no match
+        assert_eq!(expected.len(), 15 * 2 + 5);
+        assert_eq!(
+            expected
+                .iter()
+                .filter(|t| t[2] == DataValue::Null && t[3] == DataValue::Null)
+                .count(),
+            5
+        );
+        assert_eq!(join(3), expected);
+    }
+
+    #[test]
+    fn test_hash_join_spill_seen_right() {
+        // the build side is small, but the repeated right tuples to skip are not
+        let left = vec![
+            vec![DataValue::from("a"), DataValue::from(0)],
+            vec![DataValue::from("b"), DataValue::from(1)],
+        ];
+        let right = (0..40)
+            .map(|i| vec![DataValue::from(i % 2), DataValue::from(i % 8)])
+            .collect_vec();
+        let join = |spill_threshold: usize| {
+            hash_join_tuples(
+                left.clone().into_iter().map(Ok),
+                vec![1],
+                right.clone().into_iter().map(Ok),
+                vec![0],
+                true,
+                spill_threshold,
+                &Arc::new(MemoryBudget::unlimited()),
+            )
+            .unwrap()
+            .map(|t| t.unwrap())
+            .sorted()
+            .collect_vec()
+        };
+        let expected = join(usize::MAX);
+        assert_eq!(expected.len(), 8);
+        assert_eq!(join(3), expected);
+    }
 }
@@ -13,7 +13,10 @@ use miette::{bail, Diagnostic, Result};
 use thiserror::Error;
 
 use crate::data::program::{NormalFormAtom, NormalFormInlineRule};
+use crate::data::symb::Symbol;
 use crate::parse::SourceSpan;
+use crate::runtime::relation::{RelationStats, DEFAULT_SELECTIVITY};
+use crate::runtime::transact::SessionTx;
 
 #[derive(Diagnostic, Debug, Error)]
 #[error("Encountered unsafe negation, or empty rule definition")]
@@ -30,14 +33,127 @@ pub(crate) struct UnsafeNegation(#[label] pub(crate) SourceSpan);
 #[diagnostic(code(eval::unbound_variable))]
 pub(crate) struct UnboundVariable(#[label] pub(crate) SourceSpan);
 
+/// Number of rows assumed for rule applications and relations that have not been analyzed.
+const UNKNOWN_ROWS: f64 = 1000.;
+
+fn estimate_rows(stats: Option<&RelationStats>, args: &[Symbol], bound: &BTreeSet<Symbol>) -> f64 {
+    let bound = args.iter().map(|a| bound.contains(a)).collect::<Vec<_>>();
+    match stats {
+        Some(stats) => stats.estimate_rows(&bound),
+        None => bound
+            .iter()
+            .filter(|b| **b)
+            .fold(UNKNOWN_ROWS, |est, _| est * DEFAULT_SELECTIVITY),
+    }
+}
+
+/// Greedily orders the rule and relation applications so that the one expected to produce
+/// the fewest rows given the variables bound so far comes first. Unifications and searches
+/// are placed as soon as their inputs are bound, since they may bind variables for
+/// the applications after them. Everything else is left to the binding-based reordering.
+/// The body is returned untouched unless some relation in it has been analyzed,
+/// in which case the user-written order is used for breaking ties only.
+fn order_by_cost(body: Vec<NormalFormAtom>, tx: &SessionTx<'_>) -> Result<Vec<NormalFormAtom>> {
+    let stats = body
+        .iter()
+        .map(|atom| match atom {
+            NormalFormAtom::Relation(v) => tx
+                .get_relation(&v.name, false)
+                .ok()
+                .and_then(|handle| handle.stats),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if stats.iter().all(|s| s.is_none()) {
+        return Ok(body);
+    }
+
+    let mut joinable = vec![];
+    let mut others = vec![];
+    for (atom, stats) in body.into_iter().zip(stats) {
+        match atom {
+            NormalFormAtom::Rule(_) | NormalFormAtom::Relation(_) => joinable.push((atom, stats)),
+            atom => others.push(atom),
+        }
+    }
+
+    let mut bound: BTreeSet<Symbol> = BTreeSet::default();
+    let mut ordered = vec![];
+    loop {
+        // place everything that has become ready, repeatedly since placing may bind more
+        loop {
+            let mut remaining = vec![];
+            let mut progressed = false;
+            for atom in others {
+                let ready = match &atom {
+                    NormalFormAtom::Unification(u) => u.bindings_in_expr()?.is_subset(&bound),
+                    NormalFormAtom::HnswSearch(s) => bound.contains(&s.query),
+                    NormalFormAtom::FtsSearch(s) => bound.contains(&s.query),
+                    NormalFormAtom::LshSearch(s) => bound.contains(&s.query),
+                    NormalFormAtom::SpatialSearch(s) => {
+                        s.input_bindings().all(|b| bound.contains(b))
+                    }
+                    _ => false,
+                };
+                if ready {
+                    match &atom {
+                        NormalFormAtom::Unification(u) => {
+                            bound.insert(u.binding.clone());
+                        }
+                        NormalFormAtom::HnswSearch(s) => bound.extend(s.all_bindings().cloned()),
+                        NormalFormAtom::FtsSearch(s) => bound.extend(s.all_bindings().cloned()),
+                        NormalFormAtom::LshSearch(s) => bound.extend(s.all_bindings().cloned()),
+                        NormalFormAtom::SpatialSearch(s) => bound.extend(s.all_bindings().cloned()),
+                        _ => unreachable!(),
+                    }
+                    progressed = true;
+                    ordered.push(atom);
+                } else {
+                    remaining.push(atom);
+                }
+            }
+            others = remaining;
+            if !progressed {
+                break;
+            }
+        }
+
+        if joinable.is_empty() {
+            break;
+        }
+        let mut best = 0;
+        let mut best_est = f64::INFINITY;
+        for (i, (atom, stats)) in joinable.iter().enumerate() {
+            let est = match atom {
+                NormalFormAtom::Rule(r) => estimate_rows(None, &r.args, &bound),
+                NormalFormAtom::Relation(v) => estimate_rows(stats.as_ref(), &v.args, &bound),
+                _ => unreachable!(),
+            };
+            if est < best_est {
+                best = i;
+                best_est = est;
+            }
+        }
+        let (atom, _) = joinable.remove(best);
+        match &atom {
+            NormalFormAtom::Rule(r) => bound.extend(r.args.iter().cloned()),
+            NormalFormAtom::Relation(v) => bound.extend(v.args.iter().cloned()),
+            _ => unreachable!(),
+        }
+        ordered.push(atom);
+    }
+    ordered.extend(others);
+    Ok(ordered)
+}
+
 impl NormalFormInlineRule {
-    pub(crate) fn convert_to_well_ordered_rule(self) -> Result<Self> {
+    pub(crate) fn convert_to_well_ordered_rule(self, tx: &SessionTx<'_>) -> Result<Self> {
         let mut seen_variables = BTreeSet::default();
         let mut round_1_collected = vec![];
         let mut pending = vec![];
 
         // first round: collect all unifications that are completely bounded
-        for atom in self.body {
+        for atom in order_by_cost(self.body, tx)? {
             match atom {
                 NormalFormAtom::Unification(u) => {
                     if u.is_const() {
@@ -69,6 +185,9 @@ impl NormalFormInlineRule {
                 NormalFormAtom::NegatedRelation(v) => {
                     pending.push(NormalFormAtom::NegatedRelation(v))
                 }
+                NormalFormAtom::OptionalRelation(v) => {
+                    pending.push(NormalFormAtom::OptionalRelation(v))
+                }
                 NormalFormAtom::Predicate(p) => {
                     pending.push(NormalFormAtom::Predicate(p));
                 }
@@ -96,16 +215,22 @@ impl NormalFormInlineRule {
                         pending.push(NormalFormAtom::LshSearch(s));
                     }
                 }
+                NormalFormAtom::SpatialSearch(s) => {
+                    if s.input_bindings().all(|b| seen_variables.contains(b)) {
+                        seen_variables.extend(s.all_bindings().cloned());
+                        round_1_collected.push(NormalFormAtom::SpatialSearch(s));
+                    } else {
+                        pending.push(NormalFormAtom::SpatialSearch(s));
+                    }
+                }
             }
         }
 
         let mut collected = vec![];
         seen_variables.clear();
-        let mut last_pending = vec![];
         // second round: insert pending where possible
         for atom in round_1_collected {
-            mem::swap(&mut last_pending, &mut pending);
-            pending.clear();
+            let last_pending = mem::take(&mut pending);
             match atom {
                 NormalFormAtom::Rule(r) => {
                     seen_variables.extend(r.args.iter().cloned());
@@ -117,6 +242,7 @@ impl NormalFormInlineRule {
                 }
                 NormalFormAtom::NegatedRule(_)
                 | NormalFormAtom::NegatedRelation(_)
+                | NormalFormAtom::OptionalRelation(_)
                 | NormalFormAtom::Predicate(_) => {
                     unreachable!()
                 }
@@ -136,70 +262,34 @@ impl NormalFormInlineRule {
                     seen_variables.extend(s.all_bindings().cloned());
                     collected.push(NormalFormAtom::LshSearch(s));
                 }
-            }
-            for atom in last_pending.iter() {
-                match atom {
-                    NormalFormAtom::Rule(_) | NormalFormAtom::Relation(_) => unreachable!(),
-                    NormalFormAtom::NegatedRule(r) => {
-                        if r.args.iter().all(|a| seen_variables.contains(a)) {
-                            collected.push(NormalFormAtom::NegatedRule(r.clone()));
-                        } else {
-                            pending.push(NormalFormAtom::NegatedRule(r.clone()));
-                        }
-                    }
-                    NormalFormAtom::NegatedRelation(v) => {
-                        if v.args.iter().all(|a| seen_variables.contains(a)) {
-                            collected.push(NormalFormAtom::NegatedRelation(v.clone()));
-                        } else {
-                            pending.push(NormalFormAtom::NegatedRelation(v.clone()));
-                        }
-                    }
-                    NormalFormAtom::HnswSearch(s) => {
-                        if seen_variables.contains(&s.query) {
-                            seen_variables.extend(s.all_bindings().cloned());
-                            collected.push(NormalFormAtom::HnswSearch(s.clone()));
-                        } else {
-                            pending.push(NormalFormAtom::HnswSearch(s.clone()));
-                        }
-                    }
-                    NormalFormAtom::FtsSearch(s) => {
-                        if seen_variables.contains(&s.query) {
-                            seen_variables.extend(s.all_bindings().cloned());
-                            collected.push(NormalFormAtom::FtsSearch(s.clone()));
-                        } else {
-                            pending.push(NormalFormAtom::FtsSearch(s.clone()));
-                        }
-                    }
-                    NormalFormAtom::LshSearch(s) => {
-                        if seen_variables.contains(&s.query) {
-                            seen_variables.extend(s.all_bindings().cloned());
-                            collected.push(NormalFormAtom::LshSearch(s.clone()));
-                        } else {
-                            pending.push(NormalFormAtom::LshSearch(s.clone()));
-                        }
-                    }
-                    NormalFormAtom::Predicate(p) => {
-                        if p.bindings()?.is_subset(&seen_variables) {
-                            collected.push(NormalFormAtom::Predicate(p.clone()));
-                        } else {
-                            pending.push(NormalFormAtom::Predicate(p.clone()));
-                        }
-                    }
-                    NormalFormAtom::Unification(u) => {
-                        if u.bindings_in_expr()?.is_subset(&seen_variables) {
-                            collected.push(NormalFormAtom::Unification(u.clone()));
-                        } else {
-                            pending.push(NormalFormAtom::Unification(u.clone()));
-                        }
-                    }
+                NormalFormAtom::SpatialSearch(s) => {
+                    seen_variables.extend(s.all_bindings().cloned());
+                    collected.push(NormalFormAtom::SpatialSearch(s));
                 }
             }
+            pending = place_ready(last_pending, &mut seen_variables, &mut collected)?;
+        }
+
+        // third round: optional atoms come after everything else they could join with,
+        // as the variables they share with the rest of the body are the keys of the outer
+        // join. The other variables of an optional atom are bound, but possibly to null.
+        let (optional, mut pending): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|atom| matches!(atom, NormalFormAtom::OptionalRelation(_)));
+        for atom in optional {
+            if let NormalFormAtom::OptionalRelation(v) = &atom {
+                seen_variables.extend(v.args.iter().cloned());
+            }
+            collected.push(atom);
+            pending = place_ready(pending, &mut seen_variables, &mut collected)?;
         }
 
         if !pending.is_empty() {
             for atom in pending {
                 match atom {
-                    NormalFormAtom::Rule(_) | NormalFormAtom::Relation(_) => unreachable!(),
+                    NormalFormAtom::Rule(_)
+                    | NormalFormAtom::Relation(_)
+                    | NormalFormAtom::OptionalRelation(_) => unreachable!(),
                     NormalFormAtom::NegatedRule(r) => {
                         if r.args.iter().any(|a| seen_variables.contains(a)) {
                             collected.push(NormalFormAtom::NegatedRule(r.clone()));
@@ -229,6 +319,9 @@ impl NormalFormInlineRule {
                     NormalFormAtom::LshSearch(s) => {
                         bail!(UnboundVariable(s.span))
                     }
+                    NormalFormAtom::SpatialSearch(s) => {
+                        bail!(UnboundVariable(s.span))
+                    }
                 }
             }
         }
@@ -240,3 +333,44 @@ impl NormalFormInlineRule {
         })
     }
 }
+
+/// Places the pending atoms whose inputs have all been bound, returning those still pending.
+fn place_ready(
+    pending: Vec<NormalFormAtom>,
+    seen_variables: &mut BTreeSet<Symbol>,
+    collected: &mut Vec<NormalFormAtom>,
+) -> Result<Vec<NormalFormAtom>> {
+    let mut still_pending = vec![];
+    for atom in pending {
+        let ready = match &atom {
+            NormalFormAtom::Rule(_) | NormalFormAtom::Relation(_) => unreachable!(),
+            NormalFormAtom::OptionalRelation(_) => false,
+            NormalFormAtom::NegatedRule(r) => r.args.iter().all(|a| seen_variables.contains(a)),
+            NormalFormAtom::NegatedRelation(v) => v.args.iter().all(|a| seen_variables.contains(a)),
+            NormalFormAtom::HnswSearch(s) => seen_variables.contains(&s.query),
+            NormalFormAtom::FtsSearch(s) => seen_variables.contains(&s.query),
+            NormalFormAtom::LshSearch(s) => seen_variables.contains(&s.query),
+            NormalFormAtom::SpatialSearch(s) => {
+                s.input_bindings().all(|b| seen_variables.contains(b))
+            }
+            NormalFormAtom::Predicate(p) => p.bindings()?.is_subset(seen_variables),
+            NormalFormAtom::Unification(u) => u.bindings_in_expr()?.is_subset(seen_variables),
+        };
+        if !ready {
+            still_pending.push(atom);
+            continue;
+        }
+        match &atom {
+            NormalFormAtom::HnswSearch(s) => seen_variables.extend(s.all_bindings().cloned()),
+            NormalFormAtom::FtsSearch(s) => seen_variables.extend(s.all_bindings().cloned()),
+            NormalFormAtom::LshSearch(s) => seen_variables.extend(s.all_bindings().cloned()),
+            NormalFormAtom::SpatialSearch(s) => seen_variables.extend(s.all_bindings().cloned()),
+            NormalFormAtom::Unification(u) => {
+                seen_variables.insert(u.binding.clone());
+            }
+            _ => {}
+        }
+        collected.push(atom);
+    }
+    Ok(still_pending)
+}
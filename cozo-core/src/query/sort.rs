@@ -15,6 +15,7 @@ use miette::Result;
 use crate::data::program::SortDir;
 use crate::data::symb::Symbol;
 use crate::data::tuple::Tuple;
+use crate::runtime::spill::ExternalSorter;
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 
@@ -24,15 +25,15 @@ impl<'a> SessionTx<'a> {
         original: EpochStore,
         sorters: &[(Symbol, SortDir)],
         head: &[Symbol],
-    ) -> Result<Vec<Tuple>> {
+    ) -> Result<impl Iterator<Item = Tuple>> {
         let head_indices: BTreeMap<_, _> = head.iter().enumerate().map(|(i, k)| (k, i)).collect();
         let idx_sorters = sorters
             .iter()
             .map(|(k, dir)| (head_indices[k], *dir))
             .collect_vec();
 
-        let mut all_data: Vec<_> = original.all_iter().map(|v| v.into_tuple()).collect_vec();
-        all_data.sort_by(|a, b| {
+        // ties are broken by the whole tuple, which is the order of the store
+        let cmp = move |a: &Tuple, b: &Tuple| {
             for (idx, dir) in &idx_sorters {
                 match a[*idx].cmp(&b[*idx]) {
                     Ordering::Equal => {}
@@ -44,9 +45,22 @@ impl<'a> SessionTx<'a> {
                     }
                 }
             }
-            Ordering::Equal
-        });
+            a.cmp(b)
+        };
+        let mut sorter = ExternalSorter::new(&self.memory, cmp);
+        for tuple in original.into_all_iter() {
+            sorter.push(tuple)?;
+        }
+        self.memory.check()?;
 
-        Ok(all_data)
+        // errors reading back the sorted runs are returned by the next check of the budget
+        let memory = self.memory.clone();
+        Ok(sorter.finish()?.map_while(move |tuple| match tuple {
+            Ok(tuple) => Some(tuple),
+            Err(err) => {
+                memory.report(err);
+                None
+            }
+        }))
     }
 }
@@ -15,9 +15,11 @@ use pest::Parser;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::expr::{Bytecode, Expr};
+use crate::data::expr::{eval_bytecode_pred, Bytecode, Expr};
 use crate::data::program::{FixedRuleApply, InputInlineRulesOrFixed, InputProgram, RelationOp};
-use crate::data::relation::{ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::relation::{
+    ColumnDef, ForeignKey, ForeignKeyAction, NullableColType, StoredRelationMetadata,
+};
 use crate::data::symb::Symbol;
 use crate::data::tuple::{Tuple, ENCODED_KEY_MIN_LEN};
 use crate::data::value::{DataValue, ValidityTs};
@@ -32,6 +34,7 @@ use crate::runtime::relation::{
     extend_tuple_from_v, AccessLevel, InputRelationHandle, InsufficientAccessLevel, RelationHandle,
 };
 use crate::runtime::transact::SessionTx;
+use crate::runtime::view::row_changes;
 use crate::storage::Storage;
 use crate::{Db, NamedRows, SourceSpan, StoreTx};
 
@@ -72,6 +75,24 @@ impl<'a> SessionTx<'a> {
                     struct ReplaceRelationWithIndices(String);
                     bail!(ReplaceRelationWithIndices(old_handle.name.to_string()))
                 }
+                if !old_handle.views.is_empty() {
+                    #[derive(Debug, Error, Diagnostic)]
+                    #[error("cannot replace relation {0} since views read it")]
+                    #[diagnostic(code(eval::replace_rel_with_views))]
+                    struct ReplaceRelationWithViews(String);
+                    bail!(ReplaceRelationWithViews(old_handle.name.to_string()))
+                }
+                if old_handle
+                    .referenced_by
+                    .iter()
+                    .any(|r| *r != old_handle.name)
+                {
+                    #[derive(Debug, Error, Diagnostic)]
+                    #[error("cannot replace relation {0} since other relations reference it")]
+                    #[diagnostic(code(eval::replace_rel_with_references))]
+                    struct ReplaceRelationWithReferences(String);
+                    bail!(ReplaceRelationWithReferences(old_handle.name.to_string()))
+                }
                 if old_handle.access_level < AccessLevel::Normal {
                     bail!(InsufficientAccessLevel(
                         old_handle.name.to_string(),
@@ -86,6 +107,7 @@ impl<'a> SessionTx<'a> {
                     let program = parse_script(
                         trigger,
                         &Default::default(),
+                        &db.custom_registry.read().unwrap(),
                         &db.fixed_rules.read().unwrap(),
                         cur_vld,
                     )?
@@ -128,6 +150,7 @@ impl<'a> SessionTx<'a> {
             metadata,
             key_bindings,
             dep_bindings,
+            condition,
             span,
             ..
         } = meta;
@@ -182,24 +205,28 @@ impl<'a> SessionTx<'a> {
                 force_collect,
                 *span,
             )?,
-            RelationOp::Create | RelationOp::Replace | RelationOp::Put | RelationOp::Insert => self
-                .put_into_relation(
-                    db,
-                    res_iter,
-                    headers,
-                    cur_vld,
-                    callback_targets,
-                    callback_collector,
-                    propagate_triggers,
-                    &mut to_clear,
-                    &relation_store,
-                    metadata,
-                    key_bindings,
-                    dep_bindings,
-                    op == RelationOp::Insert,
-                    force_collect,
-                    *span,
-                )?,
+            RelationOp::Create
+            | RelationOp::Replace
+            | RelationOp::Put
+            | RelationOp::PutIf
+            | RelationOp::Insert => self.put_into_relation(
+                db,
+                res_iter,
+                headers,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                propagate_triggers,
+                &mut to_clear,
+                &relation_store,
+                metadata,
+                key_bindings,
+                dep_bindings,
+                condition.as_ref(),
+                op == RelationOp::Insert,
+                force_collect,
+                *span,
+            )?,
         };
 
         Ok(to_clear)
@@ -219,6 +246,7 @@ impl<'a> SessionTx<'a> {
         metadata: &StoredRelationMetadata,
         key_bindings: &[Symbol],
         dep_bindings: &[Symbol],
+        condition: Option<&Expr>,
         is_insert: bool,
         force_collect: &str,
         span: SourceSpan,
@@ -244,11 +272,13 @@ impl<'a> SessionTx<'a> {
         let need_to_collect = !force_collect.is_empty()
             || (!relation_store.is_temp
                 && (is_callback_target
+                    || !relation_store.views.is_empty()
                     || (propagate_triggers && !relation_store.put_triggers.is_empty())));
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
         let has_lsh_indices = !relation_store.lsh_indices.is_empty();
+        let has_spatial_indices = !relation_store.spatial_indices.is_empty();
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
 
@@ -269,9 +299,16 @@ impl<'a> SessionTx<'a> {
         };
         key_extractors.extend(val_extractors);
         let mut stack = vec![];
-        let hnsw_filters = Self::make_hnsw_filters(relation_store)?;
+        let hnsw_filters = self.make_hnsw_filters(relation_store)?;
         let fts_lsh_processors = self.make_fts_lsh_processors(relation_store)?;
         let lsh_perms = self.make_lsh_hash_perms(relation_store);
+        let condition = match condition {
+            None => None,
+            Some(condition) => Some(make_put_condition(condition, relation_store, headers)?),
+        };
+        let references = self.make_references(relation_store)?;
+        let mut referenced_values = vec![BTreeSet::new(); references.len()];
+        let mut constraints = relation_store.constraint_checker()?;
 
         for tuple in res_iter {
             let extracted: Vec<DataValue> = key_extractors
@@ -281,6 +318,36 @@ impl<'a> SessionTx<'a> {
 
             let key = relation_store.encode_key_for_store(&extracted, span)?;
 
+            if let Some((condition, cond_span)) = &condition {
+                let existing = if relation_store.is_temp {
+                    self.temp_store_tx.get(&key, true)?
+                } else {
+                    self.store_tx.get(&key, true)?
+                };
+                // the condition sees the existing row, or nulls if there is none,
+                // the row to be written, and the row of the query
+                let mut bindings = match existing {
+                    None => vec![DataValue::Null; relation_store.arity()],
+                    Some(existing) => {
+                        let mut tup = extracted[0..relation_store.metadata.keys.len()].to_vec();
+                        extend_tuple_from_v(&mut tup, &existing);
+                        tup
+                    }
+                };
+                bindings.extend_from_slice(&extracted);
+                bindings.extend_from_slice(&tuple);
+                if !eval_bytecode_pred(condition, &bindings, &mut stack, *cond_span)? {
+                    self.rejected_rows
+                        .entry(relation_store.name.clone())
+                        .or_default()
+                        .push(extracted);
+                    continue;
+                }
+            }
+
+            constraints.check(&extracted)?;
+            collect_referenced_values(&references, &extracted, &mut referenced_values);
+
             if is_insert {
                 let already_exists = if relation_store.is_temp {
                     self.temp_store_tx.exists(&key, true)?
@@ -304,6 +371,7 @@ impl<'a> SessionTx<'a> {
                 || has_hnsw_indices
                 || has_fts_indices
                 || has_lsh_indices
+                || has_spatial_indices
             {
                 if let Some(existing) = self.store_tx.get(&key, false)? {
                     let mut tup = extracted[0..relation_store.metadata.keys.len()].to_vec();
@@ -313,16 +381,14 @@ impl<'a> SessionTx<'a> {
                         self.del_in_fts(relation_store, &mut stack, &fts_lsh_processors, &tup)?;
                         self.del_in_lsh(relation_store, &tup)?;
                     }
+                    self.del_in_spatial(relation_store, &tup)?;
 
                     if need_to_collect {
                         old_tuples.push(DataValue::List(tup));
                     }
                 } else if has_indices {
                     for (idx_rel, extractor) in relation_store.indices.values() {
-                        let idx_tup_new = extractor
-                            .iter()
-                            .map(|i| extracted[*i].clone())
-                            .collect_vec();
+                        let idx_tup_new = idx_rel.extract_index_row(extractor, &extracted);
                         let encoded_new =
                             idx_rel.encode_key_for_store(&idx_tup_new, Default::default())?;
                         self.store_tx.put(&encoded_new, &[])?;
@@ -338,6 +404,7 @@ impl<'a> SessionTx<'a> {
                     &extracted,
                     &lsh_perms,
                 )?;
+                self.put_in_spatial(relation_store, &extracted)?;
 
                 if need_to_collect {
                     new_tuples.push(DataValue::List(extracted));
@@ -351,6 +418,9 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        // checked after all rows are written, so that rows may reference each other
+        self.ensure_referenced_rows(relation_store, &references, referenced_values, span)?;
+
         if need_to_collect && !new_tuples.is_empty() {
             self.collect_mutations(
                 db,
@@ -428,6 +498,20 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    fn put_in_spatial(&mut self, rel_handle: &RelationHandle, new_kv: &[DataValue]) -> Result<()> {
+        for (idx_handle, manifest) in rel_handle.spatial_indices.values() {
+            self.spatial_put(manifest, rel_handle, idx_handle, new_kv)?;
+        }
+        Ok(())
+    }
+
+    fn del_in_spatial(&mut self, rel_handle: &RelationHandle, old_kv: &[DataValue]) -> Result<()> {
+        for (idx_handle, manifest) in rel_handle.spatial_indices.values() {
+            self.spatial_remove(manifest, rel_handle, idx_handle, old_kv)?;
+        }
+        Ok(())
+    }
+
     fn update_in_hnsw(
         &mut self,
         relation_store: &RelationHandle,
@@ -474,7 +558,11 @@ impl<'a> SessionTx<'a> {
                 .into_diagnostic()?
                 .next()
                 .unwrap();
-            let mut code_expr = build_expr(parsed, &Default::default())?;
+            let mut code_expr = build_expr(
+                parsed,
+                &Default::default(),
+                &self.custom_registry.read().unwrap(),
+            )?;
             let binding_map = relation_store.raw_binding_map();
             code_expr.fill_binding_indices(&binding_map)?;
             let extractor = code_expr.compile()?;
@@ -489,7 +577,11 @@ impl<'a> SessionTx<'a> {
                 .into_diagnostic()?
                 .next()
                 .unwrap();
-            let mut code_expr = build_expr(parsed, &Default::default())?;
+            let mut code_expr = build_expr(
+                parsed,
+                &Default::default(),
+                &self.custom_registry.read().unwrap(),
+            )?;
             let binding_map = relation_store.raw_binding_map();
             code_expr.fill_binding_indices(&binding_map)?;
             let extractor = code_expr.compile()?;
@@ -499,6 +591,7 @@ impl<'a> SessionTx<'a> {
     }
 
     fn make_hnsw_filters(
+        &self,
         relation_store: &RelationHandle,
     ) -> Result<BTreeMap<SmartString<LazyCompact>, Vec<Bytecode>>> {
         let mut hnsw_filters = BTreeMap::new();
@@ -508,7 +601,11 @@ impl<'a> SessionTx<'a> {
                     .into_diagnostic()?
                     .next()
                     .unwrap();
-                let mut code_expr = build_expr(parsed, &Default::default())?;
+                let mut code_expr = build_expr(
+                    parsed,
+                    &Default::default(),
+                    &self.custom_registry.read().unwrap(),
+                )?;
                 let binding_map = relation_store.raw_binding_map();
                 code_expr.fill_binding_indices(&binding_map)?;
                 hnsw_filters.insert(name.clone(), code_expr.compile()?);
@@ -554,11 +651,13 @@ impl<'a> SessionTx<'a> {
         let need_to_collect = !force_collect.is_empty()
             || (!relation_store.is_temp
                 && (is_callback_target
+                    || !relation_store.views.is_empty()
                     || (propagate_triggers && !relation_store.put_triggers.is_empty())));
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
         let has_lsh_indices = !relation_store.lsh_indices.is_empty();
+        let has_spatial_indices = !relation_store.spatial_indices.is_empty();
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
 
@@ -570,9 +669,12 @@ impl<'a> SessionTx<'a> {
         )?;
 
         let mut stack = vec![];
-        let hnsw_filters = Self::make_hnsw_filters(relation_store)?;
+        let hnsw_filters = self.make_hnsw_filters(relation_store)?;
         let fts_lsh_processors = self.make_fts_lsh_processors(relation_store)?;
         let lsh_perms = self.make_lsh_hash_perms(relation_store);
+        let references = self.make_references(relation_store)?;
+        let mut referenced_values = vec![BTreeSet::new(); references.len()];
+        let mut constraints = relation_store.constraint_checker()?;
 
         for tuple in res_iter {
             let mut new_kv: Vec<DataValue> = key_extractors
@@ -612,15 +714,19 @@ impl<'a> SessionTx<'a> {
                 }
             }
             let new_val = relation_store.encode_val_for_store(&new_kv, span)?;
+            constraints.check(&new_kv)?;
+            collect_referenced_values(&references, &new_kv, &mut referenced_values);
 
             if need_to_collect
                 || has_indices
                 || has_hnsw_indices
                 || has_fts_indices
                 || has_lsh_indices
+                || has_spatial_indices
             {
                 self.del_in_fts(relation_store, &mut stack, &fts_lsh_processors, &old_kv)?;
                 self.del_in_lsh(relation_store, &old_kv)?;
+                self.del_in_spatial(relation_store, &old_kv)?;
                 self.update_in_index(relation_store, &new_kv, &old_kv)?;
 
                 if need_to_collect {
@@ -636,6 +742,7 @@ impl<'a> SessionTx<'a> {
                     &new_kv,
                     &lsh_perms,
                 )?;
+                self.put_in_spatial(relation_store, &new_kv)?;
 
                 if need_to_collect {
                     new_tuples.push(DataValue::List(new_kv));
@@ -649,6 +756,8 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        self.ensure_referenced_rows(relation_store, &references, referenced_values, span)?;
+
         if need_to_collect && !new_tuples.is_empty() {
             self.collect_mutations(
                 db,
@@ -666,6 +775,215 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    /// The positions of the columns with foreign keys, together with the referenced relations.
+    pub(crate) fn make_references(
+        &self,
+        relation_store: &RelationHandle,
+    ) -> Result<Vec<(usize, RelationHandle)>> {
+        let binding_map = relation_store.raw_binding_map();
+        relation_store
+            .metadata
+            .foreign_keys
+            .iter()
+            .map(|fk| -> Result<_> {
+                let pos = binding_map[&Symbol::new(fk.column.clone(), Default::default())];
+                Ok((pos, self.get_relation(&fk.relation, false)?))
+            })
+            .try_collect()
+    }
+
+    pub(crate) fn ensure_referenced_rows(
+        &self,
+        relation_store: &RelationHandle,
+        references: &[(usize, RelationHandle)],
+        referenced_values: Vec<BTreeSet<DataValue>>,
+        span: SourceSpan,
+    ) -> Result<()> {
+        let columns = relation_store.metadata.foreign_keys.iter().map(|fk| &fk.column);
+        for ((column, (_, target)), values) in columns.zip(references).zip(referenced_values) {
+            for value in values {
+                let key = target.encode_key_for_store(std::slice::from_ref(&value), span)?;
+                if !self.store_tx.exists(&key, false)? {
+                    bail!(ForeignKeyViolation {
+                        relation: relation_store.name.to_string(),
+                        column: column.to_string(),
+                        value,
+                        notice: format!("no such key in relation {}", target.name),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if rows of other relations reference any of the removed keys: imports remove
+    /// rows without cascading.
+    pub(crate) fn ensure_unreferenced(
+        &self,
+        relation_store: &RelationHandle,
+        removed_keys: &BTreeSet<DataValue>,
+    ) -> Result<()> {
+        if removed_keys.is_empty() {
+            return Ok(());
+        }
+        for referencing in &relation_store.referenced_by {
+            let child = self.get_relation(referencing, false)?;
+            let binding_map = child.raw_binding_map();
+            for fk in &child.metadata.foreign_keys {
+                if fk.relation != relation_store.name {
+                    continue;
+                }
+                let pos = binding_map[&Symbol::new(fk.column.clone(), Default::default())];
+                if let Some(row) = self
+                    .referencing_rows(&child, fk, pos, removed_keys)?
+                    .first()
+                {
+                    bail!(ForeignKeyViolation {
+                        relation: child.name.to_string(),
+                        column: fk.column.to_string(),
+                        value: row[pos].clone(),
+                        notice: format!(
+                            "key is still referenced after removal from relation {}",
+                            relation_store.name
+                        ),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Restricts or cascades the removal of keys referenced by rows of other relations.
+    fn remove_referencing_rows<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        propagate_triggers: bool,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        relation_store: &RelationHandle,
+        removed_keys: &BTreeSet<DataValue>,
+        span: SourceSpan,
+    ) -> Result<()> {
+        for referencing in &relation_store.referenced_by {
+            let child = self.get_relation(referencing, false)?;
+            let binding_map = child.raw_binding_map();
+            for fk in &child.metadata.foreign_keys {
+                if fk.relation != relation_store.name {
+                    continue;
+                }
+                let pos = binding_map[&Symbol::new(fk.column.clone(), Default::default())];
+                let found = self.referencing_rows(&child, fk, pos, removed_keys)?;
+                if found.is_empty() {
+                    continue;
+                }
+                match fk.on_delete {
+                    ForeignKeyAction::Restrict => bail!(ForeignKeyViolation {
+                        relation: child.name.to_string(),
+                        column: fk.column.to_string(),
+                        value: found[0][pos].clone(),
+                        notice: format!(
+                            "key is still referenced after removal from relation {}",
+                            relation_store.name
+                        ),
+                    }),
+                    ForeignKeyAction::Cascade => {
+                        let n_keys = child.metadata.keys.len();
+                        let key_bindings = child
+                            .metadata
+                            .keys
+                            .iter()
+                            .map(|k| Symbol::new(k.name.clone(), Default::default()))
+                            .collect_vec();
+                        let child_meta = InputRelationHandle {
+                            name: Symbol::new(child.name.clone(), Default::default()),
+                            metadata: StoredRelationMetadata {
+                                keys: child.metadata.keys.clone(),
+                                non_keys: vec![],
+                                foreign_keys: vec![],
+                                constraints: vec![],
+                            },
+                            key_bindings: key_bindings.clone(),
+                            dep_bindings: vec![],
+                            condition: None,
+                            span,
+                        };
+                        let cleanups = self.execute_relation(
+                            db,
+                            found.into_iter().map(|mut t| {
+                                t.truncate(n_keys);
+                                t
+                            }),
+                            RelationOp::Rm,
+                            &child_meta,
+                            &key_bindings,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                            propagate_triggers,
+                            "",
+                        )?;
+                        to_clear.extend(cleanups);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The rows of `child` whose column at `pos`, with the foreign key `fk`, holds one of
+    /// the keys. They are found by the keys of `child` if the column comes first, and by the
+    /// index on the column created with the foreign key otherwise.
+    pub(crate) fn referencing_rows(
+        &self,
+        child: &RelationHandle,
+        fk: &ForeignKey,
+        pos: usize,
+        keys: &BTreeSet<DataValue>,
+    ) -> Result<Vec<Tuple>> {
+        let mut found = vec![];
+        if pos == 0 && !child.metadata.keys.is_empty() {
+            for key in keys {
+                for tuple in child.scan_prefix(self, &vec![key.clone()]) {
+                    found.push(tuple?);
+                }
+            }
+            return Ok(found);
+        }
+        let (idx, _) = child
+            .indices
+            .values()
+            .find(|(idx, _)| idx.metadata.keys[0].name == fk.column)
+            .ok_or_else(|| MissingForeignKeyIndex(fk.column.to_string(), child.name.to_string()))?;
+        // the index holds all keys of the child after the indexed column
+        let key_positions = child
+            .metadata
+            .keys
+            .iter()
+            .map(|k| {
+                idx.metadata
+                    .keys
+                    .iter()
+                    .position(|c| c.name == k.name)
+                    .unwrap()
+            })
+            .collect_vec();
+        for key in keys {
+            for idx_tuple in idx.scan_prefix(self, &vec![key.clone()]) {
+                let idx_tuple = idx_tuple?;
+                let child_key = key_positions
+                    .iter()
+                    .map(|i| idx_tuple[*i].clone())
+                    .collect_vec();
+                if let Some(tuple) = child.get(self, &child_key)? {
+                    found.push(tuple);
+                }
+            }
+        }
+        Ok(found)
+    }
+
     fn collect_mutations<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
@@ -693,11 +1011,16 @@ impl<'a> SessionTx<'a> {
         bindings.extend(v_bindings);
 
         let kv_bindings = bindings;
+        if !relation_store.views.is_empty() {
+            let (inserted, deleted) = row_changes(&new_tuples, &old_tuples);
+            self.maintain_views(db, cur_vld, relation_store, inserted, deleted)?;
+        }
         if propagate_triggers {
             for trigger in &relation_store.put_triggers {
                 let mut program = parse_script(
                     trigger,
                     &Default::default(),
+                    &db.custom_registry.read().unwrap(),
                     &db.fixed_rules.read().unwrap(),
                     cur_vld,
                 )?
@@ -771,6 +1094,58 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    /// Writes rows of a view, which are made of keys only, keeping the indices
+    /// of the view up to date. Rows already in the view are left as they are.
+    pub(crate) fn put_view_rows(&mut self, view: &RelationHandle, rows: &[Tuple]) -> Result<()> {
+        let hnsw_filters = self.make_hnsw_filters(view)?;
+        let fts_lsh_processors = self.make_fts_lsh_processors(view)?;
+        let lsh_perms = self.make_lsh_hash_perms(view);
+        let mut stack = vec![];
+        for row in rows {
+            let key = view.encode_key_for_store(row, Default::default())?;
+            if self.store_tx.exists(&key, false)? {
+                continue;
+            }
+            for (idx_rel, extractor) in view.indices.values() {
+                let idx_tup = idx_rel.extract_index_row(extractor, row);
+                let encoded = idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
+                self.store_tx.put(&encoded, &[])?;
+            }
+            self.update_in_hnsw(view, &mut stack, &hnsw_filters, row)?;
+            self.put_in_fts(view, &mut stack, &fts_lsh_processors, row)?;
+            self.put_in_lsh(view, &mut stack, &fts_lsh_processors, row, &lsh_perms)?;
+            self.put_in_spatial(view, row)?;
+            let val = view.encode_val_for_store(row, Default::default())?;
+            self.store_tx.put(&key, &val)?;
+        }
+        Ok(())
+    }
+
+    /// Removes rows of a view, together with their entries in the indices of the view.
+    pub(crate) fn del_view_rows(&mut self, view: &RelationHandle, rows: &[Tuple]) -> Result<()> {
+        let fts_lsh_processors = self.make_fts_lsh_processors(view)?;
+        let mut stack = vec![];
+        for row in rows {
+            let key = view.encode_key_for_store(row, Default::default())?;
+            if !self.store_tx.exists(&key, false)? {
+                continue;
+            }
+            for (idx_rel, extractor) in view.indices.values() {
+                let idx_tup = idx_rel.extract_index_row(extractor, row);
+                let encoded = idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
+                self.store_tx.del(&encoded)?;
+            }
+            for (idx_handle, _) in view.hnsw_indices.values() {
+                self.hnsw_remove(view, idx_handle, row)?;
+            }
+            self.del_in_fts(view, &mut stack, &fts_lsh_processors, row)?;
+            self.del_in_lsh(view, row)?;
+            self.del_in_spatial(view, row)?;
+            self.store_tx.del(&key)?;
+        }
+        Ok(())
+    }
+
     fn update_in_index(
         &mut self,
         relation_store: &RelationHandle,
@@ -778,17 +1153,11 @@ impl<'a> SessionTx<'a> {
         old_kv: &[DataValue],
     ) -> Result<()> {
         for (idx_rel, idx_extractor) in relation_store.indices.values() {
-            let idx_tup_old = idx_extractor
-                .iter()
-                .map(|i| old_kv[*i].clone())
-                .collect_vec();
+            let idx_tup_old = idx_rel.extract_index_row(idx_extractor, old_kv);
             let encoded_old = idx_rel.encode_key_for_store(&idx_tup_old, Default::default())?;
             self.store_tx.del(&encoded_old)?;
 
-            let idx_tup_new = idx_extractor
-                .iter()
-                .map(|i| new_kv[*i].clone())
-                .collect_vec();
+            let idx_tup_new = idx_rel.extract_index_row(idx_extractor, new_kv);
             let encoded_new = idx_rel.encode_key_for_store(&idx_tup_new, Default::default())?;
             self.store_tx.put(&encoded_new, &[])?;
         }
@@ -948,15 +1317,19 @@ impl<'a> SessionTx<'a> {
         let need_to_collect = !force_collect.is_empty()
             || (!relation_store.is_temp
                 && (is_callback_target
+                    || !relation_store.views.is_empty()
                     || (propagate_triggers && !relation_store.rm_triggers.is_empty())));
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
         let has_lsh_indices = !relation_store.lsh_indices.is_empty();
+        let has_spatial_indices = !relation_store.spatial_indices.is_empty();
         let fts_processors = self.make_fts_lsh_processors(relation_store)?;
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
         let mut stack = vec![];
+        let is_referenced = !relation_store.referenced_by.is_empty();
+        let mut removed_keys = BTreeSet::new();
 
         for tuple in res_iter {
             let extracted: Vec<DataValue> = key_extractors
@@ -964,6 +1337,9 @@ impl<'a> SessionTx<'a> {
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
                 .try_collect()?;
             let key = relation_store.encode_key_for_store(&extracted, span)?;
+            if is_referenced {
+                removed_keys.insert(extracted[0].clone());
+            }
             if check_exists {
                 let exists = if relation_store.is_temp {
                     self.temp_store_tx.exists(&key, false)?
@@ -978,15 +1354,22 @@ impl<'a> SessionTx<'a> {
                     });
                 }
             }
-            if need_to_collect || has_indices || has_hnsw_indices || has_fts_indices || has_lsh_indices {
+            if need_to_collect
+                || has_indices
+                || has_hnsw_indices
+                || has_fts_indices
+                || has_lsh_indices
+                || has_spatial_indices
+            {
                 if let Some(existing) = self.store_tx.get(&key, false)? {
                     let mut tup = extracted.clone();
                     extend_tuple_from_v(&mut tup, &existing);
                     self.del_in_fts(relation_store, &mut stack, &fts_processors, &tup)?;
                     self.del_in_lsh(relation_store, &tup)?;
+                    self.del_in_spatial(relation_store, &tup)?;
                     if has_indices {
                         for (idx_rel, extractor) in relation_store.indices.values() {
-                            let idx_tup = extractor.iter().map(|i| tup[*i].clone()).collect_vec();
+                            let idx_tup = idx_rel.extract_index_row(extractor, &tup);
                             let encoded =
                                 idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
                             self.store_tx.del(&encoded)?;
@@ -1012,6 +1395,20 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        if !removed_keys.is_empty() {
+            self.remove_referencing_rows(
+                db,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                propagate_triggers,
+                to_clear,
+                relation_store,
+                &removed_keys,
+                span,
+            )?;
+        }
+
         // triggers and callbacks
         if need_to_collect && !new_tuples.is_empty() {
             let k_bindings = relation_store
@@ -1030,11 +1427,17 @@ impl<'a> SessionTx<'a> {
             kv_bindings.extend(v_bindings);
             let kv_bindings = kv_bindings;
 
+            if !relation_store.views.is_empty() {
+                let (_, deleted) = row_changes(&[], &old_tuples);
+                self.maintain_views(db, cur_vld, relation_store, vec![], deleted)?;
+            }
+
             if propagate_triggers {
                 for trigger in &relation_store.rm_triggers {
                     let mut program = parse_script(
                         trigger,
                         &Default::default(),
+                        &db.custom_registry.read().unwrap(),
                         &db.fixed_rules.read().unwrap(),
                         cur_vld,
                     )?
@@ -1117,6 +1520,22 @@ struct TransactAssertionFailure {
     notice: String,
 }
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("Foreign key violation for {value:?} in column '{column}' of {relation}: {notice}")]
+#[diagnostic(code(transact::foreign_key_violation))]
+struct ForeignKeyViolation {
+    relation: String,
+    column: String,
+    value: DataValue,
+    notice: String,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("No index on column '{0}' of {1} to find the rows referencing removed keys")]
+#[diagnostic(code(transact::missing_foreign_key_index))]
+#[diagnostic(help("Create an index on the column, or recreate the relation to get one"))]
+struct MissingForeignKeyIndex(String, String);
+
 enum DataExtractor {
     DefaultExtractor(Expr, NullableColType),
     IndexExtractor(usize, NullableColType),
@@ -1135,6 +1554,44 @@ impl DataExtractor {
     }
 }
 
+pub(crate) fn collect_referenced_values(
+    references: &[(usize, RelationHandle)],
+    tuple: &[DataValue],
+    collector: &mut [BTreeSet<DataValue>],
+) {
+    for ((pos, _), values) in references.iter().zip(collector.iter_mut()) {
+        // a null reference points nowhere and is always allowed
+        if tuple[*pos] != DataValue::Null {
+            values.insert(tuple[*pos].clone());
+        }
+    }
+}
+
+/// Compiles the condition of `:put_if` against the columns of the existing row, prefixed
+/// by `old.`, the columns of the row to be written, prefixed by `new.`, and the output
+/// columns of the query.
+fn make_put_condition(
+    condition: &Expr,
+    relation_store: &RelationHandle,
+    headers: &[Symbol],
+) -> Result<(Vec<Bytecode>, SourceSpan)> {
+    let arity = relation_store.arity();
+    let mut binding_map = BTreeMap::new();
+    for (i, header) in headers.iter().enumerate() {
+        binding_map.insert(header.clone(), 2 * arity + i);
+    }
+    for (col, i) in relation_store.raw_binding_map() {
+        binding_map.insert(Symbol::new(format!("old.{}", col.name), col.span), i);
+        binding_map.insert(
+            Symbol::new(format!("new.{}", col.name), col.span),
+            arity + i,
+        );
+    }
+    let mut condition = condition.clone();
+    condition.fill_binding_indices(&binding_map)?;
+    Ok((condition.compile()?, condition.span()))
+}
+
 fn make_extractors(
     stored: &[ColumnDef],
     input: &[ColumnDef],
@@ -1194,7 +1651,7 @@ fn make_extractor(
     }
 }
 
-fn make_const_rule(
+pub(crate) fn make_const_rule(
     program: &mut InputProgram,
     rule_name: &str,
     bindings: Vec<Symbol>,
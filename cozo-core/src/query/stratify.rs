@@ -28,11 +28,13 @@ impl NormalFormAtom {
         match self {
             NormalFormAtom::Relation(_)
             | NormalFormAtom::NegatedRelation(_)
+            | NormalFormAtom::OptionalRelation(_)
             | NormalFormAtom::Predicate(_)
             | NormalFormAtom::Unification(_)
             | NormalFormAtom::HnswSearch(_)
             | NormalFormAtom::FtsSearch(_)
-            | NormalFormAtom::LshSearch(_) => Default::default(),
+            | NormalFormAtom::LshSearch(_)
+            | NormalFormAtom::SpatialSearch(_) => Default::default(),
             NormalFormAtom::Rule(r) => BTreeMap::from([(&r.name, false)]),
             NormalFormAtom::NegatedRule(r) => BTreeMap::from([(&r.name, true)]),
         }
@@ -132,6 +134,12 @@ fn convert_normal_form_program_to_graph(
                         }
                     }
                 }
+                if nf_prog.bag {
+                    // rules with bag semantics are evaluated once, after all their dependencies
+                    for poisoned in ret.values_mut() {
+                        *poisoned = true;
+                    }
+                }
                 (k, ret)
             }
             NormalFormRulesOrFixed::Fixed { fixed } => {
@@ -185,6 +193,27 @@ fn verify_no_cycle(g: &StratifiedGraph<&'_ Symbol>, sccs: &[BTreeSet<&Symbol>])
     Ok(())
 }
 
+fn verify_non_recursive(g: &StratifiedGraph<&'_ Symbol>, sccs: &[BTreeSet<&Symbol>]) -> Result<()> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Rule '{0}' is recursive, which is not allowed with bag semantics")]
+    #[diagnostic(code(eval::recursive_bag_rule))]
+    #[diagnostic(help("Duplicate rows are only kept by non-recursive rules"))]
+    struct RecursiveBagRule(String, #[label] SourceSpan);
+
+    for scc in sccs {
+        let first = scc.iter().next().unwrap();
+        let is_recursive = scc.len() > 1
+            || g.get(first)
+                .map(|deps| deps.contains_key(first))
+                .unwrap_or(false);
+        ensure!(
+            !is_recursive,
+            RecursiveBagRule(first.to_string(), first.span)
+        );
+    }
+    Ok(())
+}
+
 fn make_scc_reduced_graph(
     sccs: &[BTreeSet<&Symbol>],
     graph: &StratifiedGraph<&Symbol>,
@@ -231,11 +260,20 @@ impl NormalFormProgram {
         let stratified_graph = convert_normal_form_program_to_graph(&self);
         let graph = reduce_to_graph(&stratified_graph);
 
-        // 1. find reachable clauses starting from the query
-        let reachable: BTreeSet<_> = reachable_components(&graph, &prog_entry)
+        // 1. find reachable clauses starting from the query and the extra outputs
+        let mut reachable: BTreeSet<_> = reachable_components(&graph, &prog_entry)
             .into_iter()
             .map(|k| (*k).clone())
             .collect();
+        for output in &self.extra_outputs {
+            if graph.contains_key(output) {
+                reachable.extend(
+                    reachable_components(&graph, &output)
+                        .into_iter()
+                        .map(|k| (*k).clone()),
+                );
+            }
+        }
         // 2. prune the graph of unreachable clauses
         let stratified_graph: StratifiedGraph<_> = stratified_graph
             .into_iter()
@@ -251,6 +289,9 @@ impl NormalFormProgram {
             .map(|scc| scc.into_iter().cloned().collect())
             .collect_vec();
         // 4. for each SCC, verify that no neg/agg edges are present so that it is really stratifiable
+        if self.bag {
+            verify_non_recursive(&stratified_graph, &sccs)?;
+        }
         verify_no_cycle(&stratified_graph, &sccs)?;
         // 5. build a reduced graph for the SCC's
         let (invert_indices, reduced_graph) = make_scc_reduced_graph(&sccs, &stratified_graph);
@@ -267,6 +308,8 @@ impl NormalFormProgram {
             .map(|_| NormalFormProgram {
                 prog: BTreeMap::new(),
                 disable_magic_rewrite: self.disable_magic_rewrite,
+                bag: self.bag,
+                extra_outputs: self.extra_outputs.clone(),
             })
             .collect_vec();
 
@@ -295,6 +338,16 @@ impl NormalFormProgram {
             }
         }
 
+        // extra outputs must survive until the end of evaluation
+        for output in &self.extra_outputs {
+            store_lifetimes.insert(
+                MagicSymbol::Muggle {
+                    inner: output.clone(),
+                },
+                n_strata,
+            );
+        }
+
         for (name, ruleset) in self.prog {
             if let Some(scc_idx) = invert_indices.get(&name) {
                 if let Some(rev_stratum_idx) = invert_sort_result.get(scc_idx) {
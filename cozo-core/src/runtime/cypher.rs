@@ -0,0 +1,109 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+
+use miette::{bail, ensure, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::symb::Symbol;
+use crate::data::tuple::TupleT;
+use crate::data::value::DataValue;
+use crate::parse::cypher::CypherLabel;
+use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel, RelationId};
+use crate::runtime::transact::SessionTx;
+use crate::{NamedRows, SourceSpan};
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad row in the Cypher label mapping: {0:?}")]
+#[diagnostic(code(eval::bad_cypher_mapping))]
+#[diagnostic(help(
+    "Rows of the relation declared with `::cypher_mapping` must start with the label and the relation name"
+))]
+struct BadCypherMapping(Vec<DataValue>);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation {0} cannot map Cypher labels")]
+#[diagnostic(code(eval::bad_cypher_mapping_relation))]
+#[diagnostic(help(
+    "The mapping relation needs at least two columns, the label and the relation name"
+))]
+struct BadCypherMappingRelation(String, #[label] SourceSpan);
+
+fn cypher_mapping_key() -> Vec<u8> {
+    vec![DataValue::Null, DataValue::from("CYPHER_MAPPING")].encode_as_key(RelationId::SYSTEM)
+}
+
+impl<'a> SessionTx<'a> {
+    /// Declare the stored relation mapping Cypher labels and relationship types to stored
+    /// relations: its first two columns are the label and the name of the relation.
+    pub(crate) fn set_cypher_mapping(&mut self, name: &Symbol) -> Result<()> {
+        let handle = self.get_relation(&name.name, false)?;
+        ensure!(
+            handle.metadata.keys.len() + handle.metadata.non_keys.len() >= 2,
+            BadCypherMappingRelation(name.name.to_string(), name.span)
+        );
+        self.store_tx
+            .put(&cypher_mapping_key(), name.name.as_bytes())
+    }
+
+    fn cypher_mapping(&self) -> Result<Option<SmartString<LazyCompact>>> {
+        Ok(self
+            .store_tx
+            .get(&cypher_mapping_key(), false)?
+            .map(|v| SmartString::from(String::from_utf8_lossy(&v).as_ref())))
+    }
+
+    pub(crate) fn show_cypher_mapping(&self) -> Result<NamedRows> {
+        let name = match self.cypher_mapping()? {
+            None => DataValue::Null,
+            Some(name) => DataValue::Str(name),
+        };
+        Ok(NamedRows::new(
+            vec!["relation".to_string()],
+            vec![vec![name]],
+        ))
+    }
+
+    /// The labels in the declared mapping relation, empty if none is declared.
+    pub(crate) fn cypher_labels(&self) -> Result<BTreeMap<SmartString<LazyCompact>, CypherLabel>> {
+        let mut labels = BTreeMap::new();
+        let name = match self.cypher_mapping()? {
+            None => return Ok(labels),
+            Some(name) => name,
+        };
+        let mapping = self.get_relation(&name, false)?;
+        if mapping.access_level < AccessLevel::ReadOnly {
+            bail!(InsufficientAccessLevel(
+                mapping.name.to_string(),
+                "reading the Cypher mapping".to_string(),
+                mapping.access_level
+            ));
+        }
+        for row in mapping.scan_all(self) {
+            let row = row?;
+            let (label, relation) = match (row.first(), row.get(1)) {
+                (Some(DataValue::Str(label)), Some(DataValue::Str(relation))) => {
+                    (label.clone(), relation.clone())
+                }
+                _ => bail!(BadCypherMapping(row)),
+            };
+            let handle = self.get_relation(&relation, false)?;
+            let columns = handle
+                .metadata
+                .keys
+                .iter()
+                .chain(handle.metadata.non_keys.iter())
+                .map(|col| col.name.clone())
+                .collect();
+            labels.insert(label, CypherLabel { relation, columns });
+        }
+        Ok(labels)
+    }
+}
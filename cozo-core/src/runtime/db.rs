@@ -13,7 +13,7 @@ use std::fmt::{Debug, Formatter};
 use std::iter;
 use std::path::Path;
 #[allow(unused_imports)]
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 #[allow(unused_imports)]
 use std::thread;
@@ -32,6 +32,8 @@ use serde_json::json;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::aggr::{check_custom_aggr_name, parse_aggr, CustomAggregation};
+use crate::data::expr::{check_custom_op_name, get_op, CustomFunction, CustomOp};
 use crate::data::functions::current_validity;
 use crate::data::json::JsonValue;
 use crate::data::program::{InputProgram, QueryAssertion, RelationOp, ReturnMutation};
@@ -40,20 +42,25 @@ use crate::data::tuple::{Tuple, TupleT};
 use crate::data::value::{DataValue, ValidityTs, LARGEST_UTF_CHAR};
 use crate::fixed_rule::DEFAULT_FIXED_RULES;
 use crate::fts::TokenizerCache;
+use crate::parse::cypher::parse_cypher;
 use crate::parse::sys::SysOp;
-use crate::parse::{parse_expressions, parse_script, CozoScript, SourceSpan};
+use crate::parse::{parse_expressions, parse_script, CozoScript, CustomRegistry, SourceSpan};
 use crate::query::compile::{CompiledProgram, CompiledRule, CompiledRuleSet};
+use crate::query::profile::QueryProfiler;
 use crate::query::ra::{
-    FilteredRA, FtsSearchRA, HnswSearchRA, InnerJoin, LshSearchRA, NegJoin, RelAlgebra, ReorderRA,
-    StoredRA, StoredWithValidityRA, TempStoreRA, UnificationRA,
+    FilteredRA, FtsSearchRA, HnswSearchRA, InnerJoin, LeftJoin, LshSearchRA, NegJoin, RelAlgebra,
+    ReorderRA, SpatialSearchRA, StoredRA, StoredWithValidityRA, TempStoreRA, UnificationRA,
 };
+use crate::query::stored::collect_referenced_values;
 #[allow(unused_imports)]
 use crate::runtime::callback::{
     CallbackCollector, CallbackDeclaration, CallbackOp, EventCallbackRegistry,
 };
 use crate::runtime::relation::{
-    extend_tuple_from_v, AccessLevel, InsufficientAccessLevel, RelationHandle, RelationId,
+    extend_tuple_from_v, foreign_key_index_name, AccessLevel, InsufficientAccessLevel,
+    RelationHandle, RelationId,
 };
+use crate::runtime::spill::MemoryBudget;
 use crate::runtime::transact::SessionTx;
 use crate::storage::temp::TempStorage;
 use crate::storage::Storage;
@@ -92,6 +99,61 @@ pub enum ScriptMutability {
     Immutable,
 }
 
+/// Resource limits of queries, in addition to timeouts.
+/// Limits are ceilings: the limits of the database, those passed with a script
+/// (e.g. the limits of a token of the HTTP server) and those given by the options of a query
+/// all apply, so that the lowest of them is in force. The options of a query may lower
+/// the other limits, but setting them higher is an error.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// Maximal number of tuples derived by a query, intermediate results included.
+    pub max_rows: Option<usize>,
+    /// Maximal memory, in bytes, held by the intermediate results of a query.
+    pub max_memory: Option<usize>,
+    /// Maximal number of epochs of evaluation of each stratum of a query.
+    pub max_epochs: Option<usize>,
+}
+
+impl QueryLimits {
+    /// The lower of the two limits for each resource.
+    pub fn tighter(self, other: &QueryLimits) -> Self {
+        fn lower(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        Self {
+            max_rows: lower(self.max_rows, other.max_rows),
+            max_memory: lower(self.max_memory, other.max_memory),
+            max_epochs: lower(self.max_epochs, other.max_epochs),
+        }
+    }
+    /// Applies the limits given by the options of a query, which may not exceed `self`.
+    pub(crate) fn lowered_by(&self, requested: &QueryLimits) -> Result<Self> {
+        for (option, ceiling, requested) in [
+            ("max_rows", self.max_rows, requested.max_rows),
+            ("max_memory", self.max_memory, requested.max_memory),
+            ("max_epochs", self.max_epochs, requested.max_epochs),
+        ] {
+            if let (Some(ceiling), Some(requested)) = (ceiling, requested) {
+                if requested > ceiling {
+                    bail!(LimitRaised(option, requested, ceiling))
+                }
+            }
+        }
+        Ok(self.tighter(requested))
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The option `:{0} {1}` exceeds the limit of {2} in force")]
+#[diagnostic(code(eval::limit_raised))]
+#[diagnostic(help(
+    "Limits of the database or of the session can only be lowered by the options of a query"
+))]
+struct LimitRaised(&'static str, usize, usize);
+
 /// The database object of Cozo.
 #[derive(Clone)]
 pub struct Db<S> {
@@ -101,12 +163,15 @@ pub struct Db<S> {
     pub(crate) queries_count: Arc<AtomicU64>,
     pub(crate) running_queries: Arc<Mutex<BTreeMap<u64, RunningQueryHandle>>>,
     pub(crate) fixed_rules: Arc<ShardedLock<BTreeMap<String, Arc<Box<dyn FixedRule>>>>>,
+    pub(crate) custom_registry: Arc<ShardedLock<CustomRegistry>>,
     pub(crate) tokenizers: Arc<TokenizerCache>,
     #[cfg(not(target_arch = "wasm32"))]
     callback_count: Arc<AtomicU32>,
     #[cfg(not(target_arch = "wasm32"))]
     pub(crate) event_callbacks: Arc<ShardedLock<EventCallbackRegistry>>,
     relation_locks: Arc<ShardedLock<BTreeMap<SmartString<LazyCompact>, Arc<ShardedLock<()>>>>>,
+    memory_budget: Arc<AtomicUsize>,
+    default_limits: Arc<ShardedLock<QueryLimits>>,
 }
 
 impl<S> Debug for Db<S> {
@@ -271,6 +336,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             queries_count: Default::default(),
             running_queries: Default::default(),
             fixed_rules: Arc::new(ShardedLock::new(DEFAULT_FIXED_RULES.clone())),
+            custom_registry: Default::default(),
             tokenizers: Arc::new(Default::default()),
             #[cfg(not(target_arch = "wasm32"))]
             callback_count: Default::default(),
@@ -278,6 +344,8 @@ impl<'s, S: Storage<'s>> Db<S> {
             #[cfg(not(target_arch = "wasm32"))]
             event_callbacks: Default::default(),
             relation_locks: Default::default(),
+            memory_budget: Arc::new(AtomicUsize::new(usize::MAX)),
+            default_limits: Default::default(),
         };
         Ok(ret)
     }
@@ -342,18 +410,22 @@ impl<'s, S: Storage<'s>> Db<S> {
                     break;
                 }
                 TransactionPayload::Query((script, params)) => {
-                    let p =
-                        match parse_script(&script, &params, &self.fixed_rules.read().unwrap(), ts)
-                        {
-                            Ok(p) => p,
-                            Err(err) => {
-                                if results.send(Err(err)).is_err() {
-                                    break;
-                                } else {
-                                    continue;
-                                }
+                    let p = match parse_script(
+                        &script,
+                        &params,
+                        &self.custom_registry.read().unwrap(),
+                        &self.fixed_rules.read().unwrap(),
+                        ts,
+                    ) {
+                        Ok(p) => p,
+                        Err(err) => {
+                            if results.send(Err(err)).is_err() {
+                                break;
+                            } else {
+                                continue;
                             }
-                        };
+                        }
+                    };
 
                     let p = match p.get_single_program() {
                         Ok(p) => p,
@@ -365,7 +437,9 @@ impl<'s, S: Storage<'s>> Db<S> {
                             }
                         }
                     };
-                    if let Some(write_lock_name) = p.needs_write_lock() {
+                    let mut write_lock_names = BTreeSet::new();
+                    p.needs_write_locks(&mut write_lock_names);
+                    for write_lock_name in write_lock_names {
                         match write_locks.entry(write_lock_name) {
                             Entry::Vacant(e) => {
                                 let lock = self
@@ -400,6 +474,18 @@ impl<'s, S: Storage<'s>> Db<S> {
         payload: &str,
         params: BTreeMap<String, DataValue>,
         mutability: ScriptMutability,
+    ) -> Result<NamedRows> {
+        self.run_script_with_limits(payload, params, mutability, QueryLimits::default())
+    }
+
+    /// Run the CozoScript passed in, with the given resource limits applying on top of
+    /// those of the database. The `params` argument is a map of parameters.
+    pub fn run_script_with_limits(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        mutability: ScriptMutability,
+        limits: QueryLimits,
     ) -> Result<NamedRows> {
         let cur_vld = current_validity();
         self.do_run_script(
@@ -407,6 +493,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             &params,
             cur_vld,
             mutability == ScriptMutability::Immutable,
+            limits,
         )
     }
 
@@ -417,7 +504,47 @@ impl<'s, S: Storage<'s>> Db<S> {
         params: BTreeMap<String, DataValue>,
     ) -> Result<NamedRows> {
         let cur_vld = current_validity();
-        self.do_run_script(payload, &params, cur_vld, true)
+        self.do_run_script(payload, &params, cur_vld, true, QueryLimits::default())
+    }
+
+    /// Run the Cypher query passed in. Labels and relationship types are mapped to stored
+    /// relations by the rows of the stored relation declared with `::cypher_mapping`, and the
+    /// query is run as the CozoScript program it translates to. The `params` argument is a map of parameters.
+    pub fn run_cypher(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        self.run_cypher_with_limits(payload, params, QueryLimits::default())
+    }
+
+    /// Run the Cypher query passed in, with the given resource limits applying on top of
+    /// those of the database.
+    pub fn run_cypher_with_limits(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        limits: QueryLimits,
+    ) -> Result<NamedRows> {
+        let cur_vld = current_validity();
+        // the labels are read in the transaction running the query,
+        // so that the query sees the mapping it was translated with
+        let mut tx = self.transact()?;
+        tx.limits = limits.tighter(&self.default_limits.read().unwrap());
+        let labels = tx.cypher_labels()?;
+        let program = parse_cypher(payload, &params, &labels)?;
+        let mut write_lock_names = BTreeSet::new();
+        program.needs_write_locks(&mut write_lock_names);
+        if !write_lock_names.is_empty() {
+            bail!("write lock required for read-only query");
+        }
+        self.execute_single_in_tx(
+            tx,
+            program,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+        )
     }
 
     /// Export relations to JSON data.
@@ -474,7 +601,9 @@ impl<'s, S: Storage<'s>> Db<S> {
     /// Import relations. The argument `data` accepts data in the shape of
     /// what was returned by [Self::export_relations].
     /// The target stored relations must already exist in the database.
-    /// Any associated indices will be updated.
+    /// Any associated indices will be updated, and column constraints and foreign keys are
+    /// checked. Removing rows still referenced by other rows fails, even for cascading
+    /// references.
     ///
     /// Note that triggers and callbacks are _not_ run for the relations, if any exists.
     /// If you need to activate triggers or callbacks, use queries with parameters.
@@ -491,6 +620,7 @@ impl<'s, S: Storage<'s>> Db<S> {
         let cur_vld = current_validity();
 
         let mut tx = self.transact_write()?;
+        let mut reference_checks = vec![];
 
         for (relation_op, in_data) in data {
             let is_delete;
@@ -509,6 +639,14 @@ impl<'s, S: Storage<'s>> Db<S> {
             }
             let handle = tx.get_relation(relation, false)?;
             let has_indices = !handle.indices.is_empty();
+            let mut constraints = handle.constraint_checker()?;
+            let references = tx.make_references(&handle)?;
+            let mut referenced_values = vec![BTreeSet::new(); references.len()];
+            let mut removed_keys = BTreeSet::new();
+            // imports bypass view maintenance, like triggers
+            for view in &handle.views {
+                tx.mark_view_stale(view)?;
+            }
 
             if handle.access_level < AccessLevel::Protected {
                 bail!(InsufficientAccessLevel(
@@ -578,8 +716,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                         extend_tuple_from_v(&mut old, &existing);
                         if is_delete || old != row {
                             for (idx_rel, extractor) in handle.indices.values() {
-                                let idx_tup =
-                                    extractor.iter().map(|i| old[*i].clone()).collect_vec();
+                                let idx_tup = idx_rel.extract_index_row(extractor, &old);
                                 let encoded =
                                     idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
                                 tx.store_tx.del(&encoded)?;
@@ -588,6 +725,9 @@ impl<'s, S: Storage<'s>> Db<S> {
                     }
                 }
                 if is_delete {
+                    if !handle.referenced_by.is_empty() {
+                        removed_keys.insert(keys[0].clone());
+                    }
                     tx.store_tx.del(&k_store)?;
                 } else {
                     let vals: Vec<_> = val_indices
@@ -600,12 +740,14 @@ impl<'s, S: Storage<'s>> Db<S> {
                         })
                         .try_collect()?;
                     let v_store = handle.encode_val_only_for_store(&vals, Default::default())?;
+                    let mut kv = keys;
+                    kv.extend(vals);
+                    constraints.check(&kv)?;
+                    collect_referenced_values(&references, &kv, &mut referenced_values);
                     tx.store_tx.put(&k_store, &v_store)?;
                     if has_indices {
-                        let mut kv = keys;
-                        kv.extend(vals);
                         for (idx_rel, extractor) in handle.indices.values() {
-                            let idx_tup = extractor.iter().map(|i| kv[*i].clone()).collect_vec();
+                            let idx_tup = idx_rel.extract_index_row(extractor, &kv);
                             let encoded =
                                 idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
                             tx.store_tx.put(&encoded, &[])?;
@@ -613,6 +755,13 @@ impl<'s, S: Storage<'s>> Db<S> {
                     }
                 }
             }
+            drop(constraints);
+            reference_checks.push((handle, references, referenced_values, removed_keys));
+        }
+        // the rows referenced by imported rows may come with the import
+        for (handle, references, referenced_values, removed_keys) in reference_checks {
+            tx.ensure_referenced_rows(&handle, &references, referenced_values, Default::default())?;
+            tx.ensure_unreferenced(&handle, &removed_keys)?;
         }
         tx.commit_tx()?;
         Ok(())
@@ -664,16 +813,30 @@ impl<'s, S: Storage<'s>> Db<S> {
     }
     /// Import data from relations in a backup file.
     /// The target stored relations must already exist in the database, and it must not
-    /// have any associated indices. If you want to import into relations with indices,
-    /// use [Db::import_relations].
+    /// have any associated indices other than those created with its foreign keys.
+    /// If you want to import into relations with indices, use [Db::import_relations].
+    /// Column constraints and foreign keys of the target relations are checked
+    /// against the imported rows.
     ///
     /// Note that triggers and callbacks are _not_ run for the relations, if any exists.
     /// If you need to activate triggers or callbacks, use queries with parameters.
-    #[allow(unused_variables)]
     pub fn import_from_backup(
         &'s self,
         in_file: impl AsRef<Path>,
         relations: &[String],
+    ) -> Result<()> {
+        self.import_from_backup_with_checks(in_file, relations, true)
+    }
+    /// Import data from relations in a backup file as [Db::import_from_backup] does, checking
+    /// the column constraints and foreign keys of the target relations only if
+    /// `check_constraints` is set. Skipping the checks is only safe for backups of databases
+    /// with the same constraints.
+    #[allow(unused_variables)]
+    pub fn import_from_backup_with_checks(
+        &'s self,
+        in_file: impl AsRef<Path>,
+        relations: &[String],
+        check_constraints: bool,
     ) -> Result<()> {
         #[cfg(not(feature = "storage-sqlite"))]
         bail!("backup requires the 'storage-sqlite' feature to be enabled");
@@ -687,6 +850,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             let source_db = crate::new_cozo_sqlite(in_file)?;
             let mut src_tx = source_db.transact()?;
             let mut dst_tx = self.transact_write()?;
+            let mut reference_checks = vec![];
 
             for relation in relations {
                 if relation.contains(':') {
@@ -695,7 +859,15 @@ impl<'s, S: Storage<'s>> Db<S> {
                 let src_handle = src_tx.get_relation(relation, false)?;
                 let dst_handle = dst_tx.get_relation(relation, false)?;
 
-                if !dst_handle.indices.is_empty() {
+                // the indices created with foreign keys are kept up to date
+                let fk_indices_only = dst_handle.indices.keys().all(|name| {
+                    dst_handle
+                        .metadata
+                        .foreign_keys
+                        .iter()
+                        .any(|fk| *name == foreign_key_index_name(&fk.column))
+                });
+                if !fk_indices_only {
                     #[derive(Debug, Error, Diagnostic)]
                     #[error("Cannot import data into relation {0} from backup as the relation has indices")]
                     #[diagnostic(code(tx::bare_import_with_indices))]
@@ -713,6 +885,9 @@ impl<'s, S: Storage<'s>> Db<S> {
                     ));
                 }
 
+                let mut constraints = dst_handle.constraint_checker()?;
+                let references = dst_tx.make_references(&dst_handle)?;
+                let mut referenced_values = vec![BTreeSet::new(); references.len()];
                 let src_lower = Tuple::default().encode_as_key(src_handle.id);
                 let src_upper = Tuple::default().encode_as_key(src_handle.id.next());
 
@@ -724,10 +899,53 @@ impl<'s, S: Storage<'s>> Db<S> {
                         Ok((src_k, src_v))
                     },
                 );
+                let n_keys = dst_handle.metadata.keys.len();
+                let needs_row = (check_constraints
+                    && (!constraints.is_empty() || !references.is_empty()))
+                    || !dst_handle.indices.is_empty();
                 for result in data_it {
                     let (key, val) = result?;
+                    if needs_row {
+                        let mut row = crate::data::tuple::decode_tuple_from_key(&key, n_keys);
+                        extend_tuple_from_v(&mut row, &val);
+                        if check_constraints {
+                            constraints.check(&row)?;
+                            collect_referenced_values(&references, &row, &mut referenced_values);
+                        }
+                        let old = if dst_handle.indices.is_empty() {
+                            None
+                        } else {
+                            dst_tx.store_tx.get(&key, false)?.map(|existing| {
+                                let mut old = row[..n_keys].to_vec();
+                                extend_tuple_from_v(&mut old, &existing);
+                                old
+                            })
+                        };
+                        for (idx_rel, extractor) in dst_handle.indices.values() {
+                            if let Some(old) = &old {
+                                let idx_tup = idx_rel.extract_index_row(extractor, old);
+                                let encoded =
+                                    idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
+                                dst_tx.store_tx.del(&encoded)?;
+                            }
+                            let idx_tup = idx_rel.extract_index_row(extractor, &row);
+                            let encoded =
+                                idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
+                            dst_tx.store_tx.put(&encoded, &[])?;
+                        }
+                    }
                     dst_tx.store_tx.put(&key, &val)?;
                 }
+                drop(constraints);
+                reference_checks.push((dst_handle, references, referenced_values));
+            }
+            for (handle, references, referenced_values) in reference_checks {
+                dst_tx.ensure_referenced_rows(
+                    &handle,
+                    &references,
+                    referenced_values,
+                    Default::default(),
+                )?;
             }
 
             src_tx.commit_tx()?;
@@ -761,6 +979,110 @@ impl<'s, S: Storage<'s>> Db<S> {
         Ok(self.fixed_rules.write().unwrap().remove(name).is_some())
     }
 
+    /// Register a custom scalar function implementation, which can then be used in
+    /// expressions (rule bodies, filters, column defaults, index extractors, etc.)
+    /// like any built-in function. `arity` is the exact number of arguments the function takes.
+    ///
+    /// Expressions that are persisted, such as column defaults, refer to custom functions by name,
+    /// so the function must be registered again each time the database is opened.
+    pub fn register_function<F>(&self, name: String, arity: usize, func: F) -> Result<()>
+    where
+        F: CustomOp + 'static,
+    {
+        check_custom_op_name(&name)?;
+        match self.custom_registry.write().unwrap().functions.entry(name) {
+            Entry::Vacant(ent) => {
+                ent.insert(CustomFunction {
+                    arity,
+                    inner: Arc::new(Box::new(func)),
+                });
+                Ok(())
+            }
+            Entry::Occupied(ent) => {
+                bail!(
+                    "A function with the name {} is already registered",
+                    ent.key()
+                )
+            }
+        }
+    }
+
+    /// Unregister a custom scalar function implementation.
+    pub fn unregister_function(&self, name: &str) -> Result<bool> {
+        if get_op(name).is_some() {
+            bail!("Cannot unregister builtin function {}", name);
+        }
+        Ok(self
+            .custom_registry
+            .write()
+            .unwrap()
+            .functions
+            .remove(name)
+            .is_some())
+    }
+
+    /// Register a custom aggregation, which can then be used in rule heads
+    /// like any built-in aggregation. If the aggregation is a meet aggregation,
+    /// it can be used in recursive rules, exactly as the built-in meet aggregations.
+    ///
+    /// As with custom functions, the aggregation is only visible to this database,
+    /// and must be registered again each time the database is opened.
+    pub fn register_aggregation<A>(&self, name: String, aggr: A) -> Result<()>
+    where
+        A: CustomAggregation + 'static,
+    {
+        check_custom_aggr_name(&name)?;
+        match self
+            .custom_registry
+            .write()
+            .unwrap()
+            .aggregations
+            .entry(name)
+        {
+            Entry::Vacant(ent) => {
+                ent.insert(Arc::new(aggr));
+                Ok(())
+            }
+            Entry::Occupied(ent) => {
+                bail!(
+                    "An aggregation with the name {} is already registered",
+                    ent.key()
+                )
+            }
+        }
+    }
+
+    /// Unregister a custom aggregation.
+    pub fn unregister_aggregation(&self, name: &str) -> Result<bool> {
+        if parse_aggr(name).is_some() {
+            bail!("Cannot unregister builtin aggregation {}", name);
+        }
+        Ok(self
+            .custom_registry
+            .write()
+            .unwrap()
+            .aggregations
+            .remove(name)
+            .is_some())
+    }
+
+    /// Set the memory budget, in bytes, of each query. Temp relations, aggregations, joins and
+    /// sorts of a query that exceeds its budget spill to temporary files instead of
+    /// growing in memory. The budget is approximate, as sizes in memory are estimated.
+    /// `None`, the default, means no limit.
+    pub fn set_memory_budget(&self, bytes: Option<usize>) {
+        self.memory_budget
+            .store(bytes.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    /// Set the resource limits applied to all queries. Queries can lower them
+    /// with `:max_rows`, `:max_memory` and `:max_epochs`, but not raise them.
+    /// Note that with a memory budget below `max_memory`, intermediate results spill to disk
+    /// instead of making the query fail.
+    pub fn set_default_limits(&self, limits: QueryLimits) {
+        *self.default_limits.write().unwrap() = limits;
+    }
+
     /// Register callback channel to receive changes when the requested relation are successfully committed.
     /// The returned ID can be used to unregister the callback channel.
     #[cfg(not(target_arch = "wasm32"))]
@@ -853,7 +1175,12 @@ impl<'s, S: Storage<'s>> Db<S> {
             temp_store_tx: self.temp_db.transact(true)?,
             relation_store_id: self.relation_store_id.clone(),
             temp_store_id: Default::default(),
+            custom_registry: self.custom_registry.clone(),
             tokenizers: self.tokenizers.clone(),
+            profiler: None,
+            memory: self.new_memory_budget(),
+            limits: *self.default_limits.read().unwrap(),
+            rejected_rows: Default::default(),
         };
         Ok(ret)
     }
@@ -863,10 +1190,20 @@ impl<'s, S: Storage<'s>> Db<S> {
             temp_store_tx: self.temp_db.transact(true)?,
             relation_store_id: self.relation_store_id.clone(),
             temp_store_id: Default::default(),
+            custom_registry: self.custom_registry.clone(),
             tokenizers: self.tokenizers.clone(),
+            profiler: None,
+            memory: self.new_memory_budget(),
+            limits: *self.default_limits.read().unwrap(),
+            rejected_rows: Default::default(),
         };
         Ok(ret)
     }
+    fn new_memory_budget(&self) -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget::new(
+            self.memory_budget.load(Ordering::Relaxed),
+        ))
+    }
 
     pub(crate) fn execute_single_program(
         &'s self,
@@ -895,16 +1232,19 @@ impl<'s, S: Storage<'s>> Db<S> {
         param_pool: &BTreeMap<String, DataValue>,
         cur_vld: ValidityTs,
         read_only: bool,
+        limits: QueryLimits,
     ) -> Result<NamedRows> {
+        let limits = limits.tighter(&self.default_limits.read().unwrap());
         match parse_script(
             payload,
             param_pool,
+            &self.custom_registry.read().unwrap(),
             &self.fixed_rules.read().unwrap(),
             cur_vld,
         )? {
-            CozoScript::Single(p) => self.execute_single(cur_vld, p, read_only),
-            CozoScript::Imperative(ps) => self.execute_imperative(cur_vld, &ps, read_only),
-            CozoScript::Sys(op) => self.run_sys_op(op, read_only),
+            CozoScript::Single(p) => self.execute_single(cur_vld, p, read_only, limits),
+            CozoScript::Imperative(ps) => self.execute_imperative(cur_vld, &ps, read_only, limits),
+            CozoScript::Sys(op) => self.run_sys_op(op, read_only, limits),
         }
     }
 
@@ -913,48 +1253,30 @@ impl<'s, S: Storage<'s>> Db<S> {
         cur_vld: ValidityTs,
         p: InputProgram,
         read_only: bool,
+        limits: QueryLimits,
     ) -> Result<NamedRows, Report> {
         let mut callback_collector = BTreeMap::new();
-        let write_lock_names = p.needs_write_lock();
-        let is_write = write_lock_names.is_some();
+        let mut write_lock_names = BTreeSet::new();
+        p.needs_write_locks(&mut write_lock_names);
+        let is_write = !write_lock_names.is_empty();
         if read_only && is_write {
             bail!("write lock required for read-only query");
         }
         let write_lock = self.obtain_relation_locks(write_lock_names.iter());
-        let _write_lock_guards = if is_write {
-            Some(write_lock[0].read().unwrap())
-        } else {
-            None
-        };
+        let _write_lock_guards = write_lock.iter().map(|l| l.read().unwrap()).collect_vec();
         let callback_targets = if is_write {
             self.current_callback_targets()
         } else {
             Default::default()
         };
-        let mut cleanups = vec![];
-        let res;
-        {
-            let mut tx = if is_write {
-                self.transact_write()?
-            } else {
-                self.transact()?
-            };
-
-            res = self.execute_single_program(
-                p,
-                &mut tx,
-                &mut cleanups,
-                cur_vld,
-                &callback_targets,
-                &mut callback_collector,
-            )?;
-
-            for (lower, upper) in cleanups {
-                tx.store_tx.del_range_from_persisted(&lower, &upper)?;
-            }
-
-            tx.commit_tx()?;
-        }
+        let mut tx = if is_write {
+            self.transact_write()?
+        } else {
+            self.transact()?
+        };
+        tx.limits = limits;
+        let res =
+            self.execute_single_in_tx(tx, p, cur_vld, &callback_targets, &mut callback_collector)?;
         #[cfg(not(target_arch = "wasm32"))]
         if !callback_collector.is_empty() {
             self.send_callbacks(callback_collector)
@@ -962,7 +1284,39 @@ impl<'s, S: Storage<'s>> Db<S> {
 
         Ok(res)
     }
-    fn explain_compiled(&self, strata: &[CompiledProgram]) -> Result<NamedRows> {
+    /// Run a single program in the given transaction and commit it.
+    fn execute_single_in_tx(
+        &'s self,
+        mut tx: SessionTx<'_>,
+        p: InputProgram,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+    ) -> Result<NamedRows> {
+        let mut cleanups = vec![];
+        let res = self.execute_single_program(
+            p,
+            &mut tx,
+            &mut cleanups,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+        )?;
+
+        for (lower, upper) in cleanups {
+            tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+        }
+
+        tx.commit_tx()?;
+        Ok(res)
+    }
+    /// When `profiler` is given, the statistics collected by it while running the program
+    /// are added to the plan, one row per node per epoch.
+    fn explain_compiled(
+        &self,
+        strata: &[CompiledProgram],
+        profiler: Option<&QueryProfiler>,
+    ) -> Result<NamedRows> {
         let mut ret: Vec<JsonValue> = vec![];
         const STRATUM: &str = "stratum";
         const ATOM_IDX: &str = "atom_idx";
@@ -973,8 +1327,13 @@ impl<'s, S: Storage<'s>> Db<S> {
         const OUT_BINDINGS: &str = "out_relation";
         const JOINS_ON: &str = "joins_on";
         const FILTERS: &str = "filters/expr";
+        const EPOCH: &str = "epoch";
+        const PRODUCED: &str = "produced";
+        const FILTERED: &str = "filtered";
+        const SCANS: &str = "scans";
+        const TIME_MS: &str = "time_ms";
 
-        let headers = vec![
+        let mut headers = vec![
             STRATUM.to_string(),
             RULE_IDX.to_string(),
             RULE_NAME.to_string(),
@@ -985,6 +1344,15 @@ impl<'s, S: Storage<'s>> Db<S> {
             FILTERS.to_string(),
             OUT_BINDINGS.to_string(),
         ];
+        if profiler.is_some() {
+            headers.extend([
+                EPOCH.to_string(),
+                PRODUCED.to_string(),
+                FILTERED.to_string(),
+                SCANS.to_string(),
+                TIME_MS.to_string(),
+            ]);
+        }
 
         for (stratum, p) in strata.iter().enumerate() {
             let mut clause_idx = -1;
@@ -994,7 +1362,9 @@ impl<'s, S: Storage<'s>> Db<S> {
                         for CompiledRule { aggr, relation, .. } in rules.iter() {
                             clause_idx += 1;
                             let mut ret_for_relation = vec![];
-                            let mut rel_stack = vec![relation];
+                            // the second element is the node whose statistics are shown:
+                            // joins with a unit on the left are shown as their right side
+                            let mut rel_stack = vec![(relation, relation)];
                             let mut idx = 0;
                             let mut atom_type = "out";
                             for (a, _) in aggr.iter().flatten() {
@@ -1017,7 +1387,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                             }));
                             idx += 1;
 
-                            while let Some(rel) = rel_stack.pop() {
+                            while let Some((rel, profiled_rel)) = rel_stack.pop() {
                                 let (atom_type, ref_name, joins_on, filters) = match rel {
                                     r @ RelAlgebra::Fixed(..) => {
                                         if r.is_unit() {
@@ -1055,7 +1425,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                                     ),
                                     RelAlgebra::Join(inner) => {
                                         if inner.left.is_unit() {
-                                            rel_stack.push(&inner.right);
+                                            rel_stack.push((&inner.right, profiled_rel));
                                             continue;
                                         }
                                         let t = inner.join_type();
@@ -1065,8 +1435,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                                             joiner,
                                             ..
                                         } = inner.as_ref();
-                                        rel_stack.push(left);
-                                        rel_stack.push(right);
+                                        rel_stack.push((left, left));
+                                        rel_stack.push((right, right));
                                         (t, json!(null), json!(joiner.as_map()), json!(null))
                                     }
                                     RelAlgebra::NegJoin(inner) => {
@@ -1077,12 +1447,24 @@ impl<'s, S: Storage<'s>> Db<S> {
                                             joiner,
                                             ..
                                         } = inner.as_ref();
-                                        rel_stack.push(left);
-                                        rel_stack.push(right);
+                                        rel_stack.push((left, left));
+                                        rel_stack.push((right, right));
+                                        (t, json!(null), json!(joiner.as_map()), json!(null))
+                                    }
+                                    RelAlgebra::LeftJoin(inner) => {
+                                        let t = inner.join_type();
+                                        let LeftJoin {
+                                            left,
+                                            right,
+                                            joiner,
+                                            ..
+                                        } = inner.as_ref();
+                                        rel_stack.push((left, left));
+                                        rel_stack.push((right, right));
                                         (t, json!(null), json!(joiner.as_map()), json!(null))
                                     }
                                     RelAlgebra::Reorder(ReorderRA { relation, .. }) => {
-                                        rel_stack.push(relation);
+                                        rel_stack.push((relation, relation));
                                         ("reorder", json!(null), json!(null), json!(null))
                                     }
                                     RelAlgebra::Filter(FilteredRA {
@@ -1090,7 +1472,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                                         filters: pred,
                                         ..
                                     }) => {
-                                        rel_stack.push(parent);
+                                        rel_stack.push((parent, parent));
                                         (
                                             "filter",
                                             json!(null),
@@ -1105,7 +1487,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                                         is_multi,
                                         ..
                                     }) => {
-                                        rel_stack.push(parent);
+                                        rel_stack.push((parent, parent));
                                         (
                                             if *is_multi { "multi-unify" } else { "unify" },
                                             json!(binding.name),
@@ -1145,8 +1527,27 @@ impl<'s, S: Storage<'s>> Db<S> {
                                             .map(|f| f.to_string())
                                             .collect_vec()),
                                     ),
+                                    RelAlgebra::SpatialSearch(SpatialSearchRA {
+                                        spatial_search,
+                                        ..
+                                    }) => (
+                                        "spatial_index",
+                                        json!(format!(
+                                            ":{}",
+                                            spatial_search.idx_handle.name
+                                        )),
+                                        json!(spatial_search
+                                            .input_bindings()
+                                            .map(|s| s.name.to_string())
+                                            .collect_vec()),
+                                        json!(spatial_search
+                                            .filter
+                                            .iter()
+                                            .map(|f| f.to_string())
+                                            .collect_vec()),
+                                    ),
                                 };
-                                ret_for_relation.push(json!({
+                                let row = json!({
                                     STRATUM: stratum,
                                     ATOM_IDX: idx,
                                     OP: atom_type,
@@ -1156,7 +1557,38 @@ impl<'s, S: Storage<'s>> Db<S> {
                                     OUT_BINDINGS: rel.bindings_after_eliminate().into_iter().map(|v| v.to_string()).collect_vec(),
                                     JOINS_ON: joins_on,
                                     FILTERS: filters,
-                                }));
+                                });
+                                let collected = match profiler {
+                                    None => vec![],
+                                    Some(profiler) => profiler.collected(profiled_rel),
+                                };
+                                if collected.is_empty() {
+                                    ret_for_relation.push(row);
+                                } else {
+                                    // the rows are reversed at the end
+                                    for (epoch, stats) in collected.into_iter().rev() {
+                                        let mut row = row.clone();
+                                        let m = row.as_object_mut().unwrap();
+                                        m.insert(EPOCH.to_string(), json!(epoch));
+                                        m.insert(
+                                            PRODUCED.to_string(),
+                                            json!(stats.produced.load(Ordering::Acquire)),
+                                        );
+                                        m.insert(
+                                            FILTERED.to_string(),
+                                            json!(stats.filtered.load(Ordering::Acquire)),
+                                        );
+                                        m.insert(
+                                            SCANS.to_string(),
+                                            json!(stats.scans.load(Ordering::Acquire)),
+                                        );
+                                        m.insert(
+                                            TIME_MS.to_string(),
+                                            json!(stats.nanos.load(Ordering::Acquire) as f64 / 1e6),
+                                        );
+                                        ret_for_relation.push(row);
+                                    }
+                                }
                                 idx += 1;
                             }
                             ret_for_relation.reverse();
@@ -1194,12 +1626,59 @@ impl<'s, S: Storage<'s>> Db<S> {
         skip_locking: bool,
     ) -> Result<NamedRows> {
         match op {
-            SysOp::Explain(prog) => {
-                let (normalized_program, _) = prog.clone().into_normalized_program(tx)?;
-                let (stratified_program, _) = normalized_program.into_stratified_program()?;
+            SysOp::Explain(prog, analyze) => {
+                let mut prog = prog.clone();
+                tx.import_rule_libraries(&mut prog, current_validity())?;
+                tx.inline_stored_functions(&mut prog)?;
+                tx.expand_path_atoms(&mut prog)?;
+                let (normalized_program, out_opts) = prog.into_normalized_program(tx)?;
+                let (stratified_program, store_lifetimes) =
+                    normalized_program.into_stratified_program()?;
                 let program = stratified_program.magic_sets_rewrite(tx)?;
                 let compiled = tx.stratified_magic_compile(program)?;
-                self.explain_compiled(&compiled)
+                if !*analyze {
+                    return self.explain_compiled(&compiled, None);
+                }
+
+                // run the query to collect the statistics, mutations are not applied
+                let poison = Poison::default();
+                if let Some(secs) = out_opts.timeout {
+                    poison.set_timeout(secs)?;
+                }
+                let limits = tx.limits.lowered_by(&out_opts.limits)?;
+                tx.memory.set_max(limits.max_memory);
+                let id = self.queries_count.fetch_add(1, Ordering::AcqRel);
+                let handle = RunningQueryHandle {
+                    started_at: seconds_since_the_epoch()?,
+                    poison: poison.clone(),
+                };
+                self.running_queries.lock().unwrap().insert(id, handle);
+                let _guard = RunningQueryCleanup {
+                    id,
+                    running_queries: self.running_queries.clone(),
+                };
+                let (total_num_to_take, num_to_skip) =
+                    if out_opts.sorters.is_empty() && !out_opts.bag {
+                        (out_opts.num_to_take(), out_opts.offset)
+                    } else {
+                        (None, None)
+                    };
+
+                let profiler = Arc::new(QueryProfiler::default());
+                tx.profiler = Some(profiler.clone());
+                let res = tx.stratified_magic_evaluate(
+                    &compiled,
+                    store_lifetimes,
+                    total_num_to_take,
+                    num_to_skip,
+                    &limits,
+                    out_opts.bag,
+                    &BTreeSet::new(),
+                    poison,
+                );
+                tx.profiler = None;
+                res?;
+                self.explain_compiled(&compiled, Some(&profiler))
             }
             SysOp::Compact => {
                 if read_only {
@@ -1211,6 +1690,49 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::Analyze(rel_names) => {
+                if read_only {
+                    bail!("Cannot analyze relations in read-only mode");
+                }
+                let rel_names = if rel_names.is_empty() {
+                    let mut names = vec![];
+                    for name in self.list_relation_names(tx)? {
+                        // hidden relations are skipped instead of failing the whole analysis
+                        if !name.contains(':')
+                            && tx.get_relation(&name, false)?.access_level > AccessLevel::Hidden
+                        {
+                            names.push(name);
+                        }
+                    }
+                    names
+                } else {
+                    rel_names.iter().map(|n| n.name.clone()).collect_vec()
+                };
+                let mut rows = vec![];
+                for name in rel_names {
+                    for (name, stats) in tx.analyze_relation(&name)? {
+                        rows.push(vec![
+                            DataValue::Str(name),
+                            DataValue::from(stats.rows as i64),
+                            DataValue::List(
+                                stats
+                                    .distinct_prefixes
+                                    .into_iter()
+                                    .map(|n| DataValue::from(n as i64))
+                                    .collect_vec(),
+                            ),
+                        ]);
+                    }
+                }
+                Ok(NamedRows::new(
+                    vec![
+                        "relation".to_string(),
+                        "rows".to_string(),
+                        "distinct_prefixes".to_string(),
+                    ],
+                    rows,
+                ))
+            }
             SysOp::ListRelations => self.list_relations(tx),
             SysOp::ListFixedRules => {
                 let rules = self.fixed_rules.read().unwrap();
@@ -1222,6 +1744,21 @@ impl<'s, S: Storage<'s>> Db<S> {
                         .collect_vec(),
                 ))
             }
+            SysOp::ListFunctions => Ok(NamedRows::new(
+                vec!["function".to_string(), "arity".to_string()],
+                self.custom_registry
+                    .read()
+                    .unwrap()
+                    .functions
+                    .iter()
+                    .map(|(name, func)| {
+                        vec![
+                            DataValue::from(name as &str),
+                            DataValue::from(func.arity as i64),
+                        ]
+                    })
+                    .collect_vec(),
+            )),
             SysOp::RemoveRelation(rel_names) => {
                 if read_only {
                     bail!("Cannot remove relations in read-only mode");
@@ -1332,6 +1869,25 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::CreateSpatialIndex(config) => {
+                if read_only {
+                    bail!("Cannot create spatial index in read-only mode");
+                }
+                if skip_locking {
+                    tx.create_spatial_index(config)?;
+                } else {
+                    let lock = self
+                        .obtain_relation_locks(iter::once(&config.base_relation))
+                        .pop()
+                        .unwrap();
+                    let _guard = lock.write().unwrap();
+                    tx.create_spatial_index(config)?;
+                }
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
             SysOp::RemoveIndex(rel_name, idx_name) => {
                 if read_only {
                     bail!("Cannot remove index in read-only mode");
@@ -1355,6 +1911,112 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::CreateView(name, query) => {
+                if read_only {
+                    bail!("Cannot create views in read-only mode");
+                }
+                let locks = if skip_locking {
+                    vec![]
+                } else {
+                    let names = tx.view_lock_names(self, name, Some(query), current_validity())?;
+                    self.obtain_relation_locks(names.iter())
+                };
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
+                tx.create_view(self, name, query, current_validity())?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RefreshView(name) => {
+                if read_only {
+                    bail!("Cannot refresh views in read-only mode");
+                }
+                let locks = if skip_locking {
+                    vec![]
+                } else {
+                    let names = tx.view_lock_names(self, name, None, current_validity())?;
+                    self.obtain_relation_locks(names.iter())
+                };
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
+                tx.refresh_view(self, name, current_validity())?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RemoveView(name) => {
+                if read_only {
+                    bail!("Cannot remove views in read-only mode");
+                }
+                let locks = if skip_locking {
+                    vec![]
+                } else {
+                    let names = tx.view_lock_names(self, name, None, current_validity())?;
+                    self.obtain_relation_locks(names.iter())
+                };
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
+                for (lower, upper) in tx.remove_view(name)? {
+                    tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+                }
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ListRuleLibraries => tx.list_rule_libraries(current_validity()),
+            SysOp::DefineRules(name, rules) => {
+                if read_only {
+                    bail!("Cannot define rule libraries in read-only mode");
+                }
+                tx.define_rule_library(name, rules)?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RemoveRules(name) => {
+                if read_only {
+                    bail!("Cannot remove rule libraries in read-only mode");
+                }
+                tx.remove_rule_library(name)?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ListStoredFunctions => tx.list_functions(),
+            SysOp::DefineFunction(name, definition) => {
+                if read_only {
+                    bail!("Cannot define functions in read-only mode");
+                }
+                tx.define_function(name, definition)?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RemoveFunction(name) => {
+                if read_only {
+                    bail!("Cannot remove functions in read-only mode");
+                }
+                tx.remove_function(name)?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ShowCypherMapping => tx.show_cypher_mapping(),
+            SysOp::SetCypherMapping(name) => {
+                if read_only {
+                    bail!("Cannot declare the Cypher mapping in read-only mode");
+                }
+                tx.set_cypher_mapping(name)?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
             SysOp::ListColumns(rs) => self.list_columns(tx, rs),
             SysOp::ListIndices(rs) => self.list_indices(tx, rs),
             SysOp::RenameRelation(rename_pairs) => {
@@ -1376,6 +2038,28 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::AlterRelation(rel_name, ops) => {
+                if read_only {
+                    bail!("Cannot alter relations in read-only mode");
+                }
+                let bounds = if skip_locking {
+                    tx.alter_relation(rel_name, ops, current_validity())?
+                } else {
+                    let lock = self
+                        .obtain_relation_locks(iter::once(&rel_name.name))
+                        .pop()
+                        .unwrap();
+                    let _guard = lock.write().unwrap();
+                    tx.alter_relation(rel_name, ops, current_validity())?
+                };
+                for (lower, upper) in bounds {
+                    tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+                }
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
             SysOp::ListRunning => self.list_running(),
             SysOp::KillRunning(id) => {
                 let queries = self.running_queries.lock().unwrap();
@@ -1438,12 +2122,18 @@ impl<'s, S: Storage<'s>> Db<S> {
             }
         }
     }
-    fn run_sys_op(&'s self, op: SysOp, read_only: bool) -> Result<NamedRows> {
+    fn run_sys_op(
+        &'s self,
+        op: SysOp,
+        read_only: bool,
+        limits: QueryLimits,
+    ) -> Result<NamedRows> {
         let mut tx = if read_only {
             self.transact()?
         } else {
             self.transact_write()?
         };
+        tx.limits = limits;
         let res = self.run_sys_op_with_tx(&mut tx, &op, read_only, false)?;
         tx.commit_tx()?;
         Ok(res)
@@ -1452,7 +2142,7 @@ impl<'s, S: Storage<'s>> Db<S> {
     pub(crate) fn run_query(
         &self,
         tx: &mut SessionTx<'_>,
-        input_program: InputProgram,
+        mut input_program: InputProgram,
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
         callback_collector: &mut CallbackCollector,
@@ -1461,8 +2151,18 @@ impl<'s, S: Storage<'s>> Db<S> {
         // cleanups contain stored relations that should be deleted at the end of query
         let mut clean_ups = vec![];
 
+        tx.import_rule_libraries(&mut input_program, cur_vld)?;
+        tx.inline_stored_functions(&mut input_program)?;
+        tx.expand_path_atoms(&mut input_program)?;
+
         // Some checks in case the query specifies mutation
-        if let Some((meta, op, _)) = &input_program.out_opts.store_relation {
+        let mutations = input_program
+            .out_opts
+            .store_relation
+            .iter()
+            .map(|(meta, op, _)| (meta, op))
+            .chain(input_program.out_opts.rule_mutations.iter().map(|(meta, op, _)| (meta, op)));
+        for (meta, op) in mutations {
             if *op == RelationOp::Create {
                 #[derive(Debug, Error, Diagnostic)]
                 #[error("Stored relation {0} conflicts with an existing one")]
@@ -1491,10 +2191,16 @@ impl<'s, S: Storage<'s>> Db<S> {
                     *op == RelationOp::Rm || *op == RelationOp::Delete || *op == RelationOp::Update,
                 )?;
             }
-        };
+        }
 
         // query compilation
         let entry_head_or_default = input_program.get_entry_out_head_or_default()?;
+        let rule_mutation_heads: Vec<_> = input_program
+            .out_opts
+            .rule_mutations
+            .iter()
+            .map(|(_, _, rule)| input_program.get_rule_out_head_or_default(rule))
+            .try_collect()?;
         let (normalized_program, out_opts) = input_program.into_normalized_program(tx)?;
         let (stratified_program, store_lifetimes) = normalized_program.into_stratified_program()?;
         let program = stratified_program.magic_sets_rewrite(tx)?;
@@ -1505,6 +2211,9 @@ impl<'s, S: Storage<'s>> Db<S> {
         if let Some(secs) = out_opts.timeout {
             poison.set_timeout(secs)?;
         }
+        // limits of the query may only lower those of the script or the database
+        let limits = tx.limits.lowered_by(&out_opts.limits)?;
+        tx.memory.set_max(limits.max_memory);
         // give the query an ID and store it so that it can be queried and cancelled
         let id = self.queries_count.fetch_add(1, Ordering::AcqRel);
 
@@ -1523,24 +2232,38 @@ impl<'s, S: Storage<'s>> Db<S> {
             running_queries: self.running_queries.clone(),
         };
 
-        let total_num_to_take = if out_opts.sorters.is_empty() {
+        // with bag semantics, limits apply to the rows with their duplicates, after evaluation,
+        // and stopping early would leave the rules feeding mutations incomplete
+        let early_limits = out_opts.sorters.is_empty()
+            && !out_opts.bag
+            && out_opts.rule_mutations.is_empty();
+        let total_num_to_take = if early_limits {
             out_opts.num_to_take()
         } else {
             None
         };
 
-        let num_to_skip = if out_opts.sorters.is_empty() {
+        let num_to_skip = if early_limits {
             out_opts.offset
         } else {
             None
         };
 
+        let extra_outputs: BTreeSet<_> = out_opts
+            .rule_mutations
+            .iter()
+            .map(|(_, _, rule)| rule.clone())
+            .collect();
+
         // the real evaluation
-        let (result_store, early_return) = tx.stratified_magic_evaluate(
+        let (result_store, extra_stores, early_return) = tx.stratified_magic_evaluate(
             &compiled,
             store_lifetimes,
             total_num_to_take,
             num_to_skip,
+            &limits,
+            out_opts.bag,
+            &extra_outputs,
             poison,
         )?;
 
@@ -1570,6 +2293,33 @@ impl<'s, S: Storage<'s>> Db<S> {
             }
         }
 
+        // mutations fed by other rules are executed in order, before the one fed by the entry
+        for ((meta, relation_op, rule), headers) in
+            out_opts.rule_mutations.iter().zip(rule_mutation_heads.iter())
+        {
+            let rows = extra_stores
+                .get(rule)
+                .into_iter()
+                .flat_map(|store| store.all_iter().map(|t| t.into_tuple()));
+            let to_clear = tx
+                .execute_relation(
+                    self,
+                    rows,
+                    *relation_op,
+                    meta,
+                    headers,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    top_level,
+                    "",
+                )
+                .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
+            clean_ups.extend(to_clear);
+            // only the mutation fed by the entry reports its rows
+            tx.rejected_rows.remove(&meta.name.name);
+        }
+
         if !out_opts.sorters.is_empty() {
             // sort outputs if required
             let sorted_result =
@@ -1603,13 +2353,15 @@ impl<'s, S: Storage<'s>> Db<S> {
                         },
                     )
                     .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
+                tx.memory.check()?;
                 clean_ups.extend(to_clear);
                 let returned_rows =
-                    tx.get_returning_rows(callback_collector, &meta.name, returning)?;
+                    tx.get_returning_rows(callback_collector, &meta.name, *relation_op, returning)?;
                 Ok((returned_rows, clean_ups))
             } else {
                 // not sorting outputs
                 let rows: Vec<Tuple> = sorted_iter.collect_vec();
+                tx.memory.check()?;
                 Ok((
                     NamedRows::new(
                         entry_head_or_default
@@ -1659,13 +2411,15 @@ impl<'s, S: Storage<'s>> Db<S> {
                         },
                     )
                     .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
+                tx.memory.check()?;
                 clean_ups.extend(to_clear);
                 let returned_rows =
-                    tx.get_returning_rows(callback_collector, &meta.name, returning)?;
+                    tx.get_returning_rows(callback_collector, &meta.name, *relation_op, returning)?;
 
                 Ok((returned_rows, clean_ups))
             } else {
                 let rows: Vec<Tuple> = scan.collect_vec();
+                tx.memory.check()?;
 
                 Ok((
                     NamedRows::new(
@@ -1758,6 +2512,17 @@ impl<'s, S: Storage<'s>> Db<S> {
                 }),
             ]);
         }
+        for (name, (rel, manifest)) in &handle.spatial_indices {
+            rows.push(vec![
+                json!(name),
+                json!("spatial"),
+                json!([rel.name]),
+                json!({
+                    "field": manifest.field,
+                    "max_entries": manifest.max_entries,
+                }),
+            ]);
+        }
         let rows = rows
             .into_iter()
             .map(|row| row.into_iter().map(DataValue::from).collect_vec())
@@ -1778,6 +2543,12 @@ impl<'s, S: Storage<'s>> Db<S> {
         let mut idx = 0;
         for col in &handle.metadata.keys {
             let default_expr = col.default_gen.as_ref().map(|gen| format!("{}", gen));
+            let reference = handle
+                .metadata
+                .foreign_keys
+                .iter()
+                .find(|fk| fk.column == col.name)
+                .map(|fk| fk.to_string());
 
             rows.push(vec![
                 json!(col.name),
@@ -1786,11 +2557,18 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(col.typing.to_string()),
                 json!(col.default_gen.is_some()),
                 json!(default_expr),
+                json!(reference),
             ]);
             idx += 1;
         }
         for col in &handle.metadata.non_keys {
             let default_expr = col.default_gen.as_ref().map(|gen| format!("{}", gen));
+            let reference = handle
+                .metadata
+                .foreign_keys
+                .iter()
+                .find(|fk| fk.column == col.name)
+                .map(|fk| fk.to_string());
 
             rows.push(vec![
                 json!(col.name),
@@ -1799,6 +2577,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(col.typing.to_string()),
                 json!(col.default_gen.is_some()),
                 json!(default_expr),
+                json!(reference),
             ]);
             idx += 1;
         }
@@ -1814,10 +2593,28 @@ impl<'s, S: Storage<'s>> Db<S> {
                 "type".to_string(),
                 "has_default".to_string(),
                 "default_expr".to_string(),
+                "references".to_string(),
             ],
             rows,
         ))
     }
+    fn list_relation_names(
+        &'s self,
+        tx: &SessionTx<'_>,
+    ) -> Result<Vec<SmartString<LazyCompact>>> {
+        let lower = vec![DataValue::from("")].encode_as_key(RelationId::SYSTEM);
+        let upper =
+            vec![DataValue::from(String::from(LARGEST_UTF_CHAR))].encode_as_key(RelationId::SYSTEM);
+        let mut ret = vec![];
+        for kv_res in tx.store_tx.range_scan(&lower, &upper) {
+            let (k_slice, v_slice) = kv_res?;
+            if upper <= k_slice {
+                break;
+            }
+            ret.push(RelationHandle::decode(&v_slice)?.name);
+        }
+        Ok(ret)
+    }
     fn list_relations(&'s self, tx: &SessionTx<'_>) -> Result<NamedRows> {
         let lower = vec![DataValue::from("")].encode_as_key(RelationId::SYSTEM);
         let upper =
@@ -1838,6 +2635,17 @@ impl<'s, S: Storage<'s>> Db<S> {
             } else {
                 meta.access_level.to_string()
             };
+            let (view, stale) = match &meta.view {
+                None => (JsonValue::Null, JsonValue::Null),
+                Some(manifest) => (
+                    json!(if manifest.incremental {
+                        "incremental"
+                    } else {
+                        "recompute"
+                    }),
+                    json!(manifest.stale),
+                ),
+            };
             rows.push(vec![
                 json!(name),
                 json!(arity),
@@ -1848,6 +2656,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(meta.rm_triggers.len()),
                 json!(meta.replace_triggers.len()),
                 json!(meta.description),
+                view,
+                stale,
             ]);
         }
         let rows = rows
@@ -1865,6 +2675,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                 "n_rm_triggers".to_string(),
                 "n_replace_triggers".to_string(),
                 "description".to_string(),
+                "view".to_string(),
+                "stale".to_string(),
             ],
             rows,
         ))
@@ -1902,7 +2714,7 @@ fn _evaluate_expressions(
     params: &BTreeMap<String, DataValue>,
     vars: &BTreeMap<String, DataValue>,
 ) -> Result<DataValue> {
-    let mut expr = parse_expressions(src, params)?;
+    let mut expr = parse_expressions(src, params, &Default::default())?;
     let mut ctx = vec![];
     let mut binding_map = BTreeMap::new();
     for (i, (k, v)) in vars.iter().enumerate() {
@@ -1914,7 +2726,7 @@ fn _evaluate_expressions(
 }
 
 fn _get_variables(src: &str, params: &BTreeMap<String, DataValue>) -> Result<BTreeSet<String>> {
-    let expr = parse_expressions(src, params)?;
+    let expr = parse_expressions(src, params, &Default::default())?;
     expr.get_variables()
 }
 
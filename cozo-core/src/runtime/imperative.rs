@@ -20,7 +20,9 @@ use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationM
 use crate::data::symb::Symbol;
 use crate::parse::{ImperativeCondition, ImperativeProgram, ImperativeStmt, SourceSpan};
 use crate::runtime::callback::CallbackCollector;
-use crate::runtime::db::{seconds_since_the_epoch, RunningQueryCleanup, RunningQueryHandle};
+use crate::runtime::db::{
+    seconds_since_the_epoch, QueryLimits, RunningQueryCleanup, RunningQueryHandle,
+};
 use crate::runtime::relation::InputRelationHandle;
 use crate::runtime::transact::SessionTx;
 use crate::{DataValue, Db, NamedRows, Poison, Storage, ValidityTs};
@@ -257,6 +259,7 @@ impl<'s, S: Storage<'s>> Db<S> {
         cur_vld: ValidityTs,
         ps: &ImperativeProgram,
         readonly: bool,
+        limits: QueryLimits,
     ) -> Result<NamedRows, Report> {
         let mut callback_collector = BTreeMap::new();
         let mut write_lock_names = BTreeSet::new();
@@ -283,6 +286,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             } else {
                 self.transact()?
             };
+            tx.limits = limits;
 
             let poison = Poison::default();
             let qid = self.queries_count.fetch_add(1, Ordering::AcqRel);
@@ -376,9 +380,12 @@ impl SessionTx<'_> {
             metadata: StoredRelationMetadata {
                 keys,
                 non_keys: vec![],
+                foreign_keys: vec![],
+                constraints: vec![],
             },
             key_bindings,
             dep_bindings: vec![],
+            condition: None,
             span: Default::default(),
         };
         let headers = meta.key_bindings.clone();
@@ -7,12 +7,18 @@
  */
 
 pub(crate) mod callback;
+pub(crate) mod cypher;
 pub(crate) mod db;
 pub(crate) mod imperative;
 pub(crate) mod relation;
+pub(crate) mod rule_lib;
+pub(crate) mod spill;
+pub(crate) mod stored_fn;
 pub(crate) mod temp_store;
 pub(crate) mod transact;
+pub(crate) mod view;
 pub(crate) mod hnsw;
 pub(crate) mod minhash_lsh;
+pub(crate) mod rtree;
 #[cfg(test)]
 mod tests;
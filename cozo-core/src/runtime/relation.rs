@@ -6,7 +6,7 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::atomic::Ordering;
 
@@ -19,19 +19,27 @@ use serde::Serialize;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::expr::{eval_bytecode_pred, Bytecode, Expr};
 use crate::data::memcmp::MemCmpEncoder;
-use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::relation::{
+    ColType, ColumnConstraint, ColumnDef, ConstraintRule, NullableColType, StoredRelationMetadata,
+};
 use crate::data::symb::Symbol;
 use crate::data::tuple::{decode_tuple_from_key, Tuple, TupleT, ENCODED_KEY_MIN_LEN};
-use crate::data::value::{DataValue, ValidityTs};
+use crate::data::value::{DataValue, DecimalWrapper, ValidityTs};
 use crate::fts::FtsIndexManifest;
 use crate::parse::expr::build_expr;
-use crate::parse::sys::{FtsIndexConfig, HnswIndexConfig, MinHashLshConfig};
+use crate::parse::sys::{
+    AlterOp, FtsIndexConfig, HnswIndexConfig, MinHashLshConfig, SpatialIndexConfig,
+};
 use crate::parse::{CozoScriptParser, Rule, SourceSpan};
 use crate::query::compile::IndexPositionUse;
+use crate::query::stored::collect_referenced_values;
 use crate::runtime::hnsw::HnswIndexManifest;
 use crate::runtime::minhash_lsh::{HashPermutations, LshParams, MinHashLshIndexManifest, Weights};
+use crate::runtime::rtree::SpatialIndexManifest;
 use crate::runtime::transact::SessionTx;
+use crate::runtime::view::ViewManifest;
 use crate::utils::TempCollector;
 use crate::{NamedRows, StoreTx};
 
@@ -90,6 +98,57 @@ pub(crate) struct RelationHandle {
         (RelationHandle, RelationHandle, MinHashLshIndexManifest),
     >,
     pub(crate) description: SmartString<LazyCompact>,
+    #[serde(default)]
+    pub(crate) stats: Option<RelationStats>,
+    /// The views reading this relation
+    #[serde(default)]
+    pub(crate) views: BTreeSet<SmartString<LazyCompact>>,
+    /// Set if this relation holds the rows of a view
+    #[serde(default)]
+    pub(crate) view: Option<ViewManifest>,
+    /// The relations with foreign keys referencing this relation
+    #[serde(default)]
+    pub(crate) referenced_by: BTreeSet<SmartString<LazyCompact>>,
+    #[serde(default)]
+    pub(crate) spatial_indices:
+        BTreeMap<SmartString<LazyCompact>, (RelationHandle, SpatialIndexManifest)>,
+}
+
+/// Statistics of a relation gathered by `::analyze`, used by the query planner.
+/// They are not maintained by mutations, so they become stale until the next analysis.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct RelationStats {
+    /// Number of rows
+    pub(crate) rows: u64,
+    /// The `i`-th element is the number of distinct values of the first `i + 1` keys
+    pub(crate) distinct_prefixes: Vec<u64>,
+}
+
+/// Fraction of rows assumed to be kept by an equality constraint on a column
+/// for which no statistics are available.
+pub(crate) const DEFAULT_SELECTIVITY: f64 = 0.1;
+
+impl RelationStats {
+    /// Estimated number of rows produced by a single lookup, where `bound` tells
+    /// for each column (keys first) whether its value is already known.
+    pub(crate) fn estimate_rows(&self, bound: &[bool]) -> f64 {
+        if self.rows == 0 {
+            return 0.;
+        }
+        let prefix_len = bound
+            .iter()
+            .take(self.distinct_prefixes.len())
+            .take_while(|b| **b)
+            .count();
+        let mut est = match prefix_len {
+            0 => self.rows as f64,
+            n => self.rows as f64 / self.distinct_prefixes[n - 1].max(1) as f64,
+        };
+        for _ in bound.iter().skip(prefix_len).filter(|b| **b) {
+            est *= DEFAULT_SELECTIVITY;
+        }
+        est
+    }
 }
 
 impl RelationHandle {
@@ -98,12 +157,14 @@ impl RelationHandle {
             || self.hnsw_indices.contains_key(index_name)
             || self.fts_indices.contains_key(index_name)
             || self.lsh_indices.contains_key(index_name)
+            || self.spatial_indices.contains_key(index_name)
     }
     pub(crate) fn has_no_index(&self) -> bool {
         self.indices.is_empty()
             && self.hnsw_indices.is_empty()
             && self.fts_indices.is_empty()
             && self.lsh_indices.is_empty()
+            && self.spatial_indices.is_empty()
     }
 }
 
@@ -149,7 +210,95 @@ struct StoredRelArityMismatch {
     span: SourceSpan,
 }
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("Row {row:?} of {relation} violates constraint {constraint}")]
+#[diagnostic(code(transact::constraint_violation))]
+pub(crate) struct ConstraintViolation {
+    relation: String,
+    constraint: String,
+    row: Vec<DataValue>,
+}
+
+/// The column constraints of a relation, compiled for checking rows before they are written.
+pub(crate) struct ConstraintChecker<'a> {
+    relation: &'a str,
+    constraints: Vec<(&'a ColumnConstraint, usize, Vec<Bytecode>, SourceSpan)>,
+    stack: Vec<DataValue>,
+}
+
+impl ConstraintChecker<'_> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.constraints.is_empty()
+    }
+    /// Checks a full row, keys followed by non-keys.
+    pub(crate) fn check(&mut self, row: &[DataValue]) -> Result<()> {
+        for (constraint, pos, bytecodes, span) in &self.constraints {
+            let val = &row[*pos];
+            if *val == DataValue::Null {
+                continue;
+            }
+            let satisfied = match &constraint.rule {
+                ConstraintRule::Check(_) => {
+                    eval_bytecode_pred(bytecodes, row, &mut self.stack, *span)?
+                }
+                ConstraintRule::OneOf(vals) => vals.contains(val),
+            };
+            ensure!(
+                satisfied,
+                ConstraintViolation {
+                    relation: self.relation.to_string(),
+                    constraint: constraint.name.to_string(),
+                    row: row.to_vec(),
+                }
+            );
+        }
+        Ok(())
+    }
+}
+
 impl RelationHandle {
+    pub(crate) fn constraint_checker(&self) -> Result<ConstraintChecker<'_>> {
+        let binding_map = self.raw_binding_map();
+        let constraints = self
+            .metadata
+            .constraints
+            .iter()
+            .map(|constraint| -> Result<_> {
+                let pos = binding_map[&Symbol::new(constraint.column.clone(), Default::default())];
+                let (bytecodes, span) = match &constraint.rule {
+                    ConstraintRule::Check(expr) => {
+                        let mut expr = expr.clone();
+                        expr.fill_binding_indices(&binding_map)?;
+                        (expr.compile()?, expr.span())
+                    }
+                    ConstraintRule::OneOf(_) => (vec![], Default::default()),
+                };
+                Ok((constraint, pos, bytecodes, span))
+            })
+            .try_collect()?;
+        Ok(ConstraintChecker {
+            relation: &self.name,
+            constraints,
+            stack: vec![],
+        })
+    }
+    /// Converts a value looked up in the column at `idx` to the type stored there. Numbers are
+    /// stored as decimals in `Decimal` columns, and would not be found otherwise.
+    pub(crate) fn coerce_lookup(&self, idx: usize, val: DataValue) -> DataValue {
+        let col = match self.metadata.keys.get(idx) {
+            Some(col) => col,
+            None => &self.metadata.non_keys[idx - self.metadata.keys.len()],
+        };
+        match (&col.typing.coltype, val) {
+            (ColType::Decimal, DataValue::Num(n)) => match n.get_decimal() {
+                Some(d) => DataValue::Decimal(DecimalWrapper(d)),
+                // infinities and NaN, kept below or above all decimals as in comparisons
+                None if n.get_float() < 0. => DataValue::Num(n),
+                None => DataValue::Bot,
+            },
+            (_, val) => val,
+        }
+    }
     pub(crate) fn raw_binding_map(&self) -> BTreeMap<Symbol, usize> {
         let mut ret = BTreeMap::new();
         for (i, col) in self.metadata.keys.iter().enumerate() {
@@ -193,6 +342,9 @@ impl RelationHandle {
         let prefix_bytes = self.id.0.to_be_bytes();
         data[0..8].copy_from_slice(&prefix_bytes);
     }
+    /// Chooses the index whose leading columns are bound the most, returning
+    /// the index, the positions of its columns in the relation, and whether the
+    /// relation itself must be looked up for columns not covered by the index.
     pub(crate) fn choose_index(
         &self,
         arg_uses: &[IndexPositionUse],
@@ -201,10 +353,11 @@ impl RelationHandle {
         if self.indices.is_empty() {
             return None;
         }
-        if *arg_uses.first().unwrap() == IndexPositionUse::Join {
-            return None;
-        }
-        let mut max_prefix_len = 0;
+        // an index is only chosen if more of its leading columns are bound than of the keys
+        let mut max_prefix_len = arg_uses[..self.metadata.keys.len()]
+            .iter()
+            .take_while(|pos_use| **pos_use == IndexPositionUse::Join)
+            .count();
         let required_positions = arg_uses
             .iter()
             .enumerate()
@@ -221,6 +374,16 @@ impl RelationHandle {
             if validity_query && *mapper.last().unwrap() != self.metadata.keys.len() - 1 {
                 continue;
             }
+            // indices on fields of records do not hold the values of the relation's columns,
+            // they are only chosen for filters on the fields
+            if manifest
+                .metadata
+                .keys
+                .iter()
+                .any(|col| col.name.contains('.'))
+            {
+                continue;
+            }
 
             let mut cur_prefix_len = 0;
             for i in mapper {
@@ -244,6 +407,31 @@ impl RelationHandle {
         }
         chosen
     }
+    /// Extracts the row of this index from a row of the indexed relation. For index columns
+    /// named by a path such as `addr.zip`, the extractor points at the record column `addr`,
+    /// and the field is taken from it, or null if it is absent.
+    pub(crate) fn extract_index_row(&self, extractor: &[usize], tuple: &[DataValue]) -> Tuple {
+        extractor
+            .iter()
+            .zip(self.metadata.keys.iter())
+            .map(|(i, col)| match col.name.split_once('.') {
+                None => tuple[*i].clone(),
+                Some((_, path)) => {
+                    let mut cur = &tuple[*i];
+                    for field in path.split('.') {
+                        cur = match cur {
+                            DataValue::Record(r) => match r.get(field) {
+                                Some(v) => v,
+                                None => return DataValue::Null,
+                            },
+                            _ => return DataValue::Null,
+                        }
+                    }
+                    cur.clone()
+                }
+            })
+            .collect_vec()
+    }
     pub(crate) fn encode_key_for_store(
         &self,
         tuple: &[DataValue],
@@ -317,12 +505,19 @@ impl RelationHandle {
     }
 }
 
+/// The name of the index created with a foreign key on the column.
+pub(crate) fn foreign_key_index_name(column: &str) -> String {
+    format!("fk_{column}")
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct InputRelationHandle {
     pub(crate) name: Symbol,
     pub(crate) metadata: StoredRelationMetadata,
     pub(crate) key_bindings: Vec<Symbol>,
     pub(crate) dep_bindings: Vec<Symbol>,
+    /// for `:put_if`, the condition that the existing row must satisfy
+    pub(crate) condition: Option<Expr>,
     pub(crate) span: SourceSpan,
 }
 
@@ -354,6 +549,34 @@ impl RelationHandle {
             RelationDeserError
         })?)
     }
+    /// Scans the whole relation, counting the rows and the distinct key prefixes.
+    /// Since the scan is in key order, a prefix is new whenever it differs from the previous row.
+    pub(crate) fn collect_stats(&self, tx: &SessionTx<'_>) -> Result<RelationStats> {
+        let n_keys = self.metadata.keys.len();
+        let mut stats = RelationStats {
+            rows: 0,
+            distinct_prefixes: vec![0; n_keys],
+        };
+        let mut prev: Option<Tuple> = None;
+        for tuple in self.scan_all(tx) {
+            let tuple = tuple?;
+            let first_diff = match &prev {
+                None => 0,
+                Some(prev) => prev
+                    .iter()
+                    .zip(tuple.iter())
+                    .take(n_keys)
+                    .position(|(a, b)| a != b)
+                    .unwrap_or(n_keys),
+            };
+            for cnt in &mut stats.distinct_prefixes[first_diff..] {
+                *cnt += 1;
+            }
+            stats.rows += 1;
+            prev = Some(tuple);
+        }
+        Ok(stats)
+    }
     pub(crate) fn scan_all<'a>(
         &self,
         tx: &'a SessionTx<'_>,
@@ -514,6 +737,9 @@ impl RelationHandle {
 
 const DEFAULT_SIZE_HINT: usize = 16;
 
+/// Number of rows read at a time when `::alter` rewrites a relation.
+const ALTER_CHUNK_LEN: usize = 1024;
+
 /// Decode tuple from key-value pairs. Used for customizing storage
 /// in trait [`StoreTx`](crate::StoreTx).
 #[inline]
@@ -606,7 +832,7 @@ impl<'a> SessionTx<'a> {
         } else {
             self.relation_store_id.fetch_add(1, Ordering::SeqCst)
         };
-        let meta = RelationHandle {
+        let mut meta = RelationHandle {
             name: input_meta.name.name,
             id: RelationId::new(last_id + 1),
             metadata,
@@ -620,7 +846,15 @@ impl<'a> SessionTx<'a> {
             fts_indices: Default::default(),
             lsh_indices: Default::default(),
             description: Default::default(),
+            stats: None,
+            views: Default::default(),
+            view: None,
+            referenced_by: Default::default(),
+            spatial_indices: Default::default(),
         };
+        self.register_foreign_keys(&mut meta)?;
+        // reports checks referring to unknown columns
+        meta.constraint_checker()?;
 
         let name_key = vec![DataValue::Str(meta.name.clone())].encode_as_key(RelationId::SYSTEM);
         let mut meta_val = vec![];
@@ -639,7 +873,73 @@ impl<'a> SessionTx<'a> {
             self.store_tx.put(&t_encoded, &meta.id.raw_encode())?;
         }
 
-        Ok(meta)
+        self.create_foreign_key_indices(meta)
+    }
+    /// The rows referencing a removed key are looked up by the keys of the relation if the
+    /// referencing column comes first, and by an index on the column otherwise. Creates the
+    /// missing indices, and returns the updated handle.
+    fn create_foreign_key_indices(&mut self, handle: RelationHandle) -> Result<RelationHandle> {
+        let unindexed = handle
+            .metadata
+            .foreign_keys
+            .iter()
+            .map(|fk| fk.column.clone())
+            .filter(|col| handle.metadata.keys.first().map(|k| &k.name) != Some(col))
+            .filter(|col| {
+                !handle
+                    .indices
+                    .contains_key(foreign_key_index_name(col).as_str())
+            })
+            .unique()
+            .collect_vec();
+        if unindexed.is_empty() {
+            return Ok(handle);
+        }
+        let rel_name = Symbol::new(handle.name.clone(), Default::default());
+        for col in unindexed {
+            self.create_index(
+                &rel_name,
+                &Symbol::new(foreign_key_index_name(&col), Default::default()),
+                &[Symbol::new(col, Default::default())],
+            )?;
+        }
+        self.get_relation(&handle.name, false)
+    }
+    /// Checks the foreign keys of a new relation, and records the relation on the relations
+    /// it references so that removals there can be restricted or cascaded.
+    fn register_foreign_keys(&mut self, handle: &mut RelationHandle) -> Result<()> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Bad reference on column '{0}' of relation '{1}': {2}")]
+        #[diagnostic(code(eval::bad_foreign_key))]
+        struct BadForeignKey(String, String, &'static str);
+
+        for fk in &handle.metadata.foreign_keys {
+            let bad =
+                |reason| BadForeignKey(fk.column.to_string(), handle.name.to_string(), reason);
+            ensure!(
+                !handle.is_temp && !fk.relation.starts_with('_'),
+                bad("temp relations cannot have references")
+            );
+            let target_keys = if fk.relation == handle.name {
+                handle.metadata.keys.clone()
+            } else {
+                self.get_relation(&fk.relation, true)?.metadata.keys
+            };
+            ensure!(
+                target_keys.len() == 1 && target_keys[0].name == fk.target,
+                bad("the referenced column must be the only key of its relation")
+            );
+        }
+        for fk in &handle.metadata.foreign_keys {
+            if fk.relation == handle.name {
+                handle.referenced_by.insert(handle.name.clone());
+            } else {
+                let mut target = self.get_relation(&fk.relation, true)?;
+                target.referenced_by.insert(handle.name.clone());
+                self.put_relation_meta(&target)?;
+            }
+        }
+        Ok(())
     }
     pub(crate) fn get_relation(&self, name: &str, lock: bool) -> Result<RelationHandle> {
         #[derive(Error, Diagnostic, Debug)]
@@ -659,7 +959,23 @@ impl<'a> SessionTx<'a> {
                 .get(&encoded, lock)?
                 .ok_or_else(|| StoredRelationNotFoundError(name.to_string()))?
         };
-        let metadata = RelationHandle::decode(&found)?;
+        let mut metadata = RelationHandle::decode(&found)?;
+        let registry = self.custom_registry.read().unwrap();
+        for col in metadata
+            .metadata
+            .keys
+            .iter_mut()
+            .chain(metadata.metadata.non_keys.iter_mut())
+        {
+            if let Some(gen) = &mut col.default_gen {
+                gen.bind_custom_functions(&registry.functions);
+            }
+        }
+        for constraint in metadata.metadata.constraints.iter_mut() {
+            if let ConstraintRule::Check(expr) = &mut constraint.rule {
+                expr.bind_custom_functions(&registry.functions);
+            }
+        }
         Ok(metadata)
     }
     pub(crate) fn describe_relation(&mut self, name: &str, description: &str) -> Result<()> {
@@ -678,6 +994,49 @@ impl<'a> SessionTx<'a> {
 
         Ok(())
     }
+    /// Gathers statistics for the relation and its indices, and stores them in the metadata.
+    pub(crate) fn analyze_relation(
+        &mut self,
+        name: &str,
+    ) -> Result<Vec<(SmartString<LazyCompact>, RelationStats)>> {
+        if name.contains(':') {
+            bail!(
+                "Cannot analyze index '{}' directly, analyze its base relation instead",
+                name
+            );
+        }
+        let mut meta = self.get_relation(name, true)?;
+        if meta.access_level < AccessLevel::ReadOnly {
+            bail!(InsufficientAccessLevel(
+                meta.name.to_string(),
+                "analyzing relation".to_string(),
+                meta.access_level
+            ));
+        }
+        let mut ret = vec![];
+        let stats = meta.collect_stats(self)?;
+        meta.stats = Some(stats.clone());
+        ret.push((meta.name.clone(), stats));
+        for (idx_handle, _) in meta.indices.values_mut() {
+            let stats = idx_handle.collect_stats(self)?;
+            idx_handle.stats = Some(stats.clone());
+            self.put_relation_meta(idx_handle)?;
+            ret.push((idx_handle.name.clone(), stats));
+        }
+        self.put_relation_meta(&meta)?;
+        Ok(ret)
+    }
+    pub(crate) fn put_relation_meta(&mut self, meta: &RelationHandle) -> Result<()> {
+        let name_key = vec![DataValue::Str(meta.name.clone())].encode_as_key(RelationId::SYSTEM);
+        let mut meta_val = vec![];
+        meta.serialize(&mut Serializer::new(&mut meta_val).with_struct_map())
+            .unwrap();
+        if meta.is_temp {
+            self.temp_store_tx.put(&name_key, &meta_val)
+        } else {
+            self.store_tx.put(&name_key, &meta_val)
+        }
+    }
     pub(crate) fn destroy_relation(&mut self, name: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
         let is_temp = name.starts_with('_');
         let mut to_clean = vec![];
@@ -692,6 +1051,24 @@ impl<'a> SessionTx<'a> {
                 name
             );
         }
+        if !store.views.is_empty() {
+            bail!(
+                "Cannot remove stored relation `{}` read by views: {}",
+                name,
+                store.views.iter().join(", ")
+            );
+        }
+        if store.referenced_by.iter().any(|r| *r != store.name) {
+            bail!(
+                "Cannot remove stored relation `{}` referenced by: {}",
+                name,
+                store
+                    .referenced_by
+                    .iter()
+                    .filter(|r| **r != store.name)
+                    .join(", ")
+            );
+        }
         if store.access_level < AccessLevel::Normal {
             bail!(InsufficientAccessLevel(
                 store.name.to_string(),
@@ -710,6 +1087,19 @@ impl<'a> SessionTx<'a> {
             to_clean.extend(more_to_clean);
         }
 
+        for k in store.spatial_indices.keys() {
+            let more_to_clean = self.destroy_relation(&format!("{name}:{k}"))?;
+            to_clean.extend(more_to_clean);
+        }
+
+        for fk in &store.metadata.foreign_keys {
+            if fk.relation != store.name {
+                let mut target = self.get_relation(&fk.relation, true)?;
+                target.referenced_by.remove(&store.name);
+                self.put_relation_meta(&target)?;
+            }
+        }
+
         let key = DataValue::from(name);
         let encoded = vec![key].encode_as_key(RelationId::SYSTEM);
         if is_temp {
@@ -738,7 +1128,7 @@ impl<'a> SessionTx<'a> {
 
     pub(crate) fn create_minhash_lsh_index(&mut self, config: &MinHashLshConfig) -> Result<()> {
         // Get relation handle
-        let mut rel_handle = self.get_relation(&config.base_relation, true)?;
+        let rel_handle = self.get_relation(&config.base_relation, true)?;
 
         // Check if index already exists
         if rel_handle.has_index(&config.index_name) {
@@ -748,6 +1138,39 @@ impl<'a> SessionTx<'a> {
             ));
         }
 
+        let params = LshParams::find_optimal_params(
+            config.target_threshold.0,
+            config.n_perm,
+            &Weights(
+                config.false_positive_weight.0,
+                config.false_negative_weight.0,
+            ),
+        );
+        let num_perm = params.b * params.r;
+        let perms = HashPermutations::new(num_perm);
+        let manifest = MinHashLshIndexManifest {
+            base_relation: config.base_relation.clone(),
+            index_name: config.index_name.clone(),
+            extractor: config.extractor.clone(),
+            n_gram: config.n_gram,
+            tokenizer: config.tokenizer.clone(),
+            filters: config.filters.clone(),
+            num_perm,
+            n_bands: params.b,
+            n_rows_in_band: params.r,
+            threshold: config.target_threshold.0,
+            perms: perms.as_bytes().to_vec(),
+        };
+        self.build_minhash_lsh_index(rel_handle, manifest)
+    }
+
+    /// Creates the relations of a MinHash-LSH index described by `manifest` and populates them
+    /// from the rows of the base relation.
+    fn build_minhash_lsh_index(
+        &mut self,
+        mut rel_handle: RelationHandle,
+        manifest: MinHashLshIndexManifest,
+    ) -> Result<()> {
         let inv_idx_keys = rel_handle.metadata.keys.clone();
         let inv_idx_vals = vec![ColumnDef {
             name: SmartString::from("minhash"),
@@ -776,44 +1199,19 @@ impl<'a> SessionTx<'a> {
         let idx_vals = vec![];
 
         let idx_handle = self.write_idx_relation(
-            &config.base_relation,
-            &config.index_name,
+            &manifest.base_relation,
+            &manifest.index_name,
             idx_keys,
             idx_vals,
         )?;
 
         let inv_idx_handle = self.write_idx_relation(
-            &config.base_relation,
-            &format!("{}:inv", config.index_name),
+            &manifest.base_relation,
+            &format!("{}:inv", manifest.index_name),
             inv_idx_keys,
             inv_idx_vals,
         )?;
 
-        // add index to relation
-        let params = LshParams::find_optimal_params(
-            config.target_threshold.0,
-            config.n_perm,
-            &Weights(
-                config.false_positive_weight.0,
-                config.false_negative_weight.0,
-            ),
-        );
-        let num_perm = params.b * params.r;
-        let perms = HashPermutations::new(num_perm);
-        let manifest = MinHashLshIndexManifest {
-            base_relation: config.base_relation.clone(),
-            index_name: config.index_name.clone(),
-            extractor: config.extractor.clone(),
-            n_gram: config.n_gram,
-            tokenizer: config.tokenizer.clone(),
-            filters: config.filters.clone(),
-            num_perm,
-            n_bands: params.b,
-            n_rows_in_band: params.r,
-            threshold: config.target_threshold.0,
-            perms: perms.as_bytes().to_vec(),
-        };
-
         // populate index
         let tokenizer =
             self.tokenizers
@@ -822,7 +1220,11 @@ impl<'a> SessionTx<'a> {
             .into_diagnostic()?
             .next()
             .unwrap();
-        let mut code_expr = build_expr(parsed, &Default::default())?;
+        let mut code_expr = build_expr(
+            parsed,
+            &Default::default(),
+            &self.custom_registry.read().unwrap(),
+        )?;
         let binding_map = rel_handle.raw_binding_map();
         code_expr.fill_binding_indices(&binding_map)?;
         let extractor = code_expr.compile()?;
@@ -866,6 +1268,112 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    pub(crate) fn create_spatial_index(&mut self, config: &SpatialIndexConfig) -> Result<()> {
+        let rel_handle = self.get_relation(&config.base_relation, true)?;
+
+        if rel_handle.has_index(&config.index_name) {
+            bail!(IndexAlreadyExists(
+                config.index_name.to_string(),
+                config.index_name.to_string()
+            ));
+        }
+
+        let field = match rel_handle
+            .metadata
+            .keys
+            .iter()
+            .chain(rel_handle.metadata.non_keys.iter())
+            .position(|col| col.name == config.field)
+        {
+            None => bail!(
+                "Cannot create spatial index with non-existent field {}",
+                config.field
+            ),
+            Some(i) => i,
+        };
+        let col = rel_handle
+            .metadata
+            .keys
+            .iter()
+            .chain(rel_handle.metadata.non_keys.iter())
+            .nth(field)
+            .unwrap();
+        if col.typing.coltype != ColType::Geometry {
+            bail!(
+                "Cannot create spatial index with field {} of type {} (expected Geometry)",
+                config.field,
+                col.typing
+            );
+        }
+
+        let manifest = SpatialIndexManifest {
+            base_relation: config.base_relation.clone(),
+            index_name: config.index_name.clone(),
+            field,
+            max_entries: config.max_entries,
+        };
+        self.build_spatial_index(rel_handle, manifest)
+    }
+
+    /// Creates the relation of an R-tree index described by `manifest` and populates it
+    /// from the rows of the base relation.
+    fn build_spatial_index(
+        &mut self,
+        mut rel_handle: RelationHandle,
+        manifest: SpatialIndexManifest,
+    ) -> Result<()> {
+        let int_col = |name: &str| ColumnDef {
+            name: SmartString::from(name),
+            typing: NullableColType {
+                coltype: ColType::Int,
+                nullable: false,
+            },
+            default_gen: None,
+        };
+        let mut idx_keys = vec![int_col("node"), int_col("child")];
+        for k in rel_handle.metadata.keys.iter() {
+            idx_keys.push(ColumnDef {
+                name: format!("src_{}", k.name).into(),
+                typing: NullableColType {
+                    coltype: k.typing.coltype.clone(),
+                    nullable: true,
+                },
+                default_gen: None,
+            });
+        }
+        let idx_vals = ["min_x", "min_y", "max_x", "max_y"]
+            .into_iter()
+            .map(|name| ColumnDef {
+                name: SmartString::from(name),
+                typing: NullableColType {
+                    coltype: ColType::Float,
+                    nullable: false,
+                },
+                default_gen: None,
+            })
+            .collect_vec();
+
+        let idx_handle = self.write_idx_relation(
+            &manifest.base_relation,
+            &manifest.index_name,
+            idx_keys,
+            idx_vals,
+        )?;
+
+        let mut existing = TempCollector::default();
+        for tuple in rel_handle.scan_all(self) {
+            existing.push(tuple?);
+        }
+        for tuple in existing.into_iter() {
+            self.spatial_put(&manifest, &rel_handle, &idx_handle, &tuple)?;
+        }
+
+        rel_handle
+            .spatial_indices
+            .insert(manifest.index_name.clone(), (idx_handle, manifest));
+        self.put_relation_meta(&rel_handle)
+    }
+
     pub(crate) fn create_fts_index(&mut self, config: &FtsIndexConfig) -> Result<()> {
         // Get relation handle
         let mut rel_handle = self.get_relation(&config.base_relation, true)?;
@@ -958,7 +1466,11 @@ impl<'a> SessionTx<'a> {
             .into_diagnostic()?
             .next()
             .unwrap();
-        let mut code_expr = build_expr(parsed, &Default::default())?;
+        let mut code_expr = build_expr(
+            parsed,
+            &Default::default(),
+            &self.custom_registry.read().unwrap(),
+        )?;
         let binding_map = rel_handle.raw_binding_map();
         code_expr.fill_binding_indices(&binding_map)?;
         let extractor = code_expr.compile()?;
@@ -1160,7 +1672,11 @@ impl<'a> SessionTx<'a> {
                 .into_diagnostic()?
                 .next()
                 .unwrap();
-            let mut code_expr = build_expr(parsed, &Default::default())?;
+            let mut code_expr = build_expr(
+                parsed,
+                &Default::default(),
+                &self.custom_registry.read().unwrap(),
+            )?;
             let binding_map = rel_handle.raw_binding_map();
             code_expr.fill_binding_indices(&binding_map)?;
             code_expr.compile()?
@@ -1220,15 +1736,21 @@ impl<'a> SessionTx<'a> {
             metadata: StoredRelationMetadata {
                 keys: idx_keys,
                 non_keys: non_idx_keys,
+                foreign_keys: vec![],
+                constraints: vec![],
             },
             key_bindings,
             dep_bindings,
+            condition: None,
             span: Default::default(),
         };
         let idx_handle = self.create_relation(idx_handle)?;
         Ok(idx_handle)
     }
 
+    /// Creates an index on columns of the relation. A column may also be a field of a record
+    /// column, named by a path such as `addr.zip`. An index leading with a field is used for
+    /// filters such as `addr->'zip' == 12345` when no keys of the relation are bound.
     pub(crate) fn create_index(
         &mut self,
         rel_name: &Symbol,
@@ -1249,14 +1771,40 @@ impl<'a> SessionTx<'a> {
         // Build column definitions
         let mut col_defs = vec![];
         'outer: for col in cols.iter() {
+            // a path such as `addr.zip` refers to a field of a record column
+            let (col_name, path) = match col.name.split_once('.') {
+                Some((col_name, path)) => (col_name, Some(path)),
+                None => (col.name.as_str(), None),
+            };
             for orig_col in rel_handle
                 .metadata
                 .keys
                 .iter()
                 .chain(rel_handle.metadata.non_keys.iter())
             {
-                if orig_col.name == col.name {
-                    col_defs.push(orig_col.clone());
+                if orig_col.name.as_str() == col_name {
+                    match path {
+                        None => col_defs.push(orig_col.clone()),
+                        Some(path) => {
+                            #[derive(Debug, Error, Diagnostic)]
+                            #[error("column {0} of type {1} has no field {2}")]
+                            #[diagnostic(code(tx::field_in_idx_not_found))]
+                            pub(crate) struct FieldInIndexNotFound(String, String, String);
+
+                            let typing = orig_col.typing.field_type(path).ok_or_else(|| {
+                                FieldInIndexNotFound(
+                                    col_name.to_string(),
+                                    orig_col.typing.to_string(),
+                                    path.to_string(),
+                                )
+                            })?;
+                            col_defs.push(ColumnDef {
+                                name: col.name.clone(),
+                                typing,
+                                default_gen: None,
+                            });
+                        }
+                    }
                     continue 'outer;
                 }
             }
@@ -1289,6 +1837,8 @@ impl<'a> SessionTx<'a> {
         let idx_meta = StoredRelationMetadata {
             keys: col_defs,
             non_keys: vec![],
+            foreign_keys: vec![],
+            constraints: vec![],
         };
 
         // create index relation
@@ -1300,6 +1850,7 @@ impl<'a> SessionTx<'a> {
             metadata: idx_meta,
             key_bindings,
             dep_bindings: vec![],
+            condition: None,
             span: Default::default(),
         };
 
@@ -1311,13 +1862,18 @@ impl<'a> SessionTx<'a> {
             .keys
             .iter()
             .map(|col| {
+                // fields of records are extracted from the record column
+                let col_name = match col.name.split_once('.') {
+                    Some((col_name, _)) => col_name,
+                    None => col.name.as_str(),
+                };
                 for (i, kc) in rel_handle.metadata.keys.iter().enumerate() {
-                    if kc.name == col.name {
+                    if kc.name.as_str() == col_name {
                         return i;
                     }
                 }
                 for (i, kc) in rel_handle.metadata.non_keys.iter().enumerate() {
-                    if kc.name == col.name {
+                    if kc.name.as_str() == col_name {
                         return i + rel_handle.metadata.keys.len();
                     }
                 }
@@ -1328,10 +1884,7 @@ impl<'a> SessionTx<'a> {
         if self.store_tx.supports_par_put() {
             for tuple in rel_handle.scan_all(self) {
                 let tuple = tuple?;
-                let extracted = extraction_indices
-                    .iter()
-                    .map(|idx| tuple[*idx].clone())
-                    .collect_vec();
+                let extracted = idx_handle.extract_index_row(&extraction_indices, &tuple);
                 let key = idx_handle.encode_key_for_store(&extracted, Default::default())?;
                 self.store_tx.par_put(&key, &[])?;
             }
@@ -1341,10 +1894,7 @@ impl<'a> SessionTx<'a> {
                 existing.push(tuple?);
             }
             for tuple in existing.into_iter() {
-                let extracted = extraction_indices
-                    .iter()
-                    .map(|idx| tuple[*idx].clone())
-                    .collect_vec();
+                let extracted = idx_handle.extract_index_row(&extraction_indices, &tuple);
                 let key = idx_handle.encode_key_for_store(&extracted, Default::default())?;
                 self.store_tx.put(&key, &[])?;
             }
@@ -1383,6 +1933,7 @@ impl<'a> SessionTx<'a> {
             && rel.hnsw_indices.remove(&idx_name.name).is_none()
             && rel.lsh_indices.remove(&idx_name.name).is_none()
             && rel.fts_indices.remove(&idx_name.name).is_none()
+            && rel.spatial_indices.remove(&idx_name.name).is_none()
         {
             #[derive(Debug, Error, Diagnostic)]
             #[error("index {0} for relation {1} not found")]
@@ -1431,6 +1982,19 @@ impl<'a> SessionTx<'a> {
                 rel.access_level
             ));
         }
+        if !rel.views.is_empty() {
+            bail!(
+                "Cannot rename stored relation `{}` read by views: {}",
+                rel.name,
+                rel.views.iter().join(", ")
+            );
+        }
+        if !rel.referenced_by.is_empty() || !rel.metadata.foreign_keys.is_empty() {
+            bail!(
+                "Cannot rename stored relation `{}` taking part in references",
+                rel.name
+            );
+        }
         rel.name = new.name.clone();
 
         let mut meta_val = vec![];
@@ -1440,6 +2004,476 @@ impl<'a> SessionTx<'a> {
 
         Ok(())
     }
+    /// Changes the columns of a stored relation in place for `::alter`. The stored rows are
+    /// rewritten and all indices rebuilt, while triggers and access levels are kept.
+    /// Constraints, references and the expressions of indices follow renamed columns.
+    pub(crate) fn alter_relation(
+        &mut self,
+        rel_name: &Symbol,
+        ops: &[AlterOp],
+        cur_vld: ValidityTs,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Cannot alter relation {0}: {1}")]
+        #[diagnostic(code(tx::bad_alter))]
+        struct BadAlter(String, String);
+
+        let handle = self.get_relation(rel_name, true)?;
+        let bad = |reason: String| BadAlter(handle.name.to_string(), reason);
+        ensure!(
+            !handle.is_temp && !handle.name.contains(':'),
+            bad("only stored relations can be altered".to_string())
+        );
+        if handle.access_level < AccessLevel::Normal {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "altering relation".to_string(),
+                handle.access_level
+            ));
+        }
+        ensure!(
+            handle.views.is_empty() && handle.view.is_none(),
+            bad("views depend on its columns".to_string())
+        );
+
+        // the columns after the changes, whether they are keys, and their positions in the old rows
+        let n_keys = handle.metadata.keys.len();
+        let mut cols = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .enumerate()
+            .map(|(i, col)| (col.clone(), i < n_keys, Some(i)))
+            .collect_vec();
+        for op in ops {
+            let name = match op {
+                AlterOp::Add(col) => {
+                    ensure!(
+                        cols.iter().all(|(c, _, _)| c.name != col.name),
+                        bad(format!("column {} already exists", col.name))
+                    );
+                    cols.push((col.clone(), false, None));
+                    continue;
+                }
+                AlterOp::Drop(name)
+                | AlterOp::Rename(name, _)
+                | AlterOp::Change(name, _)
+                | AlterOp::Move(name, _) => name,
+            };
+            let pos = cols
+                .iter()
+                .position(|(c, _, _)| c.name == name.name)
+                .ok_or_else(|| bad(format!("column {name} not found")))?;
+            // the foreign keys of other relations point at the only key
+            ensure!(
+                handle.referenced_by.is_empty()
+                    || !cols[pos].1
+                    || matches!(op, AlterOp::Rename(..)),
+                bad(format!(
+                    "column {name} is referenced, and can only be renamed"
+                ))
+            );
+            match op {
+                AlterOp::Add(_) => unreachable!(),
+                AlterOp::Drop(_) => {
+                    cols.remove(pos);
+                }
+                AlterOp::Rename(_, new_name) => {
+                    ensure!(
+                        cols.iter().all(|(c, _, _)| c.name != new_name.name),
+                        bad(format!("column {new_name} already exists"))
+                    );
+                    cols[pos].0.name = new_name.name.clone();
+                }
+                AlterOp::Change(_, typing) => cols[pos].0.typing = typing.clone(),
+                AlterOp::Move(_, to_key) => {
+                    let mut col = cols.remove(pos);
+                    col.1 = *to_key;
+                    cols.push(col);
+                }
+            }
+        }
+        // keys come first, keeping their relative order
+        cols.sort_by_key(|(_, is_key, _)| !*is_key);
+        ensure!(
+            handle.referenced_by.is_empty()
+                || cols.iter().filter(|(_, is_key, _)| *is_key).count() == 1,
+            bad("its key is referenced, and must stay the only one".to_string())
+        );
+        // the name after the changes of each old column, if it is kept
+        let new_name_of = |old_idx: usize| {
+            cols.iter()
+                .find(|(_, _, src)| *src == Some(old_idx))
+                .map(|(col, _, _)| col.name.clone())
+        };
+        let old_cols = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .collect_vec();
+        let mut renamed = BTreeMap::new();
+        let mut dropped = BTreeSet::new();
+        for (i, col) in old_cols.iter().enumerate() {
+            match new_name_of(i) {
+                None => {
+                    dropped.insert(col.name.clone());
+                }
+                Some(name) if name != col.name => {
+                    renamed.insert(col.name.clone(), name);
+                }
+                Some(_) => {}
+            }
+        }
+
+        // triggers refer to the columns by their names and positions, and are not rewritten
+        let same_layout = cols
+            .iter()
+            .map(|(col, is_key, _)| (&col.name, *is_key))
+            .eq(old_cols
+                .iter()
+                .enumerate()
+                .map(|(i, col)| (&col.name, i < n_keys)));
+        ensure!(
+            same_layout
+                || (handle.put_triggers.is_empty()
+                    && handle.rm_triggers.is_empty()
+                    && handle.replace_triggers.is_empty()),
+            bad(
+                "its triggers refer to the columns, remove them with ::set_triggers first"
+                    .to_string()
+            )
+        );
+
+        // constraints and foreign keys follow their columns, and go away with them
+        let mut constraints = vec![];
+        for constraint in &handle.metadata.constraints {
+            if dropped.contains(&constraint.column) {
+                continue;
+            }
+            let mut constraint = constraint.clone();
+            if let ConstraintRule::Check(expr) = &mut constraint.rule {
+                if let Some(binding) = expr
+                    .bindings()?
+                    .into_iter()
+                    .find(|b| dropped.contains(&b.name))
+                {
+                    bail!(bad(format!(
+                        "constraint {} refers to the dropped column {}",
+                        constraint.name, binding.name
+                    )));
+                }
+                expr.rename_bindings(&renamed);
+            }
+            if let Some(name) = renamed.get(&constraint.column) {
+                constraint.column = name.clone();
+            }
+            constraints.push(constraint);
+        }
+        let mut foreign_keys = vec![];
+        for fk in &handle.metadata.foreign_keys {
+            if dropped.contains(&fk.column) {
+                continue;
+            }
+            let mut fk = fk.clone();
+            if let Some(name) = renamed.get(&fk.column) {
+                fk.column = name.clone();
+            }
+            if fk.relation == handle.name {
+                if let Some(name) = renamed.get(&fk.target) {
+                    fk.target = name.clone();
+                }
+            }
+            foreign_keys.push(fk);
+        }
+
+        // indices are dropped before rewriting the rows, and then recreated
+        let mut indices = vec![];
+        for (idx_name, (idx_handle, extractor)) in &handle.indices {
+            // the indices of foreign keys are created again for the remaining references
+            if handle
+                .metadata
+                .foreign_keys
+                .iter()
+                .any(|fk| *idx_name == foreign_key_index_name(&fk.column))
+            {
+                continue;
+            }
+            // the specified columns are followed by the keys not among them
+            let n_specified = (0..=extractor.len())
+                .find(|n| {
+                    let (specified, rest) = extractor.split_at(*n);
+                    rest.iter()
+                        .copied()
+                        .eq((0..n_keys).filter(|k| !specified.contains(k)))
+                })
+                .unwrap_or(extractor.len());
+            let idx_cols: Vec<_> = extractor[..n_specified]
+                .iter()
+                .zip(idx_handle.metadata.keys.iter())
+                .map(|(i, idx_col)| {
+                    new_name_of(*i)
+                        .map(|name| {
+                            // fields of a record column keep their path under the new name
+                            let name = match idx_col.name.split_once('.') {
+                                Some((_, path)) => SmartString::from(format!("{name}.{path}")),
+                                None => name,
+                            };
+                            Symbol::new(name, Default::default())
+                        })
+                        .ok_or_else(|| bad(format!("a column of index {idx_name} is dropped")))
+                })
+                .try_collect()?;
+            indices.push((idx_name.clone(), idx_cols));
+        }
+        // expressions of indices refer to the columns by name, and are rewritten with the new
+        // names, keeping the rest of the code
+        let rewrite_expr = |code: &str, idx_name: &str| -> Result<String> {
+            let parsed = CozoScriptParser::parse(Rule::expr, code)
+                .into_diagnostic()?
+                .next()
+                .unwrap();
+            let mut rewritten = String::new();
+            let mut last = 0;
+            for var in parsed
+                .into_inner()
+                .flatten()
+                .filter(|p| p.as_rule() == Rule::var)
+            {
+                let name = var.as_str();
+                ensure!(
+                    !dropped.contains(name),
+                    bad(format!(
+                        "index {idx_name} refers to the dropped column {name} in an expression"
+                    ))
+                );
+                if let Some(new_name) = renamed.get(name) {
+                    rewritten.push_str(&code[last..var.as_span().start()]);
+                    rewritten.push_str(new_name);
+                    last = var.as_span().end();
+                }
+            }
+            rewritten.push_str(&code[last..]);
+            Ok(rewritten)
+        };
+        let mut hnsw_indices = vec![];
+        for (_, manifest) in handle.hnsw_indices.values() {
+            let index_filter = match &manifest.index_filter {
+                Some(f_code) => Some(rewrite_expr(f_code, &manifest.index_name)?),
+                None => None,
+            };
+            let vec_fields = manifest
+                .vec_fields
+                .iter()
+                .map(|i| {
+                    new_name_of(*i).ok_or_else(|| {
+                        bad(format!(
+                            "a column of index {} is dropped",
+                            manifest.index_name
+                        ))
+                    })
+                })
+                .try_collect()?;
+            hnsw_indices.push(HnswIndexConfig {
+                base_relation: manifest.base_relation.clone(),
+                index_name: manifest.index_name.clone(),
+                vec_dim: manifest.vec_dim,
+                dtype: manifest.dtype,
+                vec_fields,
+                distance: manifest.distance,
+                ef_construction: manifest.ef_construction,
+                m_neighbours: manifest.m_neighbours,
+                index_filter,
+                extend_candidates: manifest.extend_candidates,
+                keep_pruned_connections: manifest.keep_pruned_connections,
+            });
+        }
+        let mut fts_indices = vec![];
+        for (_, manifest) in handle.fts_indices.values() {
+            fts_indices.push(FtsIndexConfig {
+                base_relation: manifest.base_relation.clone(),
+                index_name: manifest.index_name.clone(),
+                extractor: rewrite_expr(&manifest.extractor, &manifest.index_name)?,
+                tokenizer: manifest.tokenizer.clone(),
+                filters: manifest.filters.clone(),
+            });
+        }
+        let mut lsh_indices = vec![];
+        for (_, _, manifest) in handle.lsh_indices.values() {
+            let mut manifest = manifest.clone();
+            manifest.extractor = rewrite_expr(&manifest.extractor, &manifest.index_name)?;
+            lsh_indices.push(manifest);
+        }
+        let mut spatial_indices = vec![];
+        for (_, manifest) in handle.spatial_indices.values() {
+            let field = new_name_of(manifest.field).ok_or_else(|| {
+                bad(format!(
+                    "a column of index {} is dropped",
+                    manifest.index_name
+                ))
+            })?;
+            spatial_indices.push(SpatialIndexConfig {
+                base_relation: manifest.base_relation.clone(),
+                index_name: manifest.index_name.clone(),
+                field,
+                max_entries: manifest.max_entries,
+            });
+        }
+
+        let mut to_clean = vec![];
+        let idx_names = handle
+            .indices
+            .keys()
+            .chain(handle.hnsw_indices.keys())
+            .chain(handle.fts_indices.keys())
+            .chain(handle.lsh_indices.keys())
+            .chain(handle.spatial_indices.keys());
+        for idx_name in idx_names {
+            let idx_name = Symbol::new(idx_name.clone(), Default::default());
+            to_clean.extend(self.remove_index(rel_name, &idx_name)?);
+        }
+
+        let mut new_handle = self.get_relation(rel_name, true)?;
+        new_handle.metadata.keys = cols
+            .iter()
+            .filter(|(_, is_key, _)| *is_key)
+            .map(|(col, _, _)| col.clone())
+            .collect();
+        new_handle.metadata.non_keys = cols
+            .iter()
+            .filter(|(_, is_key, _)| !*is_key)
+            .map(|(col, _, _)| col.clone())
+            .collect();
+        new_handle.metadata.constraints = constraints;
+        new_handle.metadata.foreign_keys = foreign_keys;
+        let n_new_keys = new_handle.metadata.keys.len();
+        new_handle.stats = None;
+
+        // the relations no longer referenced, and the references of other relations to a
+        // renamed key
+        for target in handle
+            .metadata
+            .foreign_keys
+            .iter()
+            .map(|fk| &fk.relation)
+            .unique()
+        {
+            if new_handle
+                .metadata
+                .foreign_keys
+                .iter()
+                .any(|fk| fk.relation == *target)
+            {
+                continue;
+            }
+            if *target == handle.name {
+                new_handle.referenced_by.remove(&handle.name);
+            } else {
+                let mut target = self.get_relation(target, true)?;
+                target.referenced_by.remove(&handle.name);
+                self.put_relation_meta(&target)?;
+            }
+        }
+        if let Some(key) = renamed.get(&handle.metadata.keys[0].name) {
+            for child in handle.referenced_by.iter().filter(|r| **r != handle.name) {
+                let mut child = self.get_relation(child, true)?;
+                for fk in child.metadata.foreign_keys.iter_mut() {
+                    if fk.relation == handle.name {
+                        fk.target = key.clone();
+                    }
+                }
+                self.put_relation_meta(&child)?;
+            }
+        }
+
+        // the rewritten rows are stored under a fresh id, and the old rows are cleaned up after
+        // the transaction commits
+        let last_id = self.relation_store_id.fetch_add(1, Ordering::SeqCst);
+        new_handle.id = RelationId::new(last_id + 1);
+        let t_encoded = vec![DataValue::Null].encode_as_key(RelationId::SYSTEM);
+        self.store_tx.put(&t_encoded, &new_handle.id.raw_encode())?;
+        let mut lower = Tuple::default().encode_as_key(handle.id);
+        let upper = Tuple::default().encode_as_key(handle.id.next());
+        to_clean.push((lower.clone(), upper.clone()));
+
+        let mut constraints = new_handle.constraint_checker()?;
+        // values of columns with changed types may no longer be found in the referenced relations
+        let references = self.make_references(&new_handle)?;
+        let mut referenced_values = vec![BTreeSet::new(); references.len()];
+        loop {
+            // the old rows are read in chunks, as the scan borrows the transaction
+            let chunk: Vec<(Vec<u8>, Vec<u8>)> = self
+                .store_tx
+                .range_scan(&lower, &upper)
+                .take(ALTER_CHUNK_LEN)
+                .try_collect()?;
+            match chunk.last() {
+                None => break,
+                Some((last_key, _)) => {
+                    lower = last_key.clone();
+                    lower.push(0);
+                }
+            }
+            for (k, v) in chunk {
+                let row = decode_tuple_from_kv(&k, &v, None);
+                let new_row: Vec<DataValue> = cols
+                    .iter()
+                    .map(|(col, _, src)| -> Result<DataValue> {
+                        match (src, &col.default_gen) {
+                            (Some(i), _) => col.typing.coerce(row[*i].clone(), cur_vld),
+                            (None, Some(gen)) => {
+                                col.typing.coerce(gen.clone().eval_to_const()?, cur_vld)
+                            }
+                            (None, None) => Ok(DataValue::Null),
+                        }
+                    })
+                    .try_collect()?;
+                constraints.check(&new_row)?;
+                collect_referenced_values(&references, &new_row, &mut referenced_values);
+                let key = new_handle.encode_key_for_store(&new_row, Default::default())?;
+                ensure!(
+                    !self.store_tx.exists(&key, false)?,
+                    bad(format!(
+                        "more than one row has the keys {:?}",
+                        &new_row[..n_new_keys]
+                    ))
+                );
+                let val = new_handle.encode_val_for_store(&new_row, Default::default())?;
+                self.store_tx.put(&key, &val)?;
+            }
+        }
+        self.put_relation_meta(&new_handle)?;
+        let references = self.make_references(&new_handle)?;
+        self.ensure_referenced_rows(
+            &new_handle,
+            &references,
+            referenced_values,
+            Default::default(),
+        )?;
+
+        for (idx_name, idx_cols) in indices {
+            let idx_name = Symbol::new(idx_name, Default::default());
+            self.create_index(rel_name, &idx_name, &idx_cols)?;
+        }
+        for config in hnsw_indices {
+            self.create_hnsw_index(&config)?;
+        }
+        for config in fts_indices {
+            self.create_fts_index(&config)?;
+        }
+        for manifest in lsh_indices {
+            let rel_handle = self.get_relation(rel_name, true)?;
+            self.build_minhash_lsh_index(rel_handle, manifest)?;
+        }
+        for config in spatial_indices {
+            self.create_spatial_index(&config)?;
+        }
+        let new_handle = self.get_relation(rel_name, false)?;
+        self.create_foreign_key_indices(new_handle)?;
+
+        Ok(to_clean)
+    }
     pub(crate) fn rename_temp_relation(&mut self, old: Symbol, new: Symbol) -> Result<()> {
         let new_key = DataValue::Str(new.name.clone());
         let new_encoded = vec![new_key].encode_as_key(RelationId::SYSTEM);
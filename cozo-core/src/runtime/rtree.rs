@@ -0,0 +1,594 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! R-tree over the bounding boxes of geometries, stored in the index relation with one row
+//! per entry. The keys of a row are the node holding the entry, the child node the entry
+//! points to (`0` for leaf entries) and the keys of the indexed row (nulls for non-leaf
+//! entries), and the values are the bounding box of the entry.
+//! The root is always node `1`, and node `0` holds a single entry recording the next free id.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use itertools::Itertools;
+use miette::{bail, ensure, miette, Result};
+use ordered_float::OrderedFloat;
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::expr::{eval_bytecode_pred, Bytecode};
+use crate::data::geometry::{bbox_area, bbox_distance, bbox_intersects, bbox_union, BBox};
+use crate::data::tuple::Tuple;
+use crate::data::value::{Coord, Geometry};
+use crate::runtime::relation::RelationHandle;
+use crate::runtime::transact::SessionTx;
+use crate::{DataValue, Expr, SourceSpan, Symbol};
+
+const META_NODE: i64 = 0;
+const ROOT_NODE: i64 = 1;
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct SpatialIndexManifest {
+    pub(crate) base_relation: SmartString<LazyCompact>,
+    pub(crate) index_name: SmartString<LazyCompact>,
+    /// position of the indexed column in the rows of the base relation
+    pub(crate) field: usize,
+    /// nodes with more entries are split
+    pub(crate) max_entries: usize,
+}
+
+impl SpatialIndexManifest {
+    fn min_entries(&self) -> usize {
+        (self.max_entries * 2 / 5).max(2)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SpatialSearch {
+    pub(crate) base_handle: RelationHandle,
+    pub(crate) idx_handle: RelationHandle,
+    pub(crate) manifest: SpatialIndexManifest,
+    pub(crate) bindings: Vec<Symbol>,
+    pub(crate) k: Option<usize>,
+    pub(crate) query: Option<Symbol>,
+    pub(crate) bbox: Option<Symbol>,
+    pub(crate) radius: Option<f64>,
+    pub(crate) bind_distance: Option<Symbol>,
+    pub(crate) filter: Option<Expr>,
+    pub(crate) span: SourceSpan,
+}
+
+impl SpatialSearch {
+    pub(crate) fn all_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.bindings.iter().chain(self.bind_distance.iter())
+    }
+    /// The bindings that must be bound before searching
+    pub(crate) fn input_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.query.iter().chain(self.bbox.iter())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Target {
+    Node(i64),
+    /// the keys of the indexed row
+    Item(Tuple),
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    bbox: BBox,
+    target: Target,
+}
+
+impl Entry {
+    fn to_row(&self, node: i64, n_keys: usize) -> Tuple {
+        let mut row = Vec::with_capacity(n_keys + 6);
+        row.push(DataValue::from(node));
+        match &self.target {
+            Target::Node(child) => {
+                row.push(DataValue::from(*child));
+                row.extend((0..n_keys).map(|_| DataValue::Null));
+            }
+            Target::Item(keys) => {
+                row.push(DataValue::from(0));
+                row.extend_from_slice(keys);
+            }
+        }
+        row.extend(self.bbox.iter().map(|v| DataValue::from(*v)));
+        row
+    }
+    fn from_row(row: Tuple, n_keys: usize) -> Self {
+        let bbox = [2, 3, 4, 5].map(|i| row[n_keys + i].get_float().unwrap());
+        let target = match row[1].get_int().unwrap() {
+            0 => Target::Item(row[2..n_keys + 2].to_vec()),
+            child => Target::Node(child),
+        };
+        Entry { bbox, target }
+    }
+}
+
+fn entries_bbox(entries: &[Entry]) -> BBox {
+    entries
+        .iter()
+        .map(|e| e.bbox)
+        .reduce(|a, b| bbox_union(&a, &b))
+        .unwrap()
+}
+
+fn bbox_contains(outer: &BBox, inner: &BBox) -> bool {
+    bbox_union(outer, inner) == *outer
+}
+
+fn enlargement(bbox: &BBox, added: &BBox) -> f64 {
+    bbox_area(&bbox_union(bbox, added)) - bbox_area(bbox)
+}
+
+/// The entry whose box needs the least enlargement to cover `bbox`, the smallest box on ties
+fn choose_subtree(entries: &[Entry], bbox: &BBox) -> usize {
+    entries
+        .iter()
+        .position_min_by(|a, b| {
+            enlargement(&a.bbox, bbox)
+                .total_cmp(&enlargement(&b.bbox, bbox))
+                .then_with(|| bbox_area(&a.bbox).total_cmp(&bbox_area(&b.bbox)))
+        })
+        .unwrap()
+}
+
+/// Guttman's quadratic split
+fn split_entries(mut entries: Vec<Entry>, min_entries: usize) -> (Vec<Entry>, Vec<Entry>) {
+    // the seeds are the pair that would waste the most area in the same node
+    let (mut seed_1, mut seed_2) = (0, 1);
+    let mut max_waste = f64::NEG_INFINITY;
+    for i in 0..entries.len() {
+        for j in i + 1..entries.len() {
+            let (a, b) = (&entries[i].bbox, &entries[j].bbox);
+            let waste = bbox_area(&bbox_union(a, b)) - bbox_area(a) - bbox_area(b);
+            if waste > max_waste {
+                max_waste = waste;
+                (seed_1, seed_2) = (i, j);
+            }
+        }
+    }
+    let second = entries.swap_remove(seed_2);
+    let first = entries.swap_remove(seed_1);
+    let (mut bbox_1, mut bbox_2) = (first.bbox, second.bbox);
+    let (mut group_1, mut group_2) = (vec![first], vec![second]);
+    while !entries.is_empty() {
+        if group_1.len() + entries.len() <= min_entries {
+            group_1.append(&mut entries);
+            break;
+        }
+        if group_2.len() + entries.len() <= min_entries {
+            group_2.append(&mut entries);
+            break;
+        }
+        // the entry with the strongest preference goes first
+        let (pos, d1, d2) = entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| {
+                (
+                    i,
+                    enlargement(&bbox_1, &e.bbox),
+                    enlargement(&bbox_2, &e.bbox),
+                )
+            })
+            .max_by(|a, b| (a.1 - a.2).abs().total_cmp(&(b.1 - b.2).abs()))
+            .unwrap();
+        let entry = entries.swap_remove(pos);
+        let to_first = match d1.total_cmp(&d2) {
+            Ordering::Less => true,
+            Ordering::Greater => false,
+            Ordering::Equal => bbox_area(&bbox_1)
+                .total_cmp(&bbox_area(&bbox_2))
+                .then(group_1.len().cmp(&group_2.len()))
+                .is_le(),
+        };
+        if to_first {
+            bbox_1 = bbox_union(&bbox_1, &entry.bbox);
+            group_1.push(entry);
+        } else {
+            bbox_2 = bbox_union(&bbox_2, &entry.bbox);
+            group_2.push(entry);
+        }
+    }
+    (group_1, group_2)
+}
+
+/// Accepts `[min_x, min_y, max_x, max_y]`, or a geometry standing for its bounding box
+fn bbox_from_value(v: &DataValue) -> Result<BBox> {
+    match v {
+        DataValue::Geometry(g) => Ok(g.bbox()),
+        DataValue::List(l) if l.len() == 4 => {
+            let mut bbox = [0.; 4];
+            for (i, x) in l.iter().enumerate() {
+                bbox[i] = x
+                    .get_float()
+                    .ok_or_else(|| miette!("Bad bounding box {:?}", v))?;
+            }
+            ensure!(
+                bbox[0] <= bbox[2] && bbox[1] <= bbox[3],
+                "Bad bounding box {:?}: the minima must come first",
+                v
+            );
+            Ok(bbox)
+        }
+        _ => bail!("Bad bounding box {:?}", v),
+    }
+}
+
+fn bbox_polygon(b: &BBox) -> Geometry {
+    let ring = [
+        (b[0], b[1]),
+        (b[2], b[1]),
+        (b[2], b[3]),
+        (b[0], b[3]),
+        (b[0], b[1]),
+    ]
+    .into_iter()
+    .map(|(x, y)| Coord { x, y })
+    .collect_vec();
+    Geometry::Polygon(vec![ring])
+}
+
+enum Pending {
+    Node(i64),
+    Item(Tuple),
+    /// a row with its exact distance as the priority
+    Found(Tuple),
+}
+
+/// Pending nodes and rows, popped in the order of distance and then of insertion
+#[derive(Default)]
+struct Frontier {
+    heap: BinaryHeap<(Reverse<OrderedFloat<f64>>, Reverse<usize>)>,
+    pending: Vec<Option<Pending>>,
+}
+
+impl Frontier {
+    fn push(&mut self, item: Pending, dist: f64) {
+        self.heap
+            .push((Reverse(OrderedFloat(dist)), Reverse(self.pending.len())));
+        self.pending.push(Some(item));
+    }
+    fn pop(&mut self) -> Option<(Pending, f64)> {
+        let (Reverse(OrderedFloat(dist)), Reverse(i)) = self.heap.pop()?;
+        Some((self.pending[i].take().unwrap(), dist))
+    }
+}
+
+type Path = Vec<(i64, Vec<Entry>, usize)>;
+
+impl<'a> SessionTx<'a> {
+    fn rtree_node(&self, idx: &RelationHandle, n_keys: usize, node: i64) -> Result<Vec<Entry>> {
+        idx.scan_prefix(self, &vec![DataValue::from(node)])
+            .map_ok(|row| Entry::from_row(row, n_keys))
+            .try_collect()
+    }
+    fn rtree_put_entry(
+        &mut self,
+        idx: &RelationHandle,
+        n_keys: usize,
+        node: i64,
+        entry: &Entry,
+    ) -> Result<()> {
+        let row = entry.to_row(node, n_keys);
+        let key = idx.encode_key_for_store(&row, Default::default())?;
+        let val = idx.encode_val_for_store(&row, Default::default())?;
+        self.store_tx.put(&key, &val)
+    }
+    fn rtree_del_entry(
+        &mut self,
+        idx: &RelationHandle,
+        n_keys: usize,
+        node: i64,
+        entry: &Entry,
+    ) -> Result<()> {
+        let row = entry.to_row(node, n_keys);
+        let key = idx.encode_key_for_store(&row, Default::default())?;
+        self.store_tx.del(&key)
+    }
+    fn rtree_new_node(&mut self, idx: &RelationHandle, n_keys: usize) -> Result<i64> {
+        let id = match self.rtree_node(idx, n_keys, META_NODE)?.pop() {
+            Some(counter) => {
+                self.rtree_del_entry(idx, n_keys, META_NODE, &counter)?;
+                match counter.target {
+                    Target::Node(id) => id,
+                    Target::Item(_) => unreachable!(),
+                }
+            }
+            None => ROOT_NODE + 1,
+        };
+        let counter = Entry {
+            bbox: [0.; 4],
+            target: Target::Node(id + 1),
+        };
+        self.rtree_put_entry(idx, n_keys, META_NODE, &counter)?;
+        Ok(id)
+    }
+    pub(crate) fn spatial_put(
+        &mut self,
+        manifest: &SpatialIndexManifest,
+        orig_table: &RelationHandle,
+        idx_table: &RelationHandle,
+        tuple: &[DataValue],
+    ) -> Result<()> {
+        let bbox = match &tuple[manifest.field] {
+            DataValue::Null => return Ok(()),
+            DataValue::Geometry(g) => g.bbox(),
+            v => bail!("Cannot put value {:?} into a spatial index", v),
+        };
+        let n_keys = orig_table.metadata.keys.len();
+
+        let mut path: Path = vec![];
+        let mut node = ROOT_NODE;
+        let mut entries = self.rtree_node(idx_table, n_keys, node)?;
+        while let Some(Entry {
+            target: Target::Node(_),
+            ..
+        }) = entries.first()
+        {
+            let chosen = choose_subtree(&entries, &bbox);
+            let child = match entries[chosen].target {
+                Target::Node(child) => child,
+                Target::Item(_) => unreachable!(),
+            };
+            path.push((node, entries, chosen));
+            node = child;
+            entries = self.rtree_node(idx_table, n_keys, node)?;
+        }
+        let item = Entry {
+            bbox,
+            target: Target::Item(tuple[..n_keys].to_vec()),
+        };
+        self.rtree_put_entry(idx_table, n_keys, node, &item)?;
+        entries.push(item);
+
+        // walk back to the root, splitting overfull nodes and enlarging the boxes
+        loop {
+            let mut sibling = None;
+            if entries.len() > manifest.max_entries {
+                let (kept, moved) = split_entries(entries, manifest.min_entries());
+                if node == ROOT_NODE {
+                    // the root keeps its id, both halves become its children
+                    for entry in kept.iter().chain(moved.iter()) {
+                        self.rtree_del_entry(idx_table, n_keys, ROOT_NODE, entry)?;
+                    }
+                    for half in [kept, moved] {
+                        let id = self.rtree_new_node(idx_table, n_keys)?;
+                        for entry in &half {
+                            self.rtree_put_entry(idx_table, n_keys, id, entry)?;
+                        }
+                        let entry = Entry {
+                            bbox: entries_bbox(&half),
+                            target: Target::Node(id),
+                        };
+                        self.rtree_put_entry(idx_table, n_keys, ROOT_NODE, &entry)?;
+                    }
+                    break;
+                }
+                let id = self.rtree_new_node(idx_table, n_keys)?;
+                for entry in &moved {
+                    self.rtree_del_entry(idx_table, n_keys, node, entry)?;
+                    self.rtree_put_entry(idx_table, n_keys, id, entry)?;
+                }
+                sibling = Some(Entry {
+                    bbox: entries_bbox(&moved),
+                    target: Target::Node(id),
+                });
+                entries = kept;
+            }
+            let (parent, mut parent_entries, pos) = match path.pop() {
+                Some(p) => p,
+                None => break,
+            };
+            let bbox = entries_bbox(&entries);
+            let unchanged = parent_entries[pos].bbox == bbox;
+            if !unchanged {
+                parent_entries[pos].bbox = bbox;
+                self.rtree_put_entry(idx_table, n_keys, parent, &parent_entries[pos])?;
+            }
+            match sibling {
+                Some(sibling) => {
+                    self.rtree_put_entry(idx_table, n_keys, parent, &sibling)?;
+                    parent_entries.push(sibling);
+                }
+                None if unchanged => break,
+                None => {}
+            }
+            node = parent;
+            entries = parent_entries;
+        }
+        Ok(())
+    }
+    /// Finds the leaf holding the row with the given keys, whose bounding box is `bbox`
+    fn rtree_find_leaf(
+        &self,
+        idx: &RelationHandle,
+        n_keys: usize,
+        node: i64,
+        bbox: &BBox,
+        keys: &[DataValue],
+        path: &mut Path,
+    ) -> Result<bool> {
+        let entries = self.rtree_node(idx, n_keys, node)?;
+        let found = entries
+            .iter()
+            .position(|e| matches!(&e.target, Target::Item(k) if k.as_slice() == keys));
+        if let Some(pos) = found {
+            path.push((node, entries, pos));
+            return Ok(true);
+        }
+        let children = entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| match e.target {
+                Target::Node(child) if bbox_contains(&e.bbox, bbox) => Some((i, child)),
+                _ => None,
+            })
+            .collect_vec();
+        for (pos, child) in children {
+            path.push((node, entries.clone(), pos));
+            if self.rtree_find_leaf(idx, n_keys, child, bbox, keys, path)? {
+                return Ok(true);
+            }
+            path.pop();
+        }
+        Ok(false)
+    }
+    /// Removes the row from the index. Underfull nodes are kept as they are,
+    /// only empty nodes are removed from the tree.
+    pub(crate) fn spatial_remove(
+        &mut self,
+        manifest: &SpatialIndexManifest,
+        orig_table: &RelationHandle,
+        idx_table: &RelationHandle,
+        tuple: &[DataValue],
+    ) -> Result<()> {
+        let bbox = match &tuple[manifest.field] {
+            DataValue::Geometry(g) => g.bbox(),
+            _ => return Ok(()),
+        };
+        let n_keys = orig_table.metadata.keys.len();
+        let mut path = vec![];
+        if !self.rtree_find_leaf(
+            idx_table,
+            n_keys,
+            ROOT_NODE,
+            &bbox,
+            &tuple[..n_keys],
+            &mut path,
+        )? {
+            return Ok(());
+        }
+        let (leaf, mut entries, pos) = path.pop().unwrap();
+        let removed = entries.remove(pos);
+        self.rtree_del_entry(idx_table, n_keys, leaf, &removed)?;
+        while let Some((parent, mut parent_entries, pos)) = path.pop() {
+            if entries.is_empty() {
+                let removed = parent_entries.remove(pos);
+                self.rtree_del_entry(idx_table, n_keys, parent, &removed)?;
+            } else {
+                let bbox = entries_bbox(&entries);
+                if parent_entries[pos].bbox == bbox {
+                    break;
+                }
+                parent_entries[pos].bbox = bbox;
+                self.rtree_put_entry(idx_table, n_keys, parent, &parent_entries[pos])?;
+            }
+            entries = parent_entries;
+        }
+        Ok(())
+    }
+    /// Rows whose geometry intersects the bounding box if given. With a query geometry,
+    /// rows are returned in the order of their distance to it, and only those within
+    /// the radius are kept. Without `k` or a radius, the rows must intersect the query.
+    pub(crate) fn spatial_search(
+        &self,
+        query: Option<&DataValue>,
+        bbox: Option<&DataValue>,
+        config: &SpatialSearch,
+        filter_code: &Option<(Vec<Bytecode>, SourceSpan)>,
+        stack: &mut Vec<DataValue>,
+    ) -> Result<Vec<Tuple>> {
+        let query = match query {
+            None => None,
+            Some(DataValue::Null) => return Ok(vec![]),
+            Some(DataValue::Geometry(g)) => Some((g, g.bbox())),
+            Some(v) => bail!("Cannot search for value {:?} in a spatial index", v),
+        };
+        let bbox = match bbox {
+            None => None,
+            Some(DataValue::Null) => return Ok(vec![]),
+            Some(v) => Some(bbox_from_value(v)?),
+        };
+        let bbox_geometry = bbox.as_ref().map(bbox_polygon);
+        let radius = match (query, config.radius, config.k) {
+            (Some(_), None, None) => Some(0.),
+            (_, radius, _) => radius,
+        };
+        let n_keys = config.base_handle.metadata.keys.len();
+
+        let mut frontier = Frontier::default();
+        frontier.push(Pending::Node(ROOT_NODE), 0.);
+        let mut ret = vec![];
+        while let Some((pending, dist)) = frontier.pop() {
+            match pending {
+                Pending::Node(node) => {
+                    for entry in self.rtree_node(&config.idx_handle, n_keys, node)? {
+                        if let Some(bbox) = &bbox {
+                            if !bbox_intersects(bbox, &entry.bbox) {
+                                continue;
+                            }
+                        }
+                        let lower_bound = match &query {
+                            None => 0.,
+                            Some((_, query_bbox)) => bbox_distance(query_bbox, &entry.bbox),
+                        };
+                        if let Some(r) = radius {
+                            if lower_bound > r {
+                                continue;
+                            }
+                        }
+                        let pending = match entry.target {
+                            Target::Node(child) => Pending::Node(child),
+                            Target::Item(keys) => Pending::Item(keys),
+                        };
+                        frontier.push(pending, lower_bound);
+                    }
+                }
+                Pending::Item(keys) => {
+                    let tuple = config
+                        .base_handle
+                        .get(self, &keys)?
+                        .ok_or_else(|| miette!("Tuple not found in base spatial relation"))?;
+                    let geometry = match &tuple[config.manifest.field] {
+                        DataValue::Geometry(g) => g,
+                        v => bail!("corrupted spatial index value {:?}", v),
+                    };
+                    if let Some(b) = &bbox_geometry {
+                        if !geometry.intersects(b) {
+                            continue;
+                        }
+                    }
+                    let dist = match &query {
+                        None => 0.,
+                        Some((q, _)) => geometry.distance(q),
+                    };
+                    if let Some(r) = radius {
+                        if dist > r {
+                            continue;
+                        }
+                    }
+                    // the distance is never below the bound used for the item,
+                    // so the row is popped again once all closer rows are
+                    frontier.push(Pending::Found(tuple), dist);
+                }
+                Pending::Found(mut tuple) => {
+                    // make sure the order is the same as in all_bindings()!!!
+                    if config.bind_distance.is_some() {
+                        tuple.push(DataValue::from(dist));
+                    }
+                    if let Some((code, span)) = filter_code {
+                        if !eval_bytecode_pred(code, &tuple, stack, *span)? {
+                            continue;
+                        }
+                    }
+                    ret.push(tuple);
+                    if let Some(k) = config.k {
+                        if ret.len() >= k {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,241 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Stored rule libraries: named sets of inline rules kept in the system relation.
+//!
+//! A query imports a library either wholesale with `use lib;`, in which case its rules
+//! join the program under their own names, or piecemeal by calling `lib.rule[...]`, in
+//! which case the rules of the library are imported under qualified names. Either way the
+//! imported rules are ordinary inline rules by the time the program is compiled.
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::program::{InputAtom, InputInlineRule, InputInlineRulesOrFixed, InputProgram};
+use crate::data::symb::Symbol;
+use crate::data::tuple::TupleT;
+use crate::data::value::{DataValue, ValidityTs, LARGEST_UTF_CHAR};
+use crate::parse::query::parse_rule_library;
+use crate::runtime::relation::{decode_tuple_from_kv, RelationId};
+use crate::runtime::transact::SessionTx;
+use crate::{NamedRows, SourceSpan};
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Rule library {0} not found")]
+#[diagnostic(code(eval::rule_library_not_found))]
+struct RuleLibraryNotFound(String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Rule {0} imported from library {1} conflicts with a rule of the same name")]
+#[diagnostic(code(eval::rule_library_conflict))]
+struct RuleLibraryConflict(String, String, #[label] SourceSpan);
+
+fn rule_library_key(name: &str) -> Vec<u8> {
+    vec![
+        DataValue::Null,
+        DataValue::from("RULES"),
+        DataValue::from(name),
+    ]
+    .encode_as_key(RelationId::SYSTEM)
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn define_rule_library(&mut self, name: &Symbol, rules: &str) -> Result<()> {
+        self.store_tx
+            .put(&rule_library_key(&name.name), rules.as_bytes())
+    }
+
+    pub(crate) fn remove_rule_library(&mut self, name: &Symbol) -> Result<()> {
+        let key = rule_library_key(&name.name);
+        if !self.store_tx.exists(&key, true)? {
+            bail!(RuleLibraryNotFound(name.name.to_string(), name.span))
+        }
+        self.store_tx.del(&key)
+    }
+
+    pub(crate) fn list_rule_libraries(&self, cur_vld: ValidityTs) -> Result<NamedRows> {
+        let lower = rule_library_key("");
+        let upper = rule_library_key(&String::from(LARGEST_UTF_CHAR));
+        let mut rows = vec![];
+        for kv_res in self.store_tx.range_scan(&lower, &upper) {
+            let (k_slice, v_slice) = kv_res?;
+            if upper <= k_slice {
+                break;
+            }
+            let name = decode_tuple_from_kv(&k_slice, &[], None).pop().unwrap();
+            let src = String::from_utf8_lossy(&v_slice);
+            let rules = parse_rule_library(&src, &self.custom_registry.read().unwrap(), cur_vld)?
+                .into_keys()
+                .map(|name| DataValue::Str(name.name))
+                .collect();
+            rows.push(vec![
+                name,
+                DataValue::List(rules),
+                DataValue::from(src.to_string()),
+            ]);
+        }
+        Ok(NamedRows::new(
+            vec![
+                "name".to_string(),
+                "rules".to_string(),
+                "definition".to_string(),
+            ],
+            rows,
+        ))
+    }
+
+    fn get_rule_library(
+        &self,
+        name: &Symbol,
+        cur_vld: ValidityTs,
+    ) -> Result<BTreeMap<Symbol, Vec<InputInlineRule>>> {
+        match self.store_tx.get(&rule_library_key(&name.name), false)? {
+            None => bail!(RuleLibraryNotFound(name.name.to_string(), name.span)),
+            Some(src) => parse_rule_library(
+                &String::from_utf8_lossy(&src),
+                &self.custom_registry.read().unwrap(),
+                cur_vld,
+            ),
+        }
+    }
+
+    /// Brings the rules of the stored libraries used by the program into it, both those
+    /// imported with `use` and those called with qualified names. Programs that have
+    /// already been resolved are left untouched.
+    pub(crate) fn import_rule_libraries(
+        &self,
+        program: &mut InputProgram,
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        for lib in mem::take(&mut program.rule_libraries) {
+            for (name, rules) in self.get_rule_library(&lib, cur_vld)? {
+                add_library_rules(program, &lib, name, rules)?;
+            }
+        }
+
+        // imported rules may in turn call other libraries, hence the loop
+        loop {
+            let mut wanted = BTreeMap::new();
+            for rules in program.prog.values() {
+                if let InputInlineRulesOrFixed::Rules { rules } = rules {
+                    for rule in rules {
+                        for atom in &rule.body {
+                            collect_qualified_calls(atom, &mut wanted);
+                        }
+                    }
+                }
+            }
+            wanted.retain(|lib: &SmartString<LazyCompact>, _| {
+                let prefix = format!("{lib}.");
+                !program.prog.keys().any(|k| k.name.starts_with(&prefix))
+            });
+            if wanted.is_empty() {
+                return Ok(());
+            }
+            for (lib, span) in wanted {
+                let lib = Symbol::new(lib, span);
+                let rules = self.get_rule_library(&lib, cur_vld)?;
+                let local: BTreeSet<_> = rules.keys().map(|k| k.name.clone()).collect();
+                for (name, mut rules) in rules {
+                    for rule in rules.iter_mut() {
+                        for atom in rule.body.iter_mut() {
+                            qualify_calls(atom, &lib.name, &local);
+                        }
+                    }
+                    let name = Symbol::new(format!("{}.{}", lib.name, name.name), name.span);
+                    add_library_rules(program, &lib, name, rules)?;
+                }
+            }
+        }
+    }
+}
+
+fn add_library_rules(
+    program: &mut InputProgram,
+    lib: &Symbol,
+    name: Symbol,
+    rules: Vec<InputInlineRule>,
+) -> Result<()> {
+    match program.prog.entry(name) {
+        Entry::Vacant(e) => {
+            e.insert(InputInlineRulesOrFixed::Rules { rules });
+        }
+        Entry::Occupied(e) => {
+            let span = match e.get() {
+                InputInlineRulesOrFixed::Rules { rules } => rules[0].span,
+                InputInlineRulesOrFixed::Fixed { fixed } => fixed.span,
+            };
+            bail!(RuleLibraryConflict(
+                e.key().name.to_string(),
+                lib.name.to_string(),
+                span
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Collects the libraries called as `lib.rule[...]` within the atom.
+fn collect_qualified_calls(
+    atom: &InputAtom,
+    collected: &mut BTreeMap<SmartString<LazyCompact>, SourceSpan>,
+) {
+    match atom {
+        InputAtom::Rule { inner } => {
+            if let Some((lib, _)) = inner.name.name.split_once('.') {
+                collected.entry(lib.into()).or_insert(inner.name.span);
+            }
+        }
+        InputAtom::Negation { inner, .. } | InputAtom::Optional { inner, .. } => {
+            collect_qualified_calls(inner, collected)
+        }
+        InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+            for atom in inner {
+                collect_qualified_calls(atom, collected)
+            }
+        }
+        // the steps of paths are stored relations, never rules
+        InputAtom::Path { .. }
+        | InputAtom::Relation { .. }
+        | InputAtom::NamedFieldRelation { .. }
+        | InputAtom::Search { .. }
+        | InputAtom::Predicate { .. }
+        | InputAtom::Unification { .. } => {}
+    }
+}
+
+/// Within a library imported under qualified names, calls between its own rules must be
+/// qualified as well.
+fn qualify_calls(atom: &mut InputAtom, lib: &str, local: &BTreeSet<SmartString<LazyCompact>>) {
+    match atom {
+        InputAtom::Rule { inner } if local.contains(&inner.name.name) => {
+            inner.name = Symbol::new(format!("{lib}.{}", inner.name.name), inner.name.span);
+        }
+        InputAtom::Negation { inner, .. } | InputAtom::Optional { inner, .. } => {
+            qualify_calls(inner, lib, local)
+        }
+        InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+            for atom in inner {
+                qualify_calls(atom, lib, local)
+            }
+        }
+        // the steps of paths are stored relations, never rules
+        InputAtom::Path { .. }
+        | InputAtom::Rule { .. }
+        | InputAtom::Relation { .. }
+        | InputAtom::NamedFieldRelation { .. }
+        | InputAtom::Search { .. }
+        | InputAtom::Predicate { .. }
+        | InputAtom::Unification { .. } => {}
+    }
+}
@@ -0,0 +1,507 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Memory accounting for query evaluation, and the temporary files that temp stores,
+//! aggregations, joins and sorts spill to once a query uses up its memory budget.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::mem;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::{Arc, Mutex};
+
+use either::{Left, Right};
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Report, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::data::tuple::Tuple;
+use crate::data::value::{DataValue, Vector};
+
+/// Number of entries written to and read from a spill file together.
+const SPILL_BLOCK_LEN: usize = 1024;
+/// A structure is only spilled once it holds at least this fraction of the budget,
+/// so that many small structures do not each produce tiny runs.
+const MIN_SPILL_SHARE: usize = 16;
+/// Maximal number of sorted runs merged at once by the external sorter.
+const MERGE_WIDTH: usize = 64;
+/// JSON values are not walked when estimating sizes.
+const JSON_SIZE_ESTIMATE: usize = 64;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The query holds more than {0} bytes of intermediate results in memory")]
+#[diagnostic(code(eval::max_memory_exceeded))]
+#[diagnostic(help(
+    "The limit is set with `:max_memory`. Set a memory budget so that results spill to disk"
+))]
+struct MaxMemoryExceeded(usize);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot spill intermediate results to disk: {0}")]
+#[diagnostic(code(eval::spill_failed))]
+#[diagnostic(help(
+    "Make sure that the temporary directory is writable and has space left, or raise the memory budget"
+))]
+struct SpillFailed(String);
+
+/// Number of spill files created, for tests to check that spilling happened.
+#[cfg(test)]
+pub(crate) static SPILL_FILES_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// The memory budget of a query. Structures holding intermediate results reserve
+/// memory from it, and spill to disk when it is exceeded.
+/// Independently, the query fails if it holds more than its maximal memory.
+pub(crate) struct MemoryBudget {
+    limit: usize,
+    max: AtomicUsize,
+    used: AtomicUsize,
+    error: Mutex<Option<Report>>,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            max: AtomicUsize::new(usize::MAX),
+            used: AtomicUsize::new(0),
+            error: Mutex::new(None),
+        }
+    }
+    #[cfg(test)]
+    pub(crate) fn unlimited() -> Self {
+        Self::new(usize::MAX)
+    }
+    pub(crate) fn is_unlimited(&self) -> bool {
+        self.limit == usize::MAX && self.max.load(atomic::Ordering::Relaxed) == usize::MAX
+    }
+    fn is_exceeded(&self) -> bool {
+        self.used.load(atomic::Ordering::Relaxed) > self.limit
+    }
+    /// Sets the maximal memory of the queries run from now on. `None` means no maximum.
+    pub(crate) fn set_max(&self, max: Option<usize>) {
+        self.max
+            .store(max.unwrap_or(usize::MAX), atomic::Ordering::Relaxed);
+    }
+    /// Records an error met by a structure that cannot return it, such as the failure
+    /// to read back spilled data while iterating. Only the first error is kept.
+    pub(crate) fn report(&self, err: Report) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(err);
+        }
+    }
+    /// Will return `Err` if an error was reported, or if the maximal memory is exceeded.
+    pub(crate) fn check(&self) -> Result<()> {
+        if let Some(err) = self.error.lock().unwrap().take() {
+            return Err(err);
+        }
+        let max = self.max.load(atomic::Ordering::Relaxed);
+        if self.used.load(atomic::Ordering::Relaxed) > max {
+            bail!(MaxMemoryExceeded(max))
+        }
+        Ok(())
+    }
+}
+
+impl Debug for MemoryBudget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "MemoryBudget({}/{})",
+            self.used.load(atomic::Ordering::Relaxed),
+            self.limit
+        )
+    }
+}
+
+/// The memory held by one structure, given back to the budget when released or dropped.
+pub(crate) struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+    // whether the budget was tracking usage when the reservation was made
+    tracked: bool,
+}
+
+impl MemoryReservation {
+    pub(crate) fn new(budget: &Arc<MemoryBudget>) -> Self {
+        Self {
+            budget: budget.clone(),
+            bytes: 0,
+            tracked: !budget.is_unlimited(),
+        }
+    }
+    pub(crate) fn grow(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        if self.tracked {
+            self.budget.used.fetch_add(bytes, atomic::Ordering::Relaxed);
+        }
+    }
+    pub(crate) fn release(&mut self) {
+        if self.tracked {
+            self.budget
+                .used
+                .fetch_sub(self.bytes, atomic::Ordering::Relaxed);
+        }
+        self.bytes = 0;
+    }
+    /// Records an error of the holder, to be returned by the next check of the budget.
+    pub(crate) fn report(&self, err: Report) {
+        self.budget.report(err)
+    }
+    /// Whether the holder should spill what it holds now.
+    pub(crate) fn should_spill(&self) -> bool {
+        self.budget.is_exceeded() && self.bytes >= self.budget.limit / MIN_SPILL_SHARE
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.release()
+    }
+}
+
+impl Debug for MemoryReservation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MemoryReservation({})", self.bytes)
+    }
+}
+
+/// A rough estimate of the memory occupied by a tuple.
+pub(crate) fn tuple_size(tuple: &[DataValue]) -> usize {
+    mem::size_of::<Tuple>() + tuple.iter().map(value_size).sum::<usize>()
+}
+
+fn value_size(value: &DataValue) -> usize {
+    mem::size_of::<DataValue>()
+        + match value {
+            DataValue::Str(s) => s.len(),
+            DataValue::Bytes(b) => b.len(),
+            DataValue::List(l) => l.iter().map(value_size).sum(),
+            DataValue::Set(s) => s.iter().map(value_size).sum(),
+            DataValue::Vec(Vector::F32(a)) => a.len() * mem::size_of::<f32>(),
+            DataValue::Vec(Vector::F64(a)) => a.len() * mem::size_of::<f64>(),
+            DataValue::Json(_) => JSON_SIZE_ESTIMATE,
+            _ => 0,
+        }
+}
+
+/// An append-only sequence of values in a temporary file, written and read in blocks.
+pub(crate) struct SpillFile<T> {
+    file: Mutex<File>,
+    blocks: Vec<(u64, usize)>,
+    end: u64,
+    pending: Vec<T>,
+    len: usize,
+}
+
+impl<T: Serialize + DeserializeOwned> SpillFile<T> {
+    pub(crate) fn new() -> Result<Self> {
+        let file = tempfile::tempfile().map_err(|err| SpillFailed(err.to_string()))?;
+        #[cfg(test)]
+        SPILL_FILES_CREATED.fetch_add(1, atomic::Ordering::Relaxed);
+        Ok(Self {
+            file: Mutex::new(file),
+            blocks: vec![],
+            end: 0,
+            pending: vec![],
+            len: 0,
+        })
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+    pub(crate) fn push(&mut self, item: T) -> Result<()> {
+        self.pending.push(item);
+        self.len += 1;
+        if self.pending.len() >= SPILL_BLOCK_LEN {
+            self.flush()?;
+        }
+        Ok(())
+    }
+    /// Writes the pending values as a block, so that they can be read back.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let data = rmp_serde::to_vec(&self.pending).map_err(|err| SpillFailed(err.to_string()))?;
+        let file = self.file.get_mut().unwrap();
+        file.seek(SeekFrom::Start(self.end))
+            .and_then(|_| file.write_all(&data))
+            .map_err(|err| SpillFailed(err.to_string()))?;
+        self.blocks.push((self.end, data.len()));
+        self.end += data.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+    fn read_block(&self, idx: usize) -> Result<Vec<T>> {
+        let (offset, len) = self.blocks[idx];
+        let mut data = vec![0; len];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut data))
+                .map_err(|err| SpillFailed(err.to_string()))?;
+        }
+        Ok(rmp_serde::from_slice(&data).map_err(|err| SpillFailed(err.to_string()))?)
+    }
+    pub(crate) fn into_iter(mut self) -> impl Iterator<Item = Result<T>> {
+        let flushed = self.flush();
+        let n_blocks = if flushed.is_ok() {
+            self.blocks.len()
+        } else {
+            0
+        };
+        flushed
+            .err()
+            .map(Err)
+            .into_iter()
+            .chain((0..n_blocks).flat_map(move |i| match self.read_block(i) {
+                Ok(block) => Left(block.into_iter().map(Ok)),
+                Err(err) => Right(iter::once(Err(err))),
+            }))
+    }
+}
+
+impl<T> Debug for SpillFile<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SpillFile({} blocks)", self.blocks.len())
+    }
+}
+
+/// What a temp store keeps with each tuple: the skip flag, and how many times the tuple
+/// was put, which only matters for stores with bag semantics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct EntryMeta {
+    pub(crate) skip: bool,
+    pub(crate) count: usize,
+}
+
+impl EntryMeta {
+    pub(crate) fn new(skip: bool) -> Self {
+        Self { skip, count: 1 }
+    }
+    /// Combines the entry with an older one for the same tuple: the skip flag of the
+    /// newer entry wins, and the counts add up.
+    fn over(self, older: Self) -> Self {
+        Self {
+            skip: self.skip,
+            count: self.count + older.count,
+        }
+    }
+}
+
+type SpilledBlock = Arc<Vec<(Tuple, EntryMeta)>>;
+
+/// Entries of a temp store spilled to disk in key order. The first key of each block is
+/// kept in memory, so that lookups and range scans only read the blocks they need.
+#[derive(Debug)]
+pub(crate) struct SortedRun {
+    file: SpillFile<(Tuple, EntryMeta)>,
+    first_keys: Vec<Tuple>,
+    cached: Mutex<Option<(usize, SpilledBlock)>>,
+}
+
+impl SortedRun {
+    /// The entries must be sorted by key, without duplicates.
+    pub(crate) fn new(entries: impl Iterator<Item = Result<(Tuple, EntryMeta)>>) -> Result<Self> {
+        let mut file = SpillFile::new()?;
+        let mut first_keys = vec![];
+        for (i, entry) in entries.enumerate() {
+            let entry = entry?;
+            if i % SPILL_BLOCK_LEN == 0 {
+                first_keys.push(entry.0.clone());
+            }
+            file.push(entry)?;
+        }
+        file.flush()?;
+        Ok(Self {
+            file,
+            first_keys,
+            cached: Mutex::new(None),
+        })
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.file.len()
+    }
+    fn block(&self, idx: usize) -> Result<SpilledBlock> {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((cached_idx, block)) = &*cached {
+            if *cached_idx == idx {
+                return Ok(block.clone());
+            }
+        }
+        let block = Arc::new(self.file.read_block(idx)?);
+        *cached = Some((idx, block.clone()));
+        Ok(block)
+    }
+    /// Returns the entry stored with the key, if the key is present.
+    pub(crate) fn get(&self, key: &[DataValue]) -> Result<Option<EntryMeta>> {
+        let idx = self.first_keys.partition_point(|k| k.as_slice() <= key);
+        if idx == 0 {
+            return Ok(None);
+        }
+        let block = self.block(idx - 1)?;
+        Ok(block
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|i| block[i].1))
+    }
+    pub(crate) fn range(
+        &self,
+        lower: &[DataValue],
+        upper: &[DataValue],
+        upper_inclusive: bool,
+    ) -> impl Iterator<Item = Result<(Tuple, EntryMeta)>> + '_ {
+        let start = self
+            .first_keys
+            .partition_point(|k| k.as_slice() <= lower)
+            .saturating_sub(1);
+        let lower = lower.to_vec();
+        let upper = upper.to_vec();
+        (start..self.first_keys.len())
+            .flat_map(move |i| match self.block(i) {
+                Ok(block) => Left((0..block.len()).map(move |j| Ok(block[j].clone()))),
+                Err(err) => Right(iter::once(Err(err))),
+            })
+            .skip_while(move |entry| matches!(entry, Ok((k, _)) if *k < lower))
+            .take_while(move |entry| match entry {
+                Ok((k, _)) => match k.cmp(&upper) {
+                    Ordering::Less => true,
+                    Ordering::Equal => upper_inclusive,
+                    Ordering::Greater => false,
+                },
+                Err(_) => true,
+            })
+    }
+    pub(crate) fn into_entries(self) -> impl Iterator<Item = Result<(Tuple, EntryMeta)>> {
+        self.file.into_iter()
+    }
+}
+
+fn key_of<K: Borrow<Tuple>>(k: &K) -> &Tuple {
+    k.borrow()
+}
+
+/// Merges layers of temp store entries, each sorted by key and given oldest first.
+/// Where a key is present in several layers, the entries are combined by [EntryMeta::over],
+/// newest first. Errors of the layers are produced as soon as they are met.
+pub(crate) fn merge_layers<'a, K: Borrow<Tuple> + 'a>(
+    layers: Vec<Box<dyn Iterator<Item = Result<(K, EntryMeta)>> + 'a>>,
+) -> impl Iterator<Item = Result<(K, EntryMeta)>> + 'a {
+    layers
+        .into_iter()
+        .enumerate()
+        .map(|(rank, layer)| layer.map_ok(move |(k, meta)| (rank, k, meta)))
+        .kmerge_by(|a, b| match (a, b) {
+            (Ok(a), Ok(b)) => match key_of(&a.1).cmp(key_of(&b.1)) {
+                Ordering::Equal => a.0 > b.0,
+                o => o == Ordering::Less,
+            },
+            (Err(_), _) => true,
+            (Ok(_), Err(_)) => false,
+        })
+        .coalesce(|a, b| match (a, b) {
+            (Ok(a), Ok(b)) if key_of(&a.1) == key_of(&b.1) => Ok(Ok((a.0, a.1, a.2.over(b.2)))),
+            (a, b) => Err((a, b)),
+        })
+        .map_ok(|(_, k, meta)| (k, meta))
+}
+
+/// Sorts tuples while keeping only as many of them in memory as the budget allows.
+/// The rest are spilled as sorted runs, which are merged when the sorted tuples are read.
+/// The comparison must be a total order, as ties are not resolved in insertion order.
+pub(crate) struct ExternalSorter<F> {
+    buffer: Vec<Tuple>,
+    runs: Vec<(usize, SpillFile<Tuple>)>,
+    reservation: MemoryReservation,
+    cmp: F,
+}
+
+impl<F: Fn(&Tuple, &Tuple) -> Ordering + Clone> ExternalSorter<F> {
+    pub(crate) fn new(budget: &Arc<MemoryBudget>, cmp: F) -> Self {
+        Self {
+            buffer: vec![],
+            runs: vec![],
+            reservation: MemoryReservation::new(budget),
+            cmp,
+        }
+    }
+    pub(crate) fn push(&mut self, tuple: Tuple) -> Result<()> {
+        self.reservation.grow(tuple_size(&tuple));
+        self.buffer.push(tuple);
+        if self.reservation.should_spill() {
+            self.spill()?;
+        }
+        Ok(())
+    }
+    fn spill(&mut self) -> Result<()> {
+        self.buffer.sort_unstable_by(&self.cmp);
+        let mut run = SpillFile::new()?;
+        for tuple in self.buffer.drain(..) {
+            run.push(tuple)?;
+        }
+        self.buffer.shrink_to_fit();
+        self.reservation.release();
+        // runs are merged in levels, so that each tuple is rewritten
+        // a logarithmic number of times at most
+        self.runs.push((0, run));
+        loop {
+            let level = self.runs.last().unwrap().0;
+            let same_level = self
+                .runs
+                .iter()
+                .rev()
+                .take_while(|(l, _)| *l == level)
+                .count();
+            if same_level < MERGE_WIDTH {
+                break;
+            }
+            let to_merge = self.runs.split_off(self.runs.len() - same_level);
+            let mut merged = SpillFile::new()?;
+            for tuple in merge_runs(to_merge.into_iter().map(|(_, r)| r), self.cmp.clone()) {
+                merged.push(tuple?)?;
+            }
+            self.runs.push((level + 1, merged));
+        }
+        Ok(())
+    }
+    pub(crate) fn finish(mut self) -> Result<impl Iterator<Item = Result<Tuple>>> {
+        if self.runs.is_empty() {
+            let mut buffer = mem::take(&mut self.buffer);
+            buffer.sort_unstable_by(&self.cmp);
+            return Ok(Left(buffer.into_iter().map(Ok)));
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        let runs = mem::take(&mut self.runs);
+        Ok(Right(merge_runs(
+            runs.into_iter().map(|(_, r)| r),
+            self.cmp,
+        )))
+    }
+}
+
+/// Errors of the runs are produced as soon as they are met.
+fn merge_runs(
+    runs: impl Iterator<Item = SpillFile<Tuple>>,
+    cmp: impl Fn(&Tuple, &Tuple) -> Ordering,
+) -> impl Iterator<Item = Result<Tuple>> {
+    runs.map(|run| run.into_iter())
+        .kmerge_by(move |a, b| match (a, b) {
+            (Ok(a), Ok(b)) => cmp(a, b) == Ordering::Less,
+            (Err(_), _) => true,
+            (Ok(_), Err(_)) => false,
+        })
+}
@@ -0,0 +1,245 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Functions written in CozoScript, kept in the system relation.
+//!
+//! The parser leaves calls to functions it does not know as unbound applications. Before a
+//! program is compiled, those naming stored functions are replaced by the bodies of the
+//! functions, so that evaluation never sees them.
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::program::{InputAtom, InputInlineRulesOrFixed, InputProgram};
+use crate::data::relation::ConstraintRule;
+use crate::data::symb::Symbol;
+use crate::data::tuple::TupleT;
+use crate::data::value::{DataValue, LARGEST_UTF_CHAR};
+use crate::parse::expr::{inline_stored_functions, StoredFunction};
+use crate::parse::parse_stored_function;
+use crate::runtime::relation::{decode_tuple_from_kv, RelationHandle, RelationId};
+use crate::runtime::transact::SessionTx;
+use crate::{NamedRows, SourceSpan};
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Stored function {0} not found")]
+#[diagnostic(code(eval::stored_fn_not_found))]
+struct StoredFunctionNotFound(String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Stored function {0} already exists")]
+#[diagnostic(code(eval::stored_fn_exists))]
+#[diagnostic(help("Drop the function first to define it anew"))]
+struct StoredFunctionExists(String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot drop stored function {0} since view {1} calls it")]
+#[diagnostic(code(eval::stored_fn_in_use))]
+struct StoredFunctionInUse(String, String, #[label] SourceSpan);
+
+fn stored_function_key(name: &str) -> Vec<u8> {
+    vec![
+        DataValue::Null,
+        DataValue::from("FUNCTIONS"),
+        DataValue::from(name),
+    ]
+    .encode_as_key(RelationId::SYSTEM)
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn define_function(&mut self, name: &Symbol, definition: &str) -> Result<()> {
+        let key = stored_function_key(&name.name);
+        if self.store_tx.exists(&key, true)? {
+            bail!(StoredFunctionExists(name.name.to_string(), name.span))
+        }
+        self.store_tx.put(&key, definition.as_bytes())
+    }
+
+    /// Removes the function, unless the query of a view calls it: views are recomputed from
+    /// their queries, which would then change meaning.
+    pub(crate) fn remove_function(&mut self, name: &Symbol) -> Result<()> {
+        let key = stored_function_key(&name.name);
+        if !self.store_tx.exists(&key, true)? {
+            bail!(StoredFunctionNotFound(name.name.to_string(), name.span))
+        }
+        let lower = vec![DataValue::from("")].encode_as_key(RelationId::SYSTEM);
+        let upper =
+            vec![DataValue::from(String::from(LARGEST_UTF_CHAR))].encode_as_key(RelationId::SYSTEM);
+        for kv_res in self.store_tx.range_scan(&lower, &upper) {
+            let (k_slice, v_slice) = kv_res?;
+            if upper <= k_slice {
+                break;
+            }
+            let handle = RelationHandle::decode(&v_slice)?;
+            if let Some(manifest) = &handle.view {
+                if manifest.functions.contains(&name.name) {
+                    bail!(StoredFunctionInUse(
+                        name.name.to_string(),
+                        handle.name.to_string(),
+                        name.span
+                    ))
+                }
+            }
+        }
+        self.store_tx.del(&key)
+    }
+
+    pub(crate) fn list_functions(&self) -> Result<NamedRows> {
+        let lower = stored_function_key("");
+        let upper = stored_function_key(&String::from(LARGEST_UTF_CHAR));
+        let mut rows = vec![];
+        for kv_res in self.store_tx.range_scan(&lower, &upper) {
+            let (k_slice, v_slice) = kv_res?;
+            if upper <= k_slice {
+                break;
+            }
+            let name = decode_tuple_from_kv(&k_slice, &[], None).pop().unwrap();
+            let src = String::from_utf8_lossy(&v_slice);
+            let func = parse_stored_function(&src, &self.custom_registry.read().unwrap())?;
+            let params = func
+                .params
+                .into_iter()
+                .map(|p| DataValue::Str(p.name))
+                .collect();
+            rows.push(vec![
+                name,
+                DataValue::List(params),
+                DataValue::from(src.to_string()),
+            ]);
+        }
+        Ok(NamedRows::new(
+            vec![
+                "name".to_string(),
+                "params".to_string(),
+                "definition".to_string(),
+            ],
+            rows,
+        ))
+    }
+
+    fn get_function(&self, name: &str) -> Result<Option<StoredFunction>> {
+        match self.store_tx.get(&stored_function_key(name), false)? {
+            None => Ok(None),
+            Some(src) => Ok(Some(parse_stored_function(
+                &String::from_utf8_lossy(&src),
+                &self.custom_registry.read().unwrap(),
+            )?)),
+        }
+    }
+
+    /// Replaces the calls to stored functions in the program, including the conditions and
+    /// column constraints of its mutations, by the bodies of the functions. Returns the names
+    /// of the functions called.
+    pub(crate) fn inline_stored_functions(
+        &self,
+        program: &mut InputProgram,
+    ) -> Result<BTreeSet<SmartString<LazyCompact>>> {
+        let mut found: BTreeMap<SmartString<LazyCompact>, Option<StoredFunction>> = BTreeMap::new();
+        let mut lookup = |name: &str| -> Result<Option<StoredFunction>> {
+            Ok(match found.entry(name.into()) {
+                Entry::Occupied(e) => e.get().clone(),
+                Entry::Vacant(e) => e.insert(self.get_function(name)?).clone(),
+            })
+        };
+        let mut inline = |expr: &mut Expr| inline_stored_functions(expr, &mut lookup, &mut vec![]);
+        for rules in program.prog.values_mut() {
+            match rules {
+                InputInlineRulesOrFixed::Rules { rules } => {
+                    for rule in rules.iter_mut() {
+                        for atom in rule.body.iter_mut() {
+                            inline_in_atom(atom, &mut inline)?;
+                        }
+                    }
+                }
+                InputInlineRulesOrFixed::Fixed { fixed } => {
+                    for expr in Arc::make_mut(&mut fixed.options).values_mut() {
+                        inline(expr)?;
+                    }
+                }
+            }
+        }
+        let out_opts = &mut program.out_opts;
+        let mutated = out_opts
+            .store_relation
+            .iter_mut()
+            .map(|(handle, _, _)| handle)
+            .chain(
+                out_opts
+                    .rule_mutations
+                    .iter_mut()
+                    .map(|(handle, _, _)| handle),
+            );
+        for handle in mutated {
+            if let Some(condition) = &mut handle.condition {
+                inline(condition)?;
+            }
+            for constraint in handle.metadata.constraints.iter_mut() {
+                if let ConstraintRule::Check(expr) = &mut constraint.rule {
+                    inline(expr)?;
+                }
+            }
+        }
+        Ok(found
+            .into_iter()
+            .filter_map(|(name, func)| func.map(|_| name))
+            .collect())
+    }
+}
+
+fn inline_in_atom(
+    atom: &mut InputAtom,
+    inline: &mut impl FnMut(&mut Expr) -> Result<()>,
+) -> Result<()> {
+    match atom {
+        InputAtom::Rule { inner } => {
+            for arg in inner.args.iter_mut() {
+                inline(arg)?;
+            }
+        }
+        InputAtom::NamedFieldRelation { inner } => {
+            for arg in inner.args.values_mut() {
+                inline(arg)?;
+            }
+        }
+        InputAtom::Relation { inner } => {
+            for arg in inner.args.iter_mut() {
+                inline(arg)?;
+            }
+        }
+        InputAtom::Predicate { inner } => inline(inner)?,
+        InputAtom::Negation { inner, .. } | InputAtom::Optional { inner, .. } => {
+            inline_in_atom(inner, inline)?
+        }
+        InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+            for atom in inner {
+                inline_in_atom(atom, inline)?;
+            }
+        }
+        InputAtom::Unification { inner } => inline(&mut inner.expr)?,
+        InputAtom::Search { inner } => {
+            for expr in inner
+                .bindings
+                .values_mut()
+                .chain(inner.parameters.values_mut())
+            {
+                inline(expr)?;
+            }
+        }
+        InputAtom::Path { inner } => {
+            inline(&mut inner.from)?;
+            inline(&mut inner.to)?;
+        }
+    }
+    Ok(())
+}
@@ -6,37 +6,101 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::collections::Bound::Included;
+use std::iter;
 use std::mem;
 use std::ops::Bound::Excluded;
+use std::sync::Arc;
 
 use either::{Left, Right};
 use itertools::Itertools;
-use miette::Result;
+use miette::{Report, Result};
 
 use crate::data::aggr::Aggregation;
 use crate::data::tuple::Tuple;
 use crate::data::value::DataValue;
+use crate::runtime::spill::{
+    merge_layers, tuple_size, EntryMeta, MemoryBudget, MemoryReservation, SortedRun,
+};
 
 /// A store holding temp data during evaluation of queries.
 /// The public interface is used in custom implementations of algorithms/utilities.
+///
+/// When the memory budget of the query is exceeded, the entries held in memory are
+/// spilled to disk as a sorted run. Later entries for the same key override earlier ones.
+/// Errors met when writing or reading the runs are reported to the memory budget,
+/// and returned by its next check.
+///
+/// With bag semantics, each tuple is kept with the number of times it was put, and is
+/// produced as many times when the store is read.
 #[derive(Default, Debug)]
 pub struct RegularTempStore {
-    inner: BTreeMap<Tuple, bool>,
+    inner: BTreeMap<Tuple, EntryMeta>,
+    // each run with its level, older runs first
+    spilled: Vec<(usize, SortedRun)>,
+    reservation: Option<MemoryReservation>,
+    bag: bool,
 }
 
 const EMPTY_TUPLE_REF: &Tuple = &vec![];
+/// Estimated memory taken by a map entry on top of its key.
+const ENTRY_OVERHEAD: usize = 32;
+/// Number of spilled runs of the same level that are merged into one run of the next level.
+/// Kept small, as lookups may read every run.
+const SPILL_MERGE_WIDTH: usize = 8;
 
 impl RegularTempStore {
+    pub(crate) fn with_budget(budget: &Arc<MemoryBudget>) -> Self {
+        Self {
+            inner: Default::default(),
+            spilled: vec![],
+            reservation: if budget.is_unlimited() {
+                None
+            } else {
+                Some(MemoryReservation::new(budget))
+            },
+            bag: false,
+        }
+    }
+    /// A store with bag semantics, to be filled by [RegularTempStore::put_counted].
+    pub(crate) fn bag_with_budget(budget: &Arc<MemoryBudget>) -> Self {
+        Self {
+            bag: true,
+            ..Self::with_budget(budget)
+        }
+    }
     pub(crate) fn wrap(self) -> TempStore {
         TempStore::Normal(self)
     }
     /// Tests if a key already exists in the store.
     pub fn exists(&self, key: &Tuple) -> bool {
-        self.inner.contains_key(key)
+        self.get(key).is_some()
+    }
+    fn get(&self, key: &Tuple) -> Option<bool> {
+        match self.inner.get(key) {
+            Some(meta) => Some(meta.skip),
+            None => self.spilled.iter().rev().find_map(|(_, run)| {
+                run.get(key)
+                    .map(|meta| meta.map(|meta| meta.skip))
+                    .unwrap_or_else(|err| self.report(err))
+            }),
+        }
+    }
+    fn report<T>(&self, err: Report) -> Option<T> {
+        if let Some(reservation) = &self.reservation {
+            reservation.report(err);
+        }
+        None
+    }
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty() && self.spilled.is_empty()
+    }
+    pub(crate) fn size_hint(&self) -> usize {
+        self.inner.len() + self.spilled.iter().map(|(_, run)| run.len()).sum::<usize>()
     }
 
     fn range_iter(
@@ -51,36 +115,179 @@ impl RegularTempStore {
         } else {
             Excluded(upper.to_vec())
         };
-        self.inner
+        let in_memory = self
+            .inner
             .range((lower_bound, upper_bound))
-            .map(|(t, skip)| TupleInIter(t, EMPTY_TUPLE_REF, *skip))
+            .map(|(t, meta)| (Cow::Borrowed(t), *meta));
+        let entries = if self.spilled.is_empty() {
+            Left(in_memory)
+        } else {
+            let mut layers: Vec<
+                Box<dyn Iterator<Item = Result<(Cow<'_, Tuple>, EntryMeta)>> + '_>,
+            > = self
+                .spilled
+                .iter()
+                .map(|(_, run)| {
+                    Box::new(
+                        run.range(lower, upper, upper_inclusive)
+                            .map_ok(|(t, meta)| (Cow::Owned(t), meta)),
+                    ) as Box<dyn Iterator<Item = _> + '_>
+                })
+                .collect_vec();
+            layers.push(Box::new(in_memory.map(Ok)));
+            Right(merge_layers(layers).map_while(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(err) => self.report(err),
+            }))
+        };
+        let bag = self.bag;
+        entries.flat_map(move |(t, meta)| {
+            let tuple = TupleInIter(t, EMPTY_TUPLE_REF, meta.skip);
+            if bag {
+                Left(iter::repeat_n(tuple, meta.count))
+            } else {
+                Right(iter::once(tuple))
+            }
+        })
+    }
+    /// Consumes the store, producing each tuple as many times as it was put.
+    fn into_tuples(self) -> impl Iterator<Item = Tuple> {
+        let bag = self.bag;
+        self.into_entries().flat_map(move |(t, meta)| {
+            if bag {
+                Left(iter::repeat_n(t, meta.count))
+            } else {
+                Right(iter::once(t))
+            }
+        })
+    }
+    fn into_entries(self) -> impl Iterator<Item = (Tuple, EntryMeta)> {
+        if self.spilled.is_empty() {
+            Left(self.inner.into_iter())
+        } else {
+            let mut layers: Vec<Box<dyn Iterator<Item = Result<(Tuple, EntryMeta)>>>> = self
+                .spilled
+                .into_iter()
+                .map(|(_, run)| -> Box<dyn Iterator<Item = _>> { Box::new(run.into_entries()) })
+                .collect_vec();
+            layers.push(Box::new(self.inner.into_iter().map(Ok)));
+            let reservation = self.reservation;
+            Right(merge_layers(layers).map_while(move |entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    if let Some(reservation) = &reservation {
+                        reservation.report(err);
+                    }
+                    None
+                }
+            }))
+        }
     }
     /// Add a tuple to the store
     pub fn put(&mut self, tuple: Tuple) {
-        self.inner.insert(tuple, false);
+        self.insert(tuple, false);
     }
     pub(crate) fn put_with_skip(&mut self, tuple: Tuple) {
-        self.inner.insert(tuple, true);
+        self.insert(tuple, true);
+    }
+    /// Add a tuple to the store with bag semantics, counting how many times it is put.
+    /// Counts of a tuple in the spilled runs add up when they are read.
+    pub(crate) fn put_counted(&mut self, tuple: Tuple) {
+        debug_assert!(self.bag);
+        let size = tuple_size(&tuple) + ENTRY_OVERHEAD;
+        let is_new = match self.inner.entry(tuple) {
+            Entry::Vacant(e) => {
+                e.insert(EntryMeta::new(false));
+                true
+            }
+            Entry::Occupied(mut e) => {
+                e.get_mut().count += 1;
+                false
+            }
+        };
+        self.grow(is_new.then_some(size));
+    }
+    fn insert(&mut self, tuple: Tuple, skip: bool) {
+        let size = tuple_size(&tuple) + ENTRY_OVERHEAD;
+        let is_new = self.inner.insert(tuple, EntryMeta::new(skip)).is_none();
+        self.grow(is_new.then_some(size));
+    }
+    /// Charges a new entry of the given size to the budget, spilling if it is exceeded.
+    fn grow(&mut self, added: Option<usize>) {
+        let should_spill = match &mut self.reservation {
+            None => false,
+            Some(reservation) => {
+                if let Some(size) = added {
+                    reservation.grow(size);
+                }
+                reservation.should_spill()
+            }
+        };
+        if should_spill {
+            if let Err(err) = self.spill() {
+                self.report::<()>(err);
+            }
+        }
+    }
+    fn spill(&mut self) -> Result<()> {
+        let entries = mem::take(&mut self.inner);
+        if let Some(reservation) = &mut self.reservation {
+            reservation.release();
+        }
+        // as in the external sorter, runs are merged in levels, so that each entry
+        // is rewritten a logarithmic number of times at most
+        self.spilled
+            .push((0, SortedRun::new(entries.into_iter().map(Ok))?));
+        loop {
+            let level = self.spilled.last().unwrap().0;
+            let same_level = self
+                .spilled
+                .iter()
+                .rev()
+                .take_while(|(l, _)| *l == level)
+                .count();
+            if same_level < SPILL_MERGE_WIDTH {
+                break;
+            }
+            let layers = self
+                .spilled
+                .split_off(self.spilled.len() - same_level)
+                .into_iter()
+                .map(|(_, run)| -> Box<dyn Iterator<Item = _>> { Box::new(run.into_entries()) })
+                .collect_vec();
+            self.spilled
+                .push((level + 1, SortedRun::new(merge_layers(layers))?));
+        }
+        Ok(())
+    }
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.spilled.clear();
+        if let Some(reservation) = &mut self.reservation {
+            reservation.release();
+        }
     }
     // returns true if prev is guaranteed to be the same as self after this function call,
     // false if we are not sure.
     pub(crate) fn merge_in(&mut self, prev: &mut Self, mut new: Self) -> bool {
-        prev.inner.clear();
-        if new.inner.is_empty() {
+        prev.clear();
+        if new.is_empty() {
             return false;
         }
-        if self.inner.is_empty() {
+        if self.is_empty() {
             mem::swap(&mut new, self);
             return true;
         }
-        for (k, v) in new.inner {
-            match self.inner.entry(k) {
-                Entry::Vacant(ent) => {
-                    prev.inner.insert(ent.key().clone(), v);
-                    ent.insert(v);
+        for (k, v) in new.into_entries() {
+            match self.get(&k) {
+                None => {
+                    prev.insert(k.clone(), v.skip);
+                    self.insert(k, v.skip);
                 }
-                Entry::Occupied(mut ent) => {
-                    ent.insert(v);
+                Some(old) => {
+                    if old != v.skip {
+                        self.insert(k, v.skip);
+                    }
                 }
             }
         }
@@ -159,7 +366,7 @@ impl MeetAggrStore {
         self.inner
             .range(lower_key..=upper_key)
             .filter_map(move |(k, v)| {
-                let ret = TupleInIter(k, v, false);
+                let ret = TupleInIter(Cow::Borrowed(k), v, false);
                 if ret.partial_cmp(&lower as &[DataValue]) == Some(Ordering::Less) {
                     None
                 } else {
@@ -239,10 +446,16 @@ impl TempStore {
     }
     fn is_empty(&self) -> bool {
         match self {
-            TempStore::Normal(n) => n.inner.is_empty(),
+            TempStore::Normal(n) => n.is_empty(),
             TempStore::MeetAggr(m) => m.inner.is_empty(),
         }
     }
+    fn size_hint(&self) -> usize {
+        match self {
+            TempStore::Normal(n) => n.size_hint(),
+            TempStore::MeetAggr(m) => m.inner.len(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -257,10 +470,10 @@ impl EpochStore {
     pub(crate) fn exists(&self, key: &Tuple) -> bool {
         self.total.exists(key)
     }
-    pub(crate) fn new_normal(arity: usize) -> Self {
+    pub(crate) fn new_normal(arity: usize, budget: &Arc<MemoryBudget>) -> Self {
         Self {
-            total: TempStore::Normal(RegularTempStore::default()),
-            delta: TempStore::Normal(RegularTempStore::default()),
+            total: TempStore::Normal(RegularTempStore::with_budget(budget)),
+            delta: TempStore::Normal(RegularTempStore::with_budget(budget)),
             use_total_for_delta: true,
             arity,
         }
@@ -292,6 +505,14 @@ impl EpochStore {
             !self.delta.is_empty()
         }
     }
+    /// Number of tuples a full scan of the store, or of its delta, produces at most.
+    pub(crate) fn size_hint(&self, delta: bool) -> usize {
+        if delta && !self.use_total_for_delta {
+            self.delta.size_hint()
+        } else {
+            self.total.size_hint()
+        }
+    }
     pub(crate) fn range_iter(
         &self,
         lower: &Tuple,
@@ -334,13 +555,23 @@ impl EpochStore {
     pub(crate) fn early_returned_iter(&self) -> impl Iterator<Item = TupleInIter<'_>> {
         self.all_iter().filter(|t| !t.should_skip())
     }
+    /// Consumes the store, producing all tuples in order.
+    pub(crate) fn into_all_iter(self) -> impl Iterator<Item = Tuple> {
+        match self.total {
+            TempStore::Normal(n) => Left(n.into_tuples()),
+            TempStore::MeetAggr(m) => Right(m.inner.into_iter().map(|(mut k, v)| {
+                k.extend(v);
+                k
+            })),
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
-pub(crate) struct TupleInIter<'a>(&'a Tuple, &'a Tuple, bool);
+#[derive(Clone)]
+pub(crate) struct TupleInIter<'a>(Cow<'a, Tuple>, &'a Tuple, bool);
 
 impl<'a> TupleInIter<'a> {
-    pub(crate) fn get(self, idx: usize) -> &'a DataValue {
+    pub(crate) fn get(&self, idx: usize) -> &DataValue {
         self.0
             .get(idx)
             .unwrap_or_else(|| self.1.get(idx - self.0.len()).unwrap())
@@ -348,31 +579,19 @@ impl<'a> TupleInIter<'a> {
     fn should_skip(&self) -> bool {
         self.2
     }
-    pub(crate) fn into_tuple(self) -> Tuple {
-        self.into_iter().cloned().collect_vec()
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &DataValue> {
+        self.0.iter().chain(self.1.iter())
     }
-}
-
-impl<'a> IntoIterator for TupleInIter<'a> {
-    type Item = &'a DataValue;
-    type IntoIter = TupleInIterIterator<'a>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        TupleInIterIterator {
-            inner: self,
-            idx: 0,
-        }
+    pub(crate) fn into_tuple(self) -> Tuple {
+        let mut ret = self.0.into_owned();
+        ret.extend_from_slice(self.1);
+        ret
     }
 }
 
-pub(crate) struct TupleInIterIterator<'a> {
-    inner: TupleInIter<'a>,
-    idx: usize,
-}
-
 impl PartialEq for TupleInIter<'_> {
     fn eq(&self, other: &Self) -> bool {
-        self.into_iter().eq(other.into_iter())
+        self.iter().eq(other.iter())
     }
 }
 
@@ -380,7 +599,7 @@ impl Eq for TupleInIter<'_> {}
 
 impl Ord for TupleInIter<'_> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.into_iter().cmp(other.into_iter())
+        self.iter().cmp(other.iter())
     }
 }
 
@@ -392,28 +611,54 @@ impl PartialOrd for TupleInIter<'_> {
 
 impl PartialEq<[DataValue]> for TupleInIter<'_> {
     fn eq(&self, other: &'_ [DataValue]) -> bool {
-        self.into_iter().eq(other.iter())
+        self.iter().eq(other.iter())
     }
 }
 
 impl PartialOrd<[DataValue]> for TupleInIter<'_> {
     fn partial_cmp(&self, other: &'_ [DataValue]) -> Option<Ordering> {
-        self.into_iter().partial_cmp(other.iter())
+        self.iter().partial_cmp(other.iter())
     }
 }
 
-impl<'a> Iterator for TupleInIterIterator<'a> {
-    type Item = &'a DataValue;
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let ret = match self.inner.0.get(self.idx) {
-            Some(d) => d,
-            None => match self.inner.1.get(self.idx - self.inner.0.len()) {
-                None => return None,
-                Some(d) => d,
-            },
-        };
-        self.idx += 1;
-        Some(ret)
+    use itertools::Itertools;
+
+    use crate::data::value::DataValue;
+    use crate::runtime::spill::MemoryBudget;
+    use crate::runtime::temp_store::RegularTempStore;
+
+    #[test]
+    fn test_spilled_runs_merged_in_levels() {
+        // every put exceeds the budget and spills a run of its own
+        let budget = Arc::new(MemoryBudget::new(1));
+        let mut store = RegularTempStore::with_budget(&budget);
+        for i in 0..1000 {
+            store.put(vec![DataValue::from(i % 500)]);
+        }
+        assert!(store.spilled.len() <= 8 * 4);
+        let tuples = store.into_tuples().collect_vec();
+        let expected = (0..500).map(|i| vec![DataValue::from(i)]).collect_vec();
+        assert_eq!(tuples, expected);
+    }
+
+    #[test]
+    fn test_bag_counts_across_spills() {
+        let budget = Arc::new(MemoryBudget::new(1));
+        let mut store = RegularTempStore::bag_with_budget(&budget);
+        for i in 0..30 {
+            store.put_counted(vec![DataValue::from(i % 3)]);
+        }
+        assert!(!store.spilled.is_empty());
+        let counted = store
+            .range_iter(&vec![], &vec![DataValue::Bot], true)
+            .map(|t| t.0.into_owned())
+            .counts();
+        assert_eq!(counted.len(), 3);
+        assert!(counted.values().all(|n| *n == 10));
+        assert_eq!(store.into_tuples().count(), 30);
     }
 }
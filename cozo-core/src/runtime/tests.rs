@@ -8,6 +8,7 @@
  */
 
 use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use itertools::Itertools;
@@ -23,7 +24,11 @@ use crate::fts::{TokenizerCache, TokenizerConfig};
 use crate::parse::SourceSpan;
 use crate::runtime::callback::CallbackOp;
 use crate::runtime::db::Poison;
-use crate::{DbInstance, FixedRule, RegularTempStore, ScriptMutability};
+use crate::runtime::spill::SPILL_FILES_CREATED;
+use crate::{
+    CustomAggregation, DbInstance, FixedRule, MeetAggrObj, NamedRows, NormalAggrObj, QueryLimits,
+    RegularTempStore, ScriptMutability,
+};
 
 #[test]
 fn test_limit_offset() {
@@ -576,6 +581,1824 @@ fn test_custom_rules() {
     assert_eq!(res.into_json()["rows"], json!([[1000], [2600]]));
 }
 
+#[test]
+fn test_custom_functions() {
+    let db = DbInstance::default();
+    db.register_function(
+        "test_fn_double".to_string(),
+        1,
+        |args: &[DataValue]| -> miette::Result<DataValue> {
+            let i = args[0]
+                .get_int()
+                .ok_or_else(|| miette::miette!("integer required"))?;
+            Ok(DataValue::from(i * 2))
+        },
+    )
+    .unwrap();
+    assert!(db
+        .register_function("test_fn_double".to_string(), 1, |_: &[DataValue]| Ok(
+            DataValue::Null
+        ))
+        .is_err());
+    assert!(db
+        .register_function("concat".to_string(), 2, |_: &[DataValue]| Ok(DataValue::Null))
+        .is_err());
+
+    let res = db
+        .run_default("?[x, y] := x in [1, 2, 3], y = test_fn_double(x), test_fn_double(y) > 4")
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2, 4], [3, 6]]));
+
+    db.run_default(":create test_fn_rel {k: Int => v: Int default test_fn_double(21)}")
+        .unwrap();
+    db.run_default("?[k] <- [[1]] :put test_fn_rel {k}").unwrap();
+    let res = db.run_default("?[v] := *test_fn_rel{v}").unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[42]]));
+
+    assert!(db.run_default("?[x] := x = test_fn_double(1, 2)").is_err());
+    assert!(db.run_default("?[x] := x = test_fn_double('a')").is_err());
+
+    let res = db.run_default("::functions").unwrap();
+    assert!(res
+        .rows
+        .contains(&vec![DataValue::from("test_fn_double"), DataValue::from(1)]));
+
+    assert!(db.unregister_function("test_fn_double").unwrap());
+    assert!(db.run_default("?[x] := x = test_fn_double(1)").is_err());
+
+    db.register_function(
+        "test_fn_double".to_string(),
+        1,
+        |args: &[DataValue]| -> miette::Result<DataValue> { Ok(args[0].clone()) },
+    )
+    .unwrap();
+    let other = DbInstance::default();
+    assert!(other.run_default("?[x] := x = test_fn_double(1)").is_err());
+
+    let counter = std::sync::Arc::new(std::sync::atomic::AtomicI64::new(0));
+    let c = counter.clone();
+    db.register_function(
+        "test_fn_next".to_string(),
+        0,
+        move |_: &[DataValue]| -> miette::Result<DataValue> {
+            Ok(DataValue::from(c.fetch_add(1, Ordering::SeqCst)))
+        },
+    )
+    .unwrap();
+    let res = db
+        .run_default("?[y] := x in [1, 2, 3], y = test_fn_next()")
+        .unwrap();
+    assert_eq!(res.rows.len(), 3);
+    assert_eq!(counter.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_custom_aggregations() {
+    struct MinInt;
+
+    impl MeetAggrObj for MinInt {
+        fn init_val(&self) -> DataValue {
+            DataValue::Null
+        }
+
+        fn update(&self, left: &mut DataValue, right: &DataValue) -> miette::Result<bool> {
+            let r = right
+                .get_int()
+                .ok_or_else(|| miette::miette!("integer required"))?;
+            match left.get_int() {
+                Some(l) if l <= r => Ok(false),
+                _ => {
+                    *left = DataValue::from(r);
+                    Ok(true)
+                }
+            }
+        }
+    }
+
+    impl CustomAggregation for MinInt {
+        fn is_meet(&self) -> bool {
+            true
+        }
+
+        fn meet_init(&self, _args: &[DataValue]) -> miette::Result<Box<dyn MeetAggrObj>> {
+            Ok(Box::new(MinInt))
+        }
+    }
+
+    #[derive(Default)]
+    struct Median {
+        vals: Vec<DataValue>,
+    }
+
+    impl NormalAggrObj for Median {
+        fn set(&mut self, value: &DataValue) -> miette::Result<()> {
+            self.vals.push(value.clone());
+            Ok(())
+        }
+
+        fn get(&self) -> miette::Result<DataValue> {
+            let mut vals = self.vals.clone();
+            vals.sort();
+            Ok(vals.get(vals.len() / 2).cloned().unwrap_or(DataValue::Null))
+        }
+    }
+
+    struct MedianAggr;
+
+    impl CustomAggregation for MedianAggr {
+        fn is_meet(&self) -> bool {
+            false
+        }
+
+        fn normal_init(&self, _args: &[DataValue]) -> miette::Result<Box<dyn NormalAggrObj>> {
+            Ok(Box::<Median>::default())
+        }
+    }
+
+    let db = DbInstance::default();
+    db.register_aggregation("test_aggr_min_int".to_string(), MinInt)
+        .unwrap();
+    db.register_aggregation("test_aggr_median".to_string(), MedianAggr)
+        .unwrap();
+    assert!(db
+        .register_aggregation("test_aggr_median".to_string(), MedianAggr)
+        .is_err());
+    assert!(db
+        .register_aggregation("min".to_string(), MedianAggr)
+        .is_err());
+
+    let res = db
+        .run_default(
+            r"
+        e[a, b, w] <- [[1, 2, 1], [2, 3, 1], [1, 3, 5], [3, 1, 1]]
+        dist[n, test_aggr_min_int(d)] := n = 1, d = 0
+        dist[n, test_aggr_min_int(d)] := dist[m, d0], e[m, n, w], d = d0 + w
+        ?[n, d] := dist[n, d]
+        ",
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1, 0], [2, 1], [3, 2]]));
+
+    let res = db
+        .run_default(
+            r"
+        ?[g, test_aggr_median(x), test_aggr_min_int(x)] := x in [5, 1, 4, 2, 3], g = x % 2
+        ",
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[0, 4, 2], [1, 3, 1]]));
+
+    assert!(db
+        .run_default(
+            r"
+        e[a, b] <- [[1, 2], [2, 3]]
+        r[n, test_aggr_median(d)] := n = 1, d = 0
+        r[n, test_aggr_median(d)] := r[m, d0], e[m, n], d = d0 + 1
+        ?[n, d] := r[n, d]
+        ",
+        )
+        .is_err());
+
+    assert!(db.unregister_aggregation("test_aggr_median").unwrap());
+    assert!(db
+        .run_default("?[test_aggr_median(x)] := x in [1, 2, 3]")
+        .is_err());
+
+    let other = DbInstance::default();
+    assert!(other
+        .run_default("?[test_aggr_min_int(x)] := x in [1, 2, 3]")
+        .is_err());
+}
+
+#[test]
+fn test_explain_analyze() {
+    let db = DbInstance::default();
+    db.run_default(":create edge {fr: Int, to: Int}").unwrap();
+    db.run_default("?[fr, to] <- [[1, 2], [2, 3], [3, 4]] :put edge {fr, to}")
+        .unwrap();
+    let res = db
+        .run_default(
+            r"
+        ::explain analyze {
+            r[a, b] := *edge{fr: a, to: b}
+            r[a, b] := r[a, c], *edge{fr: c, to: b}
+            ?[a, b] := r[a, b], b > 2
+        }
+        ",
+        )
+        .unwrap();
+    assert_eq!(
+        res.headers[9..],
+        ["epoch", "produced", "filtered", "scans", "time_ms"]
+    );
+    let sum_for = |rule: &str, op: &str, col: usize| -> i64 {
+        res.rows
+            .iter()
+            .filter(|row| row[2] == DataValue::from(rule) && row[4] == DataValue::from(op))
+            .map(|row| row[col].get_int().unwrap_or(0))
+            .sum()
+    };
+    assert_eq!(sum_for("?", "load_mem", 10), 5);
+    assert_eq!(sum_for("?", "load_mem", 11), 1);
+    assert_eq!(sum_for("r|Mff", "stored_prefix_join", 10), 3);
+    assert_eq!(sum_for("r|Mff", "stored_prefix_join", 12), 6);
+    assert!(res.rows.iter().any(|row| row[9].get_int() == Some(3)));
+
+    let res = db
+        .run_default("::explain { ?[a, b] := *edge{fr: a, to: b} }")
+        .unwrap();
+    assert_eq!(res.headers.len(), 9);
+}
+
+#[test]
+fn test_analyze_and_join_planning() {
+    let db = DbInstance::default();
+    db.run_default(":create a {x: Int}").unwrap();
+    db.run_default(":create b {x: Int, y: Int}").unwrap();
+    db.run_default(":create c {y: Int}").unwrap();
+    db.run_default("?[x] := x in int_range(10) :put a {x}")
+        .unwrap();
+    db.run_default("?[x, y] := x in int_range(10), y in int_range(20) :put b {x, y}")
+        .unwrap();
+    db.run_default("?[y] := y in int_range(0, 100, 2) :put c {y}")
+        .unwrap();
+
+    let query = "?[x, y] := *b[x, y], *c[y], *a[x]";
+    let ops_of = |script: &str| -> Vec<(String, String)> {
+        db.run_default(&format!("::explain {{ {script} }}"))
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| {
+                (
+                    row[4].get_str().unwrap().to_string(),
+                    row[5].get_str().unwrap_or_default().to_string(),
+                )
+            })
+            .collect()
+    };
+    let loaded = |ops: &[(String, String)]| {
+        ops.iter()
+            .filter(|(op, _)| op == "load_stored")
+            .map(|(_, r)| r.clone())
+            .collect_vec()
+    };
+    let before = db.run_default(query).unwrap().rows;
+    // the written order is kept without statistics
+    let ops = ops_of(query);
+    assert_eq!(loaded(&ops), [":b", ":c", ":a"]);
+    assert!(ops.iter().all(|(op, _)| op != "hash_join"));
+
+    let res = db.run_default("::analyze").unwrap();
+    assert_eq!(res.headers, ["relation", "rows", "distinct_prefixes"]);
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["a", 10, [10]], ["b", 200, [10, 200]], ["c", 50, [50]]])
+    );
+
+    // the smallest relation goes first, and `c` is scanned once
+    // instead of being looked up for each of the 200 rows from `a` and `b`
+    let ops = ops_of(query);
+    assert_eq!(loaded(&ops), [":a", ":b", ":c"]);
+    assert_eq!(ops[2].0, "stored_prefix_join");
+    assert_eq!(ops[4].0, "hash_join");
+    assert_eq!(db.run_default(query).unwrap().rows, before);
+
+    assert!(db.run_default("::analyze b, c").is_ok());
+    assert!(db.run_default("::analyze nonexistent").is_err());
+
+    // hidden relations cannot be analyzed, and are skipped when analyzing everything
+    db.run_default("::access_level hidden c").unwrap();
+    assert!(db.run_default("::analyze c").is_err());
+    let res = db.run_default("::analyze").unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["a", 10, [10]], ["b", 200, [10, 200]]])
+    );
+}
+
+#[test]
+fn test_index_selection() {
+    let db = DbInstance::default();
+    db.run_default(":create rel {a: Int, b: Int => c: Int, d: Int}")
+        .unwrap();
+    db.run_default(
+        r"?[a, b, c, d] <- [[1, 2, 5, 7], [1, 3, 5, 8], [1, 4, 6, 7], [2, 3, 5, 6], [4, 3, 5, 6]]
+        :put rel {a, b => c, d}",
+    )
+    .unwrap();
+    db.run_default("::index create rel:ac {a, c}").unwrap();
+    db.run_default("::index create rel:cd {c, d}").unwrap();
+
+    let check = |query: &str, expected: serde_json::Value, refs: &[&str], ops: &[&str]| {
+        let expl = db
+            .run_default(&format!("::explain {{ {query} }}"))
+            .unwrap()
+            .rows;
+        let used_refs = expl
+            .iter()
+            .filter_map(|row| row[5].get_str().map(|s| s.to_string()))
+            .filter(|r| r.starts_with(':'))
+            .collect_vec();
+        assert_eq!(used_refs, refs, "{query}");
+        for op in ops {
+            assert!(
+                expl.iter().any(|row| row[4].get_str() == Some(op)),
+                "{query}: {op}"
+            );
+        }
+        let res = db.run_default(query).unwrap();
+        assert_eq!(res.into_json()["rows"], expected, "{query}");
+    };
+
+    // the index binds more leading columns than the keys
+    check(
+        "?[b, d] := *rel{a: 1, c: 5, b, d}",
+        json!([[2, 7], [3, 8]]),
+        &[":rel:ac", ":rel"],
+        &["stored_prefix_join", "stored_prefix_join"],
+    );
+    // bound columns beyond the prefix of the index are filtered
+    check(
+        "?[a] := *rel{c: 5, d: 6, b: 3, a}",
+        json!([[2], [4]]),
+        &[":rel:cd"],
+        &["stored_prefix_join", "filter"],
+    );
+    // the keys are as good as any index
+    check(
+        "?[c, d] := *rel{a: 1, b: 3, c, d}",
+        json!([[5, 8]]),
+        &[":rel"],
+        &["stored_prefix_join"],
+    );
+    check(
+        "?[b, c, d] := *rel{a: 1, b, c, d}",
+        json!([[2, 5, 7], [3, 5, 8], [4, 6, 7]]),
+        &[":rel"],
+        &["stored_prefix_join"],
+    );
+}
+
+#[test]
+fn test_memory_budget() {
+    let db = DbInstance::default();
+    let reach = r"
+        edge[a, b] := a in int_range(200), b = a + 1
+        reach[a, b] := edge[a, b]
+        reach[a, c] := reach[a, b], edge[b, c]
+    ";
+    let queries = [
+        format!("{reach} ?[count(a)] := reach[a, b]"),
+        format!("{reach} ?[b, count(a), min(a)] := reach[a, b]"),
+        format!("{reach} ?[a, b] := reach[a, b] :order -b, a :limit 10 :offset 5"),
+        format!("{reach} ?[a, b] := reach[a, b], reach[b, c], c = 150"),
+    ];
+    let run_all = || {
+        queries
+            .iter()
+            .map(|q| db.run_default(q).unwrap().into_json()["rows"].clone())
+            .collect_vec()
+    };
+    let in_memory = run_all();
+    assert_eq!(in_memory[0], json!([[20100]]));
+    let spilled_before = SPILL_FILES_CREATED.load(Ordering::Relaxed);
+    db.set_memory_budget(Some(1 << 16));
+    assert_eq!(run_all(), in_memory);
+    assert!(SPILL_FILES_CREATED.load(Ordering::Relaxed) > spilled_before);
+    db.set_memory_budget(None);
+}
+
+#[test]
+fn test_query_limits() {
+    let db = DbInstance::default();
+    let count_up = |to: usize| {
+        format!(
+            r"
+            r[n] := n = 0
+            r[m] := r[n], m = n + 1, m < {to}
+            ?[count(n)] := r[n]
+            "
+        )
+    };
+    let code = |res: miette::Result<NamedRows>| res.unwrap_err().code().unwrap().to_string();
+
+    assert_eq!(
+        code(db.run_default(&format!("{} :max_epochs 10", count_up(1000)))),
+        "eval::max_epochs_exceeded"
+    );
+    let res = db.run_default(&format!("{} :max_epochs 100", count_up(50)));
+    assert_eq!(res.unwrap().into_json()["rows"], json!([[50]]));
+    assert_eq!(
+        code(db.run_default("?[a] := a in int_range(1000) :max_rows 100")),
+        "eval::max_rows_exceeded"
+    );
+    assert!(db
+        .run_default("?[a] := a in int_range(1000) :max_rows 1000")
+        .is_ok());
+    // fails while the epoch is running, long before its rows could all be derived
+    assert_eq!(
+        code(db.run_default(
+            "?[a, b] := a in int_range(100000), b in int_range(100000) :max_rows 100"
+        )),
+        "eval::max_rows_exceeded"
+    );
+    assert_eq!(
+        code(db.run_default("?[a] := a in int_range(1000) :max_memory 1000")),
+        "eval::max_memory_exceeded"
+    );
+
+    db.set_default_limits(QueryLimits {
+        max_epochs: Some(10),
+        ..Default::default()
+    });
+    assert_eq!(
+        code(db.run_default(&count_up(50))),
+        "eval::max_epochs_exceeded"
+    );
+    // the limits of the database cannot be raised by a query, only lowered
+    assert_eq!(
+        code(db.run_default(&format!("{} :max_epochs 100", count_up(50)))),
+        "eval::limit_raised"
+    );
+    assert!(db
+        .run_default(&format!("{} :max_epochs 8", count_up(5)))
+        .is_ok());
+    assert_eq!(
+        code(db.run_default(&format!("{} :max_epochs 3", count_up(5)))),
+        "eval::max_epochs_exceeded"
+    );
+    let with_limits = |limits, script: &str| {
+        db.run_script_with_limits(
+            script,
+            Default::default(),
+            ScriptMutability::Immutable,
+            limits,
+        )
+    };
+    // the lower of the limits passed with the script and those of the database applies
+    assert_eq!(
+        code(with_limits(
+            QueryLimits {
+                max_epochs: Some(100),
+                ..Default::default()
+            },
+            &count_up(50)
+        )),
+        "eval::max_epochs_exceeded"
+    );
+    assert_eq!(
+        code(with_limits(
+            QueryLimits {
+                max_rows: Some(5),
+                ..Default::default()
+            },
+            &count_up(5)
+        )),
+        "eval::max_rows_exceeded"
+    );
+    assert_eq!(
+        code(with_limits(
+            QueryLimits {
+                max_rows: Some(5),
+                ..Default::default()
+            },
+            &format!("{} :max_rows 1000", count_up(5))
+        )),
+        "eval::limit_raised"
+    );
+    db.set_default_limits(QueryLimits::default());
+    assert!(db.run_default(&count_up(50)).is_ok());
+}
+
+#[test]
+fn test_materialized_views() {
+    let db = DbInstance::default();
+    db.run_default(":create edge {fr: Int, to: Int => w: Int}")
+        .unwrap();
+    db.run_default("?[fr, to, w] <- [[1, 2, 1], [2, 3, 1]] :put edge {fr, to => w}")
+        .unwrap();
+    db.run_default(
+        r"::view create reach {
+            r[a, b] := *edge{fr: a, to: b, w}, w > 0
+            r[a, c] := r[a, b], *edge[b, c, w], w > 0
+            ?[a, b] := r[a, b]
+        }",
+    )
+    .unwrap();
+    db.run_default("::view create n_edges { ?[count(fr)] := *edge[fr, _, _] }")
+        .unwrap();
+    let reach = || {
+        db.run_default("?[a, b] := *reach[a, b]")
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+    let n_edges_status = || {
+        let rels = db.run_default("::relations").unwrap();
+        let view_col = rels.headers.iter().position(|h| h == "view").unwrap();
+        let stale_col = rels.headers.iter().position(|h| h == "stale").unwrap();
+        let row = rels
+            .rows
+            .iter()
+            .find(|row| row[0] == DataValue::from("n_edges"))
+            .unwrap();
+        (row[view_col].clone(), row[stale_col].clone())
+    };
+    assert_eq!(reach(), json!([[1, 2], [1, 3], [2, 3]]));
+    assert_eq!(
+        n_edges_status(),
+        (DataValue::from("recompute"), DataValue::from(false))
+    );
+
+    db.run_default("?[fr, to, w] <- [[3, 4, 1]] :put edge {fr, to => w}")
+        .unwrap();
+    assert_eq!(
+        reach(),
+        json!([[1, 2], [1, 3], [1, 4], [2, 3], [2, 4], [3, 4]])
+    );
+    db.run_default("?[fr, to, w] <- [[2, 3, 0]] :put edge {fr, to => w}")
+        .unwrap();
+    assert_eq!(reach(), json!([[1, 2], [3, 4]]));
+    db.run_default("?[fr, to, w] <- [[2, 3, 1]] :put edge {fr, to => w}")
+        .unwrap();
+    db.run_default("?[fr, to] <- [[3, 4]] :rm edge {fr, to}")
+        .unwrap();
+    assert_eq!(reach(), json!([[1, 2], [1, 3], [2, 3]]));
+
+    // views that cannot be maintained incrementally are recomputed with the changes
+    let n_edges = || db.run_default("?[n] := *n_edges[n]").unwrap().into_json()["rows"].clone();
+    assert_eq!(
+        n_edges_status(),
+        (DataValue::from("recompute"), DataValue::from(false))
+    );
+    assert_eq!(n_edges(), json!([[2]]));
+    db.run_default("?[fr, to, w] <- [[5, 6, 1]] :put edge {fr, to => w}")
+        .unwrap();
+    assert_eq!(n_edges(), json!([[3]]));
+
+    // imports bypass maintenance, leaving the views stale until refreshed
+    db.import_relations(BTreeMap::from([(
+        "-edge".to_string(),
+        NamedRows::new(
+            vec!["fr".to_string(), "to".to_string()],
+            vec![vec![DataValue::from(5), DataValue::from(6)]],
+        ),
+    )]))
+    .unwrap();
+    assert_eq!(
+        n_edges_status(),
+        (DataValue::from("recompute"), DataValue::from(true))
+    );
+    db.run_default("::view refresh n_edges").unwrap();
+    assert_eq!(
+        n_edges_status(),
+        (DataValue::from("recompute"), DataValue::from(false))
+    );
+    assert_eq!(n_edges(), json!([[2]]));
+
+    assert!(db
+        .run_default("?[a, b] <- [[9, 9]] :put reach {a, b}")
+        .is_err());
+    assert!(db.run_default("::remove edge").is_err());
+    db.run_default("::view drop reach").unwrap();
+    db.run_default("::view drop n_edges").unwrap();
+    db.run_default("::remove edge").unwrap();
+}
+
+#[test]
+fn test_rule_libraries() {
+    let db = DbInstance::default();
+    db.run_default(":create edge {fr: Int, to: Int}").unwrap();
+    db.run_default("?[fr, to] <- [[1, 2], [2, 3], [3, 4]] :put edge {fr, to}")
+        .unwrap();
+    db.run_default(
+        r"::rules define graph {
+            reachable[a, b] := *edge[a, b]
+            reachable[a, c] := reachable[a, b], *edge[b, c]
+        }",
+    )
+    .unwrap();
+    assert!(db.run_default("::rules define bad { ?[a] := a = 1 }").is_err());
+
+    let libs = db.run_default("::rules").unwrap();
+    assert_eq!(libs.rows.len(), 1);
+    assert_eq!(libs.rows[0][0], DataValue::from("graph"));
+    assert_eq!(db.run_default("::relations").unwrap().rows.len(), 1);
+
+    let res = db
+        .run_default("use graph; ?[b] := reachable[2, b]")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[3], [4]]));
+    let res = db
+        .run_default("?[b] := graph.reachable[3, b]")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[4]]));
+    let res = db
+        .run_default(
+            r"reachable[a, b] := a = 0, b = 1
+              ?[a, b] := graph.reachable[a, b], a == 1",
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, 2], [1, 3], [1, 4]]));
+    assert!(db.run_default("?[b] := other.reachable[3, b]").is_err());
+    // rules of the query are not extended by the rules of a library with the same name
+    assert!(db
+        .run_default(
+            r"use graph;
+              reachable[a, b] := a = 0, b = 1
+              ?[a, b] := reachable[a, b]",
+        )
+        .is_err());
+
+    // paths within a library imported under qualified names are expanded as usual
+    db.run_default(
+        r"::rules define hops {
+            two_hops[a, b] := a -[*edge / *edge]-> b
+            from_one[b] := two_hops[1, b]
+        }",
+    )
+    .unwrap();
+    let res = db
+        .run_default("?[b] := hops.from_one[b]")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[3]]));
+    db.run_default("::rules drop hops").unwrap();
+
+    db.run_default("::rules drop graph").unwrap();
+    assert!(db.run_default("use graph; ?[b] := reachable[2, b]").is_err());
+    assert!(db.run_default("::rules drop graph").is_err());
+}
+
+#[test]
+fn test_stored_functions() {
+    let db = DbInstance::default();
+    db.run_default(r"::fn create normalize_phone(s) = regex_replace_all(s, '[^0-9]', '')")
+        .unwrap();
+    db.run_default("::fn create area_code(s) = slice_string(normalize_phone(s), 0, 3)")
+        .unwrap();
+    assert!(db.run_default("::fn create length(s) = s").is_err());
+    assert!(db.run_default("::fn create bad(s) = s + t").is_err());
+
+    let fns = db.run_default("::fn").unwrap().into_json();
+    assert_eq!(fns["rows"][0][0], json!("area_code"));
+    assert_eq!(fns["rows"][1][1], json!(["s"]));
+
+    let res = db
+        .run_default(r"?[n, a] := p = '(555) 123-4567', n = normalize_phone(p), a = area_code(p)")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["5551234567", "555"]]));
+    assert!(db
+        .run_default("?[n] := n = normalize_phone('1', '2')")
+        .is_err());
+
+    db.run_default("::fn create loop(x) = loop(x)").unwrap();
+    assert!(db.run_default("?[n] := n = loop(1)").is_err());
+
+    db.run_default("::fn drop normalize_phone").unwrap();
+    assert!(db.run_default("?[a] := a = area_code('555 123')").is_err());
+    assert!(db.run_default("::fn drop normalize_phone").is_err());
+
+    db.run_default("::fn create non_negative(x) = x >= 0")
+        .unwrap();
+    assert!(db
+        .run_default("::fn create non_negative(x) = x > 0")
+        .is_err());
+    db.run_default(":create account {id: Int => balance: Int check (non_negative(balance))}")
+        .unwrap();
+    db.run_default("?[id, balance] <- [[1, 10]] :put account {id => balance}")
+        .unwrap();
+    assert!(db
+        .run_default("?[id, balance] <- [[2, -1]] :put account {id => balance}")
+        .is_err());
+    let withdraw = ":put_if account {id => balance} where non_negative(old.balance - 8)";
+    db.run_default(&format!("?[id, balance] <- [[1, 2]] {withdraw}"))
+        .unwrap();
+    let res = db
+        .run_default(&format!("?[id, balance] <- [[1, 0]] {withdraw}"))
+        .unwrap();
+    assert_eq!(res.rows.len(), 1);
+
+    db.run_default(
+        "::view create solvent { ?[id] := *account{id, balance}, non_negative(balance) }",
+    )
+    .unwrap();
+    assert!(db.run_default("::fn drop non_negative").is_err());
+    db.run_default("::view drop solvent").unwrap();
+    db.run_default("::fn drop non_negative").unwrap();
+    // the constraint keeps the body the function had when the relation was created
+    assert!(db
+        .run_default("?[id, balance] <- [[2, -1]] :put account {id => balance}")
+        .is_err());
+}
+
+#[test]
+fn test_window_aggregations() {
+    let db = DbInstance::default();
+    db.run_default(":create orders {cust: String, id: Int => ts: Int}")
+        .unwrap();
+    db.run_default(
+        r#"?[cust, id, ts] <- [["a", 1, 10], ["a", 2, 30], ["a", 3, 20], ["b", 4, 5], ["b", 5, 5], ["b", 6, 1]]
+           :put orders {cust, id => ts}"#,
+    )
+    .unwrap();
+
+    let res = db
+        .run_default("?[cust, top_n(id, 2, -ts)] := *orders{cust, id, ts}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["a", 2], ["a", 3], ["b", 4], ["b", 5]]));
+
+    let res = db
+        .run_default("?[cust, id, row_number(id, -ts)] := *orders{cust, id, ts}")
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([
+            ["a", 1, 3],
+            ["a", 2, 1],
+            ["a", 3, 2],
+            ["b", 4, 1],
+            ["b", 5, 2],
+            ["b", 6, 3]
+        ])
+    );
+
+    let res = db
+        .run_default("?[id, rank(id, -ts)] := *orders{cust: 'b', id, ts}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[4, 1], [5, 1], [6, 3]]));
+
+    let res = db
+        .run_default("?[cust, id, lag(id, ts), lead(id, ts)] := *orders{cust, id, ts}, cust = 'a'")
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([["a", 1, null, 3], ["a", 2, 3, null], ["a", 3, 1, 2]])
+    );
+
+    let res = db
+        .run_default(
+            r"
+        latest[cust, top_n(id, 1, -ts)] := *orders{cust, id, ts}
+        ?[cust, ts] := latest[cust, id], *orders{cust, id, ts}
+        ",
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["a", 30], ["b", 5]]));
+
+    assert!(db
+        .run_default("?[cust, count(id), row_number(id, ts)] := *orders{cust, id, ts}")
+        .is_err());
+    assert!(db
+        .run_default("?[cust, row_number(id, ts + 1)] := *orders{cust, id, ts}")
+        .is_err());
+    assert!(db
+        .run_default("?[cust, top_n(id)] := *orders{cust, id}")
+        .is_err());
+    assert!(db
+        .run_default("?[cust, row_number(id, ts)] := *orders{cust, id}")
+        .is_err());
+    // the rows are held in memory before the single output row is derived
+    let err = db
+        .run_default("?[top_n(a, 1, a)] := a in int_range(10000) :max_memory 10000")
+        .unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "eval::max_memory_exceeded");
+}
+
+#[test]
+fn test_bag_semantics() {
+    let db = DbInstance::default();
+    db.run_default(":create emp {id: Int => dept: String, salary: Int}")
+        .unwrap();
+    db.run_default(
+        r#"?[id, dept, salary] <- [[1, "a", 10], [2, "a", 10], [3, "b", 20]]
+           :put emp {id => dept, salary}"#,
+    )
+    .unwrap();
+
+    let res = db
+        .run_default("?[dept, salary] := *emp{dept, salary}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["a", 10], ["b", 20]]));
+    let res = db
+        .run_default("?[dept, salary] := *emp{dept, salary} :bag")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["a", 10], ["a", 10], ["b", 20]]));
+
+    let query = r"
+        r[dept, salary] := *emp{dept, salary}
+        ?[dept, sum(salary)] := r[dept, salary]
+    ";
+    let res = db.run_default(query).unwrap().into_json();
+    assert_eq!(res["rows"], json!([["a", 10.0], ["b", 20.0]]));
+    let res = db
+        .run_default(&format!("{query} :bag"))
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["a", 20.0], ["b", 20.0]]));
+
+    let res = db
+        .run_default(
+            r"
+        r[d] := *emp{dept: d}
+        s[d] := *emp{dept: d}
+        ?[d] := r[d], s[d]
+        :bag
+        ",
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["a"], ["a"], ["a"], ["a"], ["b"]]));
+
+    let res = db
+        .run_default("?[dept] := *emp{dept} :bag :limit 2")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["a"], ["a"]]));
+
+    assert!(db
+        .run_default(
+            r"
+        r[x] := x = 1
+        r[x] := r[y], x = y + 1, x < 3
+        ?[x] := r[x]
+        :bag
+        "
+        )
+        .is_err());
+}
+
+#[test]
+fn test_multi_relation_mutations() {
+    let db = DbInstance::default();
+    db.run_default(":create orders {id: Int => customer: String}")
+        .unwrap();
+    db.run_default(":create items {order_id: Int, sku: String => qty: Int}")
+        .unwrap();
+
+    let res = db
+        .run_default(
+            r"
+        order[id, customer] <- [[1, 'alice']]
+        item[order_id, sku, qty] <- [[1, 'apple', 3], [1, 'pear', 2]]
+        ?[id] := order[id, _]
+        :put orders {id => customer} from order
+        :put items {order_id, sku => qty} from item
+        ",
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1]]));
+
+    db.run_default(
+        r"
+        ?[id, customer] <- [[2, 'bob']]
+        item[order_id, sku, qty] <- [[2, 'fig', 1]]
+        :put orders {id => customer}
+        :put items {order_id, sku => qty} from item
+        ",
+    )
+    .unwrap();
+
+    // the failing insert into items rolls back the order written before it
+    assert!(db
+        .run_default(
+            r"
+        order[id, customer] <- [[3, 'carol']]
+        item[order_id, sku, qty] <- [[1, 'apple', 5]]
+        ?[id] := order[id, _]
+        :put orders {id => customer} from order
+        :insert items {order_id, sku => qty} from item
+        ",
+        )
+        .is_err());
+    assert!(db
+        .run_default("?[id] <- [[4]] :put orders from missing")
+        .is_err());
+
+    let res = db
+        .run_default("?[id, customer] := *orders{id, customer}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, "alice"], [2, "bob"]]));
+    let res = db
+        .run_default("?[order_id, sku, qty] := *items{order_id, sku, qty}")
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([[1, "apple", 3], [1, "pear", 2], [2, "fig", 1]])
+    );
+}
+
+#[test]
+fn test_put_if() {
+    let db = DbInstance::default();
+    db.run_default(":create doc {id: Int => body: String, version: Int}")
+        .unwrap();
+    db.run_default(
+        "?[id, body, version] <- [[1, 'a', 1], [2, 'b', 1]] :put doc {id => body, version}",
+    )
+    .unwrap();
+
+    let cas = ":put_if doc {id => body, version} where old.version == expected";
+    let res = db
+        .run_default(&format!(
+            "?[id, body, version, expected] <- [[1, 'a2', 2, 1]] {cas}"
+        ))
+        .unwrap()
+        .into_json();
+    assert_eq!(res["headers"], json!(["_kind", "id", "body", "version"]));
+    assert_eq!(res["rows"], json!([]));
+    // the existing row now has version 2, and the failing row is reported, not written
+    let res = db
+        .run_default(&format!(
+            "?[id, body, version, expected] <- [[1, 'a3', 2, 1], [2, 'b2', 2, 1]] {cas}"
+        ))
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["rejected", 1, "a3", 2]]));
+    // incoming values are seen under `new.`
+    let res = db
+        .run_default(
+            "?[id, body, version] <- [[2, 'b3', 2]] \
+             :put_if doc {id => body, version} where new.version > old.version",
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["rejected", 2, "b3", 2]]));
+    let res = db
+        .run_default(&format!(
+            "?[id, body, version, expected] <- [[1, 'a3', 3, 2], [2, 'b2', 2, 5]] :returning {cas}"
+        ))
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([
+            ["inserted", 1, "a3", 3],
+            ["replaced", 1, "a2", 2],
+            ["rejected", 2, "b2", 2]
+        ])
+    );
+
+    // a missing row is seen as nulls
+    db.run_default(
+        "?[id, body, version] <- [[3, 'c', 1]] :put_if doc {id => body, version} where is_null(old.version)",
+    )
+    .unwrap();
+    assert!(db
+        .run_default("?[id, body, version] <- [[4, 'd', 1]] :put_if doc {id => body, version}")
+        .is_err());
+    // stored columns are only seen with a prefix
+    assert!(db
+        .run_default(
+            "?[id, body, version] <- [[4, 'd', 1]] :put_if doc {id => body, version} where is_null(body)"
+        )
+        .is_ok());
+    assert!(db
+        .run_default(
+            "?[id, b, v] <- [[4, 'd', 1]] :put_if doc {id => body: b, version: v} where is_null(body)"
+        )
+        .is_err());
+
+    let res = db
+        .run_default("?[id, body, version] := *doc{id, body, version}")
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([[1, "a3", 3], [2, "b2", 2], [3, "c", 1]])
+    );
+}
+
+#[test]
+fn test_foreign_keys() {
+    let db = DbInstance::default();
+    db.run_default(":create node {id: Int => name: String}")
+        .unwrap();
+    db.run_default(":create edge {fr: Int references node.id, to: Int references node.id}")
+        .unwrap();
+    db.run_default(":create tag {node: Int references node.id cascade, tag: String}")
+        .unwrap();
+    db.run_default("?[id, name] <- [[1, 'a'], [2, 'b'], [3, 'c']] :put node {id => name}")
+        .unwrap();
+    db.run_default("?[fr, to] <- [[1, 2]] :put edge {fr, to}")
+        .unwrap();
+    db.run_default("?[node, tag] <- [[1, 'x'], [3, 'y'], [3, 'z']] :put tag {node, tag}")
+        .unwrap();
+
+    // dangling references are rejected
+    assert!(db
+        .run_default("?[fr, to] <- [[1, 9]] :put edge {fr, to}")
+        .is_err());
+    // removal is restricted while an edge points to the node
+    assert!(db.run_default("?[id] <- [[1]] :rm node {id}").is_err());
+    // found through the index created for the column, which is not the first key
+    assert!(db.run_default("?[id] <- [[2]] :rm node {id}").is_err());
+    let res = db.run_default("::indices edge").unwrap().into_json();
+    assert_eq!(res["rows"][0][0], json!("fk_to"));
+    assert!(db.run_default("::remove node").is_err());
+    // removal cascades to tags
+    db.run_default("?[id] <- [[3]] :rm node {id}").unwrap();
+    let res = db
+        .run_default("?[node, tag] := *tag{node, tag}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, "x"]]));
+
+    let res = db.run_default("::columns edge").unwrap().into_json();
+    assert_eq!(res["rows"][0][6], json!("node.id"));
+    let res = db.run_default("::columns tag").unwrap().into_json();
+    assert_eq!(res["rows"][0][6], json!("node.id cascade"));
+    assert_eq!(res["rows"][1][6], json!(null));
+
+    // imports are checked once all their rows are written
+    let rows = |headers: &[&str], rows: Vec<Vec<DataValue>>| {
+        NamedRows::new(headers.iter().map(|h| h.to_string()).collect(), rows)
+    };
+    let dangling = BTreeMap::from([(
+        "edge".to_string(),
+        rows(
+            &["fr", "to"],
+            vec![vec![DataValue::from(1), DataValue::from(7)]],
+        ),
+    )]);
+    assert!(db.import_relations(dangling).is_err());
+    let with_node = BTreeMap::from([
+        (
+            "edge".to_string(),
+            rows(
+                &["fr", "to"],
+                vec![vec![DataValue::from(1), DataValue::from(7)]],
+            ),
+        ),
+        (
+            "node".to_string(),
+            rows(
+                &["id", "name"],
+                vec![vec![DataValue::from(7), DataValue::from("g")]],
+            ),
+        ),
+    ]);
+    db.import_relations(with_node).unwrap();
+    let referenced = BTreeMap::from([(
+        "-node".to_string(),
+        rows(&["id"], vec![vec![DataValue::from(7)]]),
+    )]);
+    assert!(db.import_relations(referenced).is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.db");
+    db.backup_db(&backup).unwrap();
+    let restored = DbInstance::default();
+    restored
+        .run_default(":create node {id: Int => name: String}")
+        .unwrap();
+    restored
+        .run_default(":create edge {fr: Int references node.id, to: Int references node.id}")
+        .unwrap();
+    assert!(restored
+        .import_from_backup(&backup, &["edge".to_string()])
+        .is_err());
+    restored
+        .import_from_backup(&backup, &["node".to_string(), "edge".to_string()])
+        .unwrap();
+    // the index of the foreign key is filled by the import
+    assert!(restored
+        .run_default("?[id] <- [[7]] :rm node {id}")
+        .is_err());
+
+    let unchecked = DbInstance::default();
+    unchecked
+        .run_default(":create node {id: Int => name: String}")
+        .unwrap();
+    unchecked
+        .run_default(":create edge {fr: Int references node.id, to: Int references node.id}")
+        .unwrap();
+    unchecked
+        .import_from_backup_with_checks(&backup, &["edge".to_string()], false)
+        .unwrap();
+    let query = "?[fr, to] := *edge{fr, to}";
+    assert_eq!(
+        unchecked.run_default(query).unwrap().rows,
+        db.run_default(query).unwrap().rows
+    );
+}
+
+#[test]
+fn test_column_constraints() {
+    let db = DbInstance::default();
+    db.run_default(
+        r#":create person {
+            id: Int
+            =>
+            age: Int? check (age >= 0),
+            status: String in ["open", "closed"]
+        }"#,
+    )
+    .unwrap();
+    let put = ":put person {id => age, status}";
+    db.run_default(&format!(
+        r#"?[id, age, status] <- [[1, 30, "open"], [2, null, "closed"]] {put}"#
+    ))
+    .unwrap();
+
+    let err = db
+        .run_default(&format!(r#"?[id, age, status] <- [[3, -1, "open"]] {put}"#))
+        .unwrap_err();
+    // the violation is wrapped in the context of the relation being written
+    assert!(format!("{err:?}").contains("age_check"));
+    let err = db
+        .run_default(r#"?[id, status] <- [[1, "pending"]] :update person {id, status}"#)
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("status_in"));
+
+    let mut data = BTreeMap::new();
+    data.insert(
+        "person".to_string(),
+        NamedRows::new(
+            vec!["id".to_string(), "age".to_string(), "status".to_string()],
+            vec![vec![
+                DataValue::from(4),
+                DataValue::from(-5),
+                DataValue::from("open"),
+            ]],
+        ),
+    );
+    assert!(db.import_relations(data).is_err());
+
+    let res = db
+        .run_default("?[id, age, status] := *person{id, age, status}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, 30, "open"], [2, null, "closed"]]));
+}
+
+#[test]
+fn test_alter_relation() {
+    let db = DbInstance::default();
+    db.run_default(":create person {id: Int => name: String, age: Int}")
+        .unwrap();
+    db.run_default(
+        "?[id, name, age] <- [[1, 'a', 30], [2, 'b', 40]] :put person {id => name, age}",
+    )
+    .unwrap();
+    db.run_default("::index create person:by_age {age}")
+        .unwrap();
+
+    db.run_default(
+        "::alter person
+            add email: String default 'none',
+            rename name -> full_name,
+            change age: Float",
+    )
+    .unwrap();
+    let res = db
+        .run_default("?[id, full_name, age, email] := *person{id, full_name, age, email}")
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([[1, "a", 30.0, "none"], [2, "b", 40.0, "none"]])
+    );
+    // the index is rebuilt with the new type
+    let res = db
+        .run_default("?[id] := *person:by_age{age: 40.0, id}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[2]]));
+
+    db.run_default("::alter person drop email, move full_name to key")
+        .unwrap();
+    let res = db.run_default("::columns person").unwrap().into_json();
+    let cols = res["rows"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|row| (row[0].clone(), row[1].clone()))
+        .collect_vec();
+    assert_eq!(
+        cols,
+        vec![
+            (json!("id"), json!(true)),
+            (json!("full_name"), json!(true)),
+            (json!("age"), json!(false))
+        ]
+    );
+
+    assert!(db.run_default("::alter person add x: Int").is_err());
+    assert!(db.run_default("::alter person drop nothing").is_err());
+    // dropping the key would merge the rows
+    assert!(db
+        .run_default("::alter person drop id, drop full_name")
+        .is_err());
+
+    // the expressions of indices follow renamed columns
+    db.run_default("::fts create person:text {extractor: full_name, tokenizer: Simple}")
+        .unwrap();
+    db.run_default("::alter person rename full_name -> name")
+        .unwrap();
+    db.run_default("::alter person add nickname: String default ''")
+        .unwrap();
+    let res = db
+        .run_default("?[id] := ~person:text{id | query: 'a', k: 10}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1]]));
+    assert!(db.run_default("::alter person drop name").is_err());
+
+    // triggers are not rewritten
+    db.run_default("::set_triggers person on put { ?[id] := _new[id, name, age, nickname] }")
+        .unwrap();
+    assert!(db
+        .run_default("::alter person rename nickname -> alias")
+        .is_err());
+    db.run_default("::alter person change age: Int").unwrap();
+}
+
+#[test]
+fn test_alter_constrained_columns() {
+    let db = DbInstance::default();
+    db.run_default(":create node {id: Int => name: String}")
+        .unwrap();
+    db.run_default(
+        r#":create edge {
+            fr: Int references node.id,
+            to: Int references node.id
+            =>
+            weight: Float check (weight > 0),
+            note: String? check (note != '' || weight > 1)
+        }"#,
+    )
+    .unwrap();
+    db.run_default("?[id, name] <- [[1, 'a'], [2, 'b']] :put node {id => name}")
+        .unwrap();
+    db.run_default(
+        "?[fr, to, weight, note] <- [[1, 2, 1.5, null]] :put edge {fr, to => weight, note}",
+    )
+    .unwrap();
+
+    // renamed columns keep their checks and references, under the new names
+    db.run_default("::alter edge rename to -> dst, rename weight -> w")
+        .unwrap();
+    assert!(db
+        .run_default("?[fr, dst, w, note] <- [[2, 1, -1.0, null]] :put edge {fr, dst => w, note}")
+        .is_err());
+    assert!(db
+        .run_default("?[fr, dst, w, note] <- [[2, 3, 1.0, null]] :put edge {fr, dst => w, note}")
+        .is_err());
+    let res = db.run_default("::indices edge").unwrap().into_json();
+    assert_eq!(res["rows"][0][0], json!("fk_dst"));
+    assert!(db.run_default("?[id] <- [[1]] :rm node {id}").is_err());
+
+    // a check referring to a dropped column is in the way
+    assert!(db.run_default("::alter edge drop w").is_err());
+    db.run_default("::alter edge drop note, drop w").unwrap();
+    // the reference goes away with its column
+    db.run_default("::alter edge drop dst").unwrap();
+    let res = db.run_default("::indices edge").unwrap().into_json();
+    assert_eq!(res["rows"], json!([]));
+    db.run_default("?[id] <- [[2]] :rm node {id}").unwrap();
+
+    // the referenced key can only be renamed
+    assert!(db.run_default("::alter node change id: Float").is_err());
+    assert!(db.run_default("::alter node move name to key").is_err());
+    db.run_default("::alter node rename id -> node_id").unwrap();
+    assert!(db.run_default("?[fr] <- [[5]] :put edge {fr}").is_err());
+    db.run_default("?[fr] <- [[1]] :put edge {fr}").unwrap();
+}
+
+#[test]
+fn test_temporal_types() {
+    let db = DbInstance::default();
+    db.run_default(
+        r#":create event {
+            day: Date,
+            at: DateTime
+            =>
+            took: Duration
+        }"#,
+    )
+    .unwrap();
+    db.run_default(
+        r#"?[day, at, took] <- [
+            ["2024-03-01", "2024-03-01T09:00:00+08:00[Asia/Shanghai]", "1h30m"],
+            ["2023-12-31", "2023-12-31T23:59:59Z", 90],
+            ["2024-02-29", "2024-02-29T12:00:00", "45s"]
+        ] :put event {day, at => took}"#,
+    )
+    .unwrap();
+    let rows = db
+        .run_default("?[day, at, took] := *event{day, at, took}")
+        .unwrap()
+        .into_json()["rows"]
+        .clone();
+    assert_eq!(
+        rows,
+        json!([
+            ["2023-12-31", "2023-12-31T23:59:59Z", 90],
+            ["2024-02-29", "2024-02-29T12:00:00Z", 45],
+            [
+                "2024-03-01",
+                "2024-03-01T09:00:00+08:00[Asia/Shanghai]",
+                5400
+            ]
+        ])
+    );
+
+    db.run_default(":create event_copy {day: Date, at: DateTime => took: Duration}")
+        .unwrap();
+    db.run_script(
+        "?[day, at, took] <- $rows :put event_copy {day, at => took}",
+        BTreeMap::from([("rows".to_string(), DataValue::from(rows))]),
+        ScriptMutability::Mutable,
+    )
+    .unwrap();
+    let res = db
+        .run_default(
+            r#"?[day, at] := *event{day, at, took}, *event_copy{day, at, took},
+                            at > to_datetime("2024-01-01T00:00:00Z")"#,
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([
+            ["2024-02-29", "2024-02-29T12:00:00Z"],
+            ["2024-03-01", "2024-03-01T09:00:00+08:00[Asia/Shanghai]"]
+        ])
+    );
+
+    assert!(db
+        .run_default(
+            r#"?[day, at, took] <- [["2024-02-30", "2024-01-01", 1]] :put event {day, at => took}"#
+        )
+        .is_err());
+    let res = db.run_default("::columns event").unwrap().into_json();
+    assert_eq!(res["rows"][0][3], json!("Date"));
+    assert_eq!(res["rows"][1][3], json!("DateTime"));
+    assert_eq!(res["rows"][2][3], json!("Duration"));
+}
+
+#[test]
+fn test_decimal_type() {
+    let db = DbInstance::default();
+    db.run_default(":create ledger {amount: Decimal => note: String}")
+        .unwrap();
+    db.run_default(
+        r#"?[amount, note] <- [["10.5", "a"], ["-3", "b"], [0.1, "c"], [2, "d"], ["2.50", "e"]]
+           :put ledger {amount => note}"#,
+    )
+    .unwrap();
+    let res = db
+        .run_default("?[amount, note] := *ledger{amount, note}")
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([
+            ["-3", "b"],
+            ["0.1", "c"],
+            ["2", "d"],
+            ["2.5", "e"],
+            ["10.5", "a"]
+        ])
+    );
+    let res = db
+        .run_default("?[sum(amount), mean(amount)] := *ledger{amount}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["12.1", "2.42"]]));
+
+    let res = db
+        .run_default(
+            r#"?[x, y, z, eq] := x = to_decimal("0.1") + to_decimal("0.2"),
+                                 y = to_decimal("19.99") * 3 - 0.5,
+                                 z = to_decimal("1") / 4,
+                                 eq = to_decimal("0.3") == 0.3"#,
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["0.3", 59.47, "0.25", true]]));
+    assert!(db
+        .run_default(r#"?[x] := x = to_decimal("1") / 0"#)
+        .is_err());
+    assert!(db
+        .run_default(r#"?[amount, note] <- [["abc", "f"]] :put ledger {amount => note}"#)
+        .is_err());
+    let res = db.run_default("::columns ledger").unwrap().into_json();
+    assert_eq!(res["rows"][0][3], json!("Decimal"));
+
+    // numbers are converted when looked up in a `Decimal` column
+    let res = db
+        .run_default("?[note] := *ledger{amount: 2, note}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["d"]]));
+    let res = db
+        .run_default("?[amount] := *ledger{amount}, amount > 0, amount < 2.6")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["0.1"], ["2"], ["2.5"]]));
+    let res = db
+        .run_default(r#"?[amount] := *ledger{amount}, amount <= to_decimal("-3")"#)
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["-3"]]));
+    let res = db
+        .run_default("?[a] := a in [2, 3], not *ledger{amount: a}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[3]]));
+}
+
+#[test]
+fn test_spatial_index() {
+    let db = DbInstance::default();
+    db.run_default(":create places {id: Int => geom: Geometry?}")
+        .unwrap();
+    db.run_default(
+        "?[id, geom] := x in int_range(5), y in int_range(10), id = x * 10 + y, geom = st_point(x, y)
+         :put places {id => geom}",
+    )
+    .unwrap();
+    // a small fan-out makes the tree several levels deep
+    db.run_default("::spatial create places:geo {field: geom, max_entries: 4}")
+        .unwrap();
+    db.run_default(
+        "?[id, geom] := x in int_range(5, 10), y in int_range(10), id = x * 10 + y, geom = st_point(x, y)
+         :put places {id => geom}",
+    )
+    .unwrap();
+    db.run_default("?[id, geom] <- [[100, null]] :put places {id => geom}")
+        .unwrap();
+    let ids = |script: &str| db.run_default(script).unwrap().into_json()["rows"].clone();
+
+    assert_eq!(
+        ids("?[count(id)] := ~places:geo{id | bbox: [-1, -1, 20, 20]}"),
+        json!([[100]])
+    );
+    assert_eq!(
+        ids("?[id] := ~places:geo{id | bbox: [2.5, 2.5, 4, 4]}"),
+        json!([[33], [34], [43], [44]])
+    );
+    assert_eq!(
+        ids("?[id] := ~places:geo{id | query: st_point(5.2, 5.1), k: 3}"),
+        json!([[55], [56], [65]])
+    );
+    assert_eq!(
+        ids(
+            "?[id, d] := ~places:geo{id | query: st_point(0, 0), radius: 1.5, bind_distance: dist},
+                         d = round(dist * 100)
+             :order d, id"
+        ),
+        json!([[0, 0.0], [1, 100], [10, 100], [11, 141.0]])
+    );
+    assert_eq!(
+        ids("?[id] := ~places:geo{id | query: st_point(0, 0), radius: 1.5, filter: id != 0}"),
+        json!([[1], [10], [11]])
+    );
+    assert_eq!(
+        ids("?[id] := ~places:geo{id | query: to_geometry('POLYGON ((0.5 0.5, 2.5 0.5, 2.5 2.5, 0.5 2.5))')}"),
+        json!([[11], [12], [21], [22]])
+    );
+    assert_eq!(
+        ids("?[id] := q = st_point(9.4, 9.4), ~places:geo{id | query: q, k: 1}"),
+        json!([[99]])
+    );
+
+    // the index follows removals and updates
+    db.run_default("?[id] <- [[55]] :rm places {id}").unwrap();
+    db.run_default("?[id, geom] <- [[0, 'POINT (20 20)']] :put places {id => geom}")
+        .unwrap();
+    db.run_default("?[id, geom] <- [[100, 'POINT (0 0)']] :put places {id => geom}")
+        .unwrap();
+    assert_eq!(
+        ids("?[id] := ~places:geo{id | query: st_point(5.2, 5.1), k: 3}"),
+        json!([[54], [56], [65]])
+    );
+    assert_eq!(
+        ids("?[id] := ~places:geo{id | query: st_point(0, 0), radius: 1}"),
+        json!([[1], [10], [100]])
+    );
+    assert_eq!(
+        ids("?[id] := ~places:geo{id | bbox: [19, 19, 21, 21]}"),
+        json!([[0]])
+    );
+    assert_eq!(
+        ids("?[count(id)] := ~places:geo{id | bbox: [-1, -1, 20, 20]}"),
+        json!([[100]])
+    );
+
+    assert!(db.run_default("?[id] := ~places:geo{id | k: 3}").is_err());
+    assert!(db
+        .run_default("?[id] := ~places:geo{id | bbox: [0, 0, 1, 1], radius: 1}")
+        .is_err());
+    assert!(db
+        .run_default("::spatial create places:bad {field: id}")
+        .is_err());
+    let res = db.run_default("::indices places").unwrap().into_json();
+    assert_eq!(res["rows"][0][1], json!("spatial"));
+    db.run_default("::spatial drop places:geo").unwrap();
+    assert!(db
+        .run_default("?[id] := ~places:geo{id | bbox: [0, 0, 1, 1]}")
+        .is_err());
+}
+
+#[test]
+fn test_record_type() {
+    let db = DbInstance::default();
+    db.run_default(":create people {id: Int => addr: {street: String, zip: Int?}}")
+        .unwrap();
+    db.run_default(
+        "?[id, addr] := id = 1, addr = {'street': 'Main St', 'zip': 12345}
+         :put people {id => addr}",
+    )
+    .unwrap();
+    db.run_default(
+        "?[id, addr] <- [[2, ['Elm St', null]], [3, ['Oak St', 12345]]] :put people {id => addr}",
+    )
+    .unwrap();
+    let rows = |script: &str| db.run_default(script).unwrap().into_json()["rows"].clone();
+
+    assert_eq!(
+        rows("?[id, addr] := *people{id, addr}"),
+        json!([
+            [1, {"street": "Main St", "zip": 12345}],
+            [2, {"street": "Elm St", "zip": null}],
+            [3, {"street": "Oak St", "zip": 12345}]
+        ])
+    );
+    assert_eq!(
+        rows("?[id, zip] := *people{id, addr}, zip = addr->'zip'"),
+        json!([[1, 12345], [2, null], [3, 12345]])
+    );
+    assert_eq!(
+        rows("?[id] := *people{id, addr}, addr->'street' == 'Elm St'"),
+        json!([[2]])
+    );
+    assert_eq!(
+        rows("::columns people")[1][3],
+        json!("{street: String, zip: Int?}")
+    );
+    // unknown fields, missing non-null fields and mistyped fields are rejected
+    for addr in [
+        "{'street': 'X', 'city': 'Y'}",
+        "{'zip': 1}",
+        "{'street': 1}",
+        "['X', 1, 2]",
+    ] {
+        assert!(db
+            .run_default(&format!(
+                "?[id, addr] := id = 4, addr = {addr} :put people {{id => addr}}"
+            ))
+            .is_err());
+    }
+
+    db.run_default("::index create people:by_zip {addr.zip}")
+        .unwrap();
+    assert!(db
+        .run_default("::index create people:by_city {addr.city}")
+        .is_err());
+    assert_eq!(
+        rows("::columns people:by_zip")[0],
+        json!(["addr.zip", true, 0, "Int?", false, null, null])
+    );
+    assert_eq!(
+        rows("?[id] := *people:by_zip[12345, id]"),
+        json!([[1], [3]])
+    );
+
+    // filters on the field use the index when the keys are not bound
+    let uses_index = |query: &str| {
+        db.run_default(&format!("::explain {{ {query} }}"))
+            .unwrap()
+            .rows
+            .iter()
+            .any(|row| row[5].get_str() == Some(":people:by_zip"))
+    };
+    let query = "?[id] := *people{id, addr}, addr->'zip' == 12345";
+    assert!(uses_index(query));
+    assert_eq!(rows(query), json!([[1], [3]]));
+    let query = "?[id] := *people{id, addr}, addr->'zip' == null";
+    assert!(uses_index(query));
+    assert_eq!(rows(query), json!([[2]]));
+    let res = db
+        .run_script(
+            "?[id] := *people{id, addr}, addr->'zip' == $zip",
+            BTreeMap::from([("zip".to_string(), DataValue::from(12345))]),
+            ScriptMutability::Immutable,
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1], [3]]));
+    // numbers of other kinds are equal by value, so the relation is scanned for them
+    for query in [
+        "?[id] := *people{id, addr}, addr->'zip' == 12345.0",
+        "?[id] := z = 12345, *people{id, addr}, addr->'zip' == z",
+    ] {
+        assert!(!uses_index(query));
+        assert_eq!(rows(query), json!([[1], [3]]));
+    }
+
+    // the index follows updates and removals
+    db.run_default("?[id, addr] <- [[1, ['Main St', 54321]]] :put people {id => addr}")
+        .unwrap();
+    db.run_default("?[id] <- [[3]] :rm people {id}").unwrap();
+    assert_eq!(
+        rows("?[zip, id] := *people:by_zip[zip, id]"),
+        json!([[null, 2], [54321, 1]])
+    );
+
+    // the path of the field follows the record column when it is renamed
+    db.run_default("::alter people rename addr -> address")
+        .unwrap();
+    assert_eq!(
+        rows("?[zip, id] := *people:by_zip[zip, id]"),
+        json!([[null, 2], [54321, 1]])
+    );
+    assert_eq!(rows("::columns people:by_zip")[0][0], json!("address.zip"));
+}
+
+#[test]
+fn test_optional_atoms() {
+    let db = DbInstance::default();
+    db.run_default(":create person {id: Int => name: String}")
+        .unwrap();
+    db.run_default(":create phone {pid: Int => number: String}")
+        .unwrap();
+    db.run_default(":create likes {fr: Int, to: Int}").unwrap();
+    db.run_default(
+        r#"?[id, name] <- [[1, "alice"], [2, "bob"], [3, "carol"]] :put person {id => name}"#,
+    )
+    .unwrap();
+    db.run_default(r#"?[pid, number] <- [[1, "111"], [3, "333"]] :put phone {pid => number}"#)
+        .unwrap();
+    db.run_default("?[fr, to] <- [[1, 2], [2, 3]] :put likes {fr, to}")
+        .unwrap();
+
+    let query = "?[name, number] := *person{id, name}, ?*phone{pid: id, number}";
+    let res = db.run_default(query).unwrap().into_json();
+    assert_eq!(
+        res["rows"],
+        json!([["alice", "111"], ["bob", null], ["carol", "333"]])
+    );
+    let ops = db
+        .run_default(&format!("::explain {{ {query} }}"))
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| row[4].get_str().unwrap().to_string())
+        .collect_vec();
+    assert!(ops.contains(&"stored_left_prefix_join".to_string()));
+
+    let res = db
+        .run_default("?[id, fr] := *person{id}, ?*likes{fr, to: id}")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, null], [2, 1], [3, 2]]));
+
+    let res = db
+        .run_default("?[name] := *person{id, name}, ?*phone{pid: id, number}, is_null(number)")
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["bob"]]));
+
+    assert!(db
+        .run_default("?[id] := *person{id}, not ?*phone{pid: id}")
+        .is_err());
+}
+
+#[test]
+fn test_path_queries() {
+    let db = DbInstance::default();
+    db.run_default(":create follows {fr: Int, to: Int}")
+        .unwrap();
+    db.run_default(":create likes {fr: Int, to: Int}").unwrap();
+    db.run_default("?[fr, to] <- [[1, 2], [2, 3], [3, 1], [3, 4]] :put follows {fr, to}")
+        .unwrap();
+    db.run_default("?[fr, to] <- [[4, 5]] :put likes {fr, to}")
+        .unwrap();
+
+    let reached = |query: &str| db.run_default(query).unwrap().into_json()["rows"].clone();
+    assert_eq!(
+        reached("?[b] := 1 -[*follows+]-> b"),
+        json!([[1], [2], [3], [4]])
+    );
+    assert_eq!(
+        reached("?[b] := 1 -[*follows[1..2]]-> b"),
+        json!([[2], [3]])
+    );
+    assert_eq!(reached("?[b] := 1 -[*follows* / *likes]-> b"), json!([[5]]));
+    assert_eq!(
+        reached("?[b] := 4 -[^*follows | *likes]-> b"),
+        json!([[3], [5]])
+    );
+    assert_eq!(reached("?[b] := 4 -[*follows?]-> b"), json!([[4]]));
+    assert_eq!(
+        reached("?[a] := a -[(*follows / *follows)[2..]]-> 4"),
+        json!([[1], [2], [3]])
+    );
+
+    assert!(db.run_default("?[b] := 1 -[*follows[2..1]]-> b").is_err());
+}
+
+#[test]
+fn test_cypher() {
+    let db = DbInstance::default();
+    db.run_default(":create person {id: Int => name: String, age: Int}")
+        .unwrap();
+    db.run_default(":create knows {fr: Int, to: Int => since: Int}")
+        .unwrap();
+    db.run_default(":create labels {label: String => relation: String}")
+        .unwrap();
+    db.run_default(
+        r"?[id, name, age] <- [[1, 'alice', 30], [2, 'bob', 25], [3, 'carol', 35], [4, 'dave', 40]]
+          :put person {id => name, age}",
+    )
+    .unwrap();
+    db.run_default(
+        "?[fr, to, since] <- [[1, 2, 2010], [2, 3, 2015], [3, 4, 2020]] :put knows {fr, to => since}",
+    )
+    .unwrap();
+    db.run_default(
+        "?[label, relation] <- [['Person', 'person'], ['KNOWS', 'knows']] :put labels {label => relation}",
+    )
+    .unwrap();
+    // no mapping is used until one is declared
+    assert!(db
+        .run_cypher("MATCH (a:Person) RETURN a.name", Default::default())
+        .is_err());
+    db.run_default(":create narrow {label: String}").unwrap();
+    assert!(db.run_default("::cypher_mapping narrow").is_err());
+    assert!(db.run_default("::cypher_mapping nowhere").is_err());
+    db.run_default("::cypher_mapping labels").unwrap();
+    assert_eq!(
+        db.run_default("::cypher_mapping").unwrap().into_json()["rows"],
+        json!([["labels"]])
+    );
+
+    let res = db
+        .run_cypher(
+            "MATCH (a:Person)-[:KNOWS]->(b:Person) WHERE a.age > 26 \
+             RETURN a.name, b.name AS friend ORDER BY friend DESC LIMIT 1",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["headers"], json!(["a.name", "friend"]));
+    assert_eq!(res["rows"], json!([["carol", "dave"]]));
+
+    let rows = |query: &str, params: BTreeMap<String, DataValue>| {
+        db.run_cypher(query, params).unwrap().into_json()["rows"].clone()
+    };
+    assert_eq!(
+        rows(
+            "MATCH (a:Person {name: 'alice'})-[:KNOWS*2..]->(b:Person) RETURN b.name",
+            Default::default()
+        ),
+        json!([["carol"], ["dave"]])
+    );
+    assert_eq!(
+        rows(
+            "MATCH (a:Person {name: 'bob'})-[:KNOWS]-(b:Person) RETURN b.name",
+            Default::default()
+        ),
+        json!([["alice"], ["carol"]])
+    );
+    assert_eq!(
+        rows(
+            "MATCH (a:Person)<-[k:KNOWS]-(b:Person) WHERE k.since >= $year RETURN count(*) AS n",
+            BTreeMap::from([("year".to_string(), DataValue::from(2015))])
+        ),
+        json!([[2]])
+    );
+
+    assert!(db
+        .run_cypher("MATCH (a:Company) RETURN a", Default::default())
+        .is_err());
+    assert!(db
+        .run_cypher("MATCH (a:Person) RETURN a.salary", Default::default())
+        .is_err());
+
+    db.run_default("::access_level hidden labels").unwrap();
+    assert!(db
+        .run_cypher("MATCH (a:Person) RETURN a.name", Default::default())
+        .is_err());
+}
+
 #[test]
 fn test_index_short() {
     let db = DbInstance::default();
@@ -6,18 +6,25 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU32, AtomicU64};
 use std::sync::Arc;
 
+use crossbeam::sync::ShardedLock;
 use miette::{bail, Result};
-use crate::data::program::ReturnMutation;
+use smartstring::{LazyCompact, SmartString};
+use crate::data::program::{RelationOp, ReturnMutation};
 
-use crate::data::tuple::TupleT;
+use crate::data::tuple::{Tuple, TupleT};
 use crate::data::value::DataValue;
 use crate::fts::TokenizerCache;
+use crate::parse::CustomRegistry;
+use crate::query::profile::QueryProfiler;
 use crate::{CallbackOp, NamedRows};
 use crate::runtime::callback::CallbackCollector;
+use crate::runtime::db::QueryLimits;
 use crate::runtime::relation::RelationId;
+use crate::runtime::spill::MemoryBudget;
 use crate::storage::temp::TempTx;
 use crate::storage::StoreTx;
 
@@ -26,7 +33,13 @@ pub struct SessionTx<'a> {
     pub(crate) temp_store_tx: TempTx,
     pub(crate) relation_store_id: Arc<AtomicU64>,
     pub(crate) temp_store_id: AtomicU32,
+    pub(crate) custom_registry: Arc<ShardedLock<CustomRegistry>>,
     pub(crate) tokenizers: Arc<TokenizerCache>,
+    pub(crate) profiler: Option<Arc<QueryProfiler>>,
+    pub(crate) memory: Arc<MemoryBudget>,
+    pub(crate) limits: QueryLimits,
+    /// rows not written by `:put_if` because their condition failed, kept for `:returning`
+    pub(crate) rejected_rows: BTreeMap<SmartString<LazyCompact>, Vec<Tuple>>,
 }
 
 pub const CURRENT_STORAGE_VERSION: [u8; 1] = [0x00];
@@ -40,9 +53,34 @@ const STATUS_STR: &str = "status";
 const OK_STR: &str = "OK";
 
 impl<'a> SessionTx<'a> {
-    pub(crate) fn get_returning_rows(&self, callback_collector: &mut CallbackCollector, rel: &str, returning: &ReturnMutation) -> Result<NamedRows> {
+    pub(crate) fn get_returning_rows(&mut self, callback_collector: &mut CallbackCollector, rel: &str, relation_op: RelationOp, returning: &ReturnMutation) -> Result<NamedRows> {
         let returned_rows = {
             match returning {
+                // `:put_if` reports the rows whose condition failed
+                ReturnMutation::NotReturning if relation_op == RelationOp::PutIf => {
+                    let meta = self.get_relation(rel, false)?;
+                    let rows = self
+                        .rejected_rows
+                        .remove(&meta.name)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|row| {
+                            let mut v = Vec::with_capacity(row.len() + 1);
+                            v.push(DataValue::from("rejected"));
+                            v.extend(row);
+                            v
+                        })
+                        .collect();
+                    let mut header = vec!["_kind".to_string()];
+                    header.extend(
+                        meta.metadata
+                            .keys
+                            .iter()
+                            .chain(meta.metadata.non_keys.iter())
+                            .map(|s| s.name.to_string()),
+                    );
+                    NamedRows::new(header, rows)
+                }
                 ReturnMutation::NotReturning => {
                     NamedRows::new(
                         vec![STATUS_STR.to_string()],
@@ -79,6 +117,12 @@ impl<'a> SessionTx<'a> {
                             }
                         }
                     }
+                    for row in self.rejected_rows.remove(&meta.name).unwrap_or_default() {
+                        let mut v = Vec::with_capacity(target_len + 1);
+                        v.push(DataValue::from("rejected"));
+                        v.extend(row);
+                        returned_rows.push(v);
+                    }
                     let mut header = vec!["_kind".to_string()];
                     header.extend(meta.metadata.keys
                         .iter()
@@ -0,0 +1,804 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Materialized views: read-only stored relations holding the result of a query,
+//! kept up to date when the stored relations read by the query change.
+//!
+//! For queries made of positive rules without aggregations, the changed rows of a
+//! base relation are propagated through the rules by delta rules, as in semi-naive
+//! evaluation. Other views are recomputed in full, in the transaction changing the base.
+//! Imports bypass maintenance and mark the views stale instead, until `::view refresh`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::program::{
+    FixedRuleArg, InputAtom, InputInlineRule, InputInlineRulesOrFixed, InputProgram,
+    InputRelationApplyAtom, InputRuleApplyAtom, PathExpr,
+};
+use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::tuple::Tuple;
+use crate::data::value::{DataValue, ValidityTs};
+use crate::parse::parse_script;
+use crate::query::stored::make_const_rule;
+use crate::runtime::relation::{AccessLevel, InputRelationHandle, RelationHandle};
+use crate::runtime::transact::SessionTx;
+use crate::storage::Storage;
+use crate::{Db, SourceSpan};
+
+/// The definition of a materialized view, kept in the metadata of the relation holding its rows.
+#[derive(Clone, Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct ViewManifest {
+    /// The query defining the view
+    pub(crate) query: String,
+    /// The stored relations read by the query
+    pub(crate) bases: Vec<SmartString<LazyCompact>>,
+    /// The stored functions called by the query, which cannot be dropped while the view exists
+    #[serde(default)]
+    pub(crate) functions: Vec<SmartString<LazyCompact>>,
+    /// Whether changes to the base relations are propagated as deltas
+    pub(crate) incremental: bool,
+    /// Set when the base relations changed without the view being updated
+    pub(crate) stale: bool,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot create view {0}: {1}")]
+#[diagnostic(code(eval::bad_view))]
+struct BadViewDefinition(String, String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation {0} is not a view")]
+#[diagnostic(code(eval::not_a_view))]
+struct NotAView(String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot drop view {0} since view {1} reads it")]
+#[diagnostic(code(eval::view_has_dependents))]
+struct ViewHasDependents(String, String, #[label] SourceSpan);
+
+impl<'a> SessionTx<'a> {
+    /// Parses the query of a view, with the stored rule libraries it uses resolved and its
+    /// path atoms expanded. Also returns the names of the stored functions inlined.
+    fn view_program<'s, S: Storage<'s>>(
+        &self,
+        db: &Db<S>,
+        query: &str,
+        cur_vld: ValidityTs,
+    ) -> Result<(InputProgram, BTreeSet<SmartString<LazyCompact>>)> {
+        let mut program = parse_script(
+            query,
+            &Default::default(),
+            &db.custom_registry.read().unwrap(),
+            &db.fixed_rules.read().unwrap(),
+            cur_vld,
+        )?
+        .get_single_program()?;
+        self.import_rule_libraries(&mut program, cur_vld)?;
+        let functions = self.inline_stored_functions(&mut program)?;
+        self.expand_path_atoms(&mut program)?;
+        Ok((program, functions))
+    }
+
+    /// Names of the relations locked when the view is created, refreshed or removed:
+    /// the view, and the stored relations read by its query, given when creating the view.
+    pub(crate) fn view_lock_names<'s, S: Storage<'s>>(
+        &self,
+        db: &Db<S>,
+        name: &Symbol,
+        query: Option<&str>,
+        cur_vld: ValidityTs,
+    ) -> Result<BTreeSet<SmartString<LazyCompact>>> {
+        let mut names = match query {
+            Some(query) => stored_relations(&self.view_program(db, query, cur_vld)?.0),
+            None => match self.get_relation(name, false) {
+                Ok(RelationHandle {
+                    view: Some(manifest),
+                    ..
+                }) => manifest.bases.into_iter().collect(),
+                _ => BTreeSet::new(),
+            },
+        };
+        names.insert(name.name.clone());
+        Ok(names)
+    }
+
+    pub(crate) fn create_view<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        name: &Symbol,
+        query: &str,
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        let bad = |reason: &str| BadViewDefinition(name.to_string(), reason.to_string(), name.span);
+
+        if name.is_temp_store_name() || name.name.contains(':') {
+            bail!(bad("views must have the name of a regular stored relation"))
+        }
+        if self.relation_exists(name)? {
+            bail!(bad("a relation with the same name already exists"))
+        }
+        let (program, functions) = self.view_program(db, query, cur_vld)?;
+        if program.out_opts.store_relation.is_some() || !program.out_opts.rule_mutations.is_empty()
+        {
+            bail!(bad("the query cannot mutate relations"))
+        }
+        let bases = stored_relations(&program);
+        if bases.iter().any(|base| base.starts_with('_')) {
+            bail!(bad("the query cannot read temp relations"))
+        }
+        if bases.iter().any(|base| base.contains(':')) {
+            bail!(bad("the query cannot read indices directly"))
+        }
+        let incremental = is_incremental(&program);
+        let columns = view_columns(&program)?;
+        let rows = self.run_view_query(db, program, cur_vld)?;
+
+        let col_def = |name: &Symbol| ColumnDef {
+            name: name.name.clone(),
+            typing: NullableColType {
+                coltype: ColType::Any,
+                nullable: true,
+            },
+            default_gen: None,
+        };
+        let mut handle = self.create_relation(InputRelationHandle {
+            name: name.clone(),
+            metadata: StoredRelationMetadata {
+                keys: columns.iter().map(col_def).collect_vec(),
+                non_keys: vec![],
+                foreign_keys: vec![],
+                constraints: vec![],
+            },
+            key_bindings: columns,
+            dep_bindings: vec![],
+            condition: None,
+            span: name.span,
+        })?;
+        handle.access_level = AccessLevel::ReadOnly;
+        handle.view = Some(ViewManifest {
+            query: query.to_string(),
+            bases: bases.iter().cloned().collect_vec(),
+            functions: functions.into_iter().collect_vec(),
+            incremental,
+            stale: false,
+        });
+        self.put_relation_meta(&handle)?;
+        for base in &bases {
+            let mut base_handle = self.get_relation(base, true)?;
+            base_handle.views.insert(handle.name.clone());
+            self.put_relation_meta(&base_handle)?;
+        }
+        self.put_view_rows(&handle, &rows)
+    }
+
+    /// Removes the view, returning the key range to be cleared.
+    pub(crate) fn remove_view(&mut self, name: &Symbol) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut handle = self.get_relation(name, true)?;
+        let manifest = match handle.view.take() {
+            Some(manifest) => manifest,
+            None => bail!(NotAView(name.to_string(), name.span)),
+        };
+        if let Some(dependent) = handle.views.iter().next() {
+            bail!(ViewHasDependents(
+                name.to_string(),
+                dependent.to_string(),
+                name.span
+            ))
+        }
+        for base in &manifest.bases {
+            let mut base_handle = self.get_relation(base, true)?;
+            base_handle.views.remove(&handle.name);
+            self.put_relation_meta(&base_handle)?;
+        }
+        handle.access_level = AccessLevel::Normal;
+        self.put_relation_meta(&handle)?;
+        self.destroy_relation(name)
+    }
+
+    /// Recomputes the view, propagating the differences to the views reading it.
+    pub(crate) fn refresh_view<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        name: &Symbol,
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        let mut handle = self.get_relation(name, true)?;
+        let manifest = match &handle.view {
+            Some(manifest) => manifest.clone(),
+            None => bail!(NotAView(name.to_string(), name.span)),
+        };
+        let (program, _) = self.view_program(db, &manifest.query, cur_vld)?;
+        let fresh: BTreeSet<Tuple> = self
+            .run_view_query(db, program, cur_vld)?
+            .into_iter()
+            .collect();
+        let existing: BTreeSet<Tuple> = handle.scan_all(self).try_collect()?;
+        let added = fresh.difference(&existing).cloned().collect_vec();
+        let removed = existing.difference(&fresh).cloned().collect_vec();
+        self.apply_view_changes(db, cur_vld, &handle, added, removed)?;
+
+        if let Some(manifest) = &mut handle.view {
+            manifest.stale = false;
+        }
+        self.put_relation_meta(&handle)
+    }
+
+    /// Brings the views reading `base` up to date with rows added to and removed from it.
+    pub(crate) fn maintain_views<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        cur_vld: ValidityTs,
+        base: &RelationHandle,
+        inserted: Vec<Tuple>,
+        deleted: Vec<Tuple>,
+    ) -> Result<()> {
+        if inserted.is_empty() && deleted.is_empty() {
+            return Ok(());
+        }
+        for view_name in &base.views {
+            let view = self.get_relation(view_name, true)?;
+            let manifest = match &view.view {
+                Some(manifest) if !manifest.stale => manifest.clone(),
+                _ => continue,
+            };
+            if !manifest.incremental {
+                self.refresh_view(
+                    db,
+                    &Symbol::new(view_name.clone(), Default::default()),
+                    cur_vld,
+                )?;
+                continue;
+            }
+            let (program, _) = self.view_program(db, &manifest.query, cur_vld)?;
+
+            // Rows that lost a derivation are found by the delta rules over the removed
+            // rows, and are removed unless they can still be derived.
+            let mut removed = vec![];
+            if !deleted.is_empty() {
+                let mut candidates = vec![];
+                for row in self.view_delta(db, cur_vld, program.clone(), base, &deleted, true)? {
+                    if view.exists(self, &row)? {
+                        candidates.push(row);
+                    }
+                }
+                if !candidates.is_empty() {
+                    let kept = self.rederive(db, cur_vld, program.clone(), &candidates)?;
+                    removed = candidates
+                        .into_iter()
+                        .filter(|row| !kept.contains(row))
+                        .collect_vec();
+                }
+            }
+
+            let mut added = vec![];
+            if !inserted.is_empty() {
+                for row in self.view_delta(db, cur_vld, program, base, &inserted, false)? {
+                    if !view.exists(self, &row)? {
+                        added.push(row);
+                    }
+                }
+            }
+
+            self.apply_view_changes(db, cur_vld, &view, added, removed)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the view and the views reading it as stale.
+    pub(crate) fn mark_view_stale(&mut self, name: &str) -> Result<()> {
+        let mut handle = self.get_relation(name, true)?;
+        match &mut handle.view {
+            Some(manifest) if !manifest.stale => manifest.stale = true,
+            _ => return Ok(()),
+        }
+        self.put_relation_meta(&handle)?;
+        for dependent in &handle.views {
+            self.mark_view_stale(dependent)?;
+        }
+        Ok(())
+    }
+
+    fn apply_view_changes<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        cur_vld: ValidityTs,
+        view: &RelationHandle,
+        added: Vec<Tuple>,
+        removed: Vec<Tuple>,
+    ) -> Result<()> {
+        self.del_view_rows(view, &removed)?;
+        self.put_view_rows(view, &added)?;
+        self.maintain_views(db, cur_vld, view, added, removed)
+    }
+
+    fn run_view_query<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        program: InputProgram,
+        cur_vld: ValidityTs,
+    ) -> Result<Vec<Tuple>> {
+        let (res, _) = db.run_query(
+            self,
+            program,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            false,
+        )?;
+        Ok(res.rows)
+    }
+
+    /// Evaluates the rows of the view having a derivation that uses at least one of `rows`
+    /// of `base`. With `removed`, the rows are no longer in `base`, and are read as if they were.
+    fn view_delta<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        cur_vld: ValidityTs,
+        mut program: InputProgram,
+        base: &RelationHandle,
+        rows: &[Tuple],
+        removed: bool,
+    ) -> Result<Vec<Tuple>> {
+        let full_base = Symbol::new(format!("*{}", base.name), Default::default());
+        let delta_base = delta_symbol(&full_base);
+        if !rewrite_to_delta(&mut program, base, &full_base) {
+            return Ok(vec![]);
+        }
+
+        let columns = base
+            .metadata
+            .keys
+            .iter()
+            .chain(base.metadata.non_keys.iter())
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let args = columns.iter().map(binding).collect_vec();
+        let mut full_rules = vec![plain_rule(
+            columns.clone(),
+            InputAtom::Relation {
+                inner: InputRelationApplyAtom {
+                    name: Symbol::new(base.name.clone(), Default::default()),
+                    args: args.clone(),
+                    valid_at: None,
+                    span: Default::default(),
+                },
+            },
+        )];
+        if removed {
+            full_rules.push(plain_rule(columns.clone(), rule_atom(&delta_base, args)));
+        }
+        program.prog.insert(
+            full_base,
+            InputInlineRulesOrFixed::Rules { rules: full_rules },
+        );
+        make_const_rule(
+            &mut program,
+            &delta_base.name,
+            columns,
+            rows.iter().cloned().map(DataValue::List).collect_vec(),
+        );
+        self.run_view_query(db, program, cur_vld)
+    }
+
+    /// Returns those of `candidates` that the query of the view still derives.
+    fn rederive<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        cur_vld: ValidityTs,
+        mut program: InputProgram,
+        candidates: &[Tuple],
+    ) -> Result<BTreeSet<Tuple>> {
+        let entry = Symbol::new(PROG_ENTRY, Default::default());
+        let query = Symbol::new("?view", Default::default());
+        let candidates_rule = Symbol::new("Δ?", Default::default());
+        if let Some(rules) = program.prog.remove(&entry) {
+            program.prog.insert(query.clone(), rules);
+        }
+
+        let vars = (0..candidates[0].len())
+            .map(|i| Symbol::new(format!("c{i}"), Default::default()))
+            .collect_vec();
+        let args = vars.iter().map(binding).collect_vec();
+        make_const_rule(
+            &mut program,
+            &candidates_rule.name,
+            vars.clone(),
+            candidates
+                .iter()
+                .cloned()
+                .map(DataValue::List)
+                .collect_vec(),
+        );
+        let mut check = plain_rule(vars, rule_atom(&candidates_rule, args.clone()));
+        check.body.push(rule_atom(&query, args));
+        program
+            .prog
+            .insert(entry, InputInlineRulesOrFixed::Rules { rules: vec![check] });
+        Ok(self
+            .run_view_query(db, program, cur_vld)?
+            .into_iter()
+            .collect())
+    }
+}
+
+/// Splits the rows written by a mutation and the rows they replaced into the rows
+/// added to the relation and the rows removed from it.
+pub(crate) fn row_changes(
+    new_tuples: &[DataValue],
+    old_tuples: &[DataValue],
+) -> (Vec<Tuple>, Vec<Tuple>) {
+    let as_rows = |tuples: &[DataValue]| -> BTreeSet<Tuple> {
+        tuples
+            .iter()
+            .filter_map(|v| match v {
+                DataValue::List(l) => Some(l.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+    let new_rows = as_rows(new_tuples);
+    let old_rows = as_rows(old_tuples);
+    (
+        new_rows.difference(&old_rows).cloned().collect_vec(),
+        old_rows.difference(&new_rows).cloned().collect_vec(),
+    )
+}
+
+/// The names of the columns of the view, taken from the head of the entry.
+fn view_columns(program: &InputProgram) -> Result<Vec<Symbol>> {
+    let head = match program
+        .prog
+        .get(&Symbol::new(PROG_ENTRY, Default::default()))
+    {
+        Some(InputInlineRulesOrFixed::Rules { rules }) => rules.last().unwrap().head.clone(),
+        _ => program.get_entry_out_head_or_default()?,
+    };
+    let mut seen = BTreeSet::new();
+    for col in &head {
+        if !seen.insert(&col.name) {
+            bail!(BadViewDefinition(
+                col.to_string(),
+                "the columns of a view must have distinct names".to_string(),
+                col.span
+            ))
+        }
+    }
+    Ok(head)
+}
+
+fn stored_relations(program: &InputProgram) -> BTreeSet<SmartString<LazyCompact>> {
+    fn collect_atom(atom: &InputAtom, ret: &mut BTreeSet<SmartString<LazyCompact>>) {
+        match atom {
+            InputAtom::Relation { inner } => {
+                ret.insert(inner.name.name.clone());
+            }
+            InputAtom::NamedFieldRelation { inner } => {
+                ret.insert(inner.name.name.clone());
+            }
+            InputAtom::Search { inner } => {
+                ret.insert(inner.relation.name.clone());
+            }
+            InputAtom::Negation { inner, .. } | InputAtom::Optional { inner, .. } => {
+                collect_atom(inner, ret)
+            }
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                for atom in inner {
+                    collect_atom(atom, ret)
+                }
+            }
+            InputAtom::Path { inner } => collect_path(&inner.path, ret),
+            InputAtom::Rule { .. }
+            | InputAtom::Predicate { .. }
+            | InputAtom::Unification { .. } => {}
+        }
+    }
+
+    fn collect_path(path: &PathExpr, ret: &mut BTreeSet<SmartString<LazyCompact>>) {
+        match path {
+            PathExpr::Step { relation, .. } => {
+                ret.insert(relation.name.clone());
+            }
+            PathExpr::Sequence(inner) | PathExpr::Alternation(inner) => {
+                for path in inner {
+                    collect_path(path, ret)
+                }
+            }
+            PathExpr::Repetition { inner, .. } => collect_path(inner, ret),
+        }
+    }
+
+    let mut ret = BTreeSet::new();
+    for rules in program.prog.values() {
+        match rules {
+            InputInlineRulesOrFixed::Rules { rules } => {
+                for atom in rules.iter().flat_map(|rule| rule.body.iter()) {
+                    collect_atom(atom, &mut ret);
+                }
+            }
+            InputInlineRulesOrFixed::Fixed { fixed } => {
+                for arg in &fixed.rule_args {
+                    match arg {
+                        FixedRuleArg::Stored { name, .. }
+                        | FixedRuleArg::NamedStored { name, .. } => {
+                            ret.insert(name.name.clone());
+                        }
+                        FixedRuleArg::InMem { .. } => {}
+                    }
+                }
+            }
+        }
+    }
+    ret
+}
+
+/// Whether deltas can be propagated through the program: it must be made of inline rules
+/// without aggregations, reading relations only positively and at the current time.
+fn is_incremental(program: &InputProgram) -> bool {
+    fn reads_relations(atom: &InputAtom) -> bool {
+        match atom {
+            InputAtom::Predicate { .. } | InputAtom::Unification { .. } => false,
+            InputAtom::Negation { inner, .. } => reads_relations(inner),
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                inner.iter().any(reads_relations)
+            }
+            _ => true,
+        }
+    }
+    fn is_monotone(atom: &InputAtom) -> bool {
+        match atom {
+            InputAtom::Relation { inner } => inner.valid_at.is_none(),
+            InputAtom::NamedFieldRelation { inner } => inner.valid_at.is_none(),
+            InputAtom::Negation { inner, .. } => !reads_relations(inner),
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                inner.iter().all(is_monotone)
+            }
+            InputAtom::Search { .. } | InputAtom::Optional { .. } => false,
+            InputAtom::Rule { .. }
+            | InputAtom::Path { .. }
+            | InputAtom::Predicate { .. }
+            | InputAtom::Unification { .. } => true,
+        }
+    }
+
+    let opts = &program.out_opts;
+    if opts.limit.is_some() || opts.offset.is_some() || !opts.sorters.is_empty() {
+        return false;
+    }
+    program.prog.values().all(|rules| match rules {
+        InputInlineRulesOrFixed::Fixed { .. } => false,
+        InputInlineRulesOrFixed::Rules { rules } => rules.iter().all(|rule| {
+            rule.aggr.iter().all(|aggr| aggr.is_none()) && rule.body.iter().all(is_monotone)
+        }),
+    })
+}
+
+/// Rewrites the program so that its entry derives only the rows having a derivation that uses
+/// at least one row of the rule named by [delta_symbol] of `full_base`. Reads of `base` are
+/// first redirected to the rule `full_base`. Then every rule depending on it gets a delta rule,
+/// with one variant for each body atom that may see new rows, as in semi-naive evaluation.
+/// Returns `false` if the entry does not depend on `base`.
+fn rewrite_to_delta(program: &mut InputProgram, base: &RelationHandle, full_base: &Symbol) -> bool {
+    for rules in program.prog.values_mut() {
+        if let InputInlineRulesOrFixed::Rules { rules } = rules {
+            for atom in rules.iter_mut().flat_map(|rule| rule.body.iter_mut()) {
+                redirect_base(atom, base, full_base);
+            }
+        }
+    }
+
+    let mut changed = BTreeSet::from([full_base.clone()]);
+    loop {
+        let mut grown = false;
+        for (name, rules) in &program.prog {
+            if let InputInlineRulesOrFixed::Rules { rules } = rules {
+                if !changed.contains(name)
+                    && rules
+                        .iter()
+                        .any(|rule| rule.body.iter().any(|a| count_changed(a, &changed) > 0))
+                {
+                    changed.insert(name.clone());
+                    grown = true;
+                }
+            }
+        }
+        if !grown {
+            break;
+        }
+    }
+    let entry = Symbol::new(PROG_ENTRY, Default::default());
+    if !changed.contains(&entry) {
+        return false;
+    }
+
+    let mut delta_rules = BTreeMap::new();
+    for (name, rules) in &program.prog {
+        let rules = match rules {
+            InputInlineRulesOrFixed::Rules { rules } if changed.contains(name) => rules,
+            _ => continue,
+        };
+        let mut variants = vec![];
+        for rule in rules {
+            let n_changed: usize = rule.body.iter().map(|a| count_changed(a, &changed)).sum();
+            for target in 0..n_changed {
+                let mut seen = 0;
+                let mut body = rule
+                    .body
+                    .iter()
+                    .map(|a| to_delta(a, &changed, target, &mut seen))
+                    .collect_vec();
+                // evaluate the few changed rows first, binding the rest of the body
+                if let Some(pos) = body.iter().position(is_simple_delta) {
+                    let atom = body.remove(pos);
+                    body.insert(0, atom);
+                }
+                variants.push(InputInlineRule {
+                    head: rule.head.clone(),
+                    aggr: rule.aggr.clone(),
+                    body,
+                    span: rule.span,
+                });
+            }
+        }
+        delta_rules.insert(name.clone(), variants);
+    }
+    for (name, variants) in delta_rules {
+        let delta_name = if name.is_prog_entry() {
+            name
+        } else {
+            delta_symbol(&name)
+        };
+        program.prog.insert(
+            delta_name,
+            InputInlineRulesOrFixed::Rules { rules: variants },
+        );
+    }
+    true
+}
+
+/// The name of the rule holding the changed rows of a rule, which users cannot write.
+fn delta_symbol(name: &Symbol) -> Symbol {
+    Symbol::new(format!("Δ{}", name.name), name.span)
+}
+
+fn redirect_base(atom: &mut InputAtom, base: &RelationHandle, full_base: &Symbol) {
+    let (args, span) = match atom {
+        InputAtom::Relation { inner } if inner.name.name == base.name => {
+            (inner.args.clone(), inner.span)
+        }
+        InputAtom::NamedFieldRelation { inner } if inner.name.name == base.name => {
+            let args = base
+                .metadata
+                .keys
+                .iter()
+                .chain(base.metadata.non_keys.iter())
+                .map(|col| match inner.args.get(&col.name) {
+                    Some(arg) => arg.clone(),
+                    None => binding(&Symbol::new("_", inner.span)),
+                })
+                .collect_vec();
+            (args, inner.span)
+        }
+        InputAtom::Negation { inner, .. } => return redirect_base(inner, base, full_base),
+        InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+            for atom in inner.iter_mut() {
+                redirect_base(atom, base, full_base)
+            }
+            return;
+        }
+        _ => return,
+    };
+    *atom = InputAtom::Rule {
+        inner: InputRuleApplyAtom {
+            name: full_base.clone(),
+            args,
+            span,
+        },
+    };
+}
+
+fn count_changed(atom: &InputAtom, changed: &BTreeSet<Symbol>) -> usize {
+    match atom {
+        InputAtom::Rule { inner } if changed.contains(&inner.name) => 1,
+        InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+            inner.iter().map(|a| count_changed(a, changed)).sum()
+        }
+        _ => 0,
+    }
+}
+
+/// Replaces the `target`-th application of a changed rule by an application of its delta rule,
+/// `seen` counting the applications met so far.
+fn to_delta(
+    atom: &InputAtom,
+    changed: &BTreeSet<Symbol>,
+    target: usize,
+    seen: &mut usize,
+) -> InputAtom {
+    match atom {
+        InputAtom::Rule { inner } if changed.contains(&inner.name) => {
+            let hit = *seen == target;
+            *seen += 1;
+            if hit {
+                InputAtom::Rule {
+                    inner: InputRuleApplyAtom {
+                        name: delta_symbol(&inner.name),
+                        args: inner.args.clone(),
+                        span: inner.span,
+                    },
+                }
+            } else {
+                atom.clone()
+            }
+        }
+        InputAtom::Conjunction { inner, span } => InputAtom::Conjunction {
+            inner: inner
+                .iter()
+                .map(|a| to_delta(a, changed, target, seen))
+                .collect_vec(),
+            span: *span,
+        },
+        InputAtom::Disjunction { inner, .. } => {
+            // a derivation using the target goes through the branch holding it
+            for branch in inner {
+                let n = count_changed(branch, changed);
+                if (*seen..*seen + n).contains(&target) {
+                    return to_delta(branch, changed, target, seen);
+                }
+                *seen += n;
+            }
+            atom.clone()
+        }
+        _ => atom.clone(),
+    }
+}
+
+fn is_simple_delta(atom: &InputAtom) -> bool {
+    match atom {
+        InputAtom::Rule { inner } => {
+            inner.name.name.starts_with('Δ')
+                && inner
+                    .args
+                    .iter()
+                    .all(|arg| matches!(arg, Expr::Binding { .. } | Expr::Const { .. }))
+        }
+        _ => false,
+    }
+}
+
+fn binding(var: &Symbol) -> Expr {
+    Expr::Binding {
+        var: var.clone(),
+        tuple_pos: None,
+    }
+}
+
+fn rule_atom(name: &Symbol, args: Vec<Expr>) -> InputAtom {
+    InputAtom::Rule {
+        inner: InputRuleApplyAtom {
+            name: name.clone(),
+            args,
+            span: Default::default(),
+        },
+    }
+}
+
+fn plain_rule(head: Vec<Symbol>, atom: InputAtom) -> InputInlineRule {
+    InputInlineRule {
+        aggr: head.iter().map(|_| None).collect_vec(),
+        head,
+        body: vec![atom],
+        span: Default::default(),
+    }
+}
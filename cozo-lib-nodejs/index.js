@@ -154,6 +154,28 @@ class CozoDb {
     unregisterNamedRule(name) {
         return native.unregister_named_rule(this.db_id, name)
     }
+
+    registerFunction(name, arity, cb) {
+        return native.register_function(this.db_id, name, arity, async (ret_id, args) => {
+            let ret = undefined;
+            try {
+                ret = await cb(...args);
+            } catch (e) {
+                console.error(e);
+                native.respond_to_function_invocation(ret_id, '' + e, true);
+                return;
+            }
+            try {
+                native.respond_to_function_invocation(ret_id, ret, false);
+            } catch (e) {
+                console.error(e);
+            }
+        })
+    }
+
+    unregisterFunction(name) {
+        return native.unregister_function(this.db_id, name)
+    }
 }
 
 module.exports = {CozoDb: CozoDb}
@@ -9,9 +9,9 @@ use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
-use crossbeam::channel::Sender;
+use crossbeam::channel::{bounded, Sender};
 use lazy_static::lazy_static;
-use miette::{miette, Result};
+use miette::{miette, IntoDiagnostic, Result};
 use neon::prelude::*;
 use neon::types::buffer::TypedArray;
 use serde_json::json;
@@ -190,6 +190,12 @@ fn value2js<'a>(cx: &mut impl Context<'a>, val: &DataValue) -> JsResult<'a, JsVa
             target_l.as_value(cx)
         }
         DataValue::Json(JsonData(j)) => json2js(cx, j)?,
+        v @ (DataValue::Date(_)
+        | DataValue::DateTime(_)
+        | DataValue::Duration(_)
+        | DataValue::Geometry(_)
+        | DataValue::Record(_)) => json2js(cx, &serde_json::Value::from(v.clone()))?,
+        DataValue::Decimal(d) => cx.string(d.to_string()).as_value(cx),
     })
 }
 
@@ -271,6 +277,7 @@ struct Handles {
     dbs: Mutex<BTreeMap<u32, DbInstance>>,
     cb_idx: AtomicU32,
     current_cbs: Mutex<BTreeMap<u32, Sender<Result<NamedRows>>>>,
+    current_fn_cbs: Mutex<BTreeMap<u32, Sender<Result<DataValue>>>>,
     nxt_tx_id: AtomicU32,
     txs: Mutex<BTreeMap<u32, Arc<MultiTransaction>>>,
 }
@@ -776,6 +783,101 @@ fn unregister_named_rule(mut cx: FunctionContext) -> JsResult<JsBoolean> {
     Ok(cx.boolean(removed))
 }
 
+fn register_function(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let db = get_db!(cx);
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    let arity = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let callback = Arc::new(cx.argument::<JsFunction>(3)?.root(&mut cx));
+    let channel = cx.channel();
+    let (db2app_sender, db2app_receiver) = bounded(0);
+    let func = move |args: &[DataValue]| -> Result<DataValue> {
+        let (app2db_sender, app2db_receiver) = bounded(0);
+        db2app_sender
+            .send((args.to_vec(), app2db_sender))
+            .into_diagnostic()?;
+        app2db_receiver.recv().into_diagnostic()?
+    };
+    if let Err(err) = db.register_function(name, arity, func) {
+        let msg = cx.string(err.to_string());
+        return cx.throw(msg);
+    }
+    rayon::spawn(move || {
+        for (args, sender) in db2app_receiver {
+            let args: Vec<DataValue> = args;
+            let id = HANDLES.cb_idx.fetch_add(1, Ordering::AcqRel);
+            {
+                HANDLES.current_fn_cbs.lock().unwrap().insert(id, sender);
+            }
+            let cb = callback.clone();
+            channel.send(move |mut cx| {
+                let callback = cb.to_inner(&mut cx);
+                let args_js = cx.empty_array();
+                for (i, arg) in args.iter().enumerate() {
+                    let arg_js = value2js(&mut cx, arg)?;
+                    args_js.set(&mut cx, i as u32, arg_js)?;
+                }
+                let args_js = args_js.as_value(&mut cx);
+                let this = cx.undefined();
+                let ret_id = cx.number(id).as_value(&mut cx);
+                callback.call(&mut cx, this, vec![ret_id, args_js])?;
+
+                Ok(())
+            });
+        }
+    });
+
+    Ok(cx.undefined())
+}
+
+fn respond_to_function_invocation(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let ret_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    let sender = {
+        match HANDLES.current_fn_cbs.lock().unwrap().remove(&ret_id) {
+            None => {
+                let msg = cx.string("function invocation sender should only be used once");
+                return cx.throw(msg);
+            }
+            Some(s) => s,
+        }
+    };
+
+    let is_err = cx.argument::<JsBoolean>(2)?.value(&mut cx);
+    let payload = cx.argument::<JsValue>(1)?;
+    if is_err {
+        let msg = match payload.downcast::<JsString, _>(&mut cx) {
+            Ok(msg) => msg.value(&mut cx),
+            Err(_) => "Javascript function failed".to_string(),
+        };
+        let _ = sender.send(Err(miette!(msg)));
+        return Ok(cx.undefined());
+    }
+
+    let mut val = DataValue::Null;
+    if let Err(err) = js2value(&mut cx, payload, &mut val) {
+        let _ = sender.send(Err(miette!("Javascript function failed")));
+        return Err(err);
+    }
+    if let Err(err) = sender.send(Ok(val)) {
+        let msg = err.to_string();
+        let msg = cx.string(msg);
+        return cx.throw(msg);
+    }
+    Ok(cx.undefined())
+}
+
+fn unregister_function(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let db = get_db!(cx);
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    let removed = match db.unregister_function(&name) {
+        Ok(b) => b,
+        Err(msg) => {
+            let msg = cx.string(msg.to_string());
+            return cx.throw(msg);
+        }
+    };
+    Ok(cx.boolean(removed))
+}
+
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("open_db", open_db)?;
@@ -794,6 +896,12 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
         respond_to_named_rule_invocation,
     )?;
     cx.export_function("unregister_named_rule", unregister_named_rule)?;
+    cx.export_function("register_function", register_function)?;
+    cx.export_function(
+        "respond_to_function_invocation",
+        respond_to_function_invocation,
+    )?;
+    cx.export_function("unregister_function", unregister_function)?;
     cx.export_function("abort_tx", abort_tx)?;
     cx.export_function("commit_tx", commit_tx)?;
     cx.export_function("multi_transact", multi_transact)?;
@@ -7,10 +7,12 @@
  */
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
 
 use miette::{IntoDiagnostic, Report, Result};
 use pyo3::exceptions::PyException;
 use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
 use pyo3::types::{PyBool, PyByteArray, PyBytes, PyDict, PyList, PyString, PyTuple};
 use serde_json::json;
 
@@ -42,6 +44,15 @@ fn py_to_named_rows(ob: &PyAny) -> PyResult<NamedRows> {
     Ok(NamedRows::new(headers, rows))
 }
 
+/// Python's `decimal.Decimal`, imported on first use
+static DECIMAL_CLASS: GILOnceCell<PyObject> = GILOnceCell::new();
+
+fn decimal_class(py: Python<'_>) -> PyResult<&PyAny> {
+    DECIMAL_CLASS
+        .get_or_try_init(py, || Ok(py.import("decimal")?.getattr("Decimal")?.into()))
+        .map(|c| c.as_ref(py))
+}
+
 fn py_to_value(ob: &PyAny) -> PyResult<DataValue> {
     Ok(if ob.is_none() {
         DataValue::Null
@@ -49,6 +60,11 @@ fn py_to_value(ob: &PyAny) -> PyResult<DataValue> {
         DataValue::from(b.is_true())
     } else if let Ok(i) = ob.extract::<i64>() {
         DataValue::from(i)
+    } else if ob.is_instance(decimal_class(ob.py())?)? {
+        let s = ob.str()?.to_str()?;
+        DataValue::Decimal(
+            DecimalWrapper::from_str(s).map_err(|e| PyException::new_err(e.to_string()))?,
+        )
     } else if let Ok(f) = ob.extract::<f64>() {
         DataValue::from(f)
     } else if let Ok(s) = ob.extract::<String>() {
@@ -173,6 +189,18 @@ fn value_to_py(val: DataValue, py: Python<'_>) -> PyObject {
             }
         },
         DataValue::Json(JsonData(j)) => json_to_py(j, py),
+        v @ (DataValue::Date(_)
+        | DataValue::DateTime(_)
+        | DataValue::Duration(_)
+        | DataValue::Geometry(_)
+        | DataValue::Record(_)) => json_to_py(serde_json::Value::from(v), py),
+        DataValue::Decimal(d) => {
+            let s = d.to_string();
+            decimal_class(py)
+                .and_then(|c| c.call1((s.as_str(),)))
+                .map(|v| v.into_py(py))
+                .unwrap_or_else(|_| s.into_py(py))
+        }
     }
 }
 
@@ -301,6 +329,24 @@ impl CozoDbPy {
             Err(PyException::new_err(DB_CLOSED_MSG))
         }
     }
+    pub fn register_function(&self, name: String, arity: usize, callback: &PyAny) -> PyResult<()> {
+        if let Some(db) = &self.db {
+            let cb: Py<PyAny> = callback.into();
+            let func = move |args: &[DataValue]| -> Result<DataValue> {
+                Python::with_gil(|py| -> Result<DataValue> {
+                    let py_args = PyTuple::new(
+                        py,
+                        args.iter().map(|arg| value_to_py(arg.clone(), py)),
+                    );
+                    let res = cb.as_ref(py).call1(py_args).into_diagnostic()?;
+                    py_to_value(res).into_diagnostic()
+                })
+            };
+            db.register_function(name, arity, func).map_err(report2py)
+        } else {
+            Err(PyException::new_err(DB_CLOSED_MSG))
+        }
+    }
     pub fn unregister_callback(&self, id: u32) -> bool {
         if let Some(db) = &self.db {
             db.unregister_callback(id)
@@ -318,6 +364,16 @@ impl CozoDbPy {
             Ok(false)
         }
     }
+    pub fn unregister_function(&self, name: &str) -> PyResult<bool> {
+        if let Some(db) = &self.db {
+            match db.unregister_function(name) {
+                Ok(b) => Ok(b),
+                Err(err) => Err(PyException::new_err(err.to_string())),
+            }
+        } else {
+            Ok(false)
+        }
+    }
     pub fn export_relations(&self, py: Python<'_>, relations: Vec<String>) -> PyResult<PyObject> {
         if let Some(db) = &self.db {
             let res = match py.allow_threads(|| db.export_relations(relations.iter())) {